@@ -0,0 +1,19 @@
+//! Feeds arbitrary bytes to the dump parser, which must reject malformed data without panicking.
+//!
+//! This crate is not part of the main workspace. From the repository root, run the target with
+//! `cargo +nightly fuzz run dump`, or only check that it builds, which works on any toolchain, with
+//! `cargo build --manifest-path fuzz/Cargo.toml`.
+
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use rs7::lua::bytecode::{BigEndianBuffer, Dump, DumpOptions, LittleEndianBuffer};
+
+fuzz_target!(|data: &[u8]| {
+    // Both byte orders are tried regardless of the flags, so that either parser sees every input.
+    let data = Bytes::copy_from_slice(data);
+    _ = Dump::parse(LittleEndianBuffer(data.clone()), &DumpOptions::default());
+    _ = Dump::parse(BigEndianBuffer(data.clone()), &DumpOptions::default());
+    _ = Dump::parse_main_only(LittleEndianBuffer(data));
+});
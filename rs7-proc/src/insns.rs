@@ -54,9 +54,8 @@ where
     match attrs {
         Some(a) => {
             a.0.iter()
-                .find(|attr| attr.path.get_ident().unwrap().to_string() == key)
-                .map(parser)
-                .flatten()
+                .find(|attr| *attr.path.get_ident().unwrap() == key)
+                .and_then(parser)
         }
         None => None,
     }
@@ -145,12 +144,12 @@ where
     F: FnOnce(&Ident, Vec<&Ident>) -> proc_macro2::TokenStream,
 {
     let fields: Vec<_> = match &v.fields {
-        syn::Fields::Named(named) => (&named.named).iter().map(|f| f.ident.as_ref().unwrap()).collect(),
+        syn::Fields::Named(named) => named.named.iter().map(|f| f.ident.as_ref().unwrap()).collect(),
         _ => panic!("Bytecode instruction only supports named fields"),
     };
 
-    let has_bc = fields.iter().any(|f| f.to_string() == "b" || f.to_string() == "c");
-    let has_d = fields.iter().any(|f| f.to_string() == "d");
+    let has_bc = fields.iter().any(|f| *f == "b" || *f == "c");
+    let has_d = fields.iter().any(|f| *f == "d");
 
     assert!(
         !(has_d && has_bc),
@@ -186,7 +185,8 @@ pub fn bytecode_insn_impl(input: proc_macro2::TokenStream) -> proc_macro2::Token
     let instructions = variants
         .iter()
         .map(|v| {
-            let attrs = (&v.attrs)
+            let attrs = v
+                .attrs
                 .iter()
                 .filter(|a| a.path().is_ident("bytecode"))
                 .find_map(|a| match &a.meta {
@@ -218,11 +218,13 @@ pub fn bytecode_insn_impl(input: proc_macro2::TokenStream) -> proc_macro2::Token
     // Generate a collection of local functions
     let parsers = instructions.iter().map(|(v, _)| {
         generate_arm(v, |ident, fields| {
+            // See `lj_bc.h`: B occupies the most significant byte, and C shares its bits with the
+            // low half of D.
             let decoded_fields = fields.iter().map(|f| {
                 let expr = match f.to_string().as_str() {
                     "a" => quote! { ((insn >> 8) & 0xFF) as u8 },
-                    "b" => quote! { ((insn >> 16) & 0xFF) as u8 },
-                    "c" => quote! { ((insn >> 24) & 0xFF) as u8 },
+                    "b" => quote! { ((insn >> 24) & 0xFF) as u8 },
+                    "c" => quote! { ((insn >> 16) & 0xFF) as u8 },
                     "d" => quote! { ((insn >> 16) & 0xFFFF) as u16 },
                     other => panic!("Unknown field '{}': expected a, b, c, or d", other),
                 };
@@ -250,7 +252,7 @@ pub fn bytecode_insn_impl(input: proc_macro2::TokenStream) -> proc_macro2::Token
                     let function_name = syn::Ident::new(&function_name, ident.span());
 
                     quote! {
-                        #i => #function_name(insn),
+                        #i => Ok(#function_name(insn)),
                     }
                 })
             });
@@ -261,13 +263,13 @@ pub fn bytecode_insn_impl(input: proc_macro2::TokenStream) -> proc_macro2::Token
             let range_check = if start == end {
                 quote! { version >= #start }
             } else {
-                quote! { version >= #start && version < #end }
+                quote! { (#start..#end).contains(&version) }
             };
 
             if version.len() == 0 {
                 quote! {
                     if #range_check {
-                        panic!("Unsupported bytecode version {version}.")
+                        return Err(crate::lua::bytecode::DumpError::UnsupportedVersion(version));
                     }
                 }
             } else {
@@ -275,7 +277,7 @@ pub fn bytecode_insn_impl(input: proc_macro2::TokenStream) -> proc_macro2::Token
                     if #range_check {
                         return match insn & 0xFF {
                             #( #arms )*
-                            _ => panic!("Unknown bytecode instruction"),
+                            opcode => Err(crate::lua::bytecode::DumpError::UnknownOpcode { opcode: opcode as u8, version }),
                         };
                     }
                 }
@@ -283,6 +285,55 @@ pub fn bytecode_insn_impl(input: proc_macro2::TokenStream) -> proc_macro2::Token
         })
         .rev();
 
+    // An instruction's opcode is its position in the range of versions being encoded for.
+    let opcodes = versions
+        .iter()
+        .rev()
+        .flat_map(|version| {
+            let start = version.start;
+            let end = version.end;
+
+            let range_check = if start == end {
+                quote! { version >= #start }
+            } else {
+                quote! { (#start..#end).contains(&version) }
+            };
+
+            (0u8..)
+                .zip(version.instructions(&instructions))
+                .map(move |(opcode, v)| {
+                    let range_check = range_check.clone();
+                    generate_arm(v, |ident, _| quote! { Self::#ident { .. } if #range_check => Some(#opcode), })
+                })
+        })
+        .collect::<Vec<_>>();
+
+    // See `lj_bc.h` and the decoder above for the operand layout.
+    let encoders = instructions.iter().map(|(v, _)| {
+        generate_arm(v, |ident, fields| {
+            let encoded_fields = fields.iter().map(|f| {
+                let shift = match f.to_string().as_str() {
+                    "a" => 8u32,
+                    "b" => 24u32,
+                    "c" => 16u32,
+                    "d" => 16u32,
+                    other => panic!("Unknown field '{}': expected a, b, c, or d", other),
+                };
+
+                quote! { ((#f as u32) << #shift) }
+            });
+
+            quote! { Self::#ident { #(#fields),* } => #(#encoded_fields)|*, }
+        })
+    });
+
+    let mnemonics = instructions.iter().map(|(v, _)| {
+        generate_arm(v, |ident, _| {
+            let mnemonic = ident.to_string();
+            quote! { Self::#ident { .. } => #mnemonic, }
+        })
+    });
+
     quote! {
         use crate::lua::bytecode::EndianBuffer;
 
@@ -293,13 +344,63 @@ pub fn bytecode_insn_impl(input: proc_macro2::TokenStream) -> proc_macro2::Token
             ///
             /// * `data` - The instruction data to parse.
             /// * `version` - The bytecode version.
-            pub fn new<B: Buf>(data: &mut impl EndianBuffer<B>, version: u8) -> Self {
+            pub fn new<B: Buf>(data: &mut impl EndianBuffer<B>, version: u8) -> Result<Self, crate::lua::bytecode::DumpError> {
+                if data.remaining() < 4 {
+                    return Err(crate::lua::bytecode::DumpError::Truncated { needed: 4, remaining: data.remaining() });
+                }
                 let insn = data.read_u32();
 
                 #( #parsers )*
 
                 #( #implementations )*
-                panic!("Bytecode version {version} is not supported");
+                Err(crate::lua::bytecode::DumpError::UnsupportedVersion(version))
+            }
+
+            /// Returns the name of this instruction, as found in `lj_bc.h`.
+            pub fn mnemonic(&self) -> &'static str {
+                match self {
+                    #( #mnemonics )*
+                }
+            }
+
+            /// Returns the opcode of this instruction in the given bytecode version, or `None` if that
+            /// version does not have this instruction.
+            pub fn opcode(&self, version: u8) -> Option<u8> {
+                match self {
+                    #( #opcodes )*
+                    _ => None,
+                }
+            }
+
+            /// Returns the operands of this instruction, laid out as in its 32-bit word, with the opcode
+            /// bits cleared.
+            pub fn operands(&self) -> u32 {
+                match *self {
+                    #( #encoders )*
+                }
+            }
+
+            /// Returns the 32-bit word encoding this instruction in the given bytecode version, or `None`
+            /// if that version does not have this instruction.
+            pub fn word(&self, version: u8) -> Option<u32> {
+                self.opcode(version).map(|opcode| opcode as u32 | self.operands())
+            }
+
+            /// Writes this instruction to a buffer.
+            ///
+            /// # Arguments
+            ///
+            /// * `out` - The buffer to write to.
+            /// * `version` - The bytecode version to encode for.
+            /// * `big_endian` - Whether to write the word in big-endian order.
+            pub fn encode(&self, out: &mut impl ::bytes::BufMut, version: u8, big_endian: bool) -> Result<(), crate::lua::bytecode::DumpError> {
+                let word = self.word(version).ok_or(crate::lua::bytecode::DumpError::UnsupportedInstruction { mnemonic: self.mnemonic(), version })?;
+                if big_endian {
+                    out.put_u32(word);
+                } else {
+                    out.put_u32_le(word);
+                }
+                Ok(())
             }
         }
     }
@@ -353,17 +454,20 @@ mod tests {
                 #[doc = r""]
                 #[doc = r" * `data` - The instruction data to parse."]
                 #[doc = r" * `version` - The bytecode version."]
-                pub fn new<B: Buf>(data: &mut impl EndianBuffer<B>, version: u8) -> Self {
+                pub fn new<B: Buf>(data: &mut impl EndianBuffer<B>, version: u8) -> Result<Self, crate::lua::bytecode::DumpError> {
+                    if data.remaining() < 4 {
+                        return Err(crate::lua::bytecode::DumpError::Truncated { needed: 4, remaining: data.remaining() });
+                    }
                     let insn = data.read_u32();
 
                     #[inline] fn parse_a(insn: u32) -> Instruction {
                         Instruction::A { a: ((insn >> 8) & 0xFF) as u8, }
                     }
                     #[inline] fn parse_b(insn: u32) -> Instruction {
-                        Instruction::B { b: ((insn >> 16) & 0xFF) as u8, }
+                        Instruction::B { b: ((insn >> 24) & 0xFF) as u8, }
                     }
                     #[inline] fn parse_c(insn: u32) -> Instruction {
-                        Instruction::C { c: ((insn >> 24) & 0xFF) as u8, }
+                        Instruction::C { c: ((insn >> 16) & 0xFF) as u8, }
                     }
                     #[inline] fn parse_d(insn: u32) -> Instruction {
                         Instruction::D { d: ((insn >> 16) & 0xFFFF) as u16, }
@@ -377,31 +481,96 @@ mod tests {
 
                     if version >= 4u8 {
                         return match insn & 0xFF {
-                            0u32 => parse_a(insn),
-                            1u32 => parse_b(insn),
-                            2u32 => parse_ad(insn),
-                            _ => panic!("Unknown bytecode instruction"),
+                            0u32 => Ok(parse_a(insn)),
+                            1u32 => Ok(parse_b(insn)),
+                            2u32 => Ok(parse_ad(insn)),
+                            opcode => Err(crate::lua::bytecode::DumpError::UnknownOpcode { opcode: opcode as u8, version }),
                         };
                     }
-                    if version >= 2u8 && version < 4u8 {
+                    if (2u8..4u8).contains(&version) {
                         return match insn & 0xFF {
-                            0u32 => parse_a(insn),
-                            1u32 => parse_b(insn),
-                            2u32 => parse_c(insn),
-                            3u32 => parse_ad(insn),
-                            _ => panic!("Unknown bytecode instruction"),
+                            0u32 => Ok(parse_a(insn)),
+                            1u32 => Ok(parse_b(insn)),
+                            2u32 => Ok(parse_c(insn)),
+                            3u32 => Ok(parse_ad(insn)),
+                            opcode => Err(crate::lua::bytecode::DumpError::UnknownOpcode { opcode: opcode as u8, version }),
                         };
                     }
-                    if version >= 1u8 && version < 2u8 {
+                    if (1u8..2u8).contains(&version) {
                         return match insn & 0xFF {
-                            0u32 => parse_a(insn),
-                            1u32 => parse_c(insn),
-                            2u32 => parse_d(insn),
-                            3u32 => parse_ad(insn),
-                            _ => panic!("Unknown bytecode instruction"),
+                            0u32 => Ok(parse_a(insn)),
+                            1u32 => Ok(parse_c(insn)),
+                            2u32 => Ok(parse_d(insn)),
+                            3u32 => Ok(parse_ad(insn)),
+                            opcode => Err(crate::lua::bytecode::DumpError::UnknownOpcode { opcode: opcode as u8, version }),
                         };
                     }
-                    panic!("Bytecode version {version} is not supported");
+                    Err(crate::lua::bytecode::DumpError::UnsupportedVersion(version))
+                }
+
+                #[doc = r" Returns the name of this instruction, as found in `lj_bc.h`."]
+                pub fn mnemonic(&self) -> &'static str {
+                    match self {
+                        Self::A { .. } => "A",
+                        Self::B { .. } => "B",
+                        Self::C { .. } => "C",
+                        Self::D { .. } => "D",
+                        Self::AD { .. } => "AD",
+                    }
+                }
+
+                #[doc = r" Returns the opcode of this instruction in the given bytecode version, or `None` if that"]
+                #[doc = r" version does not have this instruction."]
+                pub fn opcode(&self, version: u8) -> Option<u8> {
+                    match self {
+                        Self::A { .. } if version >= 4u8 => Some(0u8),
+                        Self::B { .. } if version >= 4u8 => Some(1u8),
+                        Self::AD { .. } if version >= 4u8 => Some(2u8),
+                        Self::A { .. } if (2u8..4u8).contains(&version) => Some(0u8),
+                        Self::B { .. } if (2u8..4u8).contains(&version) => Some(1u8),
+                        Self::C { .. } if (2u8..4u8).contains(&version) => Some(2u8),
+                        Self::AD { .. } if (2u8..4u8).contains(&version) => Some(3u8),
+                        Self::A { .. } if (1u8..2u8).contains(&version) => Some(0u8),
+                        Self::C { .. } if (1u8..2u8).contains(&version) => Some(1u8),
+                        Self::D { .. } if (1u8..2u8).contains(&version) => Some(2u8),
+                        Self::AD { .. } if (1u8..2u8).contains(&version) => Some(3u8),
+                        _ => None,
+                    }
+                }
+
+                #[doc = r" Returns the operands of this instruction, laid out as in its 32-bit word, with the opcode"]
+                #[doc = r" bits cleared."]
+                pub fn operands(&self) -> u32 {
+                    match *self {
+                        Self::A { a } => ((a as u32) << 8u32),
+                        Self::B { b } => ((b as u32) << 24u32),
+                        Self::C { c } => ((c as u32) << 16u32),
+                        Self::D { d } => ((d as u32) << 16u32),
+                        Self::AD { a, d } => ((a as u32) << 8u32) | ((d as u32) << 16u32),
+                    }
+                }
+
+                #[doc = r" Returns the 32-bit word encoding this instruction in the given bytecode version, or `None`"]
+                #[doc = r" if that version does not have this instruction."]
+                pub fn word(&self, version: u8) -> Option<u32> {
+                    self.opcode(version).map(|opcode| opcode as u32 | self.operands())
+                }
+
+                #[doc = r" Writes this instruction to a buffer."]
+                #[doc = r""]
+                #[doc = r" # Arguments"]
+                #[doc = r""]
+                #[doc = r" * `out` - The buffer to write to."]
+                #[doc = r" * `version` - The bytecode version to encode for."]
+                #[doc = r" * `big_endian` - Whether to write the word in big-endian order."]
+                pub fn encode(&self, out: &mut impl ::bytes::BufMut, version: u8, big_endian: bool) -> Result<(), crate::lua::bytecode::DumpError> {
+                    let word = self.word(version).ok_or(crate::lua::bytecode::DumpError::UnsupportedInstruction { mnemonic: self.mnemonic(), version })?;
+                    if big_endian {
+                        out.put_u32(word);
+                    } else {
+                        out.put_u32_le(word);
+                    }
+                    Ok(())
                 }
             }
         };
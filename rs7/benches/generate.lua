@@ -0,0 +1,52 @@
+-- Generates the Lua source compiled into fixtures/bench.lua.jit:
+--
+--   luajit benches/generate.lua > /tmp/bench.lua
+--   cd /tmp && luajit -bg bench.lua bench.lua.jit
+--
+-- Each chunk mixes the constructs found in real scripts: closures and upvalues, table templates,
+-- numeric and generic loops, string and numeric constants, method calls and varargs.
+
+local chunks = tonumber(arg[1]) or 2048
+
+local function emit(...)
+    io.write(string.format(...), "\n")
+end
+
+emit("local M = {}")
+for i = 1, chunks do
+    emit("do")
+    emit("    local count_%d, name_%d = 0, %q", i, i, "chunk " .. i)
+    emit("    local defaults = { id = %d, name = name_%d, scale = %.3f, tags = { %q, %q, %q }, enabled = %s }",
+        i, i, i / 7, "tag" .. i % 13, "tag" .. i % 17, "tag" .. i % 19, tostring(i % 2 == 0))
+    emit("    function M.new_%d(options)", i)
+    emit("        local self = setmetatable({}, { __index = defaults })")
+    emit("        for k, v in pairs(options or {}) do self[k] = v end")
+    emit("        count_%d = count_%d + 1", i, i)
+    emit("        return self")
+    emit("    end")
+    emit("    function M.sum_%d(t, ...)", i)
+    emit("        local total = %d", i)
+    emit("        for j = 1, #t do total = total + t[j] * %.5f end", 1 + i / 1000)
+    emit("        for _, v in ipairs({ ... }) do total = total - v end")
+    emit("        if total > %d and total < 0x%x then return total, %q end", i * 3, i * 4096, "large " .. i)
+    emit("        return total %% %d, nil", i % 97 + 2)
+    emit("    end")
+    emit("    function M.describe_%d(self)", i)
+    emit("        local parts = {}")
+    emit("        for k, v in pairs(self) do parts[#parts + 1] = tostring(k) .. %q .. tostring(v) end", "=")
+    emit("        table.sort(parts)")
+    emit("        return string.format(%q, name_%d, count_%d, table.concat(parts, %q))",
+        "%s (%d): %s", i, i, ", ")
+    emit("    end")
+    emit("    function M.step_%d(x, y)", i)
+    emit("        local n = 0")
+    emit("        while x ~= y and n < %d do", i % 50 + 10)
+    emit("            if x < y then x = x + %d else x = x - %d end", i % 7 + 1, i % 5 + 1)
+    emit("            n = n + 1")
+    emit("        end")
+    emit("        repeat y = y / 2 until y < %d", i % 11 + 1)
+    emit("        return function(z) return (x + y + z) ^ 2, n, count_%d end", i)
+    emit("    end")
+    emit("end")
+end
+emit("return M")
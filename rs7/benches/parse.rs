@@ -0,0 +1,86 @@
+//! Parsing throughput benchmarks.
+//!
+//! Run with `cargo bench`. The dump is compiled by LuaJIT from the output of `generate.lua`; the
+//! other inputs are generated in memory so that only parsing is measured.
+
+use std::hint::black_box;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use rs7::{
+    lua::bytecode::{Dump, DumpOptions, LittleEndianBuffer, read_string, read_string_ref},
+    utils::{ReadVar, WriteVar},
+};
+
+fn dump(c: &mut Criterion) {
+    let dump = Bytes::from_static(include_bytes!("../fixtures/bench.lua.jit"));
+    let options = DumpOptions::default();
+
+    let mut group = c.benchmark_group("dump");
+    group.throughput(Throughput::Bytes(dump.len() as u64));
+    group.bench_function("parse", |b| {
+        b.iter(|| Dump::parse(LittleEndianBuffer(black_box(dump.clone())), &options).unwrap())
+    });
+    group.bench_function("parse_main_only", |b| {
+        b.iter(|| Dump::parse_main_only(LittleEndianBuffer(black_box(dump.clone()))).unwrap())
+    });
+    group.finish();
+}
+
+fn read_leb(c: &mut Criterion) {
+    // A stream of varints of every length, as found in prototype headers and constants.
+    let mut varints = BytesMut::new();
+    for value in (0..1u64 << 20).map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (i % 64)) {
+        varints.write_leb(value);
+    }
+    let varints = varints.freeze();
+
+    let mut group = c.benchmark_group("varint");
+    group.throughput(Throughput::Bytes(varints.len() as u64));
+    group.bench_function("read_leb", |b| {
+        b.iter(|| {
+            let mut data = black_box(varints.clone());
+            while !data.is_empty() {
+                black_box(data.read_leb::<u64>());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn read_strings(c: &mut Criterion) {
+    // A stream of length-prefixed strings of up to 64 bytes, as found in string constants.
+    let mut strings = BytesMut::new();
+    for i in 0..1usize << 16 {
+        let value = format!("{:x}", i.wrapping_mul(0x9E37_79B9_7F4A_7C15)).repeat(i % 4 + 1);
+        strings.write_leb(value.len() as u64);
+        strings.put_slice(value.as_bytes());
+    }
+    let strings = strings.freeze();
+    let options = DumpOptions::default();
+
+    let mut group = c.benchmark_group("string");
+    group.throughput(Throughput::Bytes(strings.len() as u64));
+    group.bench_function("read_string", |b| {
+        b.iter(|| {
+            let mut data = black_box(strings.clone());
+            while !data.is_empty() {
+                let size = data.read_leb::<u64>() as usize;
+                black_box(read_string(&mut data, size, &options).unwrap());
+            }
+        })
+    });
+    group.bench_function("read_string_ref", |b| {
+        b.iter(|| {
+            let mut data = black_box(strings.clone());
+            while !data.is_empty() {
+                let size = data.read_leb::<u64>() as usize;
+                black_box(read_string_ref(&mut data, size, &options).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, dump, read_leb, read_strings);
+criterion_main!(benches);
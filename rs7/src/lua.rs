@@ -1,2 +1,4 @@
+pub mod ast;
 pub mod bytecode;
+pub mod disasm;
 pub mod ir;
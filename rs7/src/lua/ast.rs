@@ -0,0 +1,464 @@
+//! Lua source constructs, as reconstructed by the decompiler.
+
+use std::fmt::{self, Write};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Ne,
+    Eq,
+    Concat,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+}
+
+impl BinaryOp {
+    /// Returns the left and right binding power of this operator (see `priority` in `lj_parse.c`).
+    fn priority(&self) -> (u8, u8) {
+        match self {
+            Self::Or => (1, 1),
+            Self::And => (2, 2),
+            Self::Lt | Self::Gt | Self::Le | Self::Ge | Self::Ne | Self::Eq => (3, 3),
+            Self::Concat => (5, 4),
+            Self::Add | Self::Sub => (6, 6),
+            Self::Mul | Self::Div | Self::Mod => (7, 7),
+            Self::Pow => (10, 9),
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Self::Or => "or",
+            Self::And => "and",
+            Self::Lt => "<",
+            Self::Gt => ">",
+            Self::Le => "<=",
+            Self::Ge => ">=",
+            Self::Ne => "~=",
+            Self::Eq => "==",
+            Self::Concat => "..",
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Mod => "%",
+            Self::Pow => "^",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    Len,
+}
+
+/// Binding power of unary operators (`UNARY_PRIORITY` in `lj_parse.c`).
+const UNARY_PRIORITY: u8 = 8;
+
+/// Controls how expressions are rendered.
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Write integer literals in hexadecimal, e.g. `0x2A`.
+    pub hex_integers: bool,
+}
+
+/// An expression rendered with the given options, see `Expression::display`.
+pub struct Formatted<'a> {
+    expression: &'a Expression,
+    options: &'a FormatOptions,
+}
+
+impl fmt::Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.expression.write(f, 0, self.options)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Nil,
+    True,
+    False,
+    Integer(i64),
+    Number(f64),
+    /// A signed 64-bit integer literal of the FFI, e.g. `1LL`.
+    Int64(i64),
+    /// An unsigned 64-bit integer literal of the FFI, e.g. `1ULL`.
+    UInt64(u64),
+    /// A complex number of the FFI, with its real and imaginary parts.
+    ///
+    /// Only imaginary literals such as `2i` exist; a real part is written as an addition.
+    Complex(f64, f64),
+    /// The bytes of a string, which need not be valid UTF-8.
+    String(Vec<u8>),
+    Vararg,
+    /// An operand which has no Lua value, such as a malformed primitive; rendered as `nil`
+    /// followed by a comment holding its description.
+    Opaque(String),
+    Local(String),
+    Upvalue(String),
+    Global(String),
+    /// A closure of the prototype at the given index in the dump.
+    Function(usize),
+    Table {
+        array: Vec<Expression>,
+        hash: Vec<(Expression, Expression)>,
+    },
+    Index(Box<Expression>, Box<Expression>),
+    Call(Box<Expression>, Vec<Expression>),
+    Unary(UnaryOp, Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    pub fn unary(op: UnaryOp, value: Expression) -> Self {
+        Self::Unary(op, Box::new(value))
+    }
+
+    pub fn binary(op: BinaryOp, lhs: Expression, rhs: Expression) -> Self {
+        Self::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    pub fn index(table: Expression, key: Expression) -> Self {
+        Self::Index(Box::new(table), Box::new(key))
+    }
+
+    pub fn call(func: Expression, args: Vec<Expression>) -> Self {
+        Self::Call(Box::new(func), args)
+    }
+
+    /// Returns the logical negation of this expression, inverting comparisons and removing a
+    /// leading `not` rather than adding one.
+    ///
+    /// Like LuaJIT, this treats `not (a < b)` as `a >= b`, which does not hold for NaN.
+    pub fn negate(self) -> Self {
+        let inverse = |op| match op {
+            BinaryOp::Lt => Some(BinaryOp::Ge),
+            BinaryOp::Ge => Some(BinaryOp::Lt),
+            BinaryOp::Le => Some(BinaryOp::Gt),
+            BinaryOp::Gt => Some(BinaryOp::Le),
+            BinaryOp::Eq => Some(BinaryOp::Ne),
+            BinaryOp::Ne => Some(BinaryOp::Eq),
+            _ => None,
+        };
+
+        match self {
+            Self::True => Self::False,
+            Self::False => Self::True,
+            Self::Unary(UnaryOp::Not, value) => *value,
+            Self::Binary(op, lhs, rhs) => match inverse(op) {
+                Some(op) => Self::Binary(op, lhs, rhs),
+                None => Self::unary(UnaryOp::Not, Self::Binary(op, lhs, rhs)),
+            },
+            value => Self::unary(UnaryOp::Not, value),
+        }
+    }
+
+    /// Renders this expression with the given options.
+    pub fn display<'a>(&'a self, options: &'a FormatOptions) -> Formatted<'a> {
+        Formatted {
+            expression: self,
+            options,
+        }
+    }
+
+    /// Returns the priority of the operation a literal is spelled with, such as the negation in
+    /// `-1` or the division in `0/0`, if it is not atomic.
+    fn literal_priority(&self) -> Option<u8> {
+        match self {
+            Self::Integer(value) | Self::Int64(value) if *value < 0 => Some(UNARY_PRIORITY),
+            Self::Number(value) if value.is_nan() => Some(BinaryOp::Div.priority().0),
+            Self::Number(value) if value.is_sign_negative() => Some(UNARY_PRIORITY),
+            Self::Complex(real, imaginary) if real.to_bits() == 0 && imaginary.is_sign_negative() => {
+                Some(UNARY_PRIORITY)
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes this expression, parenthesized if it binds looser than `limit`.
+    fn write(&self, f: &mut fmt::Formatter<'_>, limit: u8, options: &FormatOptions) -> fmt::Result {
+        if self.literal_priority().is_some_and(|priority| priority < limit) {
+            f.write_char('(')?;
+            self.write(f, 0, options)?;
+            return f.write_char(')');
+        }
+
+        match self {
+            Self::Nil => f.write_str("nil"),
+            Self::True => f.write_str("true"),
+            Self::False => f.write_str("false"),
+            Self::Integer(value) if options.hex_integers => {
+                let sign = if *value < 0 { "-" } else { "" };
+                write!(f, "{}0x{:X}", sign, value.unsigned_abs())
+            }
+            Self::Integer(value) => write!(f, "{}", value),
+            Self::Number(value) => f.write_str(&format_number(*value)),
+            Self::Int64(value) => write!(f, "{}LL", value),
+            Self::UInt64(value) => write!(f, "{}ULL", value),
+            Self::Complex(real, imaginary) if real.to_bits() == 0 => write!(f, "{}i", format_number(*imaginary)),
+            Self::Complex(real, imaginary) => {
+                Self::binary(BinaryOp::Add, Self::Number(*real), Self::Complex(0.0, *imaginary))
+                    .write(f, limit, options)
+            }
+            Self::String(value) => f.write_str(&quote(value)),
+            Self::Vararg => f.write_str("..."),
+            Self::Opaque(description) => write!(f, "nil --[[ {} ]]", description),
+            Self::Local(name) | Self::Upvalue(name) | Self::Global(name) => f.write_str(name),
+            Self::Function(index) => write!(f, "function_{}", index),
+            Self::Table { array, hash } => {
+                let mut entries: Vec<String> = array.iter().map(|value| value.display(options).to_string()).collect();
+                entries.extend(
+                    hash.iter()
+                        .map(|(key, value)| format!("[{}] = {}", key.display(options), value.display(options))),
+                );
+                if entries.is_empty() {
+                    f.write_str("{}")
+                } else {
+                    write!(f, "{{ {} }}", entries.join(", "))
+                }
+            }
+            Self::Index(table, key) => {
+                table.write_prefix(f, options)?;
+                match &**key {
+                    Self::String(name) if str::from_utf8(name).is_ok_and(is_lua_identifier) => {
+                        write!(f, ".{}", String::from_utf8_lossy(name))
+                    }
+                    key => write!(f, "[{}]", key.display(options)),
+                }
+            }
+            Self::Call(func, args) => {
+                func.write_prefix(f, options)?;
+                let args: Vec<_> = args.iter().map(|arg| arg.display(options).to_string()).collect();
+                write!(f, "({})", args.join(", "))
+            }
+            Self::Unary(op, value) => {
+                let parenthesize = UNARY_PRIORITY < limit;
+                if parenthesize {
+                    f.write_char('(')?;
+                }
+
+                match op {
+                    // `--` starts a comment.
+                    UnaryOp::Neg
+                        if matches!(**value, Self::Unary(UnaryOp::Neg, _))
+                            || value.literal_priority() == Some(UNARY_PRIORITY) =>
+                    {
+                        f.write_str("- ")?
+                    }
+                    UnaryOp::Neg => f.write_char('-')?,
+                    UnaryOp::Not => f.write_str("not ")?,
+                    UnaryOp::Len => f.write_char('#')?,
+                }
+                value.write(f, UNARY_PRIORITY, options)?;
+
+                if parenthesize {
+                    f.write_char(')')?;
+                }
+                Ok(())
+            }
+            Self::Binary(op, lhs, rhs) => {
+                let (left, right) = op.priority();
+                let parenthesize = left.min(right) < limit;
+                if parenthesize {
+                    f.write_char('(')?;
+                }
+
+                // Operands of equal priority on the associative side do not need parentheses.
+                lhs.write(f, if left > right { left + 1 } else { left }, options)?;
+                write!(f, " {} ", op.symbol())?;
+                rhs.write(f, if left > right { right } else { right + 1 }, options)?;
+
+                if parenthesize {
+                    f.write_char(')')?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes this expression as the prefix of an index or a call.
+    fn write_prefix(&self, f: &mut fmt::Formatter<'_>, options: &FormatOptions) -> fmt::Result {
+        match self {
+            Self::Local(_) | Self::Upvalue(_) | Self::Global(_) | Self::Index(..) | Self::Call(..) => {
+                self.write(f, 0, options)
+            }
+            _ => write!(f, "({})", self.display(options)),
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0, &FormatOptions::default())
+    }
+}
+
+/// Formats a number as a Lua literal, spelling out values which have none.
+fn format_number(value: f64) -> String {
+    if value.is_nan() {
+        "0/0".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "math.huge" } else { "-math.huge" }.to_string()
+    } else {
+        // Both forms use the shortest digits that read back as the same value; the exponent is only
+        // spelled out for magnitudes that would otherwise take many digits.
+        let scientific = format!("{:e}", value);
+        let exponent = scientific.split_once('e').unwrap().1.parse::<i32>().unwrap();
+        if (-5..17).contains(&exponent) {
+            format!("{}", value)
+        } else {
+            scientific
+        }
+    }
+}
+
+/// The reserved words of LuaJIT, as listed by `TKDEF` in `lj_lex.h`.
+const RESERVED_WORDS: [&str; 22] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in", "local", "nil",
+    "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Returns `true` if `name` is a Lua name, which can be written as a field name, such as in `t.name`.
+///
+/// Reserved words are not names, so `t["end"]` cannot be written as `t.end`.
+pub fn is_lua_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !RESERVED_WORDS.contains(&name)
+}
+
+/// Quotes a string so that it reads back as the same value.
+///
+/// Bytes that are not valid UTF-8 are written as decimal escapes.
+fn quote(value: &[u8]) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for chunk in value.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c if c.is_ascii_control() => _ = write!(quoted, "\\{}", c as u8),
+                c => quoted.push(c),
+            }
+        }
+        chunk.invalid().iter().for_each(|byte| _ = write!(quoted, "\\{}", byte));
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::ast::{BinaryOp, Expression, FormatOptions, UnaryOp, is_lua_identifier};
+
+    fn local(name: &str) -> Expression {
+        Expression::Local(name.to_string())
+    }
+
+    #[test]
+    pub fn test_precedence() {
+        let sum = Expression::binary(BinaryOp::Add, local("a"), local("b"));
+        let product = Expression::binary(BinaryOp::Mul, sum.clone(), Expression::Integer(2));
+        assert_eq!(product.to_string(), "(a + b) * 2");
+
+        let difference = Expression::binary(BinaryOp::Sub, local("a"), sum.clone());
+        assert_eq!(difference.to_string(), "a - (a + b)");
+
+        let power = Expression::binary(
+            BinaryOp::Pow,
+            local("a"),
+            Expression::binary(BinaryOp::Pow, local("b"), local("c")),
+        );
+        assert_eq!(power.to_string(), "a ^ b ^ c");
+
+        // Exponentiation binds tighter than unary operators on its left only.
+        let negated = Expression::unary(UnaryOp::Neg, power);
+        assert_eq!(negated.to_string(), "-a ^ b ^ c");
+
+        let base = Expression::binary(BinaryOp::Pow, Expression::unary(UnaryOp::Neg, local("a")), local("b"));
+        assert_eq!(base.to_string(), "(-a) ^ b");
+
+        let index = Expression::index(sum, Expression::String(b"k\"".to_vec()));
+        assert_eq!(index.to_string(), "(a + b)[\"k\\\"\"]");
+    }
+
+    #[test]
+    pub fn test_field_keys() {
+        let field = |key: &str| Expression::index(local("t"), Expression::String(key.as_bytes().to_vec())).to_string();
+        assert_eq!(field("_name1"), "t._name1");
+        assert_eq!(field("foo bar"), "t[\"foo bar\"]");
+        assert_eq!(field("end"), "t[\"end\"]");
+        assert_eq!(field("1st"), "t[\"1st\"]");
+        assert_eq!(field(""), "t[\"\"]");
+
+        assert!(is_lua_identifier("ends"));
+        assert!(!is_lua_identifier("goto"));
+        assert!(!is_lua_identifier("caf\u{e9}"));
+    }
+
+    #[test]
+    pub fn test_numbers() {
+        let hex = FormatOptions { hex_integers: true };
+        assert_eq!(Expression::Integer(42).to_string(), "42");
+        assert_eq!(Expression::Integer(42).display(&hex).to_string(), "0x2A");
+        assert_eq!(Expression::Integer(-255).display(&hex).to_string(), "-0xFF");
+        assert_eq!(Expression::Number(0.5).display(&hex).to_string(), "0.5");
+
+        // A negative zero real part is kept, since `-0 + 2i` and `2i` are different values.
+        assert_eq!(Expression::Complex(0.0, 2.0).to_string(), "2i");
+        assert_eq!(Expression::Complex(-0.0, 2.0).to_string(), "-0 + 2i");
+
+        let numbers = [
+            (0.1 + 0.2, "0.30000000000000004"),
+            (2.0f64.powi(53) - 1.0, "9007199254740991"),
+            (f64::MAX, "1.7976931348623157e308"),
+            (1e-7, "1e-7"),
+            (-0.0, "-0"),
+        ];
+        for (value, expected) in numbers {
+            let rendered = Expression::Number(value).to_string();
+            assert_eq!(rendered, expected);
+            assert_eq!(rendered.parse::<f64>().unwrap().to_bits(), value.to_bits());
+        }
+    }
+
+    #[test]
+    pub fn test_literal_precedence() {
+        let x = || local("x");
+        let power = |base| Expression::binary(BinaryOp::Pow, base, x()).to_string();
+        assert_eq!(power(Expression::Integer(-2)), "(-2) ^ x");
+        assert_eq!(power(Expression::Number(-0.5)), "(-0.5) ^ x");
+        assert_eq!(power(Expression::Number(f64::NEG_INFINITY)), "(-math.huge) ^ x");
+        assert_eq!(power(Expression::Number(f64::NAN)), "(0/0) ^ x");
+        assert_eq!(power(Expression::Int64(-1)), "(-1LL) ^ x");
+        assert_eq!(power(Expression::Complex(0.0, -2.0)), "(-2i) ^ x");
+        assert_eq!(power(Expression::Integer(2)), "2 ^ x");
+
+        let nan = Expression::binary(BinaryOp::Div, x(), Expression::Number(f64::NAN));
+        assert_eq!(nan.to_string(), "x / (0/0)");
+        let nan = Expression::binary(BinaryOp::Sub, x(), Expression::Number(f64::NAN));
+        assert_eq!(nan.to_string(), "x - 0/0");
+
+        // Negative literals only need parentheses where a negation would.
+        let difference = Expression::binary(BinaryOp::Sub, x(), Expression::Integer(-1));
+        assert_eq!(difference.to_string(), "x - -1");
+        assert_eq!(Expression::unary(UnaryOp::Neg, Expression::Integer(-1)).to_string(), "- -1");
+    }
+}
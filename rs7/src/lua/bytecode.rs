@@ -1,14 +1,24 @@
 pub mod constant;
 pub mod debug;
 pub mod dump;
+#[cfg(test)]
+pub(crate) mod fixture;
 pub mod instruction;
 mod primitives;
 pub mod prototype;
 pub mod reader;
 pub mod table_item;
+pub mod validate;
+pub mod value;
+pub mod writer;
 
 pub use constant::*;
+pub use debug::Debug;
 pub use dump::*;
 pub use instruction::*;
+pub use primitives::{read_string, read_string_ref};
 pub use prototype::Prototype;
 pub use reader::*;
+pub use validate::ValidationError;
+pub use value::LuaValue;
+pub use writer::WriteOptions;
@@ -1,25 +1,43 @@
 use std::{
-    fmt,
+    borrow::Cow,
+    fmt::{self, Write},
     ops::{BitOr, Shl},
 };
 
-use bytes::Buf;
+use bytes::{Buf, Bytes};
 
 use crate::{
-    lua::bytecode::{primitives::read_string, table_item::TableItem},
-    utils::{ReadVar, bits::Bits},
+    lua::bytecode::{
+        DumpError, DumpOptions,
+        primitives::{ensure_remaining, read_string_ref},
+        table_item::TableItem,
+        writer::Writer,
+    },
+    utils::{LebError, ReadVar, bits::Bits},
 };
 
-pub fn read_parts<R, T>(data: &mut R) -> T
+/// Reads a 64-bit value stored as two 32-bit ULEB128 halves, low half first.
+///
+/// `bcread_kgc` reads the halves into the `lo` and `hi` fields of a `TValue`, which are laid out
+/// according to the host endianness; the value itself does not depend on the dump's endianness.
+pub fn read_parts<R, T>(data: &mut R) -> Result<T, DumpError>
 where
     R: Buf,
     T: From<u32> + Bits + Shl<u32, Output = T> + BitOr<Output = T>,
 {
-    let hi = data.read_leb();
-    let lo = data.read_leb();
-    (T::from(hi) << u32::BITS) | T::from(lo)
+    let lo = data.try_read_leb()?;
+    let hi = data.try_read_leb()?;
+    Ok((T::from(hi) << u32::BITS) | T::from(lo))
+}
+
+/// Writes a 64-bit value as two 32-bit ULEB128 halves, in the order `read_parts` expects.
+pub(crate) fn write_parts(out: &mut Writer, value: u64) {
+    out.put_uleb(value & 0xFFFF_FFFF);
+    out.put_uleb(value >> u32::BITS);
 }
 
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Complex {
     /// A reference to a prototype in the dump.
     ///
@@ -35,7 +53,9 @@ pub enum Complex {
         real: u64,
         imaginary: u64,
     },
-    String(String),
+    /// The bytes of a string, which need not be valid UTF-8. When parsing from a `Bytes`, they
+    /// share its buffer rather than being copied, see `read_string_ref`.
+    String(Bytes),
 }
 
 impl Complex {
@@ -46,70 +66,223 @@ impl Complex {
     /// # Arguments
     ///
     /// * `data` - The data source.
-    /// * `proto` - The index of the `Prototype` this constant belongs to.
-    pub fn new(data: &mut impl Buf, proto: usize) -> Self {
-        let tp = data.read_leb::<u32>() as usize;
+    /// * `children` - The indices of the prototypes read so far that are not yet referenced by
+    ///   another prototype, the most recent last.
+    /// * `options` - Limits on the data being read.
+    pub fn new(data: &mut impl Buf, children: &mut Vec<usize>, options: &DumpOptions) -> Result<Self, DumpError> {
+        let tp = data.try_read_leb::<u32>()? as usize;
 
-        match tp {
-            0 => Self::Prototype(proto - 1),
+        let constant = match tp {
+            // Children are written right before their parent, so each reference pops the most
+            // recent one.
+            0 => Self::Prototype(children.pop().ok_or(DumpError::MissingChild)?),
             1 => {
-                let narray = data.read_leb::<u32>() as usize;
-                let nhash = data.read_leb::<u32>() as usize;
+                let narray = data.try_read_leb::<u32>()? as usize;
+                let nhash = data.try_read_leb::<u32>()? as usize;
+
+                // Each array item takes at least a byte, and each hash entry two.
+                ensure_remaining(data, narray)?;
+                ensure_remaining(data, nhash.saturating_mul(2))?;
 
-                let array = (0..narray).map(|_| TableItem::new(data)).collect();
+                let array = (0..narray)
+                    .map(|_| TableItem::new(data, options))
+                    .collect::<Result<_, DumpError>>()?;
 
                 let entries = (0..nhash)
                     .map(|_| {
-                        let key = TableItem::new(data);
-                        let value = TableItem::new(data);
+                        let key = TableItem::new(data, options)?;
+                        let value = TableItem::new(data, options)?;
 
-                        (key, value)
+                        Ok((key, value))
                     })
-                    .collect();
+                    .collect::<Result<_, DumpError>>()?;
 
                 Self::Table { array, hash: entries }
             }
             2 => {
-                let value = read_parts(data);
+                let value = read_parts(data)?;
                 Complex::Signed(u64::cast_signed(value))
             }
-            3 => Complex::Unsigned(read_parts(data)),
+            3 => Complex::Unsigned(read_parts(data)?),
             4 => {
                 // Complex
-                let real = read_parts(data);
-                let imaginary = read_parts(data);
+                let real = read_parts(data)?;
+                let imaginary = read_parts(data)?;
 
                 Complex::Complex { real, imaginary }
             }
-            5.. => Complex::String(read_string(data, tp - 5)),
+            5.. => Complex::String(read_string_ref(data, tp - 5, options)?),
+        };
+
+        Ok(constant)
+    }
+
+    /// Writes this complex constant.
+    ///
+    /// This function is an implementation of LuaJIT's `bcwrite_kgc`.
+    pub(crate) fn write(&self, out: &mut Writer) {
+        match self {
+            Self::Prototype(_) => out.put_uleb(0),
+            Self::Table { array, hash } => {
+                out.put_uleb(1);
+                out.put_uleb(array.len() as u64);
+                out.put_uleb(hash.len() as u64);
+
+                array.iter().for_each(|item| item.write(out));
+                hash.iter().for_each(|(key, value)| {
+                    key.write(out);
+                    value.write(out);
+                });
+            }
+            Self::Signed(value) => {
+                out.put_uleb(2);
+                write_parts(out, i64::cast_unsigned(*value));
+            }
+            Self::Unsigned(value) => {
+                out.put_uleb(3);
+                write_parts(out, *value);
+            }
+            Self::Complex { real, imaginary } => {
+                out.put_uleb(4);
+                write_parts(out, *real);
+                write_parts(out, *imaginary);
+            }
+            Self::String(value) => {
+                out.put_uleb(5 + value.len() as u64);
+                out.put_bytes(value);
+            }
         }
     }
+
+    /// Returns the text of this string constant, replacing bytes that are not valid UTF-8 with
+    /// `U+FFFD`, or `None` if this is not a string.
+    pub fn to_str_lossy(&self) -> Option<Cow<'_, str>> {
+        match self {
+            Self::String(value) => Some(String::from_utf8_lossy(value)),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of entries in the array part of this template table, or `None` if this
+    /// is not a table.
+    ///
+    /// The array part starts at index 0, so this is one more than the largest array index.
+    pub fn array_len(&self) -> Option<usize> {
+        match self {
+            Self::Table { array, .. } => Some(array.len()),
+            _ => None,
+        }
+    }
+
+    /// Returns the value stored at `idx` in the array part of this template table.
+    ///
+    /// Returns `None` if this is not a table or if `idx` is out of bounds; slots the constructor
+    /// left unset, such as index 0 of a table built from a list, hold `TableItem::Nil`.
+    pub fn get_array(&self, idx: usize) -> Option<&TableItem> {
+        match self {
+            Self::Table { array, .. } => array.get(idx),
+            _ => None,
+        }
+    }
+
+    /// Returns the value associated with `key` in the hash part of this template table.
+    ///
+    /// Keys are compared the way Lua compares them; see `TableItem`. Returns `None` if this is not a table or if the key is absent.
+    pub fn get_hash(&self, key: &TableItem) -> Option<&TableItem> {
+        match self {
+            Self::Table { hash, .. } => hash.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+/// A numeric constant.
+///
+/// Dumps tell integer constants apart from double constants; both are kept as raw bits so that
+/// no information is lost.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Numeric {
+    /// The bits of an IEEE 754 double if `is_number` is set; a 32-bit signed integer otherwise.
+    pub bits: u64,
+    pub is_number: bool,
 }
 
-pub struct Numeric(pub u64);
+/// The decoded value of a `Numeric` constant.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NumericValue {
+    Integer(i32),
+    Number(f64),
+}
 
 impl Numeric {
-    pub fn new(data: &mut impl Buf) -> Self {
-        let (is_number, lo) = bcread_uleb128_33(data);
+    /// Creates a new numeric constant.
+    ///
+    /// This function is an implementation of LuaJIT's `bcread_knum`.
+    pub fn new(data: &mut impl Buf) -> Result<Self, DumpError> {
+        let (is_number, lo) = bcread_uleb128_33(data)?;
         if is_number {
-            let hi = data.read_leb::<u32>();
+            let hi = data.try_read_leb::<u32>()?;
             let value = ((hi as u64) << u32::BITS) | (lo as u64);
 
-            Self(value)
+            Ok(Self::number(value))
+        } else {
+            Ok(Self::integer(u32::cast_signed(lo)))
+        }
+    }
+
+    /// Creates a double constant from its raw bits.
+    pub fn number(bits: u64) -> Self {
+        Self { bits, is_number: true }
+    }
+
+    /// Creates an integer constant.
+    pub fn integer(value: i32) -> Self {
+        Self {
+            bits: i32::cast_unsigned(value) as u64,
+            is_number: false,
+        }
+    }
+
+    /// Writes this numeric constant.
+    ///
+    /// This function is an implementation of LuaJIT's `bcwrite_knum`.
+    pub(crate) fn write(&self, out: &mut Writer) {
+        // The 33-bit ULEB128 is a regular ULEB128 of the low half, shifted left by the number tag.
+        out.put_uleb(((self.bits & 0xFFFF_FFFF) << 1) | self.is_number as u64);
+        if self.is_number {
+            out.put_uleb(self.bits >> u32::BITS);
+        }
+    }
+
+    /// Decodes the value of this constant.
+    pub fn value(&self) -> NumericValue {
+        if self.is_number {
+            NumericValue::Number(f64::from_bits(self.bits))
         } else {
-            Self(lo as u64)
+            NumericValue::Integer(u32::cast_signed(self.bits as u32))
+        }
+    }
+}
+
+/// Formats the decoded value of this constant, as LuaJIT's `tostring` would for doubles.
+impl fmt::Display for Numeric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value() {
+            NumericValue::Integer(value) => write!(f, "{}", value),
+            NumericValue::Number(value) => write!(f, "{}", format_number(value)),
         }
     }
 }
 
 impl fmt::Debug for Numeric {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:#?}", &self.0)
+        fmt::Display::fmt(self, f)
     }
 }
 
-fn bcread_uleb128_33<R: Buf>(pp: &mut R) -> (bool, u32) {
-    let mut buffer = pp.get_u8() as u32;
+fn bcread_uleb128_33<R: Buf>(pp: &mut R) -> Result<(bool, u32), DumpError> {
+    let mut buffer = pp.try_get_u8()? as u32;
     let is_number_bit = (buffer & 0b01) != 0;
 
     let mut value = buffer >> 1;
@@ -118,8 +291,11 @@ fn bcread_uleb128_33<R: Buf>(pp: &mut R) -> (bool, u32) {
         value &= 0x3F;
 
         loop {
-            assert!(shift < u32::BITS, "Parsing too much 33-bits uleb128");
-            buffer = pp.get_u8() as u32;
+            if shift >= u32::BITS {
+                return Err(DumpError::Leb(LebError::Overlong));
+            }
+
+            buffer = pp.try_get_u8()? as u32;
             value |= (buffer & 0x7F) << shift;
             shift += 7;
 
@@ -129,7 +305,7 @@ fn bcread_uleb128_33<R: Buf>(pp: &mut R) -> (bool, u32) {
         }
     }
 
-    (is_number_bit, value)
+    Ok((is_number_bit, value))
 }
 
 impl fmt::Debug for Complex {
@@ -148,7 +324,199 @@ impl fmt::Debug for Complex {
                 .field("real", real)
                 .field("imaginary", imaginary)
                 .finish(),
-            Self::String(value) => write!(f, "{:#?}", value),
+            Self::String(value) => write!(f, "{:#?}", String::from_utf8_lossy(value)),
+        }
+    }
+}
+
+/// Formats a number the way LuaJIT's `tostring` does (`%.14g`).
+pub(crate) fn format_number(value: f64) -> String {
+    const PRECISION: i32 = 14;
+
+    if value.is_nan() {
+        return "nan".to_string();
+    } else if value.is_infinite() {
+        return if value > 0.0 { "inf" } else { "-inf" }.to_string();
+    } else if value == 0.0 {
+        return if value.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+
+    let trim = |s: String| {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            s
         }
+    };
+
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent = exponent.parse::<i32>().unwrap();
+
+    if !(-4..PRECISION).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa.to_string()), sign, exponent.abs())
+    } else {
+        trim(format!("{:.*}", (PRECISION - 1 - exponent) as usize, value))
+    }
+}
+
+/// Quotes a string constant, escaping control characters and bytes that are not valid UTF-8, and
+/// truncating long strings.
+pub(crate) fn format_string(value: &[u8]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for chunk in value.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                c if c.is_ascii_control() => _ = write!(escaped, "\\{:03}", c as u8),
+                c => escaped.push(c),
+            }
+        }
+        chunk
+            .invalid()
+            .iter()
+            .for_each(|byte| _ = write!(escaped, "\\{:03}", byte));
+    }
+
+    if value.len() > 40 {
+        format!("\"{}\"~", escaped.chars().take(40).collect::<String>())
+    } else {
+        format!("\"{}\"", escaped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::lua::bytecode::{
+        BigEndianBuffer, Complex, Dump, LittleEndianBuffer, Numeric,
+        fixture::{self, Kgc, Ktab, Proto, ad},
+        format_number, format_string,
+        table_item::TableItem,
+    };
+
+    #[test]
+    pub fn test_shared_strings() {
+        // return "hello", { "x" }
+        let main = Proto {
+            instructions: vec![ad("KSTR", 0, 1), ad("TDUP", 1, 0), ad("RET", 0, 3)],
+            kgc: vec![Kgc::Str(b"hello"), Kgc::Table(vec![Ktab::Nil, Ktab::Str("x")], vec![])],
+            ..Default::default()
+        };
+
+        // Strings are slices of the buffer the dump is parsed from.
+        let bytes = fixture::Dump::stripped(vec![main]).build();
+        let dump = Dump::new(LittleEndianBuffer(bytes.clone()));
+        let input = bytes.as_ptr_range();
+
+        let Complex::String(value) = &dump.main().kgc[0] else {
+            panic!("Expected a string");
+        };
+        assert_eq!(value, &b"hello"[..]);
+        assert!(input.contains(&value.as_ptr()));
+
+        let Complex::Table { array, .. } = &dump.main().kgc[1] else {
+            panic!("Expected a table");
+        };
+        let TableItem::String(value) = &array[1] else {
+            panic!("Expected a string");
+        };
+        assert_eq!(value, &b"x"[..]);
+        assert!(input.contains(&value.as_ptr()));
+    }
+
+    #[test]
+    pub fn test_table_lookup() {
+        // `{ 10, "x", k = 1.5, [-1] = true }`
+        let main = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Table(
+                vec![Ktab::Nil, Ktab::Int(10), Ktab::Str("x")],
+                vec![(Ktab::Str("k"), Ktab::Num(1.5)), (Ktab::Int(-1), Ktab::True)],
+            )],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let table = &dump.main().kgc[0];
+
+        assert_eq!(table.array_len(), Some(3));
+        assert!(matches!(table.get_array(0), Some(TableItem::Nil)));
+        assert!(matches!(table.get_array(1), Some(TableItem::Integer(10))));
+        assert!(matches!(table.get_array(2), Some(TableItem::String(value)) if value == &b"x"[..]));
+        assert!(table.get_array(3).is_none());
+
+        let k = TableItem::String(Bytes::from_static(b"k"));
+        assert!(matches!(table.get_hash(&k), Some(TableItem::Numeric(value)) if value.to_string() == "1.5"));
+        assert!(matches!(table.get_hash(&TableItem::Integer(-1)), Some(TableItem::True)));
+        assert!(table.get_hash(&TableItem::Integer(1)).is_none());
+
+        let string = Complex::String(Bytes::from_static(b"k"));
+        assert_eq!(string.array_len(), None);
+        assert!(string.get_hash(&k).is_none());
+    }
+
+    #[test]
+    pub fn test_numeric_display() {
+        assert_eq!(Numeric::integer(42).to_string(), "42");
+        assert_eq!(Numeric::integer(-7).to_string(), "-7");
+        assert_eq!(Numeric::number(2.75f64.to_bits()).to_string(), "2.75");
+        assert_eq!(format!("{:?}", Numeric::number(0.5f64.to_bits())), "0.5");
+    }
+
+    #[test]
+    pub fn test_split_constants() {
+        let main = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            kgc: vec![Kgc::I64(-5), Kgc::U64(0x1234_5678_9ABC_DEF0), Kgc::Complex(1.5, -2.0)],
+            ..Default::default()
+        };
+
+        for flags in [0, 1] {
+            let bytes = fixture::Dump {
+                flags,
+                protos: vec![main.clone()],
+                ..Default::default()
+            }
+            .build();
+
+            let dump = if flags == 1 {
+                Dump::new(BigEndianBuffer(bytes))
+            } else {
+                Dump::new(LittleEndianBuffer(bytes))
+            };
+
+            let kgc = &dump.main().kgc;
+            assert!(matches!(kgc[0], Complex::Signed(-5)));
+            assert!(matches!(kgc[1], Complex::Unsigned(0x1234_5678_9ABC_DEF0)));
+            let Complex::Complex { real, imaginary } = kgc[2] else {
+                panic!("Expected a complex number");
+            };
+            assert_eq!((f64::from_bits(real), f64::from_bits(imaginary)), (1.5, -2.0));
+        }
+    }
+
+    #[test]
+    pub fn test_format_number() {
+        assert_eq!(format_number(1.0), "1");
+        assert_eq!(format_number(1.5), "1.5");
+        assert_eq!(format_number(-0.25), "-0.25");
+        assert_eq!(format_number(0.1), "0.1");
+        assert_eq!(format_number(1e15), "1e+15");
+        assert_eq!(format_number(1e-5), "1e-05");
+        assert_eq!(format_number(123456789012345.0), "1.2345678901234e+14");
+        assert_eq!(format_number(f64::INFINITY), "inf");
+    }
+
+    #[test]
+    pub fn test_format_string() {
+        assert_eq!(format_string(b"hello"), "\"hello\"");
+        assert_eq!(format_string(b"a\tb\n\x01"), "\"a\\tb\\n\\001\"");
+        assert_eq!(format_string(b"\xFF\xC3\xA9"), "\"\\255\u{E9}\"");
+        assert_eq!(format_string("x".repeat(41).as_bytes()), format!("\"{}\"~", "x".repeat(40)));
     }
 }
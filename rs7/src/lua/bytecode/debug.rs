@@ -1,18 +1,26 @@
-use std::fmt;
+use std::{borrow::Cow, fmt};
 
 use bytes::Buf;
 
-use crate::lua::bytecode::{EndianBuffer, primitives::read_cstring};
+use crate::lua::bytecode::{
+    DumpError, EndianBuffer,
+    primitives::{ensure_remaining, read_cstring},
+    writer::Writer,
+};
 
 pub mod variable {
-    use std::{fmt, ops::Range};
+    use std::{borrow::Cow, fmt, ops::Range};
 
     use bytes::Buf;
 
-    use crate::{lua::bytecode::primitives::read_cstring, utils::ReadVar};
+    use crate::{
+        lua::bytecode::{DumpError, primitives::read_cstring, writer::Writer},
+        utils::ReadVar,
+    };
 
     #[repr(u8)]
-    #[derive(Debug)]
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
     pub enum Type {
         End = 0,
         ForIdx = 1,
@@ -24,9 +32,9 @@ pub mod variable {
         String = 7,
     }
 
-    impl Into<u8> for Type {
-        fn into(self) -> u8 {
-            return self as u8;
+    impl From<Type> for u8 {
+        fn from(value: Type) -> Self {
+            value as u8
         }
     }
 
@@ -45,37 +53,50 @@ pub mod variable {
         }
     }
 
+    #[derive(Clone, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
     pub struct Variable {
-        pub name: String,
+        /// The bytes of the name, which need not be valid UTF-8; empty for internal variables.
+        pub name: Vec<u8>,
         pub tp: Type,
+        /// The range of bytecode positions this variable is live in, which are indices into
+        /// `Prototype::instructions`: position 0 is the function header.
         pub scope: Range<u32>,
     }
 
     impl Variable {
-        pub fn new<R>(data: &mut R, tp: u8) -> Self
+        /// Parses a variable.
+        ///
+        /// # Arguments
+        ///
+        /// * `data` - The data to parse.
+        /// * `tp` - The variable type, which was already read.
+        /// * `last_start` - The start of the scope of the previous variable, which scopes are relative to.
+        pub fn new<R>(data: &mut R, tp: u8, last_start: u32) -> Result<Self, DumpError>
         where
             R: Buf,
         {
-            let name: String = if tp >= Type::String as u8 {
-                let mut name = read_cstring(data).unwrap();
-                name.insert(0, tp as char);
+            // The type byte of a named variable is the first byte of its name.
+            let name = if tp >= Type::String as u8 {
+                let mut name = vec![tp];
+                name.extend(read_cstring(data)?);
                 name
             } else {
-                "".to_string()
+                vec![]
             };
 
-            // TODO: The scope should be relative to the last variable's scope
             let scope = if tp != Type::End as u8 {
-                Range {
-                    start: data.read_leb(),
-                    end: data.read_leb(),
-                }
+                // Positions wrap around like LuaJIT's `BCPos`, rather than overflowing.
+                let start = last_start.wrapping_add(data.try_read_leb::<u32>()?);
+                let end = start.wrapping_add(data.try_read_leb::<u32>()?);
+
+                Range { start, end }
             } else {
                 Range { start: 0, end: 0 }
             };
 
-            Self {
-                name: name,
+            Ok(Self {
+                name,
                 tp: match tp {
                     0 => Type::End,
                     1 => Type::ForIdx,
@@ -87,7 +108,29 @@ pub mod variable {
                     _ => Type::String,
                 },
                 scope,
+            })
+        }
+    }
+
+    impl Variable {
+        /// Returns the name of this variable, replacing bytes that are not valid UTF-8 with `U+FFFD`.
+        pub fn name_lossy(&self) -> Cow<'_, str> {
+            String::from_utf8_lossy(&self.name)
+        }
+
+        /// Writes this variable.
+        ///
+        /// This function is an implementation of the variable loop of `bcwrite_proto`.
+        pub(crate) fn write(&self, out: &mut Writer, last_start: u32) {
+            if self.tp == Type::String {
+                out.put_bytes(&self.name);
+                out.put_u8(0);
+            } else {
+                out.put_u8(self.tp.into());
             }
+
+            out.put_uleb((self.scope.start - last_start) as u64);
+            out.put_uleb((self.scope.end - self.scope.start) as u64);
         }
     }
 
@@ -96,60 +139,120 @@ pub mod variable {
             write!(
                 f,
                 "Variable {{ type: {:#?}, name: {:#?}, scope: {:#?} }}",
-                &self.tp, &self.name, &self.scope
+                &self.tp,
+                self.name_lossy(),
+                &self.scope
             )
         }
     }
 }
 
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Debug {
-    lines: Vec<i32>,
-    upvalues: Vec<String>,
+    /// The line of each instruction, relative to the first line of the prototype.
+    lines: Vec<u32>,
+    /// The names of the upvalues, which need not be valid UTF-8.
+    upvalues: Vec<Vec<u8>>,
     variables: Vec<variable::Variable>,
 }
 
 impl Debug {
-    pub fn new<R>(data: &mut impl EndianBuffer<R>, sizeinsn: usize, line_count: usize, upvalue_count: usize) -> Debug
+    pub fn new<R>(
+        data: &mut impl EndianBuffer<R>,
+        sizeinsn: usize,
+        line_count: usize,
+        upvalue_count: usize,
+    ) -> Result<Debug, DumpError>
     where
         R: Buf,
     {
-        let mut lines = vec![0; sizeinsn];
-        match line_count {
-            65536.. => {
-                (0..sizeinsn).for_each(|i| lines.insert(i, data.read_u32() as u32));
-            }
-            256.. => {
-                (0..sizeinsn).for_each(|i| lines.insert(i, data.read_u16() as u32));
-            }
-            _ => {
-                (0..sizeinsn).for_each(|i| lines.insert(i, data.get_u8() as u32));
-            }
+        let width = match line_count {
+            65536.. => 4,
+            256.. => 2,
+            _ => 1,
+        };
+        ensure_remaining(data.deref(), sizeinsn.saturating_mul(width))?;
+
+        let lines = match line_count {
+            65536.. => (0..sizeinsn).map(|_| data.read_u32()).collect(),
+            256.. => (0..sizeinsn).map(|_| data.read_u16() as u32).collect(),
+            _ => (0..sizeinsn).map(|_| data.get_u8() as u32).collect(),
         };
 
         let mut upvalues = Vec::with_capacity(upvalue_count);
         for _ in 0..upvalue_count {
-            match read_cstring(data.deref_mut()) {
-                Some(str) => upvalues.push(str),
-                None => panic!("Unable to parse string"),
-            };
+            upvalues.push(read_cstring(data.deref_mut())?);
         }
 
-        let mut vars = Vec::new();
+        let mut vars: Vec<variable::Variable> = Vec::new();
         loop {
-            let tp = data.get_u8();
-            if tp == variable::Type::End.into() {
+            let tp = data.try_get_u8()?;
+            if tp == u8::from(variable::Type::End) {
                 break;
             }
 
-            let var_info = variable::Variable::new(data.deref_mut(), tp);
+            let last_start = vars.last().map_or(0, |var| var.scope.start);
+            let var_info = variable::Variable::new(data.deref_mut(), tp, last_start)?;
             vars.push(var_info);
         }
 
-        Self {
-            lines: vec![],
-            upvalues: upvalues,
+        Ok(Self {
+            lines,
+            upvalues,
             variables: vars,
+        })
+    }
+
+    /// Returns the names of the upvalues of the prototype.
+    pub fn upvalues(&self) -> &[Vec<u8>] {
+        &self.upvalues
+    }
+
+    /// Returns the variables of the prototype, ordered by the start of their scope.
+    pub fn variables(&self) -> &[variable::Variable] {
+        &self.variables
+    }
+
+    /// Returns the name of the variable held in `slot` at the given bytecode position.
+    ///
+    /// Bytes that are not valid UTF-8 are replaced with `U+FFFD`. This function is an
+    /// implementation of `debug_varname`.
+    pub fn variable_name(&self, pc: u32, slot: u32) -> Option<Cow<'_, str>> {
+        self.variables
+            .iter()
+            .take_while(|var| var.scope.start <= pc)
+            .filter(|var| pc < var.scope.end)
+            .nth(slot as usize)
+            .map(variable::Variable::name_lossy)
+    }
+
+    /// Writes this debug information.
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - The buffer to write to.
+    /// * `line_count` - The amount of lines spanned by the prototype.
+    pub(crate) fn write(&self, out: &mut Writer, line_count: usize) {
+        for line in &self.lines {
+            match line_count {
+                65536.. => out.put_u32(*line),
+                256.. => out.put_u16(*line as u16),
+                _ => out.put_u8(*line as u8),
+            }
+        }
+
+        for name in &self.upvalues {
+            out.put_bytes(name);
+            out.put_u8(0);
         }
+
+        let mut last_start = 0;
+        for var in &self.variables {
+            var.write(out, last_start);
+            last_start = var.scope.start;
+        }
+        out.put_u8(variable::Type::End.into());
     }
 }
 
@@ -157,7 +260,14 @@ impl fmt::Debug for Debug {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Debug")
             .field("lines", &self.lines)
-            .field("upvalues", &self.upvalues)
+            .field(
+                "upvalues",
+                &self
+                    .upvalues
+                    .iter()
+                    .map(|name| String::from_utf8_lossy(name))
+                    .collect::<Vec<_>>(),
+            )
             .field("variables", &self.variables)
             .finish()
     }
@@ -1,18 +1,242 @@
-use std::usize;
+use std::{fmt, fs::File, io, marker::PhantomData, path::Path};
 
-use bytes::Buf;
+use bytes::{Buf, Bytes, TryGetError};
 
 use crate::{
-    lua::bytecode::{EndianBuffer, Prototype, primitives::read_string},
-    utils::ReadVar,
+    lua::bytecode::{
+        BigEndianBuffer, EndianBuffer, LittleEndianBuffer, Prototype,
+        primitives::read_string,
+        writer::{WriteOptions, Writer},
+    },
+    utils::{LebError, ReadVar},
 };
 
-#[derive(Debug)]
+/// An error raised when a bytecode dump cannot be parsed.
+#[derive(Debug, PartialEq)]
+pub enum DumpError {
+    /// The data does not start with the `\x1BLJ` signature; holds the bytes found instead.
+    InvalidHeader(Vec<u8>),
+    /// The dump uses a bytecode version other than those of LuaJIT 2.0 and 2.1.
+    UnsupportedVersion(u8),
+    /// The dump does not contain any prototype.
+    Empty,
+    /// A string is longer than `DumpOptions::max_string_length`.
+    StringTooLong { length: usize, max: usize },
+    /// The dump ends before the `needed` bytes of a value, such as a string, with only `remaining`
+    /// bytes left.
+    Truncated { needed: usize, remaining: usize },
+    /// A variable-length integer is truncated or overflows the type it is read as.
+    Leb(LebError),
+    /// A prototype references more child prototypes than were read before it.
+    MissingChild,
+    /// An instruction has an opcode which does not exist in the bytecode version of the dump.
+    UnknownOpcode { opcode: u8, version: u8 },
+    /// A string is not valid UTF-8, which `DumpOptions::lossy_strings` ruled out.
+    InvalidUtf8,
+    /// The dump ends before the terminator of a zero-terminated string, such as a variable name.
+    UnterminatedString,
+    /// The dump was produced by a GC64 build if `gc64` is set, or by another build otherwise,
+    /// which `DumpOptions::gc64` ruled out.
+    FrameLayoutMismatch { gc64: bool },
+    /// The dump uses the FFI, which `DumpOptions::allow_ffi` ruled out.
+    FfiUnsupported,
+    /// The data could not be read; holds the description of the I/O error.
+    Io(String),
+    /// An instruction being written does not exist in the bytecode version of the dump.
+    UnsupportedInstruction { mnemonic: &'static str, version: u8 },
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidHeader(found) => {
+                let found: Vec<_> = found.iter().map(|byte| format!("{:02X}", byte)).collect();
+                write!(f, "not a LuaJIT bytecode dump: expected 1B 4C 4A, found [{}]", found.join(" "))
+            }
+            Self::UnsupportedVersion(version) => write!(f, "unsupported bytecode version {}", version),
+            Self::Empty => write!(f, "bytecode dump contains no prototype"),
+            Self::StringTooLong { length, max } => {
+                write!(f, "string of {} bytes exceeds the limit of {} bytes", length, max)
+            }
+            Self::Truncated { needed, remaining } => {
+                write!(f, "bytecode dump ends after {} of the {} bytes being read", remaining, needed)
+            }
+            Self::Leb(error) => write!(f, "malformed bytecode dump: {}", error),
+            Self::MissingChild => write!(f, "prototype references a child prototype which was not read"),
+            Self::UnknownOpcode { opcode, version } => {
+                write!(f, "unknown opcode {:#04x} for bytecode version {}", opcode, version)
+            }
+            Self::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            Self::UnterminatedString => write!(f, "bytecode dump ends within a zero-terminated string"),
+            Self::Io(error) => write!(f, "failed to read bytecode dump: {}", error),
+            Self::FrameLayoutMismatch { gc64: true } => {
+                write!(f, "bytecode dump was produced by a GC64 build, which uses two-slot frames")
+            }
+            Self::FrameLayoutMismatch { gc64: false } => {
+                write!(f, "bytecode dump was not produced by a GC64 build, which uses two-slot frames")
+            }
+            Self::FfiUnsupported => write!(f, "bytecode dump uses the FFI"),
+            Self::UnsupportedInstruction { mnemonic, version } => {
+                write!(f, "instruction {} does not exist in bytecode version {}", mnemonic, version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DumpError {}
+
+impl From<LebError> for DumpError {
+    fn from(error: LebError) -> Self {
+        Self::Leb(error)
+    }
+}
+
+impl From<TryGetError> for DumpError {
+    fn from(error: TryGetError) -> Self {
+        Self::Truncated {
+            needed: error.requested,
+            remaining: error.available,
+        }
+    }
+}
+
+/// An anomaly found while parsing a bytecode dump, which did not prevent parsing it.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ParseWarning {
+    /// The dump declares flags this parser does not know about.
+    UnknownFlags(u32),
+    /// A prototype did not span the amount of bytes its header declared.
+    ///
+    /// If fewer bytes were used, the remaining ones were skipped.
+    SizeMismatch { proto: usize, declared: usize, read: usize },
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownFlags(flags) => write!(f, "unknown dump flags {:#x}", flags),
+            Self::SizeMismatch { proto, declared, read } => {
+                write!(f, "prototype {} declares {} bytes but {} were read", proto, declared, read)
+            }
+        }
+    }
+}
+
+/// Options controlling how a bytecode dump is parsed.
+#[derive(Debug, Clone)]
+pub struct DumpOptions {
+    /// Only keep the debug information of the main prototype. See `Dump::parse_main_only`.
+    pub main_only: bool,
+    /// The length, in bytes, above which a string is rejected rather than allocated.
+    ///
+    /// Lengths are read from the dump; this keeps a corrupt or hostile dump from requesting an
+    /// arbitrary amount of memory. Defaults to 16 MiB.
+    pub max_string_length: usize,
+    /// The kind of LuaJIT build the dump is expected to come from, if any.
+    ///
+    /// GC64 builds use two-slot frames, which moves the arguments of calls one slot up; LuaJIT
+    /// refuses to load a dump produced by the other kind of build, and so does this parser when
+    /// this is set. Defaults to `None`, which accepts both.
+    pub gc64: Option<bool>,
+    /// Replaces the bytes of the chunk name which are not valid UTF-8 with `U+FFFD`, rather than
+    /// failing.
+    ///
+    /// String constants hold arbitrary bytes and are kept as they are. Defaults to `true`.
+    pub lossy_strings: bool,
+    /// Accepts dumps using the FFI, rather than failing with `DumpError::FfiUnsupported`.
+    ///
+    /// Such dumps may hold cdata constants; see `Dump::requires_ffi`. Defaults to `true`.
+    pub allow_ffi: bool,
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        Self {
+            main_only: false,
+            max_string_length: 1 << 24,
+            gc64: None,
+            lossy_strings: true,
+            allow_ffi: true,
+        }
+    }
+}
+
+/// The flags of a bytecode dump, as defined by `BCDUMP_F_*` in `lj_bcdump.h`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DumpFlags(u32);
+
+impl DumpFlags {
+    /// Multi-byte values are stored in big-endian order.
+    pub const BE: Self = Self(0x01);
+    /// Debug information and the chunk name were stripped.
+    pub const STRIP: Self = Self(0x02);
+    /// Some prototypes use the FFI.
+    pub const FFI: Self = Self(0x04);
+    /// The dump was produced by a GC64 build, whose frames take two slots.
+    pub const FR2: Self = Self(0x08);
+
+    /// Every flag known to `lj_bcdump.h`.
+    const KNOWN: u32 = 0x0F;
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns `true` if every flag set in `other` is also set in these flags.
+    pub fn contains(self, other: Self) -> bool {
+        (self.0 & other.0) == other.0
+    }
+
+    pub fn is_big_endian(self) -> bool {
+        self.contains(Self::BE)
+    }
+
+    pub fn is_stripped(self) -> bool {
+        self.contains(Self::STRIP)
+    }
+
+    pub fn uses_ffi(self) -> bool {
+        self.contains(Self::FFI)
+    }
+
+    pub fn is_fr2(self) -> bool {
+        self.contains(Self::FR2)
+    }
+
+    /// Returns the flags this parser does not know about.
+    fn unknown(self) -> u32 {
+        self.0 & !Self::KNOWN
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Dump {
-    pub stripped: bool,
+    version: u8,
+    flags: DumpFlags,
     pub name: Option<String>,
     protos: Vec<Prototype>,
     main: usize,
+    warnings: Vec<ParseWarning>,
+}
+
+/// Aggregate statistics of a bytecode dump, see `Dump::summary`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpSummary {
+    pub prototype_count: usize,
+    /// The number of instructions in every prototype, not counting function headers.
+    pub total_instructions: usize,
+    /// The number of complex and numeric constants in every prototype.
+    pub total_constants: usize,
+    pub stripped: bool,
+    /// The `Prototype::index` of the main prototype.
+    pub main_index: usize,
 }
 
 impl Dump {
@@ -20,72 +244,1105 @@ impl Dump {
     ///
     /// This function is an implementation of `lj_bcread`.
     ///
+    /// # Panics
+    ///
+    /// Panics with a description of the problem if the data cannot be parsed; see `Dump::parse`.
+    ///
+    /// # Arguments:
+    ///
+    /// * `data` - The binary data to parse.
+    pub fn new<B: Buf>(data: impl EndianBuffer<B>) -> Self {
+        Self::parse(data, &DumpOptions::default()).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Reads a LuaJIT bytecode dump until the end of `reader`, and parses it.
+    ///
+    /// The byte order of the dump is taken from its header.
+    ///
+    /// # Arguments:
+    ///
+    /// * `reader` - The source of the binary data to parse.
+    pub fn from_reader(mut reader: impl io::Read) -> Result<Self, DumpError> {
+        let mut data = vec![];
+        reader
+            .read_to_end(&mut data)
+            .map_err(|error| DumpError::Io(error.to_string()))?;
+
+        Self::from_slice(&data)
+    }
+
+    /// Parses a LuaJIT bytecode dump held in memory, such as a memory-mapped file.
+    ///
+    /// The byte order of the dump is taken from its header. Strings are copied out of `data`, so
+    /// the dump does not borrow from it.
+    ///
+    /// # Arguments:
+    ///
+    /// * `data` - The binary data to parse.
+    pub fn from_slice(data: &[u8]) -> Result<Self, DumpError> {
+        // The flags are the first ULEB128 after the signature, so their low bits are in its first byte.
+        let big_endian = data
+            .get(4)
+            .is_some_and(|flags| (*flags as u32 & DumpFlags::BE.bits()) != 0);
+        if big_endian {
+            Self::parse(BigEndianBuffer(data), &DumpOptions::default())
+        } else {
+            Self::parse(LittleEndianBuffer(data), &DumpOptions::default())
+        }
+    }
+
+    /// Reads and parses the LuaJIT bytecode dump stored in a file.
+    ///
+    /// # Arguments:
+    ///
+    /// * `path` - The path of the file to parse.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, DumpError> {
+        let file = File::open(path).map_err(|error| DumpError::Io(error.to_string()))?;
+        Self::from_reader(file)
+    }
+
+    /// Parses a LuaJIT bytecode dump, only keeping the debug information of the main prototype.
+    ///
+    /// Children precede the main prototype in a dump, so every prototype still has to be read;
+    /// however, the debug information of children (line numbers, upvalue and variable names) is
+    /// skipped rather than decoded. This makes triaging what the top-level chunk does cheaper,
+    /// at the cost of `Prototype::debug` returning `None` for every child.
+    ///
     /// # Arguments:
     ///
     /// * `data` - The binary data to parse.
-    pub fn new<B: Buf>(mut data: impl EndianBuffer<B>) -> Self {
-        let header = [data.get_u8(), data.get_u8(), data.get_u8(), data.get_u8()];
-        assert!(header[..3] == [0x1B, 0x4C, 0x4A]);
+    pub fn parse_main_only<B: Buf>(data: impl EndianBuffer<B>) -> Result<Self, DumpError> {
+        let options = DumpOptions {
+            main_only: true,
+            ..Default::default()
+        };
 
-        let flags = data.read_leb::<u32>();
+        Self::parse(data, &options)
+    }
 
-        // TODO: Validate flags; if FFI we need to load ctype_ffi
+    /// Parses a LuaJIT bytecode dump.
+    ///
+    /// # Arguments:
+    ///
+    /// * `data` - The binary data to parse.
+    /// * `options` - Controls how the data is parsed.
+    pub fn parse<B: Buf>(data: impl EndianBuffer<B>, options: &DumpOptions) -> Result<Self, DumpError> {
+        let mut reader = Self::iter_prototypes(data, options)?;
+        let protos = reader.by_ref().collect::<Result<Vec<_>, _>>()?;
+        if protos.is_empty() {
+            return Err(DumpError::Empty);
+        }
 
-        let file_name = if (flags & 2) == 0 {
-            let len = data.read_leb::<u32>() as usize;
-            Some(read_string(&mut *data, len))
+        let mut instance = reader.header;
+        instance.main = protos.len() - 1;
+        instance.protos = protos;
+        instance.warnings = reader.warnings;
+        Ok(instance)
+    }
+
+    /// Parses the header of a LuaJIT bytecode dump, returning an iterator which parses its
+    /// prototypes one at a time.
+    ///
+    /// Prototypes are yielded in dump order, so children come before their parents and the main
+    /// prototype comes last. Nothing past the prototype being yielded is read, which lets callers
+    /// stop early without parsing the rest of the dump.
+    ///
+    /// # Arguments:
+    ///
+    /// * `data` - The binary data to parse.
+    /// * `options` - Controls how the data is parsed.
+    pub fn iter_prototypes<B: Buf, E: EndianBuffer<B>>(
+        mut data: E,
+        options: &DumpOptions,
+    ) -> Result<PrototypeReader<B, E>, DumpError> {
+        let header: Vec<_> = (0..data.remaining().min(4)).map(|_| data.get_u8()).collect();
+        if header.len() < 4 || header[..3] != [0x1B, 0x4C, 0x4A] {
+            return Err(DumpError::InvalidHeader(header));
+        }
+
+        if !(1..=2).contains(&header[3]) {
+            return Err(DumpError::UnsupportedVersion(header[3]));
+        }
+
+        let flags = DumpFlags::from_bits(data.try_read_leb::<u32>()?);
+        let gc64 = flags.is_fr2();
+        if options.gc64.is_some_and(|expected| expected != gc64) {
+            return Err(DumpError::FrameLayoutMismatch { gc64 });
+        }
+
+        if flags.uses_ffi() && !options.allow_ffi {
+            return Err(DumpError::FfiUnsupported);
+        }
+
+        let mut warnings = vec![];
+        if flags.unknown() != 0 {
+            warnings.push(ParseWarning::UnknownFlags(flags.unknown()));
+        }
+
+        let file_name = if !flags.is_stripped() {
+            let len = data.try_read_leb::<u32>()? as usize;
+            Some(read_string(&mut *data, len, options)?)
         } else {
             None
         };
 
-        let mut instance = Self {
-            stripped: (flags & 2) != 0,
+        let header = Self {
+            version: header[3],
+            flags,
             name: file_name,
             protos: vec![],
             main: usize::MAX,
+            warnings: vec![],
         };
 
-        while data.has_remaining() {
-            if let Some(p) = Prototype::new(&instance, &mut data, instance.protos.len(), header[3]) {
-                instance.protos.push(p);
-            }
-        }
-
-        assert!(!instance.protos.is_empty());
-
-        instance.main = instance.protos.len() - 1;
-        instance
+        Ok(PrototypeReader {
+            header,
+            data,
+            options: options.clone(),
+            index: 0,
+            children: vec![],
+            warnings,
+            failed: false,
+            buffer: PhantomData,
+        })
     }
 
     /// Returns the main prototype in this bytecode dump.
     pub fn main(&self) -> &Prototype {
         &self.protos[self.main]
     }
+
+    /// Returns `true` if the debug information and the chunk name were stripped from this dump.
+    pub fn is_stripped(&self) -> bool {
+        self.flags.is_stripped()
+    }
+
+    /// Returns `true` if this dump was produced by a GC64 build of LuaJIT, whose frames take two
+    /// slots. See `DumpOptions::gc64`.
+    pub fn is_gc64(&self) -> bool {
+        self.flags.is_fr2()
+    }
+
+    /// Returns `true` if some prototypes of this dump use the FFI, which LuaJIT must be built with
+    /// to load it.
+    ///
+    /// The only cdata constants LuaJIT writes are 64-bit integers and complex numbers, which are
+    /// decoded through `Complex`; the ctypes they were created with, such as a typedef of
+    /// `int64_t`, are not part of the dump.
+    pub fn requires_ffi(&self) -> bool {
+        self.flags.uses_ffi()
+    }
+
+    /// Returns aggregate statistics of this dump, for triage.
+    pub fn summary(&self) -> DumpSummary {
+        DumpSummary {
+            prototype_count: self.protos.len(),
+            total_instructions: self.protos.iter().map(|proto| proto.instruction_count()).sum(),
+            total_constants: self.protos.iter().map(|proto| proto.kgc.len() + proto.kn.len()).sum(),
+            stripped: self.is_stripped(),
+            main_index: self.main,
+        }
+    }
+
+    /// Returns the bytecode version declared by this dump: 1 for LuaJIT 2.0, 2 for LuaJIT 2.1.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn flags(&self) -> DumpFlags {
+        self.flags
+    }
+
+    /// Returns the anomalies found while parsing this bytecode dump.
+    pub fn warnings(&self) -> &[ParseWarning] {
+        &self.warnings
+    }
+
+    /// Returns every prototype in this bytecode dump, in dump order: children first.
+    ///
+    /// Prototypes are yielded by increasing `Prototype::index`.
+    pub fn prototypes(&self) -> impl ExactSizeIterator<Item = &Prototype> + DoubleEndedIterator {
+        self.protos.iter()
+    }
+
+    /// Returns the prototype with the given `Prototype::index`, as referred to by
+    /// `Complex::Prototype`.
+    pub fn prototype(&self, index: usize) -> Option<&Prototype> {
+        self.protos.get(index)
+    }
+
+    /// Returns the prototype with the given `Prototype::index`, to be transformed in place.
+    pub fn prototype_mut(&mut self, index: usize) -> Option<&mut Prototype> {
+        self.protos.get_mut(index)
+    }
+
+    /// Returns every prototype in this bytecode dump, each one after all of its children.
+    ///
+    /// The main prototype comes last. Dump order already guarantees this, see `prototypes`.
+    pub fn prototypes_bottom_up(&self) -> impl DoubleEndedIterator<Item = &Prototype> {
+        self.prototypes()
+    }
+
+    /// Returns every prototype in this bytecode dump, each one before all of its children.
+    ///
+    /// The main prototype comes first.
+    pub fn prototypes_top_down(&self) -> impl DoubleEndedIterator<Item = &Prototype> {
+        self.prototypes().rev()
+    }
+
+    /// Serializes this bytecode dump.
+    ///
+    /// This function is an implementation of `lj_bcwrite`. Instructions are encoded for the bytecode
+    /// version and in the byte order of this dump.
+    ///
+    /// With the default options, writing is the inverse of parsing: if this dump was parsed without
+    /// warnings, parsing the output yields a dump equal to this one, and writing that dump again
+    /// yields the same bytes.
+    ///
+    /// # Arguments:
+    ///
+    /// * `options` - Transformations to apply while writing.
+    ///
+    /// # Errors
+    ///
+    /// Fails if an instruction does not exist in the bytecode version of this dump.
+    pub fn write(&self, options: &WriteOptions) -> Result<Bytes, DumpError> {
+        let mut out = Writer::new(self.flags.is_big_endian());
+        out.put_bytes(&[0x1B, 0x4C, 0x4A, self.version]);
+        out.put_uleb(self.flags.bits() as u64);
+
+        if !self.is_stripped() {
+            let name = self.name.as_deref().unwrap_or_default();
+            out.put_uleb(name.len() as u64);
+            out.put_bytes(name.as_bytes());
+        }
+
+        for proto in &self.protos {
+            proto.write(self, &mut out, options)?;
+        }
+
+        out.put_u8(0);
+        Ok(out.freeze())
+    }
+}
+
+/// Parses the prototypes of a dump one at a time; see `Dump::iter_prototypes`.
+///
+/// Iteration stops after the first error.
+pub struct PrototypeReader<B: Buf, E: EndianBuffer<B>> {
+    /// The dump being read, without any prototype.
+    header: Dump,
+    data: E,
+    options: DumpOptions,
+    /// The index of the next prototype.
+    index: usize,
+    children: Vec<usize>,
+    warnings: Vec<ParseWarning>,
+    failed: bool,
+    buffer: PhantomData<B>,
+}
+
+impl<B: Buf, E: EndianBuffer<B>> PrototypeReader<B, E> {
+    /// Returns the bytecode version of the dump.
+    pub fn version(&self) -> u8 {
+        self.header.version
+    }
+
+    /// Returns the flags of the dump.
+    pub fn flags(&self) -> DumpFlags {
+        self.header.flags
+    }
+
+    /// Returns the chunk name of the dump, or `None` if it is stripped.
+    pub fn name(&self) -> Option<&str> {
+        self.header.name.as_deref()
+    }
+
+    /// Returns the warnings raised while parsing the header and the prototypes read so far.
+    pub fn warnings(&self) -> &[ParseWarning] {
+        &self.warnings
+    }
+}
+
+impl<B: Buf, E: EndianBuffer<B>> Iterator for PrototypeReader<B, E> {
+    type Item = Result<Prototype, DumpError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        // A zero size holds no prototype, such as the one ending the dump.
+        while self.data.has_remaining() {
+            let proto = Prototype::new(
+                &self.header,
+                &mut self.data,
+                self.index,
+                self.header.version,
+                &self.options,
+                &mut self.children,
+                &mut self.warnings,
+            );
+
+            match proto {
+                Ok(Some(proto)) => {
+                    self.index += 1;
+                    return Some(Ok(proto));
+                }
+                Ok(None) => continue,
+                Err(error) => {
+                    self.failed = true;
+                    return Some(Err(error));
+                }
+            }
+        }
+
+        None
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
-        env,
-        fs::File,
-        io::{BufReader, Read},
+        env, fs,
+        io::{self, Cursor, Read},
     };
 
     use bytes::Bytes;
 
-    use crate::lua::bytecode::{Dump, LittleEndianBuffer};
+    use crate::lua::bytecode::{
+        Complex, Dump, DumpError, DumpFlags, DumpOptions, DumpSummary, Instruction, LittleEndianBuffer, ParseWarning,
+        Prototype,
+        fixture::{self, Kgc, Kn, Ktab, Proto, ad},
+        writer::WriteOptions,
+    };
+    use crate::utils::LebError;
+
+    #[test]
+    pub fn test_unstripped() {
+        // The output of `luajit -bg` for:
+        //
+        //   local function add(a, b)
+        //     return a + b
+        //   end
+        //   local t = { x = 1, "y" }
+        //   print(add(t.x, 2))
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/add.lua.jit");
+        let dump = Dump::from_path(path).unwrap();
+        assert!(!dump.is_stripped() && dump.is_gc64());
+        assert!(dump.warnings().is_empty());
+        assert_eq!(dump.name.as_deref(), Some("@add.lua"));
+        assert_eq!(dump.prototypes().len(), 2);
+
+        let add = dump.prototype(0).unwrap();
+        assert_eq!((add.parameter_count(), add.first_line(), add.line_count()), (2, 1, 2));
+        assert!(add.instructions[1] == Instruction::ADDVV { a: 2, b: 0, c: 1 });
+        let debug = add.debug().unwrap();
+        assert_eq!(debug.variable_name(1, 0).as_deref(), Some("a"));
+        assert_eq!(debug.variable_name(1, 1).as_deref(), Some("b"));
+
+        let main = dump.main();
+        assert_eq!(main.instruction_count(), 9);
+        assert!(main.instructions[1] == Instruction::FNEW { a: 0, d: 0 });
+        assert!(matches!(&main.kgc[..], [
+            Complex::String(x),
+            Complex::String(print),
+            Complex::Table { array, hash },
+            Complex::Prototype(0),
+        ] if x == "x" && print == "print" && array.len() == 2 && hash.len() == 1));
+        let debug = main.debug().unwrap();
+        assert_eq!(debug.variable_name(9, 0).as_deref(), Some("add"));
+        assert_eq!(debug.variable_name(9, 1).as_deref(), Some("t"));
+
+        assert_eq!(dump.write(&WriteOptions::default()).unwrap(), fs::read(path).unwrap());
+    }
+
+    #[test]
+    pub fn test_bench_fixture() {
+        // The dump parsed by `benches/parse.rs`, compiled from the output of `benches/generate.lua`.
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/bench.lua.jit");
+        let dump = Dump::from_path(path).unwrap();
+        assert!(!dump.is_stripped());
+        assert!(dump.warnings().is_empty());
+        // Five functions for each of the 2048 chunks, and the main chunk.
+        assert_eq!(dump.prototypes().len(), 5 * 2048 + 1);
+
+        let main_only = Dump::parse_main_only(LittleEndianBuffer(Bytes::from(fs::read(path).unwrap()))).unwrap();
+        assert_eq!(main_only.main().instruction_count(), dump.main().instruction_count());
+
+        assert_eq!(dump.write(&WriteOptions::default()).unwrap(), fs::read(path).unwrap());
+    }
+
+    #[test]
+    pub fn test_write_without_name() {
+        // Unstripped dumps always hold a chunk name, which may be empty.
+        let mut dump = Dump::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/add.lua.jit")).unwrap();
+        dump.name = None;
+
+        let bytes = dump.write(&WriteOptions::default()).unwrap();
+        let written = Dump::parse(LittleEndianBuffer(bytes), &DumpOptions::default()).unwrap();
+        assert_eq!(written.name.as_deref(), Some(""));
+        assert_eq!(written.prototypes().len(), 2);
+    }
+
+    #[test]
+    pub fn test_from_path() {
+        // The output of `luajit -bs` for `print("hello")`.
+        let dump = Dump::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/hello.lua.jit")).unwrap();
+        assert!(dump.is_stripped());
+        assert_eq!(dump.main().instruction_count(), 4);
+
+        let missing = Dump::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/missing.lua.jit"));
+        assert!(matches!(missing, Err(DumpError::Io(_))));
+    }
+
+    #[test]
+    pub fn test_from_reader() {
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("KSHORT", 0, 7), ad("RET1", 0, 2)],
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump::stripped(vec![main.clone()]).build();
+        let dump = Dump::from_reader(Cursor::new(bytes.to_vec())).unwrap();
+        assert_eq!(dump.main().instruction_count(), 2);
+
+        let big_endian = fixture::Dump {
+            flags: 1 | 2,
+            ..fixture::Dump::stripped(vec![main])
+        }
+        .build();
+        let dump = Dump::from_reader(Cursor::new(big_endian.to_vec())).unwrap();
+        assert!(dump.flags().is_big_endian());
+        assert_eq!(
+            dump.main().instructions,
+            Dump::new(LittleEndianBuffer(bytes)).main().instructions
+        );
+
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk on fire"))
+            }
+        }
+        assert_eq!(
+            Dump::from_reader(Failing).unwrap_err().to_string(),
+            "failed to read bytecode dump: disk on fire"
+        );
+        assert_eq!(
+            Dump::from_reader(Cursor::new(vec![])).unwrap_err(),
+            DumpError::InvalidHeader(vec![])
+        );
+    }
+
+    #[test]
+    pub fn test_from_slice() {
+        let data = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/hello.lua.jit")).unwrap();
+        let owned = Dump::new(LittleEndianBuffer(Bytes::from(data.clone())));
+
+        let dump = Dump::from_slice(&data).unwrap();
+        assert!(dump == owned);
+        assert!(Dump::new(LittleEndianBuffer(&data[..])) == owned);
+
+        let bytes = fixture::Dump {
+            flags: 1,
+            protos: vec![Proto {
+                instructions: vec![ad("RET0", 0, 1)],
+                kgc: vec![Kgc::Str(b"slice")],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+        .build();
+        let dump = Dump::from_slice(&bytes).unwrap();
+        assert!(dump.flags().is_big_endian());
+        assert_eq!(dump.main().string_constant(0), Some(&b"slice"[..]));
+    }
+
+    #[test]
+    pub fn test_parse_main_only() {
+        let debug = |line| fixture::Debug {
+            firstline: line,
+            numline: 1,
+            lines: vec![line],
+            ..Default::default()
+        };
+
+        let child = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            debug: Some(debug(2)),
+            ..Default::default()
+        };
+
+        let main = Proto {
+            flags: 1,
+            framesize: 1,
+            instructions: vec![ad("FNEW", 0, 0), ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Child],
+            debug: Some(fixture::Debug {
+                firstline: 0,
+                numline: 3,
+                lines: vec![1, 3],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump {
+            protos: vec![child, main],
+            ..Default::default()
+        }
+        .build();
+
+        let dump = Dump::parse_main_only(LittleEndianBuffer(bytes)).unwrap();
+        assert_eq!(dump.prototypes().len(), 2);
+        assert!(dump.prototype(0).unwrap().debug().is_none());
+        assert_eq!(dump.main().instruction_count(), 2);
+        assert!(dump.main().debug().is_some());
+        assert_eq!(dump.main().line_count(), 3);
+
+        let garbage = Dump::parse_main_only(LittleEndianBuffer(Bytes::from_static(b"\x1BLua")));
+        assert_eq!(garbage.unwrap_err(), DumpError::InvalidHeader(b"\x1BLua".to_vec()));
+    }
 
     #[test]
-    pub fn test_bc() {
-        let file = File::open(format!("{}/Downloads/ai.lua.jit", env::home_dir().unwrap().to_string_lossy())).unwrap();
-        let mut reader = BufReader::new(file);
+    pub fn test_invalid_header() {
+        let parse = |bytes: &'static [u8]| {
+            Dump::parse(LittleEndianBuffer(Bytes::from_static(bytes)), &DumpOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+
+        // A PUC Lua 5.1 chunk.
+        let message = parse(b"\x1BLuaQ\x00\x01\x04");
+        assert!(message.contains("found [1B 4C 75 61]"), "{}", message);
+
+        assert!(parse(b"\x1BL").contains("found [1B 4C]"));
+        assert_eq!(parse(b"\x1BLJ\x07\x02"), "unsupported bytecode version 7");
+    }
+
+    #[test]
+    pub fn test_versions() {
+        // LuaJIT 2.0 has neither ISTYPE nor ISNUM, which shifts every later opcode down by two.
+        let mov = |opcode: u32| opcode | 1 << 16;
+        let parse = |version: u8, instructions: Vec<u32>| {
+            let main = Proto {
+                framesize: 2,
+                instructions,
+                ..Default::default()
+            };
+
+            let data = fixture::Dump {
+                version,
+                ..fixture::Dump::stripped(vec![main])
+            };
+            Dump::parse(LittleEndianBuffer(data.build()), &DumpOptions::default()).unwrap()
+        };
+
+        let legacy = parse(1, vec![mov(16), 71 | 1 << 16]);
+        let recent = parse(2, vec![mov(18), ad("RET0", 0, 1)]);
+
+        assert_eq!((legacy.version(), recent.version()), (1, 2));
+        assert_eq!(legacy.main().instructions, recent.main().instructions);
+        assert!(legacy.main().instructions[1] == Instruction::MOV { a: 0, d: 1 });
+    }
+
+    #[test]
+    pub fn test_traversal_order() {
+        let proto = |kgc: Vec<Kgc>| Proto {
+            flags: if kgc.is_empty() { 0 } else { 1 },
+            framesize: 1,
+            instructions: vec![ad("RET0", 0, 1)],
+            kgc,
+            ..Default::default()
+        };
+
+        // The main prototype defines `a` and `b`, and `a` defines `g`.
+        let protos = vec![
+            proto(vec![]),
+            proto(vec![Kgc::Child]),
+            proto(vec![]),
+            proto(vec![Kgc::Child, Kgc::Child]),
+        ];
+        let dump = fixture::parse(protos);
+
+        let children = |proto: &Prototype| -> Vec<usize> {
+            proto
+                .kgc
+                .iter()
+                .filter_map(|constant| match constant {
+                    Complex::Prototype(index) => Some(*index),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let bottom_up: Vec<_> = dump.prototypes_bottom_up().map(|proto| proto.index).collect();
+        assert_eq!(bottom_up, [0, 1, 2, 3]);
+        let top_down: Vec<_> = dump.prototypes_top_down().map(|proto| proto.index).collect();
+        assert_eq!(top_down, [3, 2, 1, 0]);
+
+        let position = |order: &[usize], index| order.iter().position(|other| *other == index).unwrap();
+        for proto in dump.prototypes_bottom_up() {
+            for child in children(proto) {
+                assert!(position(&bottom_up, child) < position(&bottom_up, proto.index));
+                assert!(position(&top_down, child) > position(&top_down, proto.index));
+            }
+        }
+        assert_eq!(children(dump.main()), [2, 1]);
+    }
+
+    #[test]
+    pub fn test_iter_prototypes() {
+        let proto = |kgc: Vec<Kgc>| Proto {
+            flags: if kgc.is_empty() { 0 } else { 1 },
+            framesize: 1,
+            instructions: vec![ad("RET0", 0, 1)],
+            kgc,
+            ..Default::default()
+        };
+
+        let protos = vec![
+            proto(vec![]),
+            proto(vec![Kgc::Child]),
+            proto(vec![]),
+            proto(vec![Kgc::Child, Kgc::Child]),
+        ];
+        let bytes = fixture::Dump {
+            protos,
+            ..Default::default()
+        }
+        .build();
+
+        let eager = Dump::new(LittleEndianBuffer(bytes.clone()));
+        let reader = Dump::iter_prototypes(LittleEndianBuffer(bytes.clone()), &DumpOptions::default()).unwrap();
+        assert_eq!(reader.name(), Some("test.lua"));
+        assert_eq!(reader.version(), eager.version());
+
+        let lazy = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(lazy.iter().eq(eager.prototypes()));
+
+        let mut reader = Dump::iter_prototypes(LittleEndianBuffer(bytes), &DumpOptions::default()).unwrap();
+        assert!(reader.next().unwrap().unwrap() == *eager.prototype(0).unwrap());
+    }
+
+    #[test]
+    pub fn test_summary() {
+        // print("hello")
+        let dump = Dump::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/hello.lua.jit")).unwrap();
+        assert_eq!(
+            dump.summary(),
+            DumpSummary {
+                prototype_count: 1,
+                total_instructions: 4,
+                total_constants: 2,
+                stripped: true,
+                main_index: 0,
+            }
+        );
+
+        let child = Proto {
+            framesize: 1,
+            instructions: vec![ad("KNUM", 0, 0), ad("RET1", 0, 2)],
+            kn: vec![Kn::Num(0.5)],
+            ..Default::default()
+        };
+        let main = Proto {
+            flags: 1,
+            framesize: 1,
+            instructions: vec![ad("FNEW", 0, 0), ad("RET1", 0, 2)],
+            kgc: vec![Kgc::Child],
+            ..Default::default()
+        };
+        let data = fixture::Dump {
+            protos: vec![child, main],
+            ..Default::default()
+        };
+        let summary = Dump::new(LittleEndianBuffer(data.build())).summary();
+        assert_eq!((summary.prototype_count, summary.main_index), (2, 1));
+        assert_eq!((summary.total_instructions, summary.total_constants), (4, 2));
+        assert!(!summary.stripped);
+    }
+
+    #[test]
+    pub fn test_clone() {
+        let dump = Dump::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/hello.lua.jit")).unwrap();
+        let mut copy = dump.clone();
+        assert!(copy == dump);
+
+        copy.name = Some("copy.lua".to_string());
+        let main = copy.prototype_mut(0).unwrap();
+        main.instructions.insert(1, Instruction::KSHORT { a: 0, d: 1 });
+        main.kgc.clear();
+
+        assert_eq!(dump.name, None);
+        assert_eq!(dump.main().instruction_count(), 4);
+        assert_eq!(dump.main().kgc.len(), 2);
+        assert_eq!(copy.main().instruction_count(), 5);
+        assert!(copy != dump);
+    }
+
+    #[test]
+    pub fn test_prototype_lookup() {
+        // return function() return function() end end
+        let g = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            ..Default::default()
+        };
+        let closure = || Proto {
+            flags: 1,
+            framesize: 1,
+            instructions: vec![ad("FNEW", 0, 0), ad("RET1", 0, 2)],
+            kgc: vec![Kgc::Child],
+            ..Default::default()
+        };
+        let dump = fixture::parse(vec![g, closure(), closure()]);
+        assert_eq!(dump.prototypes().count(), 3);
+
+        for proto in dump.prototypes() {
+            assert!(std::ptr::eq(dump.prototype(proto.index).unwrap(), proto));
+            for constant in &proto.kgc {
+                if let Complex::Prototype(index) = constant {
+                    assert_eq!(dump.prototype(*index).unwrap().index, proto.index - 1);
+                }
+            }
+        }
+        assert!(dump.prototype(3).is_none());
+    }
+
+    #[test]
+    pub fn test_string_length_limit() {
+        let main = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Str(b"hello")],
+            ..Default::default()
+        };
+        let bytes = fixture::Dump::stripped(vec![main]).build();
+
+        let options = DumpOptions {
+            max_string_length: 4,
+            ..Default::default()
+        };
+        let error = Dump::parse(LittleEndianBuffer(bytes.clone()), &options).unwrap_err();
+        assert_eq!(error, DumpError::StringTooLong { length: 5, max: 4 });
+        assert!(Dump::parse(LittleEndianBuffer(bytes), &DumpOptions::default()).is_ok());
+
+        // A chunk name claiming to span 4 GiB, followed by nothing.
+        let absurd = Bytes::from_static(&[0x1B, 0x4C, 0x4A, 0x02, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+        let error = Dump::parse(LittleEndianBuffer(absurd), &DumpOptions::default()).unwrap_err();
+        assert!(matches!(
+            error,
+            DumpError::StringTooLong {
+                length: 0xFFFF_FFFF,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_flags() {
+        let main = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![1],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // As produced by `luajit -bg`, which keeps debug information.
+        let bytes = fixture::Dump {
+            protos: vec![main.clone()],
+            ..Default::default()
+        }
+        .build();
+        let debug = Dump::new(LittleEndianBuffer(bytes));
+        assert_eq!(debug.flags(), DumpFlags::from_bits(0));
+        assert!(!debug.flags().is_stripped() && !debug.is_stripped());
+        assert_eq!(debug.name.as_deref(), Some("test.lua"));
+
+        let bytes = fixture::Dump {
+            flags: 2 | 4,
+            ..fixture::Dump::stripped(vec![Proto { debug: None, ..main }])
+        }
+        .build();
+        let stripped = Dump::new(LittleEndianBuffer(bytes));
+        let flags = stripped.flags();
+        assert!(flags.is_stripped() && stripped.is_stripped());
+        assert!(flags.uses_ffi() && !flags.is_big_endian() && !flags.is_fr2());
+        assert!(flags.contains(DumpFlags::STRIP) && !flags.contains(DumpFlags::BE));
+        assert_eq!(stripped.name, None);
+    }
+
+    #[test]
+    pub fn test_ffi() {
+        // return 1LL
+        let main = Proto {
+            flags: 4,
+            framesize: 1,
+            instructions: vec![ad("KCDATA", 0, 0), ad("RET1", 0, 2)],
+            kgc: vec![Kgc::I64(1)],
+            ..Default::default()
+        };
+        let bytes = fixture::Dump {
+            flags: 2 | 4,
+            protos: vec![main],
+            ..Default::default()
+        }
+        .build();
+
+        let dump = Dump::new(LittleEndianBuffer(bytes.clone()));
+        assert!(dump.requires_ffi());
+        assert!(matches!(dump.main().kgc[0], Complex::Signed(1)));
+
+        let options = DumpOptions {
+            allow_ffi: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            Dump::parse(LittleEndianBuffer(bytes), &options).unwrap_err(),
+            DumpError::FfiUnsupported
+        );
+
+        let hello = Dump::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/hello.lua.jit")).unwrap();
+        assert!(!hello.requires_ffi());
+    }
+
+    #[test]
+    pub fn test_gc64() {
+        let build = |flags| {
+            let main = Proto {
+                instructions: vec![ad("RET0", 0, 1)],
+                ..Default::default()
+            };
+
+            fixture::Dump {
+                flags,
+                protos: vec![main],
+                ..Default::default()
+            }
+            .build()
+        };
+
+        let parse = |bytes: &Bytes, gc64| {
+            let options = DumpOptions {
+                gc64,
+                ..Default::default()
+            };
+            Dump::parse(LittleEndianBuffer(bytes.clone()), &options)
+        };
+
+        let gc64 = build(2 | 8);
+        assert!(parse(&gc64, None).unwrap().is_gc64());
+        assert!(parse(&gc64, Some(true)).unwrap().warnings().is_empty());
+        assert_eq!(
+            parse(&gc64, Some(false)).unwrap_err(),
+            DumpError::FrameLayoutMismatch { gc64: true }
+        );
+
+        let regular = build(2);
+        assert!(!parse(&regular, None).unwrap().is_gc64());
+        assert!(parse(&regular, Some(false)).is_ok());
+        assert_eq!(
+            parse(&regular, Some(true)).unwrap_err().to_string(),
+            "bytecode dump was not produced by a GC64 build, which uses two-slot frames"
+        );
+    }
+
+    #[test]
+    pub fn test_size_mismatch_warning() {
+        let main = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump::stripped(vec![main]).build();
+        let dump = Dump::new(LittleEndianBuffer(bytes.clone()));
+        assert!(dump.warnings().is_empty());
+
+        // Pad the prototype with two bytes, which are declared in its size.
+        let (header, body) = bytes.split_at(6);
+        let body = &body[..body.len() - 1];
+        let size = bytes[5] as usize;
+        let padded = [&header[..5], &[size as u8 + 2], body, &[0xAA, 0xBB, 0]].concat();
+
+        let dump = Dump::new(LittleEndianBuffer(Bytes::from(padded)));
+        assert_eq!(dump.main().instruction_count(), 1);
+        assert_eq!(
+            dump.warnings(),
+            [ParseWarning::SizeMismatch {
+                proto: 0,
+                declared: size + 2,
+                read: size,
+            }]
+        );
+    }
+
+    #[test]
+    pub fn test_truncated() {
+        let child = Proto {
+            numparams: 1,
+            framesize: 2,
+            instructions: vec![ad("KSHORT", 1, 7), ad("RET1", 1, 2)],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![1, 1],
+                variables: vec![("x", 0, 3)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let main = Proto {
+            flags: 1,
+            framesize: 2,
+            instructions: vec![ad("FNEW", 0, 0), ad("TDUP", 1, 1), ad("KNUM", 1, 1), ad("RET0", 0, 1)],
+            uvs: vec![0x8000],
+            kgc: vec![
+                Kgc::Str(b"hello"),
+                Kgc::I64(-2),
+                Kgc::Table(vec![Ktab::Nil, Ktab::Num(1.5)], vec![(Ktab::Str("k"), Ktab::Int(-1))]),
+                Kgc::Child,
+            ],
+            kn: vec![Kn::Int(-3), Kn::Num(2.75)],
+            debug: Some(fixture::Debug {
+                firstline: 0,
+                numline: 4,
+                lines: vec![1, 2, 3, 4],
+                upvalues: vec!["up"],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let build = |protos| {
+            fixture::Dump {
+                protos,
+                ..Default::default()
+            }
+            .build()
+        };
+        let bytes = build(vec![child.clone(), main]);
+        assert!(Dump::parse(LittleEndianBuffer(&bytes[..]), &DumpOptions::default()).is_ok());
+
+        // Every prefix ends within the header or a prototype, except those ending right after the
+        // child or the main prototype; none of them may panic.
+        let sizes = [build(vec![child]).len() - 1, bytes.len() - 1];
+        for len in 0..bytes.len() - 1 {
+            let parsed = Dump::parse(LittleEndianBuffer(&bytes[..len]), &DumpOptions::default());
+            assert_eq!(parsed.is_ok(), sizes.contains(&len), "prefix of {} bytes", len);
+        }
+
+        let parsed = Dump::parse(LittleEndianBuffer(&b"\x1BLJ\x02"[..]), &DumpOptions::default());
+        assert_eq!(parsed.unwrap_err(), DumpError::Leb(LebError::Truncated));
+    }
+
+    #[test]
+    pub fn test_oversized_counts() {
+        let data = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/hello.lua.jit")).unwrap();
+        assert_eq!(data[0x0C], 4);
+
+        // Four billion instructions, where the prototype declares 35 bytes.
+        let huge = [&data[..0x0C], &[0xFF, 0xFF, 0xFF, 0xFF, 0x0F], &data[0x0D..]].concat();
+        assert!(matches!(Dump::from_slice(&huge), Err(DumpError::Truncated { .. })));
+
+        // Enough data is left for 64 instructions and the two string constants, but the prototype
+        // is too small to hold them.
+        let padded = [&data[..0x0C], &[0x40], &data[0x0D..], &[0; 0x100]].concat();
+        assert_eq!(
+            Dump::from_slice(&padded).unwrap_err(),
+            DumpError::Truncated {
+                needed: 0x40 * 4 + 2,
+                remaining: 0x23,
+            }
+        );
+
+        // A template table with four billion array items.
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("TDUP", 0, 0), ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Table(vec![], vec![])],
+            ..Default::default()
+        };
+        let bytes = fixture::Dump::stripped(vec![main]).build();
+        let offset = bytes.len() - 4;
+        assert_eq!(bytes[offset..], [1, 0, 0, 0]);
+        let huge = [
+            &bytes[..offset + 1],
+            &[0xFF, 0xFF, 0xFF, 0xFF, 0x0F],
+            &bytes[offset + 2..],
+        ]
+        .concat();
+        assert!(matches!(Dump::from_slice(&huge), Err(DumpError::Truncated { .. })));
+    }
+
+    #[test]
+    pub fn test_missing_child() {
+        let main = Proto {
+            flags: 1,
+            framesize: 1,
+            instructions: vec![ad("FNEW", 0, 0), ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Child],
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump::stripped(vec![main]).build();
+        let error = Dump::parse(LittleEndianBuffer(bytes), &DumpOptions::default()).unwrap_err();
+        assert_eq!(error, DumpError::MissingChild);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn test_serialize() {
+        // local t = { 1, k = true }; return t, "s", 2.5
+        let main = Proto {
+            framesize: 3,
+            instructions: vec![ad("TDUP", 0, 0), ad("KSTR", 1, 1), ad("KNUM", 2, 0), ad("RET", 0, 4)],
+            kgc: vec![
+                Kgc::Str(b"s"),
+                Kgc::Table(vec![Ktab::Nil, Ktab::Int(1)], vec![(Ktab::Str("k"), Ktab::True)]),
+            ],
+            kn: vec![Kn::Num(2.5)],
+            debug: Some(fixture::Debug {
+                numline: 1,
+                lines: vec![1, 1, 1, 1],
+                variables: vec![("t", 2, 5)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump {
+            protos: vec![main],
+            ..Default::default()
+        }
+        .build();
+        let json = serde_json::to_value(Dump::new(LittleEndianBuffer(bytes))).unwrap();
+        assert_eq!(json["version"], 2);
+        assert_eq!(json["name"], "test.lua");
+
+        // Instructions are their mnemonic and operands.
+        let main = &json["protos"][0];
+        assert_eq!(main["instructions"][0], serde_json::json!({ "op": "FUNCF", "a": 3 }));
+        assert_eq!(main["instructions"][1], serde_json::json!({ "op": "TDUP", "a": 0, "d": 0 }));
+        assert_eq!(main["instructions"][4], serde_json::json!({ "op": "RET", "a": 0, "d": 4 }));
 
-        // Can i avoid this ?
-        let mut data = Vec::with_capacity(reader.get_ref().metadata().map_or(0, |m| m.len()) as usize);
-        _ = reader.read_to_end(&mut data);
-        let bytes = Bytes::from(data);
+        let table = &main["kgc"][1]["Table"];
+        assert_eq!(table["array"], serde_json::json!(["Nil", { "Integer": 1 }]));
+        assert_eq!(table["hash"][0], serde_json::json!([{ "String": [107] }, "True"]));
+        assert_eq!(main["kgc"][0], serde_json::json!({ "String": [115] }));
+        assert_eq!(
+            main["kn"][0],
+            serde_json::json!({ "bits": 2.5f64.to_bits(), "is_number": true })
+        );
 
-        let dump = Dump::new(LittleEndianBuffer(bytes));
-        println!("{:#?}", dump);
+        let variable = &main["debug"]["variables"][0];
+        assert_eq!(variable["name"], serde_json::json!([116]));
+        assert_eq!(variable["tp"], "String");
+        assert_eq!(variable["scope"], serde_json::json!({ "start": 2, "end": 5 }));
     }
 }
@@ -0,0 +1,330 @@
+//! Hand-assembled LuaJIT bytecode dumps for unit tests.
+//!
+//! This is deliberately independent from the parser so that it can act as an oracle: everything
+//! here follows `lj_bcwrite.c` directly.
+#![allow(dead_code)]
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// LuaJIT 2.1 opcode names, in opcode order.
+#[rustfmt::skip]
+pub(crate) const OPCODES: [&str; 97] = [
+    "ISLT", "ISGE", "ISLE", "ISGT", "ISEQV", "ISNEV", "ISEQS", "ISNES", "ISEQN", "ISNEN", "ISEQP", "ISNEP",
+    "ISTC", "ISFC", "IST", "ISF", "ISTYPE", "ISNUM", "MOV", "NOT", "UNM", "LEN",
+    "ADDVN", "SUBVN", "MULVN", "DIVVN", "MODVN", "ADDNV", "SUBNV", "MULNV", "DIVNV", "MODNV",
+    "ADDVV", "SUBVV", "MULVV", "DIVVV", "MODVV", "POW", "CAT",
+    "KSTR", "KCDATA", "KSHORT", "KNUM", "KPRI", "KNIL",
+    "UGET", "USETV", "USETS", "USETN", "USETP", "UCLO", "FNEW",
+    "TNEW", "TDUP", "GGET", "GSET", "TGETV", "TGETS", "TGETB", "TGETR", "TSETV", "TSETS", "TSETB", "TSETM", "TSETR",
+    "CALLM", "CALL", "CALLMT", "CALLT", "ITERC", "ITERN", "VARG", "ISNEXT",
+    "RETM", "RET", "RET0", "RET1",
+    "FORI", "JFORI", "FORL", "IFORL", "JFORL", "ITERL", "IITERL", "JITERL", "LOOP", "ILOOP", "JLOOP", "JMP",
+    "FUNCF", "IFUNCF", "JFUNCF", "FUNCV", "IFUNCV", "JFUNCV", "FUNCC", "FUNCCW",
+];
+
+pub(crate) fn opcode(name: &str) -> u8 {
+    OPCODES
+        .iter()
+        .position(|op| *op == name)
+        .unwrap_or_else(|| panic!("Unknown opcode {name}")) as u8
+}
+
+/// Encodes an instruction in the AD format.
+pub(crate) fn ad(name: &str, a: u8, d: u16) -> u32 {
+    opcode(name) as u32 | (a as u32) << 8 | (d as u32) << 16
+}
+
+/// Encodes an instruction in the ABC format.
+pub(crate) fn abc(name: &str, a: u8, b: u8, c: u8) -> u32 {
+    opcode(name) as u32 | (a as u32) << 8 | (c as u32) << 16 | (b as u32) << 24
+}
+
+/// Encodes a branching instruction at `pc` that jumps to `target`.
+pub(crate) fn jump(name: &str, a: u8, pc: usize, target: usize) -> u32 {
+    ad(name, a, (target as isize - pc as isize - 1 + 0x8000) as u16)
+}
+
+pub(crate) fn uleb(out: &mut BytesMut, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.put_u8(byte);
+            return;
+        }
+
+        out.put_u8(byte | 0x80);
+    }
+}
+
+/// A template table entry (`bcwrite_ktabk`).
+#[derive(Clone)]
+pub(crate) enum Ktab {
+    Nil,
+    False,
+    True,
+    Int(i32),
+    Num(f64),
+    Str(&'static str),
+}
+
+/// A complex constant (`bcwrite_kgc`).
+#[derive(Clone)]
+pub(crate) enum Kgc {
+    Child,
+    Table(Vec<Ktab>, Vec<(Ktab, Ktab)>),
+    I64(i64),
+    U64(u64),
+    Complex(f64, f64),
+    Str(&'static [u8]),
+}
+
+/// A numeric constant (`bcwrite_knum`).
+#[derive(Clone)]
+pub(crate) enum Kn {
+    Int(i32),
+    Num(f64),
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct Debug {
+    pub firstline: u32,
+    pub numline: u32,
+    /// One entry per instruction.
+    pub lines: Vec<u32>,
+    pub upvalues: Vec<&'static str>,
+    /// Variable name and absolute scope; single-byte names below 7 are internal variable types.
+    pub variables: Vec<(&'static str, u32, u32)>,
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct Proto {
+    pub flags: u8,
+    pub numparams: u8,
+    pub framesize: u8,
+    /// Instructions, excluding the function header.
+    pub instructions: Vec<u32>,
+    pub uvs: Vec<u16>,
+    /// Complex constants, in dump order: an operand `d` refers to `kgc[kgc.len() - 1 - d]`.
+    pub kgc: Vec<Kgc>,
+    pub kn: Vec<Kn>,
+    pub debug: Option<Debug>,
+}
+
+pub(crate) struct Dump {
+    pub version: u8,
+    pub flags: u32,
+    pub name: &'static str,
+    /// Prototypes, children first.
+    pub protos: Vec<Proto>,
+}
+
+impl Default for Dump {
+    fn default() -> Self {
+        Self {
+            version: 2,
+            flags: 0,
+            name: "test.lua",
+            protos: vec![],
+        }
+    }
+}
+
+impl Dump {
+    /// A stripped dump of the given prototypes.
+    pub fn stripped(protos: Vec<Proto>) -> Self {
+        Self {
+            flags: 2,
+            protos,
+            ..Default::default()
+        }
+    }
+
+    fn big_endian(&self) -> bool {
+        (self.flags & 1) != 0
+    }
+
+    fn stripped_flag(&self) -> bool {
+        (self.flags & 2) != 0
+    }
+
+    fn put_u16(&self, out: &mut BytesMut, value: u16) {
+        if self.big_endian() {
+            out.put_u16(value)
+        } else {
+            out.put_u16_le(value)
+        }
+    }
+
+    fn put_u32(&self, out: &mut BytesMut, value: u32) {
+        if self.big_endian() {
+            out.put_u32(value)
+        } else {
+            out.put_u32_le(value)
+        }
+    }
+
+    pub fn build(&self) -> Bytes {
+        let mut out = BytesMut::new();
+        out.put_slice(&[0x1B, 0x4C, 0x4A, self.version]);
+        uleb(&mut out, self.flags as u64);
+        if !self.stripped_flag() {
+            uleb(&mut out, self.name.len() as u64);
+            out.put_slice(self.name.as_bytes());
+        }
+
+        for proto in &self.protos {
+            let body = self.build_proto(proto);
+            uleb(&mut out, body.len() as u64);
+            out.put_slice(&body);
+        }
+
+        out.put_u8(0);
+        out.freeze()
+    }
+
+    fn build_proto(&self, proto: &Proto) -> BytesMut {
+        let mut out = BytesMut::new();
+        out.put_slice(&[proto.flags, proto.numparams, proto.framesize, proto.uvs.len() as u8]);
+        uleb(&mut out, proto.kgc.len() as u64);
+        uleb(&mut out, proto.kn.len() as u64);
+        uleb(&mut out, proto.instructions.len() as u64);
+
+        let debug = match (&proto.debug, self.stripped_flag()) {
+            (Some(debug), false) => Some((debug, self.build_debug(proto, debug))),
+            _ => None,
+        };
+
+        if !self.stripped_flag() {
+            match &debug {
+                Some((info, bytes)) => {
+                    uleb(&mut out, bytes.len() as u64);
+                    uleb(&mut out, info.firstline as u64);
+                    uleb(&mut out, info.numline as u64);
+                }
+                None => uleb(&mut out, 0),
+            }
+        }
+
+        proto.instructions.iter().for_each(|insn| self.put_u32(&mut out, *insn));
+        proto.uvs.iter().for_each(|uv| self.put_u16(&mut out, *uv));
+        proto.kgc.iter().for_each(|k| build_kgc(&mut out, k));
+        proto.kn.iter().for_each(|k| build_kn(&mut out, k));
+
+        if let Some((_, bytes)) = debug {
+            out.put_slice(&bytes);
+        }
+
+        out
+    }
+
+    fn build_debug(&self, proto: &Proto, debug: &Debug) -> BytesMut {
+        let mut out = BytesMut::new();
+        assert_eq!(debug.lines.len(), proto.instructions.len());
+        for line in &debug.lines {
+            let delta = line - debug.firstline;
+            match debug.numline {
+                65536.. => self.put_u32(&mut out, delta),
+                256.. => self.put_u16(&mut out, delta as u16),
+                _ => out.put_u8(delta as u8),
+            }
+        }
+
+        for name in &debug.upvalues {
+            out.put_slice(name.as_bytes());
+            out.put_u8(0);
+        }
+
+        let mut last = 0;
+        for (name, start, end) in &debug.variables {
+            out.put_slice(name.as_bytes());
+            if name.len() != 1 || name.as_bytes()[0] >= 7 {
+                out.put_u8(0);
+            }
+
+            uleb(&mut out, (start - last) as u64);
+            uleb(&mut out, (end - start) as u64);
+            last = *start;
+        }
+
+        out.put_u8(0);
+        out
+    }
+}
+
+/// Builds a stripped dump of the given prototypes, and parses it.
+pub(crate) fn parse(protos: Vec<Proto>) -> crate::lua::bytecode::Dump {
+    crate::lua::bytecode::Dump::new(crate::lua::bytecode::LittleEndianBuffer(Dump::stripped(protos).build()))
+}
+
+fn build_ktab(out: &mut BytesMut, item: &Ktab) {
+    match item {
+        Ktab::Nil => uleb(out, 0),
+        Ktab::False => uleb(out, 1),
+        Ktab::True => uleb(out, 2),
+        Ktab::Int(value) => {
+            uleb(out, 3);
+            uleb(out, *value as u32 as u64);
+        }
+        Ktab::Num(value) => {
+            let bits = value.to_bits();
+            uleb(out, 4);
+            uleb(out, bits & 0xFFFF_FFFF);
+            uleb(out, bits >> 32);
+        }
+        Ktab::Str(value) => {
+            uleb(out, 5 + value.len() as u64);
+            out.put_slice(value.as_bytes());
+        }
+    }
+}
+
+fn build_kgc(out: &mut BytesMut, constant: &Kgc) {
+    let parts = |out: &mut BytesMut, bits: u64| {
+        uleb(out, bits & 0xFFFF_FFFF);
+        uleb(out, bits >> 32);
+    };
+
+    match constant {
+        Kgc::Child => uleb(out, 0),
+        Kgc::Table(array, hash) => {
+            uleb(out, 1);
+            uleb(out, array.len() as u64);
+            uleb(out, hash.len() as u64);
+            array.iter().for_each(|item| build_ktab(out, item));
+            hash.iter().for_each(|(key, value)| {
+                build_ktab(out, key);
+                build_ktab(out, value);
+            });
+        }
+        Kgc::I64(value) => {
+            uleb(out, 2);
+            parts(out, *value as u64);
+        }
+        Kgc::U64(value) => {
+            uleb(out, 3);
+            parts(out, *value);
+        }
+        Kgc::Complex(real, imaginary) => {
+            uleb(out, 4);
+            parts(out, real.to_bits());
+            parts(out, imaginary.to_bits());
+        }
+        Kgc::Str(value) => {
+            uleb(out, 5 + value.len() as u64);
+            out.put_slice(value);
+        }
+    }
+}
+
+fn build_kn(out: &mut BytesMut, constant: &Kn) {
+    // A 33-bit ULEB128 whose least significant bit tells doubles apart from integers.
+    match constant {
+        Kn::Int(value) => uleb(out, (*value as u32 as u64) << 1),
+        Kn::Num(value) => {
+            let bits = value.to_bits();
+            uleb(out, ((bits & 0xFFFF_FFFF) << 1) | 1);
+            uleb(out, bits >> 32);
+        }
+    }
+}
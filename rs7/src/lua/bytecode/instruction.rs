@@ -4,8 +4,12 @@ use rs7_proc::BytecodeInstruction;
 
 use bytes::Buf;
 
+/// A bytecode instruction, named as in `lj_bc.h`.
+///
+/// When serialized, an instruction is its mnemonic under `op`, followed by its operands.
 #[rustfmt::skip]
 #[derive(BytecodeInstruction, Copy, Clone, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(tag = "op"))]
 pub enum Instruction {
     ISLT { a: u8, d: u16 },
     ISGE { a: u8, d: u16 },
@@ -85,9 +89,9 @@ pub enum Instruction {
     TSETV { a: u8, b: u8, c: u8 },
     TSETS { a: u8, b: u8, c: u8 },
     TSETB { a: u8, b: u8, c: u8 },
+    TSETM { a: u8, d: u16 },
     #[bytecode(added = 2)]
     TSETR { a: u8, b: u8, c: u8 },
-    TSETM { a: u8, d: u16 },
 
     // Calls and vararg handling.
     CALLM { a: u8, b: u8, c: u8 },
@@ -131,7 +135,101 @@ pub enum Instruction {
     FUNC { a: u8 },
 }
 
-impl Instruction {}
+impl Instruction {
+    /// Returns the A operand of this instruction, as found in bits 8 to 15 of its word.
+    pub fn a(&self) -> u8 {
+        (self.operands() >> 8) as u8
+    }
+
+    /// Returns the B operand of this instruction, as found in bits 24 to 31 of its word.
+    ///
+    /// This is meaningless for instructions in the AD format.
+    pub fn b(&self) -> u8 {
+        (self.operands() >> 24) as u8
+    }
+
+    /// Returns the C operand of this instruction, as found in bits 16 to 23 of its word.
+    ///
+    /// This is meaningless for instructions in the AD format.
+    pub fn c(&self) -> u8 {
+        (self.operands() >> 16) as u8
+    }
+
+    /// Returns the D operand of this instruction, as found in bits 16 to 31 of its word.
+    ///
+    /// For instructions in the ABC format, this is the concatenation of B and C.
+    pub fn d(&self) -> u16 {
+        (self.operands() >> 16) as u16
+    }
+
+    /// Returns the generic form of an instruction specialized by the parser.
+    ///
+    /// When the iterator of a generic `for` looks like `pairs` or `next`, LuaJIT optimistically
+    /// emits `ISNEXT`/`ITERN` instead of `JMP`/`ITERC`. `ISNEXT` patches itself back to `JMP` and
+    /// the loop falls back to `ITERC` semantics if the guess is wrong at runtime (see `lj_dispatch.c`),
+    /// so both forms describe the same `for ... in` loop in source.
+    pub fn despecialize(self) -> Self {
+        match self {
+            Self::ISNEXT { a, d } => Self::JMP { a, d },
+            Self::ITERN { a, b, c } => Self::ITERC { a, b, c },
+            insn => insn,
+        }
+    }
+
+    /// Returns the index of the instruction this instruction branches to, if any.
+    ///
+    /// Returns `None` as well if the target would come before the first instruction, which only
+    /// happens in corrupt dumps.
+    ///
+    /// # Arguments
+    ///
+    /// * `pc` - The index of this instruction in its prototype.
+    pub fn branch_target(&self, pc: usize) -> Option<usize> {
+        pc.checked_add_signed(self.branch_offset()? + 1)
+    }
+
+    /// Returns the signed distance from the instruction following this one to its branch target,
+    /// if this instruction branches.
+    pub fn branch_offset(&self) -> Option<isize> {
+        match *self {
+            Self::UCLO { d, .. }
+            | Self::ISNEXT { d, .. }
+            | Self::FORI { d, .. }
+            | Self::JFORI { d, .. }
+            | Self::FORL { d, .. }
+            | Self::IFORL { d, .. }
+            | Self::ITERL { d, .. }
+            | Self::IITERL { d, .. }
+            | Self::LOOP { d, .. }
+            | Self::ILOOP { d, .. }
+            | Self::JMP { d, .. } => Some(d as isize - 0x8000),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if this instruction is a comparison or a test, which are always followed by a `JMP`.
+    pub fn is_condition(&self) -> bool {
+        matches!(
+            self,
+            Self::ISLT { .. }
+                | Self::ISGE { .. }
+                | Self::ISLE { .. }
+                | Self::ISGT { .. }
+                | Self::ISEQV { .. }
+                | Self::ISNEV { .. }
+                | Self::ISEQS { .. }
+                | Self::ISNES { .. }
+                | Self::ISEQN { .. }
+                | Self::ISNEN { .. }
+                | Self::ISEQP { .. }
+                | Self::ISNEP { .. }
+                | Self::ISTC { .. }
+                | Self::ISFC { .. }
+                | Self::IST { .. }
+                | Self::ISF { .. }
+        )
+    }
+}
 
 impl fmt::Debug for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -237,3 +335,174 @@ impl fmt::Debug for Instruction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use crate::lua::bytecode::{
+        Dump, DumpError, Instruction, LittleEndianBuffer,
+        fixture::{self, Kgc, Proto, abc, ad, jump},
+    };
+
+    /// `for k, v in pairs(t) do x = k end`, compiled with the given loop header and iterator call.
+    fn generic_for(header: &str, iterator: &str) -> Dump {
+        let main = Proto {
+            framesize: 7,
+            instructions: vec![
+                ad("GGET", 0, 0),
+                ad("GGET", 1, 1),
+                abc("CALL", 0, 4, 2),
+                jump(header, 3, 3, 5),
+                ad("MOV", 6, 3),
+                abc(iterator, 3, 3, 3),
+                jump("ITERL", 3, 6, 4),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"t"), Kgc::Str(b"pairs")],
+            ..Default::default()
+        };
+
+        fixture::parse(vec![main])
+    }
+
+    #[test]
+    pub fn test_mnemonic() {
+        for (opcode, name) in fixture::OPCODES.iter().enumerate() {
+            let bytes = Bytes::copy_from_slice(&(opcode as u32).to_le_bytes());
+            let insn = Instruction::new(&mut LittleEndianBuffer(bytes), 2).unwrap();
+
+            assert_eq!(insn.mnemonic(), *name);
+            assert_eq!(insn.opcode(2), Some(opcode as u8));
+        }
+    }
+
+    #[test]
+    pub fn test_decode() {
+        // ADDVV 1 2 3: opcode 0x20, then A, C and B.
+        let bytes = Bytes::from_static(&[0x20, 0x01, 0x03, 0x02]);
+        let insn = Instruction::new(&mut LittleEndianBuffer(bytes), 2).unwrap();
+        assert!(insn == Instruction::ADDVV { a: 1, b: 2, c: 3 });
+        assert_eq!(insn.word(2), Some(abc("ADDVV", 1, 2, 3)));
+    }
+
+    #[test]
+    pub fn test_decode_table_stores() {
+        // TSETM 3 0 (opcode 0x3F), then TSETR 1 2 3 (opcode 0x40), as numbered by LuaJIT 2.1.
+        let bytes = Bytes::from_static(&[0x3F, 0x03, 0x00, 0x00, 0x40, 0x01, 0x03, 0x02]);
+        let mut data = LittleEndianBuffer(bytes);
+        assert!(Instruction::new(&mut data, 2).unwrap() == Instruction::TSETM { a: 3, d: 0 });
+        assert!(Instruction::new(&mut data, 2).unwrap() == Instruction::TSETR { a: 1, b: 2, c: 3 });
+    }
+
+    #[test]
+    pub fn test_decode_errors() {
+        let decode = |bytes: &'static [u8], version| Instruction::new(&mut LittleEndianBuffer(bytes), version);
+
+        assert!(decode(&[97, 0, 0, 0], 2).unwrap() == Instruction::FUNC { a: 0 });
+        assert_eq!(
+            decode(&[98, 0, 0, 0], 2).unwrap_err(),
+            DumpError::UnknownOpcode { opcode: 98, version: 2 }
+        );
+        // LuaJIT 2.0 has four opcodes less.
+        assert_eq!(
+            decode(&[94, 0, 0, 0], 1).unwrap_err(),
+            DumpError::UnknownOpcode { opcode: 94, version: 1 }
+        );
+        assert_eq!(
+            decode(&[0, 0, 0], 2).unwrap_err(),
+            DumpError::Truncated {
+                needed: 4,
+                remaining: 3
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_operands() {
+        let insn = Instruction::TGETS { a: 4, b: 5, c: 250 };
+        assert_eq!((insn.a(), insn.b(), insn.c()), (4, 5, 250));
+        assert_eq!(insn.d(), 0x05FA);
+
+        let insn = Instruction::KSHORT { a: 7, d: 0xFFFB };
+        assert_eq!((insn.a(), insn.d()), (7, 0xFFFB));
+        assert_eq!((insn.b(), insn.c()), (0xFF, 0xFB));
+    }
+
+    #[test]
+    pub fn test_branch_target() {
+        let jmp = |d| Instruction::JMP { a: 0, d };
+        assert_eq!(jmp(0x8000).branch_target(0), Some(1));
+        assert_eq!(jmp(0x7FFF).branch_target(0), Some(0));
+        assert_eq!(jmp(0x7FFE).branch_target(3), Some(2));
+
+        // A target before the first instruction does not wrap around.
+        assert_eq!(jmp(0x7FFE).branch_target(0), None);
+        assert_eq!(jmp(0).branch_target(0), None);
+        assert_eq!(jmp(0).branch_offset(), Some(-0x8000));
+
+        assert_eq!(Instruction::RET0 { a: 0, d: 1 }.branch_target(0), None);
+    }
+
+    #[test]
+    pub fn test_encode_round_trip() {
+        let words = [
+            abc("ADDVV", 1, 2, 3),
+            abc("TGETS", 4, 5, 250),
+            ad("KSHORT", 7, (-5i16) as u16),
+            ad("GGET", 255, 0xFFFF),
+            jump("JMP", 2, 10, 4),
+            ad("RET1", 0, 2),
+        ];
+
+        let mut data = BytesMut::new();
+        words.iter().for_each(|word| data.put_u32_le(*word));
+        let data = data.freeze();
+
+        let mut buffer = LittleEndianBuffer(data.clone());
+        let mut out = BytesMut::new();
+        for word in words {
+            let insn = Instruction::new(&mut buffer, 2).unwrap();
+            assert_eq!(insn.word(2), Some(word));
+            insn.encode(&mut out, 2, false).unwrap();
+        }
+
+        assert_eq!(out.freeze(), data);
+    }
+
+    #[test]
+    pub fn test_encode_versions() {
+        // LuaJIT 2.0 has neither ISTYPE nor ISNUM, which shifts every later opcode down by two.
+        let mov = Instruction::MOV { a: 1, d: 2 };
+        assert_eq!((mov.opcode(1), mov.opcode(2)), (Some(16), Some(18)));
+        assert_eq!(mov.word(1), Some(0x0002_0110));
+
+        let istype = Instruction::ISTYPE { a: 0, d: 3 };
+        assert_eq!(istype.opcode(1), None);
+        assert_eq!(
+            istype.encode(&mut BytesMut::new(), 1, false),
+            Err(DumpError::UnsupportedInstruction {
+                mnemonic: "ISTYPE",
+                version: 1
+            })
+        );
+
+        let mut out = BytesMut::new();
+        mov.encode(&mut out, 1, true).unwrap();
+        mov.encode(&mut out, 2, false).unwrap();
+        assert_eq!(&out[..], &[0x00, 0x02, 0x01, 0x10, 0x12, 0x01, 0x02, 0x00]);
+    }
+
+    #[test]
+    pub fn test_despecialize_generic_for() {
+        let (specialized, generic) = (generic_for("ISNEXT", "ITERN"), generic_for("JMP", "ITERC"));
+        let despecialize = |dump: &Dump| {
+            dump.main()
+                .instructions
+                .iter()
+                .map(|insn| insn.despecialize())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(despecialize(&specialized), despecialize(&generic));
+    }
+}
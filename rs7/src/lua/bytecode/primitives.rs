@@ -1,32 +1,147 @@
-use bytes::Buf;
+use bytes::{Buf, Bytes};
 
-pub fn read_cstring<R>(data: &mut R) -> Option<String>
+use crate::lua::bytecode::{DumpError, DumpOptions};
+
+/// Fails unless at least `needed` bytes remain in `data`.
+///
+/// This is checked before reads of fixed-size values, which would otherwise panic.
+pub fn ensure_remaining(data: &impl Buf, needed: usize) -> Result<(), DumpError> {
+    if needed > data.remaining() {
+        return Err(DumpError::Truncated {
+            needed,
+            remaining: data.remaining(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Reads a zero-terminated string, consuming its terminator.
+///
+/// Fails if the data ends before the terminator. Like string constants, the string may hold any
+/// byte, so it is not decoded.
+pub fn read_cstring<R>(data: &mut R) -> Result<Vec<u8>, DumpError>
 where
     R: Buf,
 {
     let mut str = vec![];
     loop {
+        if !data.has_remaining() {
+            return Err(DumpError::UnterminatedString);
+        }
+
         match data.get_u8() {
             0 => break,
             value => str.push(value),
         };
     }
 
-    String::from_utf8(str).ok()
+    Ok(str)
+}
+
+/// Reads a string of `size` bytes.
+///
+/// Fails rather than allocating if the string is longer than `DumpOptions::max_string_length`, or
+/// if fewer than `size` bytes remain. Lua strings may hold arbitrary bytes; those which are not
+/// valid UTF-8 are either rejected or replaced, see `DumpOptions::lossy_strings`.
+pub fn read_string<R>(data: &mut R, size: usize, options: &DumpOptions) -> Result<String, DumpError>
+where
+    R: Buf,
+{
+    // The slice is uniquely owned unless the source is shared, in which case this copies it.
+    let bytes = Vec::from(read_string_ref(data, size, options)?);
+
+    match String::from_utf8(bytes) {
+        Ok(value) => Ok(value),
+        Err(error) if options.lossy_strings => Ok(String::from_utf8_lossy(error.as_bytes()).into_owned()),
+        Err(_) => Err(DumpError::InvalidUtf8),
+    }
 }
 
-pub fn read_string<R>(data: &mut R, size: usize) -> String
+/// Reads a string of `size` bytes without copying it, if the data source is itself a `Bytes`.
+///
+/// This is subject to the same limits as `read_string`, but returns the raw bytes of the string:
+/// they are not validated as UTF-8, and `DumpOptions::lossy_strings` is ignored.
+pub fn read_string_ref<R>(data: &mut R, size: usize, options: &DumpOptions) -> Result<Bytes, DumpError>
 where
     R: Buf,
 {
-    let mut s = String::with_capacity(size);
+    let max = options.max_string_length;
+    if size > max {
+        return Err(DumpError::StringTooLong { length: size, max });
+    }
 
-    unsafe {
-        let buf = s.as_mut_vec();
-        buf.set_len(size);
+    ensure_remaining(data, size)?;
+    Ok(data.copy_to_bytes(size))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::lua::bytecode::{
+        DumpError, DumpOptions,
+        primitives::{read_cstring, read_string, read_string_ref},
+    };
+
+    #[test]
+    pub fn test_read_cstring() {
+        let mut data: &[u8] = b"up\0next";
+        assert_eq!(read_cstring(&mut data).unwrap(), b"up");
+        assert_eq!(data, b"next");
+
+        assert_eq!(read_cstring(&mut data), Err(DumpError::UnterminatedString));
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    pub fn test_read_string() {
+        let strict = DumpOptions {
+            lossy_strings: false,
+            ..Default::default()
+        };
+
+        let mut data: &[u8] = b"hello, world";
+        assert_eq!(read_string(&mut data, 5, &strict).unwrap(), "hello");
+        assert_eq!(data, b", world");
 
-        data.copy_to_slice(&mut buf[..size]);
+        // The length is read from the dump, and may exceed what is left of it.
+        let mut data: &[u8] = b"abc";
+        assert_eq!(
+            read_string(&mut data, 4, &strict),
+            Err(DumpError::Truncated {
+                needed: 4,
+                remaining: 3,
+            })
+        );
+
+        let mut data: &[u8] = b"\xFFab";
+        assert_eq!(read_string(&mut data, 3, &strict), Err(DumpError::InvalidUtf8));
+        let mut data: &[u8] = b"\xFFab";
+        assert_eq!(read_string(&mut data, 3, &DumpOptions::default()).unwrap(), "\u{FFFD}ab");
     }
 
-    s
+    #[test]
+    pub fn test_read_string_ref() {
+        let options = DumpOptions::default();
+        let source = Bytes::from_static(b"hello, world");
+
+        let mut data = source.clone();
+        let borrowed = read_string_ref(&mut data, 5, &options).unwrap();
+        let mut copy: &[u8] = &source;
+        assert_eq!(&borrowed[..], read_string(&mut copy, 5, &options).unwrap().as_bytes());
+        assert_eq!(&data[..], copy);
+
+        // The slice shares the memory of the source.
+        assert_eq!(borrowed.as_ptr(), source.as_ptr());
+
+        let mut data = Bytes::from_static(b"abc");
+        assert_eq!(
+            read_string_ref(&mut data, 4, &options),
+            Err(DumpError::Truncated {
+                needed: 4,
+                remaining: 3,
+            })
+        );
+    }
 }
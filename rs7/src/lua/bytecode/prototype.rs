@@ -1,30 +1,133 @@
-use std::fmt;
+use std::{
+    borrow::Cow,
+    fmt::{self, Write},
+};
 
 use bytes::Buf;
 
 use crate::{
-    lua::bytecode::{Complex, Dump, EndianBuffer, Instruction, Numeric, debug::Debug},
+    lua::bytecode::{
+        Complex, Dump, DumpError, DumpOptions, EndianBuffer, Instruction, Numeric, NumericValue, ParseWarning,
+        debug::Debug,
+        format_number, format_string,
+        primitives::ensure_remaining,
+        writer::{Constants, WriteOptions, Writer},
+    },
     utils::ReadVar,
 };
 
-#[derive(Debug, Copy, Clone)]
-pub struct Upvalue(u16);
+/// An upvalue descriptor, telling a closure where to capture an upvalue from when it is created.
+///
+/// The high bits are the `PROTO_UV_*` flags of `lj_obj.h`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Upvalue(pub u16);
+
+impl Upvalue {
+    /// The upvalue captures a local variable of the enclosing function.
+    const LOCAL: u16 = 0x8000;
+    /// The captured variable is never assigned to after its initialization.
+    const IMMUTABLE: u16 = 0x4000;
+
+    /// Returns `true` if this upvalue captures a slot of the enclosing function, rather than one
+    /// of its upvalues.
+    pub fn is_local(&self) -> bool {
+        self.0 & Self::LOCAL != 0
+    }
+
+    /// Returns `true` if the captured variable is never assigned to once initialized.
+    pub fn is_immutable(&self) -> bool {
+        self.0 & Self::IMMUTABLE != 0
+    }
+
+    /// Returns the slot of the enclosing function this upvalue captures if it is local, or the
+    /// index of the upvalue of the enclosing function otherwise.
+    pub fn slot(&self) -> u16 {
+        self.0 & !(Self::LOCAL | Self::IMMUTABLE)
+    }
+}
+
+/// The flags of a prototype, as defined by `PROTO_*` in `lj_obj.h`.
+///
+/// Dumps only ever record `CHILD`, `VARARG` and `FFI`; the other flags are set by the JIT compiler
+/// at runtime, but are still decoded if present.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProtoFlags(u8);
+
+impl ProtoFlags {
+    /// The prototype has child prototypes.
+    pub const CHILD: Self = Self(0x01);
+    /// The prototype accepts variable arguments.
+    pub const VARARG: Self = Self(0x02);
+    /// The prototype uses the FFI, such as cdata constants.
+    pub const FFI: Self = Self(0x04);
+    /// The prototype may not be compiled.
+    pub const NOJIT: Self = Self(0x08);
+    /// The prototype contains loops that were patched to not be compiled.
+    pub const ILOOP: Self = Self(0x10);
 
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Returns `true` if every flag set in `other` is also set in these flags.
+    pub fn contains(self, other: Self) -> bool {
+        (self.0 & other.0) == other.0
+    }
+
+    pub fn has_children(self) -> bool {
+        self.contains(Self::CHILD)
+    }
+
+    pub fn is_vararg(self) -> bool {
+        self.contains(Self::VARARG)
+    }
+
+    pub fn uses_ffi(self) -> bool {
+        self.contains(Self::FFI)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Prototype {
     /// Index of this prototype within its dump.
     pub index: usize,
 
-    flags: u8,
+    flags: ProtoFlags,
     numparams: u8,
     framesize: u8,
+    firstline: u32,
+    numline: u32,
     debug: Option<Debug>,
 
+    /// The instructions, starting with the `FUNCF` or `FUNCV` header LuaJIT prepends when loading
+    /// the prototype, which is not part of the dump.
     pub instructions: Vec<Instruction>,
     pub uvs: Vec<Upvalue>,
     pub kgc: Vec<Complex>,
     pub kn: Vec<Numeric>,
 }
 
+/// Fails unless `needed` bytes fit both in the `size` bytes a prototype declares, and in what is
+/// left of `data`.
+fn check_size(needed: usize, size: usize, data: &impl Buf) -> Result<(), DumpError> {
+    ensure_remaining(data, needed)?;
+    if needed > size {
+        return Err(DumpError::Truncated {
+            needed,
+            remaining: size,
+        });
+    }
+
+    Ok(())
+}
+
 impl Prototype {
     /// Parses a LuaJIT prototype.
     ///
@@ -36,29 +139,47 @@ impl Prototype {
     /// * `data` - The data to parse.
     /// * `index` - The index of this prototype in the `Dump`.
     /// * `version` - The bytecode version.
-    pub fn new<B>(dump: &Dump, data: &mut impl EndianBuffer<B>, index: usize, version: u8) -> Option<Self>
+    /// * `options` - Limits on the data being read, and whether debug information is skipped unless
+    ///   this prototype is the last one.
+    /// * `children` - The prototypes not yet referenced by another one, see `Complex::new`. This
+    ///   prototype is pushed onto it once read.
+    /// * `warnings` - Collects the anomalies found while parsing.
+    pub fn new<B>(
+        dump: &Dump,
+        data: &mut impl EndianBuffer<B>,
+        index: usize,
+        version: u8,
+        options: &DumpOptions,
+        children: &mut Vec<usize>,
+        warnings: &mut Vec<ParseWarning>,
+    ) -> Result<Option<Self>, DumpError>
     where
         B: Buf,
     {
-        let size = data.read_leb::<u32>();
+        let size = data.try_read_leb::<u32>()? as usize;
         if size == 0 {
-            return None;
+            return Ok(None);
         }
 
-        let flags = data.get_u8();
-        let numparams = data.get_u8();
-        let framesize = data.get_u8();
-        let sizeuv = data.get_u8() as usize;
+        // The main prototype is the last one, followed by a zero size. When the buffer is not
+        // contiguous this cannot be checked, in which case debug information is kept.
+        let skip_debug = options.main_only && data.chunk().get(size).is_some_and(|next| *next != 0);
+        let remaining = data.remaining();
+
+        let flags = data.try_get_u8()?;
+        let numparams = data.try_get_u8()?;
+        let framesize = data.try_get_u8()?;
+        let sizeuv = data.try_get_u8()? as usize;
 
-        let sizekgc = data.read_leb::<u32>();
-        let sizekn = data.read_leb::<u32>();
-        let sizeinsn = data.read_leb::<u32>() as usize;
+        let sizekgc = data.try_read_leb::<u32>()?;
+        let sizekn = data.try_read_leb::<u32>()?;
+        let sizeinsn = data.try_read_leb::<u32>()? as usize;
 
-        let (sizedbg, _firstline, numline) = if !dump.stripped {
-            let sizedbg = data.read_leb::<u32>();
+        let (sizedbg, firstline, numline) = if !dump.is_stripped() {
+            let sizedbg = data.try_read_leb::<u32>()?;
             let (firstline, numline) = if sizedbg != 0 {
-                let firstline = data.read_leb::<u32>();
-                let numline = data.read_leb::<u32>() as usize;
+                let firstline = data.try_read_leb::<u32>()?;
+                let numline = data.try_read_leb::<u32>()?;
 
                 (firstline, numline)
             } else {
@@ -70,34 +191,318 @@ impl Prototype {
             (0, 0, 0)
         };
 
-        // LuaJIT: prepends FUNCF opcode where A = framesize
-        let instructions = (0..sizeinsn).map(|_| Instruction::new(data, version)).collect();
+        // Counts are read from the dump, so they are checked before allocating anything: each
+        // instruction takes 4 bytes, each upvalue 2, and each constant at least 1.
+        let needed = [
+            sizeinsn.saturating_mul(4),
+            sizeuv * 2,
+            sizekgc as usize,
+            sizekn as usize,
+        ]
+        .into_iter()
+        .fold(0, usize::saturating_add);
+        check_size(needed, size, data.deref())?;
+
+        // LuaJIT prepends the header, whose A operand is the frame size, see `bcread_bytecode`.
+        let header = if ProtoFlags::from_bits(flags).is_vararg() {
+            Instruction::FUNCV { a: framesize }
+        } else {
+            Instruction::FUNCF { a: framesize }
+        };
+        let instructions = std::iter::once(Ok(header))
+            .chain((0..sizeinsn).map(|_| Instruction::new(data, version)))
+            .collect::<Result<_, _>>()?;
 
         let upvalues = (0..sizeuv).map(|_| Upvalue(data.read_u16())).collect();
 
-        let complex_constants = (0..sizekgc).map(|_| Complex::new(data.deref_mut(), index)).collect();
+        let complex_constants = (0..sizekgc)
+            .map(|_| Complex::new(data.deref_mut(), children, options))
+            .collect::<Result<_, DumpError>>()?;
 
-        let numeric_constants = (0..sizekn).map(|_| Numeric::new(data.deref_mut())).collect();
+        let numeric_constants = (0..sizekn)
+            .map(|_| Numeric::new(data.deref_mut()))
+            .collect::<Result<_, DumpError>>()?;
 
-        let debug = if sizedbg > 0 {
-            Some(Debug::new(data, sizeinsn, numline, sizeuv))
-        } else {
+        let debug = if sizedbg == 0 {
+            None
+        } else if skip_debug {
+            ensure_remaining(data.deref(), sizedbg as usize)?;
+            data.advance(sizedbg as usize);
             None
+        } else {
+            Some(Debug::new(data, sizeinsn, numline as usize, sizeuv)?)
         };
 
-        // TODO: Validate that we read `size` bytes.
+        let read = remaining - data.remaining();
+        if read != size {
+            warnings.push(ParseWarning::SizeMismatch {
+                proto: index,
+                declared: size,
+                read,
+            });
+
+            if read < size {
+                ensure_remaining(data.deref(), size - read)?;
+                data.advance(size - read);
+            }
+        }
 
-        Some(Self {
+        children.push(index);
+        Ok(Some(Self {
             index,
-            flags,
+            flags: ProtoFlags::from_bits(flags),
             numparams,
             framesize,
+            firstline,
+            numline,
             debug,
             instructions,
             uvs: upvalues,
             kgc: complex_constants,
             kn: numeric_constants,
-        })
+        }))
+    }
+
+    /// Returns the line this prototype is defined at, or 0 if the dump is stripped.
+    pub fn first_line(&self) -> u32 {
+        self.firstline
+    }
+
+    /// Returns the amount of lines spanned by this prototype, or 0 if the dump is stripped.
+    pub fn line_count(&self) -> u32 {
+        self.numline
+    }
+
+    /// Returns the amount of instructions of this prototype, excluding the `FUNCF` header.
+    pub fn instruction_count(&self) -> usize {
+        self.instructions.len().saturating_sub(1)
+    }
+
+    /// Returns `true` if this prototype has no instruction besides its header.
+    pub fn is_empty(&self) -> bool {
+        self.instruction_count() == 0
+    }
+
+    /// Returns the complex constant an operand refers to, such as the D operand of `KSTR`, `TDUP`
+    /// or `FNEW`.
+    ///
+    /// Operands address complex constants from the end of `kgc`, which LuaJIT indexes with the
+    /// negated operand: `idx` refers to `kgc[kgc.len() - 1 - idx]`.
+    pub fn gc_constant(&self, idx: u32) -> Option<&Complex> {
+        self.kgc.get(self.kgc.len().checked_sub(idx as usize + 1)?)
+    }
+
+    /// Returns the string constant an operand refers to, such as the D operand of `KSTR` or
+    /// `GGET`; see `gc_constant` for how it is addressed.
+    ///
+    /// Returns `None` if the constant does not exist or is not a string.
+    pub fn string_constant(&self, idx: u32) -> Option<&[u8]> {
+        match self.gc_constant(idx)? {
+            Complex::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the numeric constant an operand refers to, such as the D operand of `KNUM`.
+    ///
+    /// Unlike complex constants, numeric constants are addressed from the start: `idx` refers to
+    /// `kn[idx]`.
+    pub fn numeric_constant(&self, idx: u32) -> Option<&Numeric> {
+        self.kn.get(idx as usize)
+    }
+
+    /// Returns the amount of fixed parameters of this prototype.
+    pub fn parameter_count(&self) -> u8 {
+        self.numparams
+    }
+
+    /// Returns the amount of slots of the frame of this prototype.
+    pub fn frame_size(&self) -> u8 {
+        self.framesize
+    }
+
+    pub fn flags(&self) -> ProtoFlags {
+        self.flags
+    }
+
+    /// Returns the slot holding the fixed parameter at index `i`, if there is such a parameter.
+    ///
+    /// Fixed parameters are always held in the first slots: vararg functions copy them above the
+    /// variable arguments on entry, and two-slot frames keep the frame link below the first slot.
+    pub fn param_slot(&self, i: usize) -> Option<u32> {
+        (i < self.numparams as usize).then_some(i as u32)
+    }
+
+    /// Returns `true` if this prototype accepts variable arguments.
+    pub fn is_vararg(&self) -> bool {
+        self.flags.is_vararg()
+    }
+
+    /// Returns `true` if this prototype only builds a table out of constants and returns it.
+    ///
+    /// This is a heuristic recognizing data files, such as configuration scripts, whose contents
+    /// are better dumped than decompiled. Such a prototype has no branch, call, nor access to
+    /// globals or upvalues: it only loads constants, stores them into tables, and returns one of
+    /// the tables it created. Only slots holding constants or those tables may be copied or stored.
+    pub fn is_data_function(&self) -> bool {
+        let Some((Instruction::RET1 { a: result, .. }, body)) = self.instructions.get(1..).and_then(<[_]>::split_last)
+        else {
+            return false;
+        };
+
+        // Parameters, and slots that were never written, hold values from outside of the prototype.
+        let mut constant = [false; 256];
+        let mut returns_table = false;
+        for insn in body {
+            match *insn {
+                Instruction::TNEW { a, .. } | Instruction::TDUP { a, .. } => {
+                    constant[a as usize] = true;
+                    returns_table |= a == *result;
+                }
+                Instruction::KSTR { a, .. }
+                | Instruction::KCDATA { a, .. }
+                | Instruction::KSHORT { a, .. }
+                | Instruction::KNUM { a, .. }
+                | Instruction::KPRI { a, .. } => constant[a as usize] = true,
+                Instruction::KNIL { a, d } => constant
+                    .iter_mut()
+                    .take(d as usize + 1)
+                    .skip(a as usize)
+                    .for_each(|slot| *slot = true),
+                Instruction::MOV { a, d } if constant.get(d as usize) == Some(&true) => constant[a as usize] = true,
+                Instruction::TSETV { a, b, c }
+                    if constant[a as usize] && constant[b as usize] && constant[c as usize] => {}
+                Instruction::TSETS { a, b, .. } | Instruction::TSETB { a, b, .. }
+                    if constant[a as usize] && constant[b as usize] => {}
+                _ => return false,
+            }
+        }
+
+        returns_table
+    }
+
+    /// Returns the index of the instruction the instruction at `pc` branches to, if any.
+    ///
+    /// Comparisons and tests branch through the `JMP` that follows them, whose target is returned.
+    /// See `Instruction::branch_target` for the other instructions. Targets outside of this
+    /// prototype, or its header, are never returned.
+    pub fn branch_target(&self, pc: usize) -> Option<usize> {
+        let insn = self.instructions.get(pc)?;
+        let target = if insn.is_condition() {
+            self.instructions.get(pc + 1)?.branch_target(pc + 1)
+        } else {
+            insn.branch_target(pc)
+        };
+
+        target.filter(|target| (1..self.instructions.len()).contains(target))
+    }
+
+    /// Returns the debug information of this prototype, if the dump is not stripped.
+    pub fn debug(&self) -> Option<&Debug> {
+        self.debug.as_ref()
+    }
+
+    /// Returns the name of the variable captured by the upvalue at `idx` in `uvs`.
+    ///
+    /// Returns `None` if the dump is stripped or if there is no such upvalue.
+    pub fn upvalue_name(&self, idx: usize) -> Option<Cow<'_, str>> {
+        self.debug()?
+            .upvalues()
+            .get(idx)
+            .map(|name| String::from_utf8_lossy(name))
+    }
+
+    /// Lists the constants of this prototype along with their resolved values.
+    ///
+    /// Instruction operands refer to complex constants from the end of `kgc`, so each of them is
+    /// shown with both its position in the table and the operand addressing it. Numeric constants
+    /// are addressed directly.
+    pub fn constants_report(&self) -> String {
+        let mut report = format!("-- prototype {}: {} kgc, {} kn\n", self.index, self.kgc.len(), self.kn.len());
+
+        for (index, constant) in self.kgc.iter().enumerate() {
+            let value = match constant {
+                Complex::Prototype(child) => format!("prototype {}", child),
+                Complex::Table { array, hash } => format!("table [{} array, {} hash]", array.len(), hash.len()),
+                Complex::Signed(value) => format!("cdata {}LL", value),
+                Complex::Unsigned(value) => format!("cdata {}ULL", value),
+                Complex::Complex { real, imaginary } => {
+                    let imaginary = format_number(f64::from_bits(*imaginary));
+                    let sign = if imaginary.starts_with('-') { "" } else { "+" };
+                    format!("cdata {}{}{}i", format_number(f64::from_bits(*real)), sign, imaginary)
+                }
+                Complex::String(value) => format!("string {}", format_string(value)),
+            };
+
+            let operand = self.kgc.len() - 1 - index;
+            _ = writeln!(report, "kgc[{}] D={} {}", index, operand, value);
+        }
+
+        for (index, constant) in self.kn.iter().enumerate() {
+            let value = match constant.value() {
+                NumericValue::Integer(value) => format!("integer {}", value),
+                NumericValue::Number(value) => format!("number {}", format_number(value)),
+            };
+
+            _ = writeln!(report, "kn[{}] D={} {}", index, index, value);
+        }
+
+        report
+    }
+
+    /// Writes this prototype, prefixed with its size.
+    ///
+    /// This function is an implementation of `bcwrite_proto`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dump` - The dump this prototype belongs to.
+    /// * `out` - The buffer to write to.
+    /// * `options` - Transformations to apply while writing.
+    pub(crate) fn write(&self, dump: &Dump, out: &mut Writer, options: &WriteOptions) -> Result<(), DumpError> {
+        let constants = Constants::new(self, options);
+
+        let debug = self.debug.as_ref().filter(|_| !dump.is_stripped()).map(|debug| {
+            let mut data = out.nested();
+            debug.write(&mut data, self.numline as usize);
+            data.freeze()
+        });
+
+        let mut body = out.nested();
+        body.put_bytes(&[self.flags.bits(), self.numparams, self.framesize, self.uvs.len() as u8]);
+        body.put_uleb(constants.kgc.len() as u64);
+        body.put_uleb(constants.kn.len() as u64);
+        body.put_uleb(constants.instructions.len() as u64);
+
+        if !dump.is_stripped() {
+            match &debug {
+                Some(data) => {
+                    body.put_uleb(data.len() as u64);
+                    body.put_uleb(self.firstline as u64);
+                    body.put_uleb(self.numline as u64);
+                }
+                None => body.put_uleb(0),
+            }
+        }
+
+        for insn in &constants.instructions {
+            let word = insn.word(dump.version()).ok_or(DumpError::UnsupportedInstruction {
+                mnemonic: insn.mnemonic(),
+                version: dump.version(),
+            })?;
+            body.put_u32(word);
+        }
+        self.uvs.iter().for_each(|uv| body.put_u16(uv.0));
+        constants.kgc.iter().for_each(|k| k.write(&mut body));
+        constants.kn.iter().for_each(|k| k.write(&mut body));
+
+        if let Some(data) = debug {
+            body.put_bytes(&data);
+        }
+
+        out.put_uleb(body.len() as u64);
+        out.put_bytes(&body.freeze());
+        Ok(())
     }
 }
 
@@ -121,3 +526,343 @@ impl fmt::Debug for Prototype {
             .finish_non_exhaustive()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::bytecode::{
+        Complex, Dump, Instruction, LittleEndianBuffer, NumericValue,
+        fixture::{self, Kgc, Kn, Ktab, Proto, abc, ad, jump},
+        prototype::{ProtoFlags, Upvalue},
+        writer::WriteOptions,
+    };
+
+    #[test]
+    pub fn test_upvalue() {
+        let decoded = |uv: u16| {
+            let uv = Upvalue(uv);
+            (uv.is_local(), uv.is_immutable(), uv.slot())
+        };
+
+        assert_eq!(decoded(0x0003), (false, false, 3));
+        assert_eq!(decoded(0x4001), (false, true, 1));
+        assert_eq!(decoded(0x8002), (true, false, 2));
+        assert_eq!(decoded(0xC0FF), (true, true, 0xFF));
+    }
+
+    #[test]
+    pub fn test_branch_target() {
+        // function(x) while x do x = x - 1 end end
+        let main = Proto {
+            numparams: 1,
+            framesize: 1,
+            instructions: vec![
+                ad("IST", 0, 0),
+                jump("JMP", 1, 1, 5),
+                jump("LOOP", 1, 2, 5),
+                abc("SUBVN", 0, 0, 0),
+                jump("JMP", 1, 4, 0),
+                ad("RET0", 0, 1),
+            ],
+            kn: vec![Kn::Int(1)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let targets: Vec<_> = (0..=7).map(|pc| dump.main().branch_target(pc)).collect();
+        assert_eq!(targets, [None, Some(6), Some(6), Some(6), None, Some(1), None, None]);
+
+        // Targets past either end of the prototype are not returned.
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("JMP", 0, 0), ad("IST", 0, 0), jump("JMP", 1, 2, 4), ad("RET0", 0, 1)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let targets: Vec<_> = (0..5).map(|pc| dump.main().branch_target(pc)).collect();
+        assert_eq!(targets, [None; 5]);
+    }
+
+    #[test]
+    pub fn test_upvalue_name() {
+        // local a, b = 1, 2; return function() return a + b end
+        let child = Proto {
+            framesize: 2,
+            instructions: vec![
+                ad("UGET", 0, 0),
+                ad("UGET", 1, 1),
+                abc("ADDVV", 0, 0, 1),
+                ad("RET1", 0, 2),
+            ],
+            uvs: vec![0x8000, 0x8001],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![1, 1, 1, 1],
+                upvalues: vec!["a", "b"],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let main = Proto {
+            flags: 1,
+            framesize: 3,
+            instructions: vec![
+                ad("KSHORT", 0, 1),
+                ad("KSHORT", 1, 2),
+                ad("FNEW", 2, 0),
+                ad("RET1", 2, 2),
+            ],
+            kgc: vec![Kgc::Child],
+            debug: Some(fixture::Debug {
+                firstline: 0,
+                numline: 1,
+                lines: vec![1, 1, 1, 1],
+                variables: vec![("a", 2, 5), ("b", 2, 5)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let data = fixture::Dump {
+            protos: vec![child, main],
+            ..Default::default()
+        };
+        let dump = Dump::new(LittleEndianBuffer(data.build()));
+        let closure = dump.prototype(0).unwrap();
+        assert_eq!(closure.upvalue_name(0), Some("a".into()));
+        assert_eq!(closure.upvalue_name(1), Some("b".into()));
+        assert_eq!(closure.upvalue_name(2), None);
+        assert_eq!(dump.main().upvalue_name(0), None);
+    }
+
+    #[test]
+    pub fn test_instruction_count() {
+        let parse = |instructions: Vec<u32>| {
+            let main = Proto {
+                framesize: 1,
+                instructions,
+                ..Default::default()
+            };
+
+            let dump = fixture::parse(vec![main]);
+            (dump.main().instruction_count(), dump.main().is_empty())
+        };
+
+        assert_eq!(
+            parse(vec![ad("KSHORT", 0, 1), ad("KSHORT", 0, 2), ad("RET1", 0, 2)]),
+            (3, false)
+        );
+        assert_eq!(parse(vec![]), (0, true));
+    }
+
+    #[test]
+    pub fn test_flags() {
+        // local function f(...) return ... end
+        let child = Proto {
+            flags: 2,
+            framesize: 2,
+            instructions: vec![abc("VARG", 0, 0, 0), ad("RETM", 0, 0)],
+            ..Default::default()
+        };
+        let main = Proto {
+            flags: 3,
+            framesize: 1,
+            instructions: vec![ad("FNEW", 0, 0), ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Child],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![child, main]);
+        let child = dump.prototype(0).unwrap().flags();
+        assert!(child.is_vararg() && !child.has_children() && !child.uses_ffi());
+
+        let main = dump.main().flags();
+        assert!(main.has_children() && main.is_vararg());
+        assert!(main.contains(ProtoFlags::CHILD) && !main.contains(ProtoFlags::NOJIT));
+        assert_eq!(main.bits(), 3);
+    }
+
+    #[test]
+    pub fn test_header() {
+        let child = Proto {
+            flags: 2,
+            framesize: 2,
+            instructions: vec![abc("VARG", 0, 0, 0), ad("RETM", 0, 0)],
+            ..Default::default()
+        };
+        let main = Proto {
+            flags: 1,
+            framesize: 3,
+            instructions: vec![ad("FNEW", 0, 0), ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Child],
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump::stripped(vec![child, main]).build();
+        let dump = Dump::new(LittleEndianBuffer(bytes.clone()));
+        assert!(dump.prototype(0).unwrap().instructions[0] == Instruction::FUNCV { a: 2 });
+        assert!(dump.main().instructions[0] == Instruction::FUNCF { a: 3 });
+        assert_eq!(dump.main().instructions[0].a(), 3);
+        assert!(dump.main().instructions[1] == Instruction::FNEW { a: 0, d: 0 });
+        assert_eq!(dump.main().instruction_count(), 2);
+
+        // The header is not written back.
+        assert_eq!(dump.write(&WriteOptions::default()).unwrap(), bytes);
+    }
+
+    #[test]
+    pub fn test_constant_lookup() {
+        // local t = { 1 }; return "a", "b", t, 2.5
+        let main = Proto {
+            framesize: 4,
+            instructions: vec![
+                ad("TDUP", 0, 0),
+                ad("KSTR", 1, 1),
+                ad("KSTR", 2, 2),
+                ad("KNUM", 3, 0),
+                ad("RET", 1, 5),
+            ],
+            kgc: vec![
+                Kgc::Str(b"b"),
+                Kgc::Str(b"a"),
+                Kgc::Table(vec![Ktab::Nil, Ktab::Int(1)], vec![]),
+            ],
+            kn: vec![Kn::Num(2.5)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let main = dump.main();
+        assert!(matches!(main.gc_constant(0), Some(Complex::Table { array, .. }) if array.len() == 2));
+        assert_eq!(main.string_constant(1), Some(&b"a"[..]));
+        assert_eq!(main.string_constant(2), Some(&b"b"[..]));
+        assert_eq!(main.string_constant(0), None);
+        assert!(main.gc_constant(3).is_none());
+
+        assert_eq!(main.numeric_constant(0).unwrap().value(), NumericValue::Number(2.5));
+        assert!(main.numeric_constant(1).is_none());
+    }
+
+    #[test]
+    pub fn test_param_slot() {
+        // function(a, b, ...) return b, ... end
+        let main = Proto {
+            flags: 2,
+            numparams: 2,
+            framesize: 4,
+            instructions: vec![ad("MOV", 2, 1), abc("VARG", 3, 0, 0), ad("RETM", 2, 1)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let main = dump.main();
+        assert!(main.is_vararg());
+        assert_eq!(main.param_slot(0), Some(0));
+        assert_eq!(main.param_slot(1), Some(1));
+        assert_eq!(main.param_slot(2), None);
+    }
+
+    #[test]
+    pub fn test_data_function() {
+        let is_data_function = |instructions: Vec<u32>| {
+            let main = Proto {
+                framesize: 2,
+                instructions,
+                kgc: vec![Kgc::Str(b"name"), Kgc::Table(vec![Ktab::Nil, Ktab::Int(1)], vec![])],
+                ..Default::default()
+            };
+
+            let dump = fixture::parse(vec![main]);
+            dump.main().is_data_function()
+        };
+
+        // return { 1, name = {} }
+        assert!(is_data_function(vec![
+            ad("TDUP", 0, 0),
+            ad("TNEW", 1, 0),
+            abc("TSETS", 1, 0, 1),
+            ad("RET1", 0, 2),
+        ]));
+
+        // return { name = name }
+        assert!(!is_data_function(vec![
+            ad("TNEW", 0, 0),
+            ad("GGET", 1, 1),
+            abc("TSETS", 1, 0, 1),
+            ad("RET1", 0, 2),
+        ]));
+
+        // function(x) local t = {} local y = x t[1] = y return t end
+        let main = Proto {
+            numparams: 1,
+            framesize: 3,
+            instructions: vec![
+                ad("TNEW", 1, 0),
+                ad("MOV", 2, 0),
+                abc("TSETB", 2, 1, 1),
+                ad("RET1", 1, 2),
+            ],
+            ..Default::default()
+        };
+        assert!(!fixture::parse(vec![main]).main().is_data_function());
+
+        // local x = 1; return { x }
+        assert!(is_data_function(vec![
+            ad("KSHORT", 0, 1),
+            ad("TNEW", 1, 0),
+            ad("MOV", 2, 0),
+            abc("TSETB", 2, 1, 1),
+            ad("RET1", 1, 2),
+        ]));
+
+        // function(x) return { x } end
+        assert!(!is_data_function(vec![
+            ad("TNEW", 1, 0),
+            abc("TSETB", 0, 1, 1),
+            ad("RET1", 1, 2)
+        ]));
+
+        // local t = {}; return "name"
+        assert!(!is_data_function(vec![ad("TNEW", 0, 0), ad("KSTR", 1, 1), ad("RET1", 1, 2)]));
+        assert!(!is_data_function(vec![ad("TNEW", 0, 0), ad("RET0", 0, 1)]));
+    }
+
+    #[test]
+    pub fn test_constants_report() {
+        let child = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            ..Default::default()
+        };
+
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("RET0", 0, 1)],
+            kgc: vec![
+                Kgc::Str(b"print"),
+                Kgc::Child,
+                Kgc::Table(vec![Ktab::Nil, Ktab::Int(1)], vec![(Ktab::Str("k"), Ktab::True)]),
+                Kgc::Complex(0.5, 1e20),
+                Kgc::Complex(1.0, -0.25),
+            ],
+            kn: vec![Kn::Int(2), Kn::Num(0.5)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![child, main]);
+
+        assert_eq!(
+            dump.main().constants_report(),
+            "\
+-- prototype 1: 5 kgc, 2 kn
+kgc[0] D=4 string \"print\"
+kgc[1] D=3 prototype 0
+kgc[2] D=2 table [2 array, 1 hash]
+kgc[3] D=1 cdata 0.5+1e+20i
+kgc[4] D=0 cdata 1-0.25i
+kn[0] D=0 integer 2
+kn[1] D=1 number 0.5
+"
+        );
+    }
+}
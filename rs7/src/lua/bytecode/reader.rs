@@ -10,6 +10,8 @@ pub trait EndianBuffer<B: Buf>: DerefMut<Target = B> {
     fn read_i16(&mut self) -> i16;
     fn read_i32(&mut self) -> i32;
     fn read_i64(&mut self) -> i64;
+    fn read_f32(&mut self) -> f32;
+    fn read_f64(&mut self) -> f64;
 }
 
 pub struct NativeEndianBuffer<B: Buf>(pub B);
@@ -40,6 +42,14 @@ impl<B: Buf> EndianBuffer<B> for NativeEndianBuffer<B> {
     fn read_i64(&mut self) -> i64 {
         self.get_i64_ne()
     }
+
+    fn read_f32(&mut self) -> f32 {
+        self.get_f32_ne()
+    }
+
+    fn read_f64(&mut self) -> f64 {
+        self.get_f64_ne()
+    }
 }
 
 impl<B: Buf> EndianBuffer<B> for LittleEndianBuffer<B> {
@@ -66,6 +76,14 @@ impl<B: Buf> EndianBuffer<B> for LittleEndianBuffer<B> {
     fn read_i64(&mut self) -> i64 {
         self.get_i64_le()
     }
+
+    fn read_f32(&mut self) -> f32 {
+        self.get_f32_le()
+    }
+
+    fn read_f64(&mut self) -> f64 {
+        self.get_f64_le()
+    }
 }
 
 impl<B: Buf> EndianBuffer<B> for BigEndianBuffer<B> {
@@ -92,6 +110,14 @@ impl<B: Buf> EndianBuffer<B> for BigEndianBuffer<B> {
     fn read_i64(&mut self) -> i64 {
         self.get_i64()
     }
+
+    fn read_f32(&mut self) -> f32 {
+        self.get_f32()
+    }
+
+    fn read_f64(&mut self) -> f64 {
+        self.get_f64()
+    }
 }
 
 macro_rules! impl_deref {
@@ -115,3 +141,27 @@ macro_rules! impl_deref {
 impl_deref!(NativeEndianBuffer);
 impl_deref!(LittleEndianBuffer);
 impl_deref!(BigEndianBuffer);
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::lua::bytecode::{BigEndianBuffer, EndianBuffer, LittleEndianBuffer, NativeEndianBuffer};
+
+    #[test]
+    pub fn test_read_float() {
+        let value = 1.5e-300f64;
+
+        let mut data = BigEndianBuffer(Bytes::copy_from_slice(&value.to_be_bytes()));
+        assert_eq!(data.read_f64(), value);
+        let mut data = LittleEndianBuffer(Bytes::copy_from_slice(&value.to_le_bytes()));
+        assert_eq!(data.read_f64(), value);
+        let mut data = NativeEndianBuffer(Bytes::copy_from_slice(&value.to_ne_bytes()));
+        assert_eq!(data.read_f64(), value);
+
+        let mut data = BigEndianBuffer(Bytes::copy_from_slice(&(-0.25f32).to_be_bytes()));
+        assert_eq!(data.read_f32(), -0.25);
+        let mut data = LittleEndianBuffer(Bytes::copy_from_slice(&(-0.25f32).to_le_bytes()));
+        assert_eq!(data.read_f32(), -0.25);
+    }
+}
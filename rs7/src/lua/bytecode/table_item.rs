@@ -1,46 +1,148 @@
-use std::fmt;
+use std::{
+    borrow::Cow,
+    fmt,
+    hash::{Hash, Hasher},
+};
 
-use bytes::Buf;
+use bytes::{Buf, Bytes};
 
 use crate::{
-    lua::bytecode::{Numeric, primitives::read_string},
+    lua::bytecode::{DumpError, DumpOptions, Numeric, NumericValue, primitives::read_string_ref, writer::Writer},
     utils::ReadVar,
 };
 
+/// A key or value of a template table.
+///
+/// Items compare the way Lua compares table keys: a double with an integral value is the same
+/// key as the integer of that value, and `-0.0` is the same key as `0`. NaN can never be a table
+/// key in Lua; so that equality stays reflexive, every NaN compares equal to every other NaN.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum TableItem {
     Nil,
     False,
     True,
     Integer(i32),
     Numeric(Numeric),
-    String(String),
+    /// The bytes of a string, which need not be valid UTF-8. When parsing from a `Bytes`, they
+    /// share its buffer rather than being copied, see `read_string_ref`.
+    String(Bytes),
 }
 
 impl TableItem {
     // bcread_ktabk
-    pub fn new(data: &mut impl Buf) -> Self {
-        let tp = data.read_leb::<u32>() as usize;
+    pub fn new(data: &mut impl Buf, options: &DumpOptions) -> Result<Self, DumpError> {
+        let tp = data.try_read_leb::<u32>()? as usize;
 
-        match tp {
+        let item = match tp {
             0 => Self::Nil,
             1 => Self::False,
             2 => Self::True,
-            3 => Self::Integer(u32::cast_signed(data.read_leb::<u32>())),
+            // The bits of the integer are written as an unsigned LEB, so negative integers take five
+            // bytes and are recovered by reinterpreting them.
+            3 => Self::Integer(u32::cast_signed(data.try_read_leb::<u32>()?)),
             4 => {
-                // Yes, this is correct. We don't use the constructor here.
-                // Don't fucking ask me.
-
-                let lo = data.read_leb::<u32>() as u64;
-                let hi = data.read_leb::<u32>() as u64;
+                // Unlike `bcread_knum`, the halves are plain ULEB128s and there is no integer tag:
+                // integers have their own type above, so `Numeric::new` does not apply.
+                let lo = data.try_read_leb::<u32>()? as u64;
+                let hi = data.try_read_leb::<u32>()? as u64;
 
                 let value = (hi << u32::BITS) | lo;
-                Self::Numeric(Numeric(value))
+                Self::Numeric(Numeric::number(value))
+            }
+            5.. => Self::String(read_string_ref(data, tp - 5, options)?),
+        };
+
+        Ok(item)
+    }
+
+    /// Returns the key this item is equal to, normalizing numbers.
+    fn key(&self) -> Key<'_> {
+        match self {
+            Self::Nil => Key::Nil,
+            Self::False => Key::False,
+            Self::True => Key::True,
+            Self::Integer(value) => Key::Integer(*value as i64),
+            Self::Numeric(value) => match value.value() {
+                NumericValue::Integer(value) => Key::Integer(value as i64),
+                NumericValue::Number(value) if value.is_nan() => Key::Number(f64::NAN.to_bits()),
+                // Integral doubles past the range of `i64` are kept as doubles; they cannot equal
+                // any integer stored in a dump.
+                NumericValue::Number(value) if value.fract() == 0.0 && value.abs() < i64::MAX as f64 => {
+                    Key::Integer(value as i64)
+                }
+                NumericValue::Number(value) => Key::Number(value.to_bits()),
+            },
+            Self::String(value) => Key::String(value),
+        }
+    }
+
+    /// Decodes this item if it is a number, be it an integer or a double.
+    pub fn value(&self) -> Option<NumericValue> {
+        match self {
+            Self::Integer(value) => Some(NumericValue::Integer(*value)),
+            Self::Numeric(value) => Some(value.value()),
+            _ => None,
+        }
+    }
+
+    /// Returns the text of this string, replacing bytes that are not valid UTF-8 with `U+FFFD`, or
+    /// `None` if this is not a string.
+    pub fn to_str_lossy(&self) -> Option<Cow<'_, str>> {
+        match self {
+            Self::String(value) => Some(String::from_utf8_lossy(value)),
+            _ => None,
+        }
+    }
+
+    // bcwrite_ktabk
+    pub(crate) fn write(&self, out: &mut Writer) {
+        match self {
+            Self::Nil => out.put_uleb(0),
+            Self::False => out.put_uleb(1),
+            Self::True => out.put_uleb(2),
+            Self::Integer(value) => {
+                out.put_uleb(3);
+                out.put_uleb(i32::cast_unsigned(*value) as u64);
+            }
+            Self::Numeric(value) => {
+                out.put_uleb(4);
+                out.put_uleb(value.bits & 0xFFFF_FFFF);
+                out.put_uleb(value.bits >> u32::BITS);
+            }
+            Self::String(value) => {
+                out.put_uleb(5 + value.len() as u64);
+                out.put_bytes(value);
             }
-            5.. => Self::String(read_string(data, tp - 5)),
         }
     }
 }
 
+/// The normalized form of a `TableItem`, which equality and hashing are defined on.
+#[derive(PartialEq, Eq, Hash)]
+enum Key<'a> {
+    Nil,
+    False,
+    True,
+    Integer(i64),
+    Number(u64),
+    String(&'a [u8]),
+}
+
+impl PartialEq for TableItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for TableItem {}
+
+impl Hash for TableItem {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
 impl fmt::Debug for TableItem {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -48,8 +150,99 @@ impl fmt::Debug for TableItem {
             Self::False => write!(f, "False"),
             Self::True => write!(f, "True"),
             Self::Integer(value) => write!(f, "{{ Integer: {:#?} }}", value),
-            Self::Numeric(value) => write!(f, "{{ Numeric: {:#?} }}", value.0),
-            Self::String(value) => write!(f, "{:#?}", value),
+            Self::Numeric(value) => write!(f, "{{ Numeric: {} }}", value),
+            Self::String(value) => write!(f, "{:#?}", String::from_utf8_lossy(value)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        hash::{BuildHasher, RandomState},
+    };
+
+    use bytes::Bytes;
+
+    use crate::lua::bytecode::{
+        Complex, DumpOptions, Numeric, NumericValue,
+        fixture::{self, Kgc, Ktab, Proto, ad},
+        table_item::TableItem,
+        writer::Writer,
+    };
+
+    fn number(value: f64) -> TableItem {
+        TableItem::Numeric(Numeric::number(value.to_bits()))
+    }
+
+    #[test]
+    pub fn test_equality() {
+        let string = |value: &str| TableItem::String(Bytes::copy_from_slice(value.as_bytes()));
+        assert!(string("a") == string("a"));
+        assert!(string("a") != string("b"));
+        assert!(string("1") != TableItem::Integer(1));
+
+        assert!(TableItem::Integer(-3) == TableItem::Integer(-3));
+        assert!(TableItem::Integer(1) != TableItem::Integer(2));
+        assert!(TableItem::False != TableItem::Nil);
+
+        assert!(number(1.5) == number(1.5));
+        assert!(number(1.5) != number(2.5));
+        assert!(number(2.0) == TableItem::Integer(2));
+        assert!(number(-0.0) == TableItem::Integer(0));
+        assert!(number(2.5) != TableItem::Integer(2));
+        assert!(number(f64::NAN) == number(-f64::NAN));
+    }
+
+    #[test]
+    pub fn test_hash() {
+        let state = RandomState::new();
+        assert_eq!(state.hash_one(number(3.0)), state.hash_one(TableItem::Integer(3)));
+
+        let keys: HashSet<_> = [TableItem::Integer(1), number(1.0), number(1.5), TableItem::True]
+            .into_iter()
+            .collect();
+        assert_eq!(keys.len(), 3);
+    }
+
+    #[test]
+    pub fn test_negative_integers() {
+        // `{ -1, [-2147483648] = -7 }`, as written by LuaJIT.
+        let encoded: [(&[u8], i32); 3] = [
+            (&[0x03, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F], -1),
+            (&[0x03, 0x80, 0x80, 0x80, 0x80, 0x08], i32::MIN),
+            (&[0x03, 0xF9, 0xFF, 0xFF, 0xFF, 0x0F], -7),
+        ];
+
+        for (bytes, expected) in encoded {
+            let mut data = Bytes::from_static(bytes);
+            let item = TableItem::new(&mut data, &DumpOptions::default()).unwrap();
+            assert!(data.is_empty());
+            assert!(matches!(item, TableItem::Integer(value) if value == expected));
+
+            let mut out = Writer::new(false);
+            item.write(&mut out);
+            assert_eq!(&out.freeze()[..], bytes);
+        }
+    }
+
+    #[test]
+    pub fn test_numeric_value() {
+        // return { 1.5, 2 }
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("TDUP", 0, 0), ad("RET1", 0, 2)],
+            kgc: vec![Kgc::Table(vec![Ktab::Nil, Ktab::Num(1.5), Ktab::Int(2)], vec![])],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let Complex::Table { array, .. } = &dump.main().kgc[0] else {
+            panic!("Expected a table");
+        };
+
+        let values: Vec<_> = array.iter().map(TableItem::value).collect();
+        assert_eq!(values, [None, Some(NumericValue::Number(1.5)), Some(NumericValue::Integer(2))]);
+    }
+}
@@ -0,0 +1,248 @@
+//! Sanity checks of decoded prototypes.
+//!
+//! LuaJIT trusts the bytecode it loads: a slot past the frame or a branch past the end of a
+//! function is undefined behavior rather than an error. These checks catch such corrupt or
+//! hand-edited dumps before they are run or analyzed.
+
+use std::fmt;
+
+use crate::lua::bytecode::{Instruction, Prototype};
+
+/// An inconsistency found by `Prototype::validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// The instruction at `pc` accesses `slot`, which is not below the `framesize` of its prototype.
+    SlotOutOfRange { pc: usize, slot: usize, framesize: u8 },
+    /// The instruction at `pc` branches to `target`, which is not the index of an instruction.
+    BranchOutOfRange { pc: usize, target: isize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SlotOutOfRange { pc, slot, framesize } => write!(
+                f,
+                "instruction {} accesses slot {}, past the frame size of {}",
+                pc, slot, framesize
+            ),
+            Self::BranchOutOfRange { pc, target } => {
+                write!(f, "instruction {} branches to {}, which is not an instruction", pc, target)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Returns the highest slot an instruction reads or writes, if it accesses any.
+///
+/// Operands that only mark where a range of slots starts, such as the A operand of `JMP` which
+/// closes upvalues from there, may be equal to the frame size and are not counted. Calls are
+/// checked as if the frame link took no slot, which is the lowest bound on either kind of build.
+fn highest_slot(insn: &Instruction) -> Option<isize> {
+    use Instruction as I;
+
+    // The last slot of a range of `count` slots starting at `base`.
+    let range = |base: u8, count: isize| base as isize + count - 1;
+
+    let highest = match *insn {
+        I::ISLT { a, d }
+        | I::ISGE { a, d }
+        | I::ISLE { a, d }
+        | I::ISGT { a, d }
+        | I::ISEQV { a, d }
+        | I::ISNEV { a, d }
+        | I::ISTC { a, d }
+        | I::ISFC { a, d }
+        | I::MOV { a, d }
+        | I::NOT { a, d }
+        | I::UNM { a, d }
+        | I::LEN { a, d } => (a as isize).max(d as isize),
+        I::IST { d } | I::ISF { d } => d as isize,
+        I::USETV { d, .. } => d as isize,
+        I::ISEQS { a, .. }
+        | I::ISNES { a, .. }
+        | I::ISEQN { a, .. }
+        | I::ISNEN { a, .. }
+        | I::ISEQP { a, .. }
+        | I::ISNEP { a, .. }
+        | I::ISTYPE { a, .. }
+        | I::ISNUM { a, .. }
+        | I::KSTR { a, .. }
+        | I::KCDATA { a, .. }
+        | I::KSHORT { a, .. }
+        | I::KNUM { a, .. }
+        | I::KPRI { a, .. }
+        | I::UGET { a, .. }
+        | I::FNEW { a, .. }
+        | I::TNEW { a, .. }
+        | I::TDUP { a, .. }
+        | I::GGET { a, .. }
+        | I::GSET { a, .. }
+        | I::RET1 { a, .. } => a as isize,
+        I::KNIL { a, d } => (a as isize).max(d as isize),
+        I::ADDVN { a, b, .. }
+        | I::SUBVN { a, b, .. }
+        | I::MULVN { a, b, .. }
+        | I::DIVVN { a, b, .. }
+        | I::MODVN { a, b, .. }
+        | I::ADDNV { a, b, .. }
+        | I::SUBNV { a, b, .. }
+        | I::MULNV { a, b, .. }
+        | I::DIVNV { a, b, .. }
+        | I::MODNV { a, b, .. }
+        | I::TGETS { a, b, .. }
+        | I::TGETB { a, b, .. }
+        | I::TSETS { a, b, .. }
+        | I::TSETB { a, b, .. } => a.max(b) as isize,
+        I::ADDVV { a, b, c }
+        | I::SUBVV { a, b, c }
+        | I::MULVV { a, b, c }
+        | I::DIVVV { a, b, c }
+        | I::MODVV { a, b, c }
+        | I::POW { a, b, c }
+        | I::CAT { a, b, c }
+        | I::TGETV { a, b, c }
+        | I::TGETR { a, b, c }
+        | I::TSETV { a, b, c }
+        | I::TSETR { a, b, c } => a.max(b).max(c) as isize,
+        I::TSETM { a, .. } => a as isize,
+        // The callee is at `a`, followed by `c - 1` arguments; `b - 1` results replace them.
+        I::CALL { a, b, c } => range(a, c as isize).max(range(a, b as isize - 1)),
+        I::CALLM { a, b, c } => range(a, c as isize + 1).max(range(a, b as isize - 1)),
+        I::CALLT { a, d } => range(a, d as isize),
+        I::CALLMT { a, d } => range(a, d as isize + 1),
+        // The iterator state is copied from the three slots below `a` to `a` and up.
+        I::ITERC { a, b, .. } | I::ITERN { a, b, .. } => range(a, 3).max(range(a, b as isize - 1)),
+        I::VARG { a, b, .. } => range(a, b as isize - 1),
+        I::ISNEXT { a, .. } | I::ITERL { a, .. } | I::IITERL { a, .. } | I::JITERL { a, .. } => a as isize,
+        I::RETM { a, d } => range(a, d as isize),
+        I::RET { a, d } => range(a, d as isize - 1),
+        // The index, limit, step and loop variable of a numeric `for`.
+        I::FORI { a, .. } | I::JFORI { a, .. } | I::FORL { a, .. } | I::IFORL { a, .. } | I::JFORL { a, .. } => {
+            range(a, 4)
+        }
+        I::USETS { .. }
+        | I::USETN { .. }
+        | I::USETP { .. }
+        | I::UCLO { .. }
+        | I::RET0 { .. }
+        | I::LOOP { .. }
+        | I::ILOOP { .. }
+        | I::JLOOP { .. }
+        | I::JMP { .. }
+        | I::FUNCF { .. }
+        | I::IFUNCF { .. }
+        | I::JFUNCF { .. }
+        | I::FUNCV { .. }
+        | I::IFUNCV { .. }
+        | I::JFUNCV { .. }
+        | I::FUNCC { .. }
+        | I::FUNCCW { .. }
+        | I::FUNC { .. } => return None,
+    };
+
+    Some(highest)
+}
+
+impl Prototype {
+    /// Checks that the instructions of this prototype only access slots of its frame, and only
+    /// branch to its own instructions.
+    ///
+    /// Returns the first inconsistency found, in instruction order.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let framesize = self.frame_size();
+        for (pc, insn) in self.instructions.iter().enumerate() {
+            if let Some(slot) = highest_slot(insn).filter(|slot| *slot >= framesize as isize) {
+                return Err(ValidationError::SlotOutOfRange {
+                    pc,
+                    slot: slot as usize,
+                    framesize,
+                });
+            }
+
+            // Nothing branches back to the header.
+            let Some(offset) = insn.branch_offset() else {
+                continue;
+            };
+            let target = pc as isize + 1 + offset;
+            if !(1..self.instructions.len() as isize).contains(&target) {
+                return Err(ValidationError::BranchOutOfRange { pc, target });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::bytecode::{
+        fixture::{self, Proto, abc, ad, jump},
+        validate::ValidationError,
+    };
+
+    fn validate(framesize: u8, instructions: Vec<u32>) -> Result<(), ValidationError> {
+        let main = Proto {
+            numparams: 1,
+            framesize,
+            instructions,
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        dump.main().validate()
+    }
+
+    #[test]
+    pub fn test_valid() {
+        // function(x) if x then x = x + x end return x end
+        let instructions = vec![
+            ad("IST", 0, 0),
+            jump("JMP", 1, 1, 3),
+            abc("ADDVV", 0, 0, 0),
+            ad("RET1", 0, 2),
+        ];
+        assert_eq!(validate(1, instructions), Ok(()));
+
+        // The base of the slots closed by a jump may be the frame size itself.
+        assert_eq!(validate(1, vec![jump("JMP", 1, 0, 1), ad("RET0", 0, 1)]), Ok(()));
+    }
+
+    #[test]
+    pub fn test_slot_out_of_range() {
+        let instructions = vec![ad("KSHORT", 1, 1), abc("ADDVV", 0, 0, 2), ad("RET1", 0, 2)];
+        let error = validate(2, instructions).unwrap_err();
+        assert_eq!(
+            error,
+            ValidationError::SlotOutOfRange {
+                pc: 2,
+                slot: 2,
+                framesize: 2,
+            }
+        );
+        assert_eq!(error.to_string(), "instruction 2 accesses slot 2, past the frame size of 2");
+
+        // A numeric `for` uses four slots from its base.
+        assert!(validate(3, vec![jump("FORI", 0, 0, 1), ad("RET0", 0, 1)]).is_err());
+    }
+
+    #[test]
+    pub fn test_branch_out_of_range() {
+        let error = validate(1, vec![jump("JMP", 1, 0, 5), ad("RET0", 0, 1)]).unwrap_err();
+        assert_eq!(error, ValidationError::BranchOutOfRange { pc: 1, target: 6 });
+
+        let error = validate(1, vec![ad("RET0", 0, 1), ad("JMP", 1, 0)]).unwrap_err();
+        assert_eq!(
+            error,
+            ValidationError::BranchOutOfRange {
+                pc: 2,
+                target: 3 - 0x8000
+            }
+        );
+
+        // The header is not a target either.
+        let error = validate(1, vec![ad("JMP", 1, 0x7FFE), ad("RET0", 0, 1)]).unwrap_err();
+        assert_eq!(error, ValidationError::BranchOutOfRange { pc: 1, target: 0 });
+    }
+}
@@ -0,0 +1,102 @@
+//! Plain Lua values, decoded from the constants of a dump.
+//!
+//! Template tables hold the constant entries of table constructors, which is where embedded data,
+//! such as configuration tables, ends up. Converting them to `LuaValue`s gives access to that data
+//! without decompiling the code around it.
+
+use bytes::Bytes;
+
+use crate::lua::bytecode::{Complex, NumericValue, table_item::TableItem};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LuaValue {
+    Nil,
+    Boolean(bool),
+    Integer(i32),
+    Number(f64),
+    /// The bytes of a string, shared with the constant it was decoded from.
+    String(Bytes),
+    Table {
+        /// The values at keys `1` through `array.len()`.
+        array: Vec<LuaValue>,
+        /// Every other entry, in dump order.
+        hash: Vec<(LuaValue, LuaValue)>,
+    },
+}
+
+impl From<&TableItem> for LuaValue {
+    fn from(item: &TableItem) -> Self {
+        match item {
+            TableItem::Nil => Self::Nil,
+            TableItem::False => Self::Boolean(false),
+            TableItem::True => Self::Boolean(true),
+            TableItem::Integer(value) => Self::Integer(*value),
+            TableItem::Numeric(value) => match value.value() {
+                NumericValue::Integer(value) => Self::Integer(value),
+                NumericValue::Number(value) => Self::Number(value),
+            },
+            TableItem::String(value) => Self::String(value.clone()),
+        }
+    }
+}
+
+impl Complex {
+    /// Converts this constant to a Lua value, if it is a string or a template table.
+    pub fn to_value(&self) -> Option<LuaValue> {
+        match self {
+            Self::String(value) => Some(LuaValue::String(value.clone())),
+            Self::Table { array, hash } => {
+                // The array part starts at index 0, which constructors only fill explicitly.
+                let mut entries = vec![];
+                if let Some(first) = array.first().filter(|item| !matches!(item, TableItem::Nil)) {
+                    entries.push((LuaValue::Integer(0), first.into()));
+                }
+                entries.extend(hash.iter().map(|(key, value)| (key.into(), value.into())));
+
+                Some(LuaValue::Table {
+                    array: array.iter().skip(1).map(LuaValue::from).collect(),
+                    hash: entries,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::lua::bytecode::{
+        LuaValue,
+        fixture::{self, Kgc, Ktab, Proto, ad},
+    };
+
+    #[test]
+    pub fn test_template_table() {
+        // return { 1, "two", [0] = 0.5, name = "x", enabled = true }
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("TDUP", 0, 0), ad("RET1", 0, 2)],
+            kgc: vec![Kgc::Table(
+                vec![Ktab::Num(0.5), Ktab::Int(1), Ktab::Str("two")],
+                vec![(Ktab::Str("name"), Ktab::Str("x")), (Ktab::Str("enabled"), Ktab::True)],
+            )],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let string = |value: &str| LuaValue::String(Bytes::copy_from_slice(value.as_bytes()));
+        assert_eq!(
+            dump.main().kgc[0].to_value(),
+            Some(LuaValue::Table {
+                array: vec![LuaValue::Integer(1), string("two")],
+                hash: vec![
+                    (LuaValue::Integer(0), LuaValue::Number(0.5)),
+                    (string("name"), string("x")),
+                    (string("enabled"), LuaValue::Boolean(true)),
+                ],
+            })
+        );
+    }
+}
@@ -0,0 +1,535 @@
+//! Bytecode dump serialization.
+//!
+//! Each parsed type writes itself next to its parser; this module provides the output buffer and
+//! the transformations that may be applied while writing. The format follows `lj_bcwrite.c`.
+
+use std::collections::HashMap;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{
+    lua::bytecode::{Complex, Instruction, Numeric, Prototype},
+    utils::WriteVar,
+};
+
+/// Options controlling how a `Dump` is written.
+#[derive(Debug, Default, Clone)]
+pub struct WriteOptions {
+    /// Merges equal constants within each prototype, rewriting the operands that refer to them.
+    ///
+    /// Child prototypes and template tables are never merged.
+    pub deduplicate_constants: bool,
+}
+
+/// An output buffer honoring the endianness of the dump being written.
+pub(crate) struct Writer {
+    data: BytesMut,
+    big_endian: bool,
+}
+
+impl Writer {
+    pub fn new(big_endian: bool) -> Self {
+        Self {
+            data: BytesMut::new(),
+            big_endian,
+        }
+    }
+
+    /// Creates an empty buffer with the same endianness as this one.
+    pub fn nested(&self) -> Self {
+        Self::new(self.big_endian)
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.data.put_u8(value);
+    }
+
+    pub fn put_u16(&mut self, value: u16) {
+        if self.big_endian {
+            self.data.put_u16(value)
+        } else {
+            self.data.put_u16_le(value)
+        }
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        if self.big_endian {
+            self.data.put_u32(value)
+        } else {
+            self.data.put_u32_le(value)
+        }
+    }
+
+    pub fn put_uleb(&mut self, value: u64) {
+        self.data.write_leb(value);
+    }
+
+    pub fn put_bytes(&mut self, value: &[u8]) {
+        self.data.put_slice(value);
+    }
+
+    pub fn freeze(self) -> Bytes {
+        self.data.freeze()
+    }
+}
+
+/// A complex constant that may be shared between several operands.
+#[derive(PartialEq, Eq, Hash)]
+enum ComplexKey<'a> {
+    Signed(i64),
+    Unsigned(u64),
+    Complex(u64, u64),
+    String(&'a [u8]),
+}
+
+impl<'a> ComplexKey<'a> {
+    fn new(constant: &'a Complex) -> Option<Self> {
+        match constant {
+            Complex::Prototype(_) | Complex::Table { .. } => None,
+            Complex::Signed(value) => Some(Self::Signed(*value)),
+            Complex::Unsigned(value) => Some(Self::Unsigned(*value)),
+            Complex::Complex { real, imaginary } => Some(Self::Complex(*real, *imaginary)),
+            Complex::String(value) => Some(Self::String(value)),
+        }
+    }
+}
+
+/// The constants and instructions of a prototype, as they should be written.
+pub(crate) struct Constants<'a> {
+    pub kgc: Vec<&'a Complex>,
+    pub kn: Vec<&'a Numeric>,
+    /// The instructions following the header, which is not written.
+    pub instructions: Vec<Instruction>,
+}
+
+impl<'a> Constants<'a> {
+    pub fn new(proto: &'a Prototype, options: &WriteOptions) -> Self {
+        if !options.deduplicate_constants {
+            return Self {
+                kgc: proto.kgc.iter().collect(),
+                kn: proto.kn.iter().collect(),
+                instructions: proto.instructions[1..].to_vec(),
+            };
+        }
+
+        // Operands refer to complex constants from the end of the table. Keeping the last
+        // occurrence of each constant guarantees that operands only ever shrink, which matters
+        // for the 8-bit operands of `TGETS` and `TSETS`.
+        let mut kgc = vec![];
+        let mut kgc_operands = vec![0u16; proto.kgc.len()];
+        let mut seen = HashMap::new();
+        for (operand, constant) in proto.kgc.iter().rev().enumerate() {
+            let index = match ComplexKey::new(constant) {
+                Some(key) => *seen.entry(key).or_insert_with(|| {
+                    kgc.push(constant);
+                    kgc.len() - 1
+                }),
+                None => {
+                    kgc.push(constant);
+                    kgc.len() - 1
+                }
+            };
+
+            kgc_operands[operand] = index as u16;
+        }
+        kgc.reverse();
+
+        let mut kn = vec![];
+        let mut kn_operands = vec![0u16; proto.kn.len()];
+        let mut seen = HashMap::new();
+        for (operand, constant) in proto.kn.iter().enumerate() {
+            kn_operands[operand] = *seen.entry((constant.bits, constant.is_number)).or_insert_with(|| {
+                kn.push(constant);
+                kn.len() as u16 - 1
+            });
+        }
+
+        let instructions = proto.instructions[1..]
+            .iter()
+            .map(|insn| remap(*insn, |d| kgc_operands[d as usize], |d| kn_operands[d as usize]))
+            .collect();
+
+        Self { kgc, kn, instructions }
+    }
+}
+
+/// Rewrites the constant operands of an instruction.
+///
+/// # Arguments
+///
+/// * `insn` - The instruction to rewrite.
+/// * `gc` - Maps a complex constant operand to its new value.
+/// * `num` - Maps a numeric constant operand to its new value.
+fn remap(insn: Instruction, gc: impl Fn(u16) -> u16, num: impl Fn(u16) -> u16) -> Instruction {
+    use Instruction as I;
+
+    let gc8 = |c: u8| gc(c as u16) as u8;
+    let num8 = |c: u8| num(c as u16) as u8;
+
+    match insn {
+        I::ISEQS { a, d } => I::ISEQS { a, d: gc(d) },
+        I::ISNES { a, d } => I::ISNES { a, d: gc(d) },
+        I::ISEQN { a, d } => I::ISEQN { a, d: num(d) },
+        I::ISNEN { a, d } => I::ISNEN { a, d: num(d) },
+        I::ADDVN { a, b, c } => I::ADDVN { a, b, c: num8(c) },
+        I::SUBVN { a, b, c } => I::SUBVN { a, b, c: num8(c) },
+        I::MULVN { a, b, c } => I::MULVN { a, b, c: num8(c) },
+        I::DIVVN { a, b, c } => I::DIVVN { a, b, c: num8(c) },
+        I::MODVN { a, b, c } => I::MODVN { a, b, c: num8(c) },
+        I::ADDNV { a, b, c } => I::ADDNV { a, b, c: num8(c) },
+        I::SUBNV { a, b, c } => I::SUBNV { a, b, c: num8(c) },
+        I::MULNV { a, b, c } => I::MULNV { a, b, c: num8(c) },
+        I::DIVNV { a, b, c } => I::DIVNV { a, b, c: num8(c) },
+        I::MODNV { a, b, c } => I::MODNV { a, b, c: num8(c) },
+        I::KSTR { a, d } => I::KSTR { a, d: gc(d) },
+        I::KCDATA { a, d } => I::KCDATA { a, d: gc(d) },
+        I::KNUM { a, d } => I::KNUM { a, d: num(d) },
+        I::USETS { a, d } => I::USETS { a, d: gc(d) },
+        I::USETN { a, d } => I::USETN { a, d: num(d) },
+        I::FNEW { a, d } => I::FNEW { a, d: gc(d) },
+        I::TDUP { a, d } => I::TDUP { a, d: gc(d) },
+        I::GGET { a, d } => I::GGET { a, d: gc(d) },
+        I::GSET { a, d } => I::GSET { a, d: gc(d) },
+        I::TGETS { a, b, c } => I::TGETS { a, b, c: gc8(c) },
+        I::TSETS { a, b, c } => I::TSETS { a, b, c: gc8(c) },
+        I::TSETM { a, d } => I::TSETM { a, d: num(d) },
+        insn => insn,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+
+    use crate::lua::bytecode::{
+        BigEndianBuffer, Complex, Dump, DumpError, Instruction, LittleEndianBuffer, NumericValue, Prototype,
+        fixture::{self, Kgc, Kn, Ktab, Proto, abc, ad},
+        writer::WriteOptions,
+    };
+
+    fn string(proto: &Prototype, d: usize) -> &[u8] {
+        match &proto.kgc[proto.kgc.len() - 1 - d] {
+            Complex::String(value) => value,
+            other => panic!("Expected a string, found {:?}", other),
+        }
+    }
+
+    /// Asserts that writing a dump and parsing the result yields the same dump, and that writing
+    /// is stable from then on.
+    fn assert_roundtrip_stable(bytes: Bytes) {
+        let parsed = Dump::from_reader(Cursor::new(bytes.to_vec())).unwrap();
+        assert!(parsed.warnings().is_empty(), "{:?}", parsed.warnings());
+
+        let written = parsed.write(&WriteOptions::default()).unwrap();
+        let reparsed = Dump::from_reader(Cursor::new(written.to_vec())).unwrap();
+        assert!(reparsed == parsed, "{:#?}\n!=\n{:#?}", reparsed, parsed);
+        assert_eq!(reparsed.write(&WriteOptions::default()).unwrap(), written);
+    }
+
+    #[test]
+    pub fn test_roundtrip_stable() {
+        // A stripped function with every kind of complex constant.
+        let constants = Proto {
+            flags: 4,
+            framesize: 1,
+            instructions: vec![ad("KSTR", 0, 0), ad("KCDATA", 0, 1), ad("TDUP", 0, 4), ad("RET0", 0, 1)],
+            kgc: vec![
+                Kgc::Table(vec![Ktab::Nil, Ktab::False], vec![(Ktab::Num(1.5), Ktab::Str("v"))]),
+                Kgc::Complex(0.0, 2.0),
+                Kgc::U64(u64::MAX),
+                Kgc::I64(-1),
+                Kgc::Str(b"nul\x00byte"),
+            ],
+            kn: vec![Kn::Num(f64::NAN), Kn::Int(i32::MIN)],
+            ..Default::default()
+        };
+        assert_roundtrip_stable(
+            fixture::Dump {
+                flags: 2 | 4,
+                protos: vec![constants.clone()],
+                ..Default::default()
+            }
+            .build(),
+        );
+
+        // The same function in a big-endian dump.
+        assert_roundtrip_stable(
+            fixture::Dump {
+                flags: 1 | 2 | 4,
+                protos: vec![constants],
+                ..Default::default()
+            }
+            .build(),
+        );
+
+        // A nested function with debug information, including upvalues and variable scopes.
+        let child = Proto {
+            numparams: 1,
+            framesize: 2,
+            instructions: vec![ad("UGET", 1, 0), abc("ADDVV", 1, 0, 1), ad("RET1", 1, 2)],
+            uvs: vec![0x8000],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 2,
+                lines: vec![1, 2, 2],
+                upvalues: vec!["y"],
+                variables: vec![("x", 0, 4)],
+            }),
+            ..Default::default()
+        };
+        let main = Proto {
+            flags: 1,
+            framesize: 1,
+            instructions: vec![ad("KSHORT", 0, 3), ad("FNEW", 1, 0), ad("RET1", 1, 2)],
+            kgc: vec![Kgc::Child],
+            debug: Some(fixture::Debug {
+                firstline: 0,
+                numline: 3,
+                lines: vec![1, 3, 3],
+                variables: vec![("y", 2, 4)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_roundtrip_stable(
+            fixture::Dump {
+                protos: vec![child, main],
+                ..Default::default()
+            }
+            .build(),
+        );
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        let child = Proto {
+            numparams: 1,
+            framesize: 2,
+            instructions: vec![abc("ADDVN", 1, 0, 0), ad("RET1", 1, 2)],
+            uvs: vec![0x8000],
+            kn: vec![Kn::Int(-3)],
+            debug: Some(fixture::Debug {
+                firstline: 2,
+                numline: 300,
+                lines: vec![2, 301],
+                upvalues: vec!["up"],
+                variables: vec![("x", 0, 2), ("\u{4}", 1, 2)],
+            }),
+            ..Default::default()
+        };
+
+        let main = Proto {
+            flags: 3,
+            framesize: 3,
+            instructions: vec![ad("FNEW", 0, 0), ad("TDUP", 1, 2), ad("KNUM", 2, 0), ad("RET0", 0, 1)],
+            kgc: vec![
+                Kgc::Table(
+                    vec![Ktab::Nil, Ktab::Int(-7), Ktab::Num(0.5)],
+                    vec![(Ktab::Str("k"), Ktab::True)],
+                ),
+                Kgc::Str(b"name"),
+                Kgc::Child,
+            ],
+            kn: vec![Kn::Num(-0.0), Kn::Int(2)],
+            debug: Some(fixture::Debug {
+                firstline: 0,
+                numline: 4,
+                lines: vec![1, 1, 3, 4],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        for flags in [0, 1] {
+            let bytes = fixture::Dump {
+                flags,
+                protos: vec![child.clone(), main.clone()],
+                ..Default::default()
+            }
+            .build();
+
+            let dump = if flags == 1 {
+                Dump::new(BigEndianBuffer(bytes.clone()))
+            } else {
+                Dump::new(LittleEndianBuffer(bytes.clone()))
+            };
+
+            assert_eq!(dump.write(&WriteOptions::default()).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    pub fn test_legacy_version() {
+        // MOV 0 1 and RET0 0 1, numbered as in LuaJIT 2.0.
+        let main = Proto {
+            framesize: 2,
+            instructions: vec![16 | 1 << 16, 71 | 1 << 16],
+            ..Default::default()
+        };
+
+        for flags in [0, 1] {
+            let bytes = fixture::Dump {
+                version: 1,
+                flags,
+                protos: vec![main.clone()],
+                ..Default::default()
+            }
+            .build();
+
+            let mut dump = if flags == 1 {
+                Dump::new(BigEndianBuffer(bytes.clone()))
+            } else {
+                Dump::new(LittleEndianBuffer(bytes.clone()))
+            };
+
+            assert!(dump.main().instructions[1] == Instruction::MOV { a: 0, d: 1 });
+            assert_eq!(dump.write(&WriteOptions::default()).unwrap(), bytes);
+
+            // LuaJIT 2.0 has no ISTYPE.
+            dump.prototype_mut(0).unwrap().instructions[1] = Instruction::ISTYPE { a: 0, d: 3 };
+            assert_eq!(
+                dump.write(&WriteOptions::default()),
+                Err(DumpError::UnsupportedInstruction {
+                    mnemonic: "ISTYPE",
+                    version: 1
+                })
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_deduplicate_constants() {
+        let main = Proto {
+            framesize: 4,
+            instructions: vec![
+                ad("KSTR", 0, 0),
+                ad("KSTR", 1, 1),
+                ad("KSTR", 2, 2),
+                ad("GGET", 3, 2),
+                abc("TGETS", 3, 3, 0),
+                ad("KNUM", 0, 0),
+                ad("KNUM", 1, 1),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"a"), Kgc::Str(b"b"), Kgc::Str(b"a")],
+            kn: vec![Kn::Num(1.5), Kn::Num(1.5)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+
+        let options = WriteOptions {
+            deduplicate_constants: true,
+        };
+        let dump = Dump::new(LittleEndianBuffer(dump.write(&options).unwrap()));
+        let proto = dump.main();
+
+        assert_eq!(proto.kgc.len(), 2);
+        assert_eq!(proto.kn.len(), 1);
+        let resolved: Vec<_> = proto
+            .instructions
+            .iter()
+            .filter_map(|insn| match *insn {
+                Instruction::KSTR { d, .. } | Instruction::GGET { d, .. } => Some(string(proto, d as usize)),
+                Instruction::TGETS { c, .. } => Some(string(proto, c as usize)),
+                Instruction::KNUM { d, .. } => {
+                    assert_eq!(d, 0);
+                    None
+                }
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(resolved, [b"a", b"b", b"a", b"a", b"a"].map(|value| &value[..]));
+    }
+
+    #[test]
+    pub fn test_binary_strings() {
+        // `return "\255bin\0"`: string constants are byte strings, which need not be UTF-8.
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("KSTR", 0, 0), ad("RET1", 0, 2)],
+            kgc: vec![Kgc::Str(b"\xFFbin\x00")],
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump::stripped(vec![main]).build();
+        let dump = Dump::new(LittleEndianBuffer(bytes.clone()));
+        assert_eq!(string(dump.main(), 0), b"\xFFbin\x00");
+        assert_eq!(dump.main().kgc[0].to_str_lossy().unwrap(), "\u{FFFD}bin\0");
+        assert_eq!(dump.write(&WriteOptions::default()).unwrap(), bytes);
+    }
+
+    #[test]
+    pub fn test_binary_names() {
+        // Debug names are byte strings too: the first byte of a name doubles as its type, so a
+        // leading byte above 0x7F must survive, and invalid UTF-8 must round-trip unchanged.
+        let main = Proto {
+            framesize: 2,
+            instructions: vec![ad("KSHORT", 0, 1), ad("KSHORT", 1, 2), ad("RET0", 0, 1)],
+            debug: Some(fixture::Debug {
+                numline: 1,
+                lines: vec![1, 1, 1],
+                variables: vec![("é", 1, 3), ("ét", 2, 3)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let mut bytes = fixture::Dump {
+            protos: vec![main],
+            ..Default::default()
+        }
+        .build()
+        .to_vec();
+        let at = bytes.windows(4).position(|w| w == b"\xC3\xA9t\0").unwrap();
+        bytes[at..at + 3].copy_from_slice(b"\xE9t\xE9");
+        let bytes = Bytes::from(bytes);
+
+        let dump = Dump::new(LittleEndianBuffer(bytes.clone()));
+        let debug = dump.main().debug().unwrap();
+        assert_eq!(debug.variables()[0].name, "é".as_bytes());
+        assert_eq!(debug.variables()[1].name, b"\xE9t\xE9");
+        assert_eq!(debug.variable_name(2, 0).unwrap(), "é");
+        assert_eq!(debug.variable_name(2, 1).unwrap(), "\u{FFFD}t\u{FFFD}");
+        assert_eq!(dump.write(&WriteOptions::default()).unwrap(), bytes);
+    }
+
+    #[test]
+    pub fn test_integral_numbers() {
+        // `bcwrite_knum` narrows integral doubles, but other producers may not: these must neither
+        // be narrowed nor merged with the integers of the same value, or the dump changes.
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("RET0", 0, 1)],
+            kn: vec![Kn::Num(2.0), Kn::Int(2), Kn::Num(-0.0), Kn::Num(0.0), Kn::Int(0)],
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump::stripped(vec![main]).build();
+        let dump = Dump::new(LittleEndianBuffer(bytes.clone()));
+
+        let options = WriteOptions {
+            deduplicate_constants: true,
+        };
+        let written = dump.write(&options).unwrap();
+        assert_eq!(written, bytes);
+
+        let values: Vec<_> = Dump::new(LittleEndianBuffer(written))
+            .main()
+            .kn
+            .iter()
+            .map(|constant| match constant.value() {
+                NumericValue::Integer(value) => format!("{}", value),
+                NumericValue::Number(value) => format!("{:?}", value),
+            })
+            .collect();
+        assert_eq!(values, ["2.0", "2", "-0.0", "0.0", "0"]);
+    }
+}
@@ -0,0 +1,525 @@
+//! A textual disassembler for bytecode dumps.
+//!
+//! The output mimics the listing produced by `luajit -bl` (see `jit/bc.lua`), so that both can be
+//! diffed against each other when validating the parser. Single instructions can also be
+//! formatted inline with `Instruction::fmt_with`.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    fmt::{self, Write},
+};
+
+use crate::lua::bytecode::{Complex, Dump, Instruction, NumericValue, Prototype, format_number, format_string};
+
+/// The kind of an instruction operand.
+///
+/// This mirrors `BCMode` in `lj_bc.h`, except modes that do not affect formatting are merged.
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    None,
+    /// A slot, a literal, or a primitive.
+    Plain,
+    Uv,
+    Lits,
+    Num,
+    Str,
+    Func,
+    Jump,
+}
+
+/// The decoded operands of an instruction.
+struct Layout {
+    name: &'static str,
+    a: (Mode, u8),
+    b: Option<u8>,
+    d: (Mode, u16),
+}
+
+#[rustfmt::skip]
+fn layout(insn: &Instruction) -> Layout {
+    use Instruction as I;
+    use Mode::*;
+
+    let name = insn.mnemonic();
+    let ad = |ma, a: u8, md, d: u16| Layout { name, a: (ma, a), b: Option::None, d: (md, d) };
+    let abc = |a: u8, b: u8, mc, c: u8| Layout { name, a: (Plain, a), b: Some(b), d: (mc, c as u16) };
+
+    match *insn {
+        I::ISLT { a, d } => ad(Plain, a, Plain, d),
+        I::ISGE { a, d } => ad(Plain, a, Plain, d),
+        I::ISLE { a, d } => ad(Plain, a, Plain, d),
+        I::ISGT { a, d } => ad(Plain, a, Plain, d),
+        I::ISEQV { a, d } => ad(Plain, a, Plain, d),
+        I::ISNEV { a, d } => ad(Plain, a, Plain, d),
+        I::ISEQS { a, d } => ad(Plain, a, Str, d),
+        I::ISNES { a, d } => ad(Plain, a, Str, d),
+        I::ISEQN { a, d } => ad(Plain, a, Num, d),
+        I::ISNEN { a, d } => ad(Plain, a, Num, d),
+        I::ISEQP { a, d } => ad(Plain, a, Plain, d),
+        I::ISNEP { a, d } => ad(Plain, a, Plain, d),
+        I::ISTC { a, d } => ad(Plain, a, Plain, d),
+        I::ISFC { a, d } => ad(Plain, a, Plain, d),
+        I::IST { d } => ad(None, 0, Plain, d),
+        I::ISF { d } => ad(None, 0, Plain, d),
+        I::ISTYPE { a, d } => ad(Plain, a, Plain, d),
+        I::ISNUM { a, d } => ad(Plain, a, Plain, d),
+        I::MOV { a, d } => ad(Plain, a, Plain, d),
+        I::NOT { a, d } => ad(Plain, a, Plain, d),
+        I::UNM { a, d } => ad(Plain, a, Plain, d),
+        I::LEN { a, d } => ad(Plain, a, Plain, d),
+        I::ADDVN { a, b, c } => abc(a, b, Num, c),
+        I::SUBVN { a, b, c } => abc(a, b, Num, c),
+        I::MULVN { a, b, c } => abc(a, b, Num, c),
+        I::DIVVN { a, b, c } => abc(a, b, Num, c),
+        I::MODVN { a, b, c } => abc(a, b, Num, c),
+        I::ADDNV { a, b, c } => abc(a, b, Num, c),
+        I::SUBNV { a, b, c } => abc(a, b, Num, c),
+        I::MULNV { a, b, c } => abc(a, b, Num, c),
+        I::DIVNV { a, b, c } => abc(a, b, Num, c),
+        I::MODNV { a, b, c } => abc(a, b, Num, c),
+        I::ADDVV { a, b, c } => abc(a, b, Plain, c),
+        I::SUBVV { a, b, c } => abc(a, b, Plain, c),
+        I::MULVV { a, b, c } => abc(a, b, Plain, c),
+        I::DIVVV { a, b, c } => abc(a, b, Plain, c),
+        I::MODVV { a, b, c } => abc(a, b, Plain, c),
+        I::POW { a, b, c } => abc(a, b, Plain, c),
+        I::CAT { a, b, c } => abc(a, b, Plain, c),
+        I::KSTR { a, d } => ad(Plain, a, Str, d),
+        I::KCDATA { a, d } => ad(Plain, a, Plain, d),
+        I::KSHORT { a, d } => ad(Plain, a, Lits, d),
+        I::KNUM { a, d } => ad(Plain, a, Num, d),
+        I::KPRI { a, d } => ad(Plain, a, Plain, d),
+        I::KNIL { a, d } => ad(Plain, a, Plain, d),
+        I::UGET { a, d } => ad(Plain, a, Uv, d),
+        I::USETV { a, d } => ad(Uv, a, Plain, d),
+        I::USETS { a, d } => ad(Uv, a, Str, d),
+        I::USETN { a, d } => ad(Uv, a, Num, d),
+        I::USETP { a, d } => ad(Uv, a, Plain, d),
+        I::UCLO { a, d } => ad(Plain, a, Jump, d),
+        I::FNEW { a, d } => ad(Plain, a, Func, d),
+        I::TNEW { a, d } => ad(Plain, a, Plain, d),
+        I::TDUP { a, d } => ad(Plain, a, Plain, d),
+        I::GGET { a, d } => ad(Plain, a, Str, d),
+        I::GSET { a, d } => ad(Plain, a, Str, d),
+        I::TGETV { a, b, c } => abc(a, b, Plain, c),
+        I::TGETS { a, b, c } => abc(a, b, Str, c),
+        I::TGETB { a, b, c } => abc(a, b, Plain, c),
+        I::TGETR { a, b, c } => abc(a, b, Plain, c),
+        I::TSETV { a, b, c } => abc(a, b, Plain, c),
+        I::TSETS { a, b, c } => abc(a, b, Str, c),
+        I::TSETB { a, b, c } => abc(a, b, Plain, c),
+        I::TSETR { a, b, c } => abc(a, b, Plain, c),
+        I::TSETM { a, d } => ad(Plain, a, Num, d),
+        I::CALLM { a, b, c } => abc(a, b, Plain, c),
+        I::CALL { a, b, c } => abc(a, b, Plain, c),
+        I::CALLMT { a, d } => ad(Plain, a, Plain, d),
+        I::CALLT { a, d } => ad(Plain, a, Plain, d),
+        I::ITERC { a, b, c } => abc(a, b, Plain, c),
+        I::ITERN { a, b, c } => abc(a, b, Plain, c),
+        I::VARG { a, b, c } => abc(a, b, Plain, c),
+        I::ISNEXT { a, d } => ad(Plain, a, Jump, d),
+        I::RETM { a, d } => ad(Plain, a, Plain, d),
+        I::RET { a, d } => ad(Plain, a, Plain, d),
+        I::RET0 { a, d } => ad(Plain, a, Plain, d),
+        I::RET1 { a, d } => ad(Plain, a, Plain, d),
+        I::FORI { a, d } => ad(Plain, a, Jump, d),
+        I::JFORI { a, d } => ad(Plain, a, Jump, d),
+        I::FORL { a, d } => ad(Plain, a, Jump, d),
+        I::IFORL { a, d } => ad(Plain, a, Jump, d),
+        I::JFORL { a, d } => ad(Plain, a, Plain, d),
+        I::ITERL { a, d } => ad(Plain, a, Jump, d),
+        I::IITERL { a, d } => ad(Plain, a, Jump, d),
+        I::JITERL { a, d } => ad(Plain, a, Plain, d),
+        I::LOOP { a, d } => ad(Plain, a, Jump, d),
+        I::ILOOP { a, d } => ad(Plain, a, Jump, d),
+        I::JLOOP { a, d } => ad(Plain, a, Plain, d),
+        I::JMP { a, d } => ad(Plain, a, Jump, d),
+        I::FUNCF { a } => ad(Plain, a, None, 0),
+        I::IFUNCF { a } => ad(Plain, a, None, 0),
+        I::JFUNCF { a, d } => ad(Plain, a, Plain, d),
+        I::FUNCV { a } => ad(Plain, a, None, 0),
+        I::IFUNCV { a } => ad(Plain, a, None, 0),
+        I::JFUNCV { a, d } => ad(Plain, a, Plain, d),
+        I::FUNCC { a } => ad(Plain, a, None, 0),
+        I::FUNCCW { a } => ad(Plain, a, None, 0),
+        I::FUNC { a } => ad(Plain, a, None, 0),
+    }
+}
+
+/// Returns the absolute target of a jump instruction located at `pc`.
+fn jump_target(pc: usize, d: u16) -> isize {
+    pc as isize + d as isize - 0x7FFF
+}
+
+/// Returns the name of the chunk a dump was compiled from, or `?` if the dump is stripped.
+fn chunk_name(dump: &Dump) -> &str {
+    dump.name.as_deref().unwrap_or("?")
+}
+
+/// Returns the name of an upvalue; this is empty if the dump is stripped.
+fn upvalue_name(proto: &Prototype, index: usize) -> Option<Cow<'_, str>> {
+    if index >= proto.uvs.len() {
+        return None;
+    }
+
+    Some(proto.upvalue_name(index).unwrap_or_default())
+}
+
+/// Resolves the constant or upvalue referred to by the D (or C) operand of an instruction.
+///
+/// Child prototypes are only resolved, to the line they start at, if `dump` is given.
+fn constant(dump: Option<&Dump>, proto: &Prototype, layout: &Layout) -> Option<String> {
+    let (mode, d) = layout.d;
+    match mode {
+        Mode::Func => match proto.gc_constant(d as u32)? {
+            Complex::Prototype(index) => {
+                let dump = dump?;
+                let child = dump.prototype(*index)?;
+                Some(format!("{}:{}", chunk_name(dump), child.first_line()))
+            }
+            _ => None,
+        },
+        Mode::Uv => upvalue_name(proto, d as usize).map(Cow::into_owned),
+        Mode::Str => proto.string_constant(d as u32).map(format_string),
+        Mode::Num => {
+            let value = match proto.numeric_constant(d as u32)?.value() {
+                NumericValue::Integer(value) => value as f64,
+                NumericValue::Number(value) => value,
+            };
+
+            // TSETM stores its starting index in the mantissa of a biased double.
+            let value = if layout.name == "TSETM" {
+                value - (1u64 << 52) as f64
+            } else {
+                value
+            };
+
+            Some(format_number(value))
+        }
+        _ => None,
+    }
+}
+
+/// An instruction formatted with its operands resolved against its prototype.
+///
+/// This is returned by `Instruction::fmt_with`.
+pub struct FormattedInstruction<'a> {
+    insn: &'a Instruction,
+    proto: &'a Prototype,
+    pc: usize,
+}
+
+impl fmt::Display for FormattedInstruction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let layout = layout(self.insn);
+        let (mode_a, a) = layout.a;
+        let (mode_d, d) = layout.d;
+
+        // Upvalues are named after their variable when the dump has debug information.
+        let upvalue = |index: usize| match upvalue_name(self.proto, index) {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => index.to_string(),
+        };
+
+        let mut operands = vec![];
+        match mode_a {
+            Mode::None => {}
+            Mode::Uv => operands.push(upvalue(a as usize)),
+            _ => operands.push(a.to_string()),
+        }
+        operands.extend(layout.b.map(|b| b.to_string()));
+        match mode_d {
+            Mode::None => {}
+            // Targets of corrupt jumps may be negative, print them as such rather than wrapping.
+            Mode::Jump => operands.extend(
+                self.insn
+                    .branch_offset()
+                    .map(|offset| format!("=> {:04}", self.pc as isize + 1 + offset)),
+            ),
+            Mode::Lits => operands.push((d as i16).to_string()),
+            Mode::Uv => operands.push(upvalue(d as usize)),
+            _ => operands.push(constant(None, self.proto, &layout).unwrap_or_else(|| d.to_string())),
+        }
+
+        write!(f, "{}", layout.name)?;
+        if !operands.is_empty() {
+            write!(f, " {}", operands.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Instruction {
+    /// Formats this instruction on a single line, resolving its operands against the prototype
+    /// it belongs to: string and numeric constants are replaced by their value, upvalues by their
+    /// name, and branches by the index of their target.
+    ///
+    /// # Arguments
+    ///
+    /// * `proto` - The prototype this instruction belongs to.
+    /// * `pc` - The index of this instruction in the instructions of `proto`.
+    pub fn fmt_with<'a>(&'a self, proto: &'a Prototype, pc: usize) -> FormattedInstruction<'a> {
+        FormattedInstruction { insn: self, proto, pc }
+    }
+}
+
+/// Disassembles a `Dump` into a listing matching `luajit -bl`.
+pub struct Disassembler<'a> {
+    dump: &'a Dump,
+    mnemonics: &'a [&'a str],
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(dump: &'a Dump) -> Self {
+        Self { dump, mnemonics: &[] }
+    }
+
+    /// Overrides the names of opcodes, for forks of LuaJIT that rename or add opcodes but keep
+    /// their numbering.
+    ///
+    /// # Arguments
+    ///
+    /// * `mnemonics` - The names to use, indexed by opcode. Opcodes with an empty name or past the
+    ///   end of this table keep their usual name.
+    pub fn with_mnemonics(mut self, mnemonics: &'a [&'a str]) -> Self {
+        self.mnemonics = mnemonics;
+        self
+    }
+
+    fn write_instruction(
+        &self,
+        f: &mut impl Write,
+        proto: &Prototype,
+        pc: usize,
+        insn: &Instruction,
+        target: bool,
+    ) -> fmt::Result {
+        let layout = layout(insn);
+        let (mode_a, a) = layout.a;
+        let (mode_d, d) = layout.d;
+
+        let a = if mode_a == Mode::None {
+            String::new()
+        } else {
+            a.to_string()
+        };
+        let name = insn
+            .opcode(self.dump.version())
+            .and_then(|opcode| self.mnemonics.get(opcode as usize))
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&layout.name);
+        write!(f, "{:04} {} {:<6} {:>3} ", pc, if target { "=>" } else { "  " }, name, a)?;
+
+        if mode_d == Mode::Jump {
+            return writeln!(f, "=> {:04}", jump_target(pc, d));
+        } else if layout.b.is_none() && mode_d == Mode::None {
+            return writeln!(f);
+        }
+
+        let mut comment = constant(Some(self.dump), proto, &layout);
+        if mode_a == Mode::Uv {
+            let name = upvalue_name(proto, layout.a.1 as usize).unwrap_or_default();
+            comment = Some(match comment {
+                Some(comment) => format!("{} ; {}", name, comment),
+                None => name.to_string(),
+            });
+        }
+
+        let d = if mode_d == Mode::Lits {
+            d as i16 as i32
+        } else {
+            d as i32
+        };
+        match (layout.b, comment) {
+            (Some(b), Some(comment)) => writeln!(f, "{:>3} {:>3}  ; {}", b, d, comment),
+            (Some(b), None) => writeln!(f, "{:>3} {:>3}", b, d),
+            (None, Some(comment)) => writeln!(f, "{:>3}      ; {}", d, comment),
+            (None, None) => writeln!(f, "{:>3}", d),
+        }
+    }
+
+    fn write_prototype(&self, f: &mut impl Write, proto: &Prototype) -> fmt::Result {
+        writeln!(
+            f,
+            "-- BYTECODE -- {}:{}-{}",
+            chunk_name(self.dump),
+            proto.first_line(),
+            proto.first_line() + proto.line_count()
+        )?;
+
+        // Like LuaJIT, the header is not listed, so instructions are numbered from 1.
+        let instructions = proto.instructions.iter().enumerate().skip(1);
+
+        let targets = instructions
+            .clone()
+            .filter_map(|(pc, insn)| match layout(insn).d {
+                (Mode::Jump, d) => Some(jump_target(pc, d)),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+
+        for (pc, insn) in instructions {
+            self.write_instruction(f, proto, pc, insn, targets.contains(&(pc as isize)))?;
+        }
+
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for Disassembler<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Children are stored before their parents, which is the order `-bl` lists them in.
+        for proto in self.dump.prototypes() {
+            self.write_prototype(f, proto)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Dump {
+    /// Returns a listing of every prototype in this dump, matching the output of `luajit -bl`.
+    pub fn disassemble(&self) -> String {
+        Disassembler::new(self).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        bytecode::{
+            Dump, Instruction, LittleEndianBuffer,
+            fixture::{self, Kgc, Kn, Proto, abc, ad, jump},
+        },
+        disasm::Disassembler,
+    };
+
+    #[test]
+    pub fn test_disassemble() {
+        let child = Proto {
+            framesize: 1,
+            instructions: vec![ad("RET0", 0, 1)],
+            debug: Some(fixture::Debug {
+                firstline: 3,
+                numline: 1,
+                lines: vec![4],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let main = Proto {
+            flags: 1,
+            framesize: 5,
+            instructions: vec![
+                ad("KSTR", 0, 0),
+                ad("GGET", 1, 1),
+                ad("MOV", 2, 0),
+                ad("KNUM", 3, 0),
+                abc("CALL", 1, 1, 3),
+                ad("IST", 0, 0),
+                jump("JMP", 1, 6, 8),
+                ad("KSHORT", 1, (-5i16) as u16),
+                ad("FNEW", 1, 2),
+                ad("UGET", 2, 0),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Child, Kgc::Str(b"print"), Kgc::Str(b"hello")],
+            kn: vec![Kn::Num(1.5)],
+            ..Default::default()
+        };
+
+        let data = fixture::Dump {
+            protos: vec![child, main],
+            ..Default::default()
+        };
+
+        let dump = Dump::new(LittleEndianBuffer(data.build()));
+        assert_eq!(
+            dump.disassemble(),
+            "\
+-- BYTECODE -- test.lua:3-4
+0001    RET0     0   1
+
+-- BYTECODE -- test.lua:0-0
+0001    KSTR     0   0      ; \"hello\"
+0002    GGET     1   1      ; \"print\"
+0003    MOV      2   0
+0004    KNUM     3   0      ; 1.5
+0005    CALL     1   1   3
+0006    IST          0
+0007    JMP      1 => 0009
+0008    KSHORT   1  -5
+0009 => FNEW     1   2      ; test.lua:3
+0010    UGET     2   0
+0011    RET0     0   1
+
+"
+        );
+    }
+
+    #[test]
+    pub fn test_mnemonic_override() {
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("KSHORT", 0, 1), ad("RET0", 0, 1)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let mut mnemonics = vec![""; fixture::opcode("KSHORT") as usize];
+        mnemonics.push("KINT");
+
+        assert_eq!(
+            Disassembler::new(&dump).with_mnemonics(&mnemonics).to_string(),
+            "\
+-- BYTECODE -- ?:0-0
+0001    KINT     0   1
+0002    RET0     0   1
+
+"
+        );
+    }
+
+    #[test]
+    pub fn test_fmt_with() {
+        let main = Proto {
+            framesize: 2,
+            instructions: vec![
+                ad("KSTR", 0, 0),
+                abc("ADDVN", 1, 0, 0),
+                ad("KSHORT", 1, (-5i16) as u16),
+                jump("JMP", 1, 3, 0),
+                ad("IST", 0, 0),
+                ad("FUNCF", 2, 0),
+                ad("JMP", 0, 0),
+            ],
+            kgc: vec![Kgc::Str(b"hello")],
+            kn: vec![Kn::Num(1.5)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let proto = dump.main();
+        let formatted: Vec<_> = (0..)
+            .zip(&proto.instructions)
+            .skip(1)
+            .map(|(pc, insn)| insn.fmt_with(proto, pc).to_string())
+            .collect();
+
+        assert_eq!(
+            formatted,
+            [
+                "KSTR 0, \"hello\"",
+                "ADDVN 1, 0, 1.5",
+                "KSHORT 1, -5",
+                "JMP 1, => 0001",
+                "IST 0",
+                "FUNCF 2",
+                "JMP 0, => -32760"
+            ]
+        );
+        assert_eq!(Instruction::KSTR { a: 0, d: 1 }.fmt_with(proto, 0).to_string(), "KSTR 0, 1");
+    }
+}
@@ -13,14 +13,30 @@
 ///   * a `Slot`
 ///   * an `Expr`
 ///
+pub mod cfg;
+pub mod closures;
+pub mod codegen;
 pub mod emitter;
+pub mod expressions;
 pub mod function;
 pub mod insn;
+pub mod locals;
+pub mod loops;
 pub mod module;
 pub mod printer;
+pub mod slots;
+pub mod structure;
+pub mod visitor;
 
+pub use cfg::*;
+pub use closures::*;
 pub use emitter::*;
+pub use expressions::*;
 pub use function::*;
 pub use insn::*;
+pub use locals::*;
+pub use loops::*;
 pub use module::*;
-pub use printer::*;
+pub use slots::*;
+pub use structure::*;
+pub use visitor::*;
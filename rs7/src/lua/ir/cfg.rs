@@ -0,0 +1,298 @@
+//! Control-flow graphs of lifted instructions.
+
+use std::{collections::BTreeSet, fmt::Write};
+
+use crate::lua::ir::{BasicOperand, Emitter, Insn, Label, SlotSet, Visitor};
+
+/// The index of a basic block in a `Cfg`.
+pub type BlockId = usize;
+
+/// A straight-line sequence of IR instructions.
+pub struct BasicBlock<'a> {
+    /// Index of the first instruction of this block in the instruction stream.
+    pub start: usize,
+    pub instructions: &'a [Insn],
+}
+
+impl<'a> BasicBlock<'a> {
+    pub fn new(start: usize, instructions: &'a [Insn]) -> Self {
+        Self { start, instructions }
+    }
+
+    /// Computes the variable slots defined in this block, and the slots it reads before writing
+    /// them (which are live on entry if they are read at all).
+    ///
+    /// Returns the `(def, use)` pair.
+    pub fn def_use(&self) -> (SlotSet, SlotSet) {
+        #[derive(Default)]
+        struct DefUse {
+            def: SlotSet,
+            uses: SlotSet,
+        }
+
+        impl Visitor for DefUse {
+            fn visit_use(&mut self, operand: &BasicOperand) {
+                if let BasicOperand::Var(slot) = operand
+                    && !self.def.contains(*slot)
+                {
+                    self.uses.insert(*slot);
+                }
+            }
+
+            fn visit_def(&mut self, operand: &BasicOperand) {
+                if let BasicOperand::Var(slot) = operand {
+                    self.def.insert(*slot);
+                }
+            }
+        }
+
+        let mut visitor = DefUse::default();
+        self.instructions.iter().for_each(|insn| insn.accept(&mut visitor));
+        (visitor.def, visitor.uses)
+    }
+}
+
+/// Returns the label an instruction may branch to, and whether it may fall through to the next
+/// instruction.
+pub(crate) fn branch(insn: &Insn) -> (Option<&Label>, bool) {
+    match insn {
+        Insn::ConditionalBranch { target, .. } => (Some(target), true),
+        Insn::Branch { target } => (Some(target), false),
+        Insn::NumericForInit { exit, .. } => (Some(exit), true),
+        Insn::NumericForLoop { body, .. } | Insn::GenericForLoop { body, .. } => (Some(body), true),
+        Insn::TailCall { .. } | Insn::Return { .. } => (None, false),
+        Insn::Nop
+        | Insn::Assign { .. }
+        | Insn::ConditionalAssign { .. }
+        | Insn::StoreMulti { .. }
+        | Insn::TypeCheck { .. }
+        | Insn::CloseUpvalues { .. }
+        | Insn::Call { .. } => (None, true),
+    }
+}
+
+/// The control-flow graph of a lifted function.
+pub struct Cfg<'a> {
+    blocks: Vec<BasicBlock<'a>>,
+    successors: Vec<Vec<BlockId>>,
+}
+
+impl<'a> Cfg<'a> {
+    /// Splits the instructions of an emitter into basic blocks.
+    ///
+    /// Leaders are the first instruction, every branch target, and every instruction following a
+    /// branch or a return.
+    pub fn new(emitter: &'a Emitter) -> Self {
+        let instructions = emitter.instructions();
+
+        // Resolves a label to the IR instruction it points at.
+        let target = |label: &Label| match label {
+            Label::Label { bc } => Some(emitter.ir_index(*bc)).filter(|ir| *ir < instructions.len()),
+            Label::None => None,
+        };
+
+        let mut leaders = BTreeSet::from([0]);
+        for (ir, insn) in instructions.iter().enumerate() {
+            let (label, fallthrough) = branch(insn);
+            if label.is_some() || !fallthrough {
+                leaders.insert(ir + 1);
+            }
+            leaders.extend(label.and_then(target));
+        }
+        leaders.retain(|ir| *ir < instructions.len());
+
+        let starts: Vec<_> = leaders.into_iter().collect();
+        let blocks: Vec<_> = starts
+            .iter()
+            .enumerate()
+            .map(|(id, start)| {
+                let end = starts.get(id + 1).copied().unwrap_or(instructions.len());
+                BasicBlock::new(*start, &instructions[*start..end])
+            })
+            .collect();
+
+        let block_of = |ir: usize| starts.binary_search(&ir).ok();
+        let successors = blocks
+            .iter()
+            .enumerate()
+            .map(|(id, block)| {
+                let (label, fallthrough) = branch(&block.instructions[block.instructions.len() - 1]);
+
+                let mut successors = vec![];
+                if fallthrough && id + 1 < blocks.len() {
+                    successors.push(id + 1);
+                }
+                if let Some(block) = label.and_then(target).and_then(block_of)
+                    && !successors.contains(&block)
+                {
+                    successors.push(block);
+                }
+                successors
+            })
+            .collect();
+
+        Self { blocks, successors }
+    }
+
+    pub fn blocks(&self) -> &[BasicBlock<'a>] {
+        &self.blocks
+    }
+
+    /// Returns the blocks control may flow to from `block`; the fallthrough block comes first.
+    pub fn successors(&self, block: BlockId) -> &[BlockId] {
+        &self.successors[block]
+    }
+
+    /// Renders this graph in the Graphviz DOT language.
+    ///
+    /// Each block is labeled with the range of IR instructions it spans, followed by the
+    /// instructions themselves. The edges leaving a conditional branch are labeled with the
+    /// outcome of the condition that follows them.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+
+        for (id, block) in self.blocks.iter().enumerate() {
+            let end = block.start + block.instructions.len();
+            let mut label = format!("{}..{}\\l", block.start, end);
+            for (ir, insn) in (block.start..).zip(block.instructions) {
+                let insn = insn.to_string().replace('\\', "\\\\").replace('"', "\\\"");
+                _ = write!(label, "{:04}  {}\\l", ir, insn);
+            }
+            _ = writeln!(dot, "    b{} [label=\"{}\"];", id, label);
+        }
+
+        for (id, block) in self.blocks.iter().enumerate() {
+            let conditional = matches!(block.instructions.last(), Some(Insn::ConditionalBranch { .. }));
+            for (index, successor) in self.successors[id].iter().enumerate() {
+                // The fallthrough block comes first, unless the branch targets it as well.
+                let fallthrough = index == 0 && *successor == id + 1;
+                match (conditional, fallthrough) {
+                    (true, true) => _ = writeln!(dot, "    b{} -> b{} [label=\"false\"];", id, successor),
+                    (true, false) => _ = writeln!(dot, "    b{} -> b{} [label=\"true\"];", id, successor),
+                    (false, _) => _ = writeln!(dot, "    b{} -> b{};", id, successor),
+                }
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        bytecode::{
+            Instruction,
+            fixture::{self, Proto, ad, jump},
+        },
+        ir::{BasicBlock, Cfg, Emitter, Insn, SlotSet},
+    };
+
+    #[test]
+    pub fn test_def_use() {
+        let mut emitter = Emitter::new();
+        [
+            Instruction::MOV { a: 1, d: 0 },
+            Instruction::ADDVV { a: 2, b: 1, c: 3 },
+            Instruction::MOV { a: 0, d: 2 },
+            Instruction::TGETV { a: 4, b: 0, c: 5 },
+            Instruction::RET { a: 3, d: 3 },
+        ]
+        .into_iter()
+        .enumerate()
+        .for_each(|(pc, insn)| Insn::parse(insn, pc, false, &mut emitter));
+
+        let block = BasicBlock::new(0, &emitter.instructions);
+        let (def, uses) = block.def_use();
+
+        assert_eq!(def, SlotSet::from_iter([0, 1, 2, 4]));
+        assert_eq!(uses, SlotSet::from_iter([0, 3, 5]));
+    }
+
+    #[test]
+    pub fn test_def_use_out_of_range() {
+        // The loop variable and the results of the call lie beyond the widest frame.
+        let mut emitter = Emitter::new();
+        [
+            Instruction::FORI { a: 254, d: 0x8000 },
+            Instruction::CALL { a: 255, b: 4, c: 1 },
+        ]
+        .into_iter()
+        .enumerate()
+        .for_each(|(pc, insn)| Insn::parse(insn, pc, false, &mut emitter));
+
+        let (def, uses) = BasicBlock::new(0, &emitter.instructions).def_use();
+        assert_eq!(def, SlotSet::from_iter([255]));
+        assert_eq!(uses, SlotSet::from_iter([254, 255]));
+    }
+
+    /// Lifts the instructions of a stripped main prototype.
+    fn lift(numparams: u8, instructions: Vec<u32>) -> Emitter {
+        let main = Proto {
+            numparams,
+            framesize: 3,
+            instructions,
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let mut emitter = Emitter::new();
+        dump.main()
+            .instructions
+            .iter()
+            .enumerate()
+            .for_each(|(pc, insn)| Insn::parse(*insn, pc, false, &mut emitter));
+        emitter
+    }
+
+    #[test]
+    pub fn test_if_else() {
+        // function(x, y) local z; if x < y then z = 1 else z = 2 end return z end
+        let emitter = lift(
+            2,
+            vec![
+                ad("ISGE", 0, 1),
+                jump("JMP", 3, 1, 4),
+                ad("KSHORT", 2, 1),
+                jump("JMP", 3, 3, 5),
+                ad("KSHORT", 2, 2),
+                ad("RET1", 2, 2),
+            ],
+        );
+
+        let cfg = Cfg::new(&emitter);
+        let starts: Vec<_> = cfg.blocks().iter().map(|block| block.start).collect();
+        assert_eq!(starts, [0, 2, 4, 5]);
+
+        let successors: Vec<_> = (0..cfg.blocks().len()).map(|id| cfg.successors(id)).collect();
+        assert_eq!(successors, [&[1, 2][..], &[3], &[3], &[]]);
+    }
+
+    #[test]
+    pub fn test_to_dot() {
+        // function(x, y) if x < y then return x end return y end
+        let emitter = lift(
+            2,
+            vec![
+                ad("ISGE", 0, 1),
+                jump("JMP", 2, 1, 3),
+                ad("RET1", 0, 2),
+                ad("RET1", 1, 2),
+            ],
+        );
+
+        assert_eq!(
+            Cfg::new(&emitter).to_dot(),
+            r#"digraph cfg {
+    node [shape=box, fontname="monospace"];
+    b0 [label="0..2\l0000  nop\l0001  if v0 >= v1 goto 0004\l"];
+    b1 [label="2..3\l0002  return v0\l"];
+    b2 [label="3..4\l0003  return v1\l"];
+    b0 -> b1 [label="false"];
+    b0 -> b2 [label="true"];
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Recognition of function definitions.
+//!
+//! `function obj.m(x)` is sugar for `obj.m = function(x) ... end`, which compiles to an `FNEW`
+//! immediately stored with `GSET` or `TSETS`. Methods (`function obj:m(x)`) additionally declare
+//! an implicit first parameter named `self`, which is only visible in unstripped dumps.
+
+use std::borrow::Cow;
+
+use crate::lua::bytecode::{Complex, Dump, Instruction, Prototype};
+
+/// Where a function definition stores its closure.
+#[derive(Debug, PartialEq)]
+pub enum DefinitionTarget {
+    /// `function name()`.
+    Global(String),
+    /// `function table.name()` or `function table:name()`.
+    Field { table: String, name: String },
+}
+
+/// A named function definition.
+#[derive(Debug, PartialEq)]
+pub struct Definition {
+    /// Index of the `FNEW` instruction.
+    pub pc: usize,
+    /// Index of the defined prototype in the dump.
+    pub proto: usize,
+    pub target: DefinitionTarget,
+    /// Whether the definition uses the `:` syntax, hiding the implicit `self` parameter.
+    pub method: bool,
+    /// The declared parameters, excluding `self` for methods.
+    pub parameters: Vec<String>,
+    pub vararg: bool,
+}
+
+impl Definition {
+    /// Renders the name of the defined function, e.g. `obj:m`.
+    pub fn name(&self) -> String {
+        match &self.target {
+            DefinitionTarget::Global(name) => name.clone(),
+            DefinitionTarget::Field { table, name } => {
+                format!("{}{}{}", table, if self.method { ':' } else { '.' }, name)
+            }
+        }
+    }
+
+    /// Renders the first line of the definition, e.g. `function obj:m(x)`.
+    pub fn header(&self) -> String {
+        let mut parameters = self.parameters.clone();
+        if self.vararg {
+            parameters.push("...".to_string());
+        }
+
+        format!("function {}({})", self.name(), parameters.join(", "))
+    }
+}
+
+fn string(proto: &Prototype, d: u16) -> Option<String> {
+    proto
+        .string_constant(d as u32)
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// Names the value held in `slot` right before `pc`: the global it was just loaded from, or the
+/// local variable it belongs to.
+fn slot_name(proto: &Prototype, pc: usize, slot: u8) -> String {
+    let global = match pc.checked_sub(1).map(|pc| proto.instructions[pc]) {
+        Some(Instruction::GGET { a, d }) if a == slot => string(proto, d),
+        _ => None,
+    };
+
+    global
+        .or_else(|| {
+            let debug = proto.debug()?;
+            debug.variable_name(pc as u32, slot as u32).map(Cow::into_owned)
+        })
+        .unwrap_or_else(|| format!("slot{}", slot))
+}
+
+/// Recognizes every named function definition in a prototype.
+pub fn find_definitions(dump: &Dump, proto: &Prototype) -> Vec<Definition> {
+    let mut definitions = vec![];
+
+    for (pc, pair) in proto.instructions.windows(2).enumerate() {
+        let Instruction::FNEW { a: closure, d } = pair[0] else {
+            continue;
+        };
+
+        let Some(Complex::Prototype(index)) = proto.gc_constant(d as u32) else {
+            continue;
+        };
+        let Some(child) = dump.prototype(*index) else {
+            continue;
+        };
+
+        let target = match pair[1] {
+            Instruction::GSET { a, d } if a == closure => string(proto, d).map(DefinitionTarget::Global),
+            Instruction::TSETS { a, b, c } if a == closure => {
+                string(proto, c as u16).map(|name| DefinitionTarget::Field {
+                    table: slot_name(proto, pc, b),
+                    name,
+                })
+            }
+            _ => None,
+        };
+
+        let Some(target) = target else {
+            continue;
+        };
+
+        // Parameters are the first variables of a prototype.
+        let mut parameters: Vec<_> = (0..child.parameter_count() as usize)
+            .map(|i| {
+                child
+                    .debug()
+                    .and_then(|debug| debug.variables().get(i))
+                    .map_or_else(|| format!("arg{}", i), |var| var.name_lossy().into_owned())
+            })
+            .collect();
+
+        let method =
+            matches!(target, DefinitionTarget::Field { .. }) && parameters.first().is_some_and(|p| p == "self");
+        if method {
+            parameters.remove(0);
+        }
+
+        definitions.push(Definition {
+            pc,
+            proto: *index,
+            target,
+            method,
+            parameters,
+            vararg: child.is_vararg(),
+        });
+    }
+
+    definitions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        bytecode::{
+            Dump, LittleEndianBuffer,
+            fixture::{self, Kgc, Proto, abc, ad},
+        },
+        ir::closures::find_definitions,
+    };
+
+    fn definition(parameters: Vec<(&'static str, u32, u32)>, store: u32) -> String {
+        let child = Proto {
+            numparams: parameters.len() as u8,
+            framesize: 2,
+            instructions: vec![ad("RET0", 0, 1)],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![2],
+                variables: parameters,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let main = Proto {
+            flags: 1,
+            framesize: 2,
+            instructions: vec![ad("GGET", 0, 0), ad("FNEW", 1, 1), store, ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Str(b"m"), Kgc::Child, Kgc::Str(b"obj")],
+            debug: Some(fixture::Debug {
+                firstline: 0,
+                numline: 3,
+                lines: vec![1, 3, 1, 3],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump {
+            protos: vec![child, main],
+            ..Default::default()
+        }
+        .build();
+
+        let dump = Dump::new(LittleEndianBuffer(bytes));
+        let definitions = find_definitions(&dump, dump.main());
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].proto, 0);
+        definitions[0].header()
+    }
+
+    #[test]
+    pub fn test_method_definition() {
+        // function obj:m(x) end
+        let header = definition(vec![("self", 0, 2), ("x", 0, 2)], abc("TSETS", 1, 0, 2));
+        assert_eq!(header, "function obj:m(x)");
+    }
+
+    #[test]
+    pub fn test_field_definition() {
+        // function obj.m(a, b) end
+        let header = definition(vec![("a", 0, 2), ("b", 0, 2)], abc("TSETS", 1, 0, 2));
+        assert_eq!(header, "function obj.m(a, b)");
+    }
+
+    #[test]
+    pub fn test_global_definition() {
+        // function m(self) end
+        let header = definition(vec![("self", 0, 2)], ad("GSET", 1, 2));
+        assert_eq!(header, "function m(self)");
+    }
+}
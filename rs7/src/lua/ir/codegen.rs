@@ -0,0 +1,1141 @@
+//! Lua source reconstruction.
+//!
+//! A temporary is folded into the expression reading it when it is read exactly once, later in the
+//! same basic block, and only other folded values are computed in between; every other instruction
+//! becomes a statement. Control flow is recovered by `structure`; branches it cannot structure are
+//! written as `goto`, and generic `for` loops are left as comments. Within a `Module`, named
+//! function definitions are written with their bodies; other closures are referred to by index.
+
+use std::{collections::BTreeSet, fmt::Write};
+
+use crate::lua::{
+    ast::{Expression, FormatOptions, is_lua_identifier},
+    ir::{
+        BasicBlock, BasicOperand, Cfg, Declaration, Definition, DefinitionTarget, Expr, Expressions, Function, Insn,
+        Loop, Module, Operand, Place, SlotSet, StructuredStmt, Visitor, declarations, deferred_initializer,
+        find_definitions, find_loops, numeric_for_bounds, structure,
+    },
+};
+
+/// Counts the reads of a slot by an instruction, and whether it writes the slot.
+struct Access {
+    slot: BasicOperand,
+    reads: usize,
+    written: bool,
+}
+
+impl Access {
+    fn of(insn: &Insn, slot: u32) -> Self {
+        let mut access = Self {
+            slot: BasicOperand::Var(slot),
+            reads: 0,
+            written: false,
+        };
+        insn.accept(&mut access);
+        access
+    }
+}
+
+impl Visitor for Access {
+    fn visit_use(&mut self, operand: &BasicOperand) {
+        if *operand == self.slot {
+            self.reads += 1;
+        }
+    }
+
+    fn visit_def(&mut self, operand: &BasicOperand) {
+        self.written |= *operand == self.slot;
+    }
+}
+
+/// Returns `true` if an instruction is written as a statement that uses the values it reads.
+fn consumes(insn: &Insn) -> bool {
+    matches!(
+        insn,
+        Insn::Assign { .. }
+            | Insn::ConditionalAssign { .. }
+            | Insn::ConditionalBranch { .. }
+            | Insn::Call { iterator: false, .. }
+            | Insn::TailCall { .. }
+            | Insn::Return { .. }
+            | Insn::NumericForInit { .. }
+    )
+}
+
+/// Returns `true` if an expression can name the table of a function definition, such as `a.b`.
+fn is_name(expression: &Expression) -> bool {
+    match expression {
+        Expression::Local(_) | Expression::Upvalue(_) | Expression::Global(_) => true,
+        Expression::Index(table, key) => {
+            matches!(&**key, Expression::String(name) if str::from_utf8(name).is_ok_and(is_lua_identifier))
+                && is_name(table)
+        }
+        _ => false,
+    }
+}
+
+/// Computes the slots live on exit of each block of a control-flow graph.
+fn live_out(cfg: &Cfg) -> Vec<SlotSet> {
+    let def_use: Vec<_> = cfg.blocks().iter().map(BasicBlock::def_use).collect();
+    let mut live_in = vec![SlotSet::new(); def_use.len()];
+    let mut live_out = live_in.clone();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (id, (def, uses)) in def_use.iter().enumerate().rev() {
+            let out = cfg
+                .successors(id)
+                .iter()
+                .fold(SlotSet::new(), |out, successor| out.union(&live_in[*successor]));
+            let entry = uses.union(&out.difference(def));
+
+            changed |= entry != live_in[id];
+            live_in[id] = entry;
+            live_out[id] = out;
+        }
+    }
+
+    live_out
+}
+
+struct Codegen<'f, 'a> {
+    function: &'f Function<'a>,
+    /// The module of the function, whose named definitions are written with their bodies.
+    module: Option<&'f Module<'a>>,
+    definitions: Vec<Definition>,
+    /// Whether the value computed by each instruction is folded into the expression reading it.
+    folded: Vec<bool>,
+    declarations: Vec<Declaration>,
+    /// The slots without a name in the debug information that were declared with `local`.
+    declared: SlotSet,
+    /// The nil initializers of locals declared by a later assignment instead, which are not written.
+    deferred: BTreeSet<usize>,
+    /// The assignments declaring a local whose nil initializer is not written.
+    initializers: BTreeSet<usize>,
+    /// The branch targets written as labels.
+    labels: BTreeSet<usize>,
+    /// The first instruction of each basic block.
+    leaders: BTreeSet<usize>,
+    loops: Vec<Loop>,
+    expressions: Expressions<'a>,
+    options: &'f FormatOptions,
+    out: String,
+}
+
+impl<'f, 'a> Codegen<'f, 'a> {
+    fn new(function: &'f Function<'a>, module: Option<&'f Module<'a>>, cfg: &Cfg, options: &'f FormatOptions) -> Self {
+        let mut codegen = Self {
+            function,
+            module,
+            definitions: module.map_or_else(Vec::new, |module| find_definitions(module.dump(), function.prototype())),
+            folded: vec![],
+            declarations: declarations(function.prototype()),
+            declared: SlotSet::new(),
+            deferred: BTreeSet::new(),
+            initializers: BTreeSet::new(),
+            labels: BTreeSet::new(),
+            leaders: cfg.blocks().iter().map(|block| block.start).collect(),
+            loops: find_loops(&function.prototype().instructions),
+            expressions: Expressions::new(function.prototype(), 0, 0).with_options(options),
+            options,
+            out: String::new(),
+        };
+        codegen.folded = codegen.find_folded(cfg);
+
+        // The nil initializer is the last instruction writing the slot before the assignment.
+        let instructions = function.instructions();
+        for declaration in &codegen.declarations {
+            if let Some(ir) = deferred_initializer(declaration, function, cfg) {
+                let initializer = (0..ir)
+                    .rev()
+                    .find(|initializer| Access::of(&instructions[*initializer], declaration.slot).written);
+                codegen.deferred.extend(initializer);
+                codegen.initializers.insert(ir);
+            }
+        }
+        codegen
+    }
+
+    fn is_parameter(&self, slot: u32) -> bool {
+        let proto = self.function.prototype();
+        (0..proto.parameter_count() as usize).any(|i| proto.param_slot(i) == Some(slot))
+    }
+
+    /// Returns the position at which the debug information names the slot once the instruction at
+    /// `ir` ran, if it does.
+    ///
+    /// A local comes into scope after the statement computing its initial value, and the scope of a
+    /// local ends right after the last instruction assigning it. The hidden control variables of
+    /// `for` loops have no name.
+    fn name_position(&self, slot: u32, ir: usize) -> Option<usize> {
+        let function = self.function;
+        let pc = function.emitter().position(ir);
+        let debug = function.prototype().debug()?;
+        let named = |pc: usize| {
+            debug
+                .variable_name(pc as u32, slot)
+                .is_some_and(|name| !name.is_empty())
+        };
+        if let Some(pc) = [pc + 1, pc].into_iter().find(|pc| named(*pc)) {
+            return Some(pc);
+        }
+
+        // The initial values of a multiple assignment or the entries of a table constructor are
+        // computed after the first value of the statement is stored.
+        let declaration = self
+            .declarations
+            .iter()
+            .find(|declaration| declaration.slot == slot && declaration.pc > pc)?;
+        let end = function.emitter().ir_index(declaration.pc);
+        let rewritten = (ir + 1..end).any(|ir| Access::of(&function.instructions()[ir], slot).written);
+        (!rewritten).then_some(declaration.pc)
+    }
+
+    /// Returns `true` if the debug information names the slot once the instruction at `ir` ran.
+    fn is_named(&self, slot: u32, ir: usize) -> bool {
+        self.name_position(slot, ir).is_some()
+    }
+
+    /// Decides which instructions compute a value that is folded into the expression reading it.
+    fn find_folded(&self, cfg: &Cfg) -> Vec<bool> {
+        let instructions = self.function.instructions();
+        let live = live_out(cfg);
+
+        // Whether a value is folded depends on the instructions that follow it.
+        let mut folded = vec![false; instructions.len()];
+        for (id, block) in cfg.blocks().iter().enumerate().rev() {
+            let end = block.start + block.instructions.len();
+            for def in (block.start..end).rev() {
+                let slot = match &instructions[def] {
+                    // Multi-result tails are always consumed by the next instruction that uses one.
+                    Insn::Assign {
+                        rhs: Operand::Expr(Expr::Vararg { multi: true }),
+                        ..
+                    }
+                    | Insn::Call {
+                        results: None,
+                        iterator: false,
+                        ..
+                    } => {
+                        folded[def] = true;
+                        continue;
+                    }
+                    Insn::Assign {
+                        lhs: Place::Basic(BasicOperand::Var(slot)),
+                        ..
+                    } => *slot,
+                    Insn::Call {
+                        results: Some(results),
+                        iterator: false,
+                        ..
+                    } if results.len() == 1 => results.start,
+                    _ => continue,
+                };
+
+                folded[def] = !self.is_parameter(slot)
+                    && !self.is_named(slot, def)
+                    && self.is_read_once(def, slot, end, &live[id], &folded);
+            }
+        }
+
+        folded
+    }
+
+    /// Returns `true` if the value the instruction at `def` stores in a slot is read by a single
+    /// statement before the end of its block, with only folded values computed in between.
+    fn is_read_once(&self, def: usize, slot: u32, end: usize, live: &SlotSet, folded: &[bool]) -> bool {
+        let instructions = self.function.instructions();
+
+        let mut reader = None;
+        let mut overwritten = false;
+        for (ir, insn) in (def + 1..end).zip(&instructions[def + 1..end]) {
+            let access = Access::of(insn, slot);
+            if access.reads > 0 {
+                if reader.is_some() || access.reads > 1 {
+                    return false;
+                }
+                reader = Some(ir);
+            }
+
+            if access.written {
+                // A conditional assignment keeps the value on one of the paths.
+                if matches!(insn, Insn::ConditionalAssign { .. }) {
+                    return false;
+                }
+                overwritten = true;
+                break;
+            }
+        }
+
+        let Some(reader) = reader else {
+            return false;
+        };
+
+        // The control slots of a numeric `for` loop are only read by the loop itself.
+        let hidden = matches!(instructions[reader], Insn::NumericForInit { .. });
+        (overwritten || hidden || !live.contains(slot))
+            && consumes(&instructions[reader])
+            && (def + 1..reader).all(|ir| folded[ir] || matches!(instructions[ir], Insn::Nop))
+    }
+
+    fn collect_labels(&mut self, statement: &StructuredStmt) {
+        match statement {
+            StructuredStmt::Block(statements) => statements.iter().for_each(|statement| self.collect_labels(statement)),
+            StructuredStmt::If { then, otherwise, .. } => {
+                self.collect_labels(then);
+                otherwise.iter().for_each(|otherwise| self.collect_labels(otherwise));
+            }
+            StructuredStmt::While { body, .. } | StructuredStmt::NumericFor { body, .. } => self.collect_labels(body),
+            StructuredStmt::Goto { target, .. } => _ = self.labels.insert(*target),
+            StructuredStmt::Insn(_) | StructuredStmt::Break { .. } => {}
+        }
+    }
+
+    fn render(&self, expression: &Expression) -> String {
+        self.expressions.display(expression).to_string()
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        _ = writeln!(self.out, "{}{}", "    ".repeat(depth), text);
+    }
+
+    /// Writes a `return` or `break` statement, which must be the last statement of its block.
+    fn terminator(&mut self, depth: usize, text: &str, last: bool) {
+        match last {
+            true => self.line(depth, text),
+            false => self.line(depth, &format!("do {} end", text)),
+        }
+    }
+
+    /// Names the slot an instruction writes to, returning whether it must be declared.
+    fn variable(&mut self, slot: u32, ir: usize) -> (bool, String) {
+        self.expressions.discard(slot);
+
+        let pc = self.function.emitter().position(ir);
+        let Some(named) = self.name_position(slot, ir) else {
+            let declare = !self.is_parameter(slot) && self.declared.insert(slot);
+            return (declare, self.render(&self.expressions.slot(slot)));
+        };
+
+        let declare = named > pc
+            && self
+                .declarations
+                .iter()
+                .any(|declaration| declaration.pc == named && declaration.slot == slot);
+
+        self.expressions.name_at(slot, named);
+        (declare, self.render(&self.expressions.slot(slot)))
+    }
+
+    fn place(&mut self, place: &Place, ir: usize) -> (bool, String) {
+        match place {
+            Place::Basic(BasicOperand::Var(slot)) => self.variable(*slot, ir),
+            Place::Basic(operand) => (false, self.render(&self.expressions.basic(operand))),
+            Place::Index(table, key) => {
+                let index = Expression::index(self.expressions.basic(table), self.expressions.basic(key));
+                (false, self.render(&index))
+            }
+        }
+    }
+
+    /// Renders the first line of the numeric `for` loop initialized by the instruction at `ir`.
+    fn numeric_for(&mut self, ir: usize) -> String {
+        let function = self.function;
+        let pc = function.emitter().position(ir);
+        let lp = self
+            .loops
+            .iter()
+            .find(|lp| lp.header == pc)
+            .expect("Structured numeric for loops are found by find_loops");
+        let bounds = numeric_for_bounds(function.prototype(), lp, function.is_gc64());
+
+        let insn = &function.instructions()[ir];
+        let Insn::NumericForInit { base, .. } = *insn else {
+            unreachable!("Expected a numeric for loop");
+        };
+        (base..base + 3).for_each(|slot| self.expressions.discard(slot));
+        self.expressions.discard_written(insn);
+        bounds.header(self.options)
+    }
+
+    fn condition(&mut self, ir: usize) -> Expression {
+        self.expressions.seek(self.function.emitter().position(ir));
+        let Insn::ConditionalBranch { cond, .. } = &self.function.instructions()[ir] else {
+            unreachable!("Expected a conditional branch");
+        };
+        self.expressions.operand(cond)
+    }
+
+    /// Returns the index of the first IR instruction of a statement, if it has one.
+    fn start(statement: &StructuredStmt) -> Option<usize> {
+        match statement {
+            StructuredStmt::Block(statements) => statements.first().and_then(Self::start),
+            StructuredStmt::Insn(ir)
+            | StructuredStmt::If { branch: ir, .. }
+            | StructuredStmt::NumericFor { init: ir, .. }
+            | StructuredStmt::Break { branch: ir }
+            | StructuredStmt::Goto { branch: ir, .. } => Some(*ir),
+            StructuredStmt::While { header, exit, body } => match exit {
+                Some(_) => Some(header.start),
+                None => Self::start(body),
+            },
+        }
+    }
+
+    /// Writes the statements of a block.
+    fn write_block(&mut self, block: &StructuredStmt, depth: usize) {
+        let StructuredStmt::Block(statements) = block else {
+            unreachable!("Expected a block");
+        };
+
+        for (index, statement) in statements.iter().enumerate() {
+            self.write_statement(statement, depth, index + 1 == statements.len());
+        }
+    }
+
+    fn write_statement(&mut self, statement: &StructuredStmt, depth: usize, last: bool) {
+        if let Some(start) = Self::start(statement)
+            && self.labels.contains(&start)
+        {
+            self.line(depth, &format!("::label{}::", start));
+        }
+
+        match statement {
+            StructuredStmt::Block(_) => {
+                self.line(depth, "do");
+                self.write_block(statement, depth + 1);
+                self.line(depth, "end");
+            }
+            StructuredStmt::Insn(ir) => self.write_insn(*ir, depth, last),
+            StructuredStmt::If {
+                branch,
+                then,
+                otherwise,
+            } => {
+                let cond = self.condition(*branch).negate();
+                self.line(depth, &format!("if {} then", self.render(&cond)));
+                self.write_block(then, depth + 1);
+                if let Some(otherwise) = otherwise {
+                    self.line(depth, "else");
+                    self.write_block(otherwise, depth + 1);
+                }
+                self.line(depth, "end");
+            }
+            StructuredStmt::While {
+                header,
+                exit: Some(exit),
+                body,
+            } if header.clone().all(|ir| self.folded[ir]) => {
+                header.clone().for_each(|ir| self.write_insn(ir, depth, false));
+                let cond = self.condition(*exit).negate();
+                self.line(depth, &format!("while {} do", self.render(&cond)));
+                self.write_block(body, depth + 1);
+                self.line(depth, "end");
+            }
+            // The condition needs statements to be evaluated.
+            StructuredStmt::While { header, exit, body } => {
+                self.line(depth, "while true do");
+                header.clone().for_each(|ir| self.write_insn(ir, depth + 1, false));
+                if let Some(exit) = exit {
+                    let cond = self.condition(*exit);
+                    self.line(depth + 1, &format!("if {} then break end", self.render(&cond)));
+                }
+                self.write_block(body, depth + 1);
+                self.line(depth, "end");
+            }
+            StructuredStmt::NumericFor { init, body } => {
+                let header = self.numeric_for(*init);
+                self.line(depth, &header);
+                self.write_block(body, depth + 1);
+                self.line(depth, "end");
+            }
+            StructuredStmt::Break { branch } => match &self.function.instructions()[*branch] {
+                Insn::ConditionalBranch { .. } => {
+                    let cond = self.condition(*branch);
+                    self.line(depth, &format!("if {} then break end", self.render(&cond)));
+                }
+                _ => self.terminator(depth, "break", last),
+            },
+            StructuredStmt::Goto { branch, target } => match &self.function.instructions()[*branch] {
+                Insn::ConditionalBranch { .. } => {
+                    let cond = self.condition(*branch);
+                    self.line(depth, &format!("if {} then goto label{} end", self.render(&cond), target));
+                }
+                _ => self.line(depth, &format!("goto label{}", target)),
+            },
+        }
+    }
+
+    /// Writes the function definition whose closure is stored by the instruction at `ir`, if any.
+    ///
+    /// Returns `false` if the store is written as an assignment instead.
+    fn write_definition(&mut self, ir: usize, depth: usize) -> bool {
+        let function = self.function;
+        let pc = function.emitter().position(ir);
+        let Some(module) = self.module else {
+            return false;
+        };
+        // The closure is created right before being stored, and folded into the store.
+        let Some(definition) = self
+            .definitions
+            .iter()
+            .find(|definition| definition.pc + 1 == pc && ir > 0 && self.folded[ir - 1])
+        else {
+            return false;
+        };
+        let (Some(child), Insn::Assign { lhs, .. }) =
+            (module.functions().get(definition.proto), &function.instructions()[ir])
+        else {
+            return false;
+        };
+
+        // The table is named as it is elsewhere in the source, rather than after the slot holding it.
+        let target = match (lhs, &definition.target) {
+            (Place::Basic(BasicOperand::Global(_)), DefinitionTarget::Global(name)) if is_lua_identifier(name) => {
+                DefinitionTarget::Global(name.clone())
+            }
+            (Place::Index(table, _), DefinitionTarget::Field { name, .. }) if is_lua_identifier(name) => {
+                let table = self.expressions.basic(table);
+                if !is_name(&table) {
+                    return false;
+                }
+                DefinitionTarget::Field {
+                    table: self.render(&table),
+                    name: name.clone(),
+                }
+            }
+            _ => return false,
+        };
+
+        let header = Definition {
+            target,
+            parameters: definition.parameters.clone(),
+            ..*definition
+        }
+        .header();
+        let body = generate(child, self.module, self.options);
+
+        self.line(depth, &header);
+        body.lines().for_each(|line| self.line(depth + 1, line));
+        self.line(depth, "end");
+        true
+    }
+
+    fn write_insn(&mut self, ir: usize, depth: usize, last: bool) {
+        let function = self.function;
+        let insn = &function.instructions()[ir];
+        self.expressions.seek(function.emitter().position(ir));
+        if self.leaders.contains(&ir) {
+            self.expressions.clear();
+        }
+
+        if self.folded[ir] {
+            self.expressions.assign(insn);
+            return;
+        }
+
+        if self.write_definition(ir, depth) {
+            return;
+        }
+
+        match insn {
+            Insn::Nop | Insn::CloseUpvalues { .. } | Insn::Branch { .. } => {}
+            Insn::Assign { lhs, rhs } => {
+                let value = self.expressions.operand(rhs);
+                let (declare, name) = self.place(lhs, ir);
+                match declare || self.initializers.contains(&ir) {
+                    _ if self.deferred.contains(&ir) => {}
+                    true if value == Expression::Nil => self.line(depth, &format!("local {}", name)),
+                    true => self.line(depth, &format!("local {} = {}", name, self.render(&value))),
+                    false => self.line(depth, &format!("{} = {}", name, self.render(&value))),
+                }
+            }
+            Insn::ConditionalAssign { cond, lhs, rhs } => {
+                let cond = self.render(&self.expressions.operand(cond));
+                let value = self.render(&self.expressions.operand(rhs));
+                let (declare, name) = self.place(lhs, ir);
+                if declare {
+                    self.line(depth, &format!("local {}", name));
+                }
+                self.line(depth, &format!("if {} then {} = {} end", cond, name, value));
+            }
+            Insn::Call {
+                func,
+                args,
+                results,
+                multi,
+                iterator: false,
+                ..
+            } => {
+                let args = self.expressions.values(args, *multi);
+                let call = self.render(&Expression::call(self.expressions.basic(func), args));
+
+                let variables: Vec<_> = results
+                    .iter()
+                    .flat_map(Clone::clone)
+                    .map(|slot| self.variable(slot, ir))
+                    .collect();
+                let names: Vec<_> = variables.iter().map(|(_, name)| name.as_str()).collect();
+                match variables.iter().any(|(declare, _)| *declare) {
+                    _ if names.is_empty() => self.line(depth, &call),
+                    true => self.line(depth, &format!("local {} = {}", names.join(", "), call)),
+                    false => self.line(depth, &format!("{} = {}", names.join(", "), call)),
+                }
+            }
+            Insn::TailCall { base, args, multi } => {
+                let args = self.expressions.values(args, *multi);
+                let call = self.render(&Expression::call(self.expressions.slot(*base), args));
+                self.terminator(depth, &format!("return {}", call), last);
+            }
+            Insn::Return { values, multi } => {
+                let values = self.expressions.values(values, *multi);
+                let values: Vec<_> = values.iter().map(|value| self.render(value)).collect();
+
+                // Functions implicitly return nothing at their end.
+                if values.is_empty() && ir + 1 == function.instructions().len() {
+                    return;
+                }
+
+                match values.is_empty() {
+                    true => self.terminator(depth, "return", last),
+                    false => self.terminator(depth, &format!("return {}", values.join(", ")), last),
+                }
+            }
+            Insn::StoreMulti { lhs } => {
+                let values = self.expressions.values(&[], true);
+                let values: Vec<_> = values.iter().map(|value| self.render(value)).collect();
+                let (_, lhs) = self.place(lhs, ir);
+                self.line(depth, &format!("-- {}, ... = {}", lhs, values.join(", ")));
+            }
+            _ => self.line(depth, &format!("-- {}", insn)),
+        }
+
+        self.expressions.discard_written(insn);
+    }
+}
+
+impl Function<'_> {
+    /// Reconstructs the Lua source of the body of this function, one statement per line.
+    ///
+    /// Only straight-line code, `if` blocks, `while` loops and numeric `for` loops are recovered;
+    /// see the module documentation.
+    pub fn to_lua(&self) -> String {
+        self.to_lua_with(&FormatOptions::default())
+    }
+
+    /// Reconstructs the Lua source of the body of this function, rendering expressions with the
+    /// given options; see `to_lua`.
+    pub fn to_lua_with(&self, options: &FormatOptions) -> String {
+        generate(self, None, options)
+    }
+}
+
+impl Module<'_> {
+    /// Reconstructs the Lua source of the main chunk of this module, one statement per line.
+    ///
+    /// Unlike `Function::to_lua`, named function definitions are written with their bodies.
+    pub fn to_lua(&self) -> String {
+        self.to_lua_with(&FormatOptions::default())
+    }
+
+    /// Reconstructs the Lua source of the main chunk of this module, rendering expressions with the
+    /// given options; see `to_lua`.
+    pub fn to_lua_with(&self, options: &FormatOptions) -> String {
+        generate(self.main(), Some(self), options)
+    }
+}
+
+/// Reconstructs the Lua source of the body of a function, writing the definitions it holds with
+/// their bodies if its module is given.
+fn generate<'a>(function: &Function<'a>, module: Option<&Module<'a>>, options: &FormatOptions) -> String {
+    let cfg = Cfg::new(function.emitter());
+    let statement = structure(&cfg);
+
+    let mut codegen = Codegen::new(function, module, &cfg, options);
+    codegen.collect_labels(&statement);
+    codegen.write_block(&statement, 0);
+
+    let end = function.instructions().len();
+    if codegen.labels.contains(&end) {
+        codegen.line(0, &format!("::label{}::", end));
+    }
+
+    codegen.out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        ast::FormatOptions,
+        bytecode::{
+            Dump, LittleEndianBuffer,
+            fixture::{self, Kgc, Kn, Proto, abc, ad, jump},
+        },
+        ir::{Function, Module},
+    };
+
+    fn to_lua(main: Proto) -> String {
+        let bytes = fixture::Dump {
+            flags: if main.debug.is_some() { 0 } else { 2 },
+            protos: vec![main],
+            ..Default::default()
+        }
+        .build();
+        let dump = Dump::new(LittleEndianBuffer(bytes));
+        Function::from_prototype(dump.main(), false).to_lua()
+    }
+
+    #[test]
+    pub fn test_arithmetic() {
+        // function(a, b) local c = a * b + 1 return c - a end
+        let main = Proto {
+            numparams: 2,
+            framesize: 4,
+            instructions: vec![
+                abc("MULVV", 2, 0, 1),
+                abc("ADDVN", 2, 2, 0),
+                abc("SUBVV", 3, 2, 0),
+                ad("RET1", 3, 2),
+            ],
+            kn: vec![Kn::Int(1)],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![1; 4],
+                variables: vec![("a", 0, 5), ("b", 0, 5), ("c", 3, 5)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(to_lua(main), "local c = a * b + 1\nreturn c - a\n");
+    }
+
+    #[test]
+    pub fn test_unstripped_fixture() {
+        // local function add(a, b) return a + b end local t = { x = 1, "y" } print(add(t.x, 2))
+        let dump = Dump::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/add.lua.jit")).unwrap();
+        let module = Module::from_dump(&dump);
+
+        assert_eq!(
+            module.to_lua(),
+            "local add = function_0\nlocal t = { \"y\", [\"x\"] = 1 }\nprint(add(t.x, 2))\n"
+        );
+    }
+
+    #[test]
+    pub fn test_booleans() {
+        // local a, b = true, false, and the other way around; each local is declared on its own.
+        let locals = |first, second| Proto {
+            framesize: 2,
+            instructions: vec![ad("KPRI", 0, first), ad("KPRI", 1, second), ad("RET0", 0, 1)],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![1; 3],
+                variables: vec![("a", 2, 4), ("b", 3, 4)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(to_lua(locals(2, 1)), "local a = true\nlocal b = false\n");
+        assert_eq!(to_lua(locals(1, 2)), "local a = false\nlocal b = true\n");
+
+        // A malformed primitive is kept rather than guessed.
+        assert_eq!(to_lua(locals(3, 0)), "local a = nil --[[ primitive 3 ]]\nlocal b\n");
+    }
+
+    #[test]
+    pub fn test_constructor_scope() {
+        // local n, t = 1, { x = g }, which come into scope once the table is filled
+        let main = Proto {
+            framesize: 3,
+            instructions: vec![
+                ad("KSHORT", 0, 1),
+                ad("TNEW", 1, 0),
+                ad("GGET", 2, 0),
+                abc("TSETS", 2, 1, 1),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"x"), Kgc::Str(b"g")],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![1; 5],
+                variables: vec![("n", 5, 6), ("t", 5, 6)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(to_lua(main), "local n = 1\nlocal t = {}\nt.x = g\n");
+    }
+
+    #[test]
+    pub fn test_deferred_declaration() {
+        // local x; x = g + 1; print(x)
+        let main = Proto {
+            framesize: 3,
+            instructions: vec![
+                ad("KPRI", 0, 0),
+                ad("GGET", 1, 0),
+                abc("ADDVN", 0, 1, 0),
+                ad("GGET", 1, 1),
+                ad("MOV", 2, 0),
+                abc("CALL", 1, 1, 2),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"print"), Kgc::Str(b"g")],
+            kn: vec![Kn::Int(1)],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![1; 7],
+                variables: vec![("x", 2, 8)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(to_lua(main), "local x = g + 1\nprint(x)\n");
+    }
+
+    #[test]
+    pub fn test_statements() {
+        // print(x.y) x.y = 1
+        let main = Proto {
+            framesize: 2,
+            instructions: vec![
+                ad("GGET", 0, 0),
+                ad("GGET", 1, 1),
+                abc("TGETS", 1, 1, 2),
+                abc("CALL", 0, 1, 2),
+                ad("GGET", 0, 1),
+                ad("KSHORT", 1, 1),
+                abc("TSETS", 1, 0, 2),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"y"), Kgc::Str(b"x"), Kgc::Str(b"print")],
+            ..Default::default()
+        };
+
+        assert_eq!(to_lua(main), "print(x.y)\nx.y = 1\n");
+    }
+
+    #[test]
+    pub fn test_concat() {
+        // return x .. "=" .. y
+        let main = Proto {
+            framesize: 3,
+            instructions: vec![
+                ad("GGET", 0, 0),
+                ad("KSTR", 1, 1),
+                ad("GGET", 2, 2),
+                abc("CAT", 0, 0, 2),
+                ad("RET1", 0, 2),
+            ],
+            kgc: vec![Kgc::Str(b"y"), Kgc::Str(b"="), Kgc::Str(b"x")],
+            ..Default::default()
+        };
+
+        assert_eq!(to_lua(main), "return x .. \"=\" .. y\n");
+    }
+
+    #[test]
+    pub fn test_hex_integers() {
+        // print(x[16]) x = 255
+        let main = Proto {
+            framesize: 2,
+            instructions: vec![
+                ad("GGET", 0, 0),
+                ad("GGET", 1, 1),
+                abc("TGETB", 1, 1, 16),
+                abc("CALL", 0, 1, 2),
+                ad("KSHORT", 0, 255),
+                ad("GSET", 0, 1),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"x"), Kgc::Str(b"print")],
+            ..Default::default()
+        };
+        let dump = fixture::parse(vec![main]);
+        let function = Function::from_prototype(dump.main(), false);
+
+        let hex = FormatOptions { hex_integers: true };
+        assert_eq!(function.to_lua(), "print(x[16])\nx = 255\n");
+        assert_eq!(function.to_lua_with(&hex), "print(x[0x10])\nx = 0xFF\n");
+    }
+
+    #[test]
+    pub fn test_if_return() {
+        // function(a) if a < 0 then return -a end return a end
+        let main = Proto {
+            numparams: 1,
+            framesize: 2,
+            instructions: vec![
+                ad("KSHORT", 1, 0),
+                ad("ISGE", 0, 1),
+                jump("JMP", 2, 2, 5),
+                ad("UNM", 1, 0),
+                ad("RET1", 1, 2),
+                ad("RET1", 0, 2),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(to_lua(main), "if arg0 < 0 then\n    return -arg0\nend\nreturn arg0\n");
+    }
+
+    #[test]
+    pub fn test_if_else() {
+        // function(a) local b if a then b = 1 else b = 2 end return b end
+        let main = Proto {
+            numparams: 1,
+            framesize: 2,
+            instructions: vec![
+                ad("KPRI", 1, 0),
+                ad("ISF", 0, 0),
+                jump("JMP", 2, 2, 5),
+                ad("KSHORT", 1, 1),
+                jump("JMP", 2, 4, 6),
+                ad("KSHORT", 1, 2),
+                ad("RET1", 1, 2),
+            ],
+            ..Default::default()
+        };
+
+        let expected = "local var1\nif arg0 then\n    var1 = 1\nelse\n    var1 = 2\nend\nreturn var1\n";
+        assert_eq!(to_lua(main), expected);
+    }
+
+    #[test]
+    pub fn test_infinite_loop() {
+        // ::top:: f() goto top
+        let main = Proto {
+            framesize: 1,
+            instructions: vec![ad("GGET", 0, 0), abc("CALL", 0, 1, 1), jump("JMP", 0, 2, 0)],
+            kgc: vec![Kgc::Str(b"f")],
+            ..Default::default()
+        };
+
+        assert_eq!(to_lua(main), "while true do\n    f()\nend\n");
+    }
+
+    #[test]
+    pub fn test_while_true_break() {
+        // function(x) while true do x = x + 1 if 10 < x then break end end end
+        let main = Proto {
+            numparams: 1,
+            framesize: 2,
+            instructions: vec![
+                jump("LOOP", 1, 0, 6),
+                abc("ADDVN", 0, 0, 0),
+                ad("KSHORT", 1, 10),
+                ad("ISLT", 1, 0),
+                jump("JMP", 1, 4, 6),
+                jump("JMP", 1, 5, 0),
+                ad("RET0", 0, 1),
+            ],
+            kn: vec![Kn::Int(1)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            to_lua(main),
+            "while true do\n    arg0 = arg0 + 1\n    if 10 < arg0 then break end\nend\n"
+        );
+    }
+
+    #[test]
+    pub fn test_while() {
+        // function(x) while x do x = f(x) end end
+        let main = Proto {
+            numparams: 1,
+            framesize: 3,
+            instructions: vec![
+                ad("ISF", 0, 0),
+                jump("JMP", 1, 1, 8),
+                ad("LOOP", 1, 0),
+                ad("GGET", 1, 0),
+                ad("MOV", 2, 0),
+                abc("CALL", 1, 2, 2),
+                ad("MOV", 0, 1),
+                jump("JMP", 1, 7, 0),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"f")],
+            ..Default::default()
+        };
+
+        assert_eq!(to_lua(main), "while arg0 do\n    arg0 = f(arg0)\nend\n");
+    }
+
+    #[test]
+    pub fn test_numeric_for() {
+        // function(n) for i = 1, n, 2 do if i then break end print(i) end end
+        let main = |debug| Proto {
+            numparams: 1,
+            framesize: 7,
+            instructions: vec![
+                ad("KSHORT", 1, 1),
+                ad("MOV", 2, 0),
+                ad("KSHORT", 3, 2),
+                jump("FORI", 1, 3, 10),
+                ad("IST", 0, 4),
+                jump("JMP", 5, 5, 10),
+                ad("GGET", 5, 0),
+                ad("MOV", 6, 4),
+                abc("CALL", 5, 1, 2),
+                jump("FORL", 1, 9, 4),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"print")],
+            debug,
+            ..Default::default()
+        };
+
+        let debug = fixture::Debug {
+            firstline: 1,
+            numline: 1,
+            lines: vec![1; 11],
+            variables: vec![
+                ("n", 0, 12),
+                ("\u{1}", 4, 11),
+                ("\u{2}", 4, 11),
+                ("\u{3}", 4, 11),
+                ("i", 5, 11),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            to_lua(main(Some(debug))),
+            "for i = 1, n, 2 do\n    if i then break end\n    print(i)\nend\n"
+        );
+        assert_eq!(
+            to_lua(main(None)),
+            "for var4 = 1, arg0, 2 do\n    if var4 then break end\n    print(var4)\nend\n"
+        );
+    }
+
+    #[test]
+    pub fn test_stale_temporaries() {
+        // z = y for k in pairs(t) do x = k end
+        let main = Proto {
+            framesize: 6,
+            instructions: vec![
+                ad("GGET", 3, 0),
+                ad("GSET", 3, 1),
+                ad("GGET", 0, 2),
+                ad("GGET", 1, 3),
+                abc("CALL", 0, 4, 2),
+                jump("JMP", 3, 5, 7),
+                ad("GSET", 3, 4),
+                abc("ITERC", 3, 2, 3),
+                jump("ITERL", 3, 8, 6),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![
+                Kgc::Str(b"x"),
+                Kgc::Str(b"t"),
+                Kgc::Str(b"pairs"),
+                Kgc::Str(b"z"),
+                Kgc::Str(b"y"),
+            ],
+            ..Default::default()
+        };
+
+        // The body is reached by a branch, and reads the control variable written by the iterator.
+        assert_eq!(
+            to_lua(main),
+            "z = y\nlocal var0, var1, var2 = pairs(t)\ngoto label8\nx = var3\n::label8::\n-- v3..v3 = v0(v1, v2)\n-- iterloop v3 goto 0007\n"
+        );
+    }
+
+    #[test]
+    pub fn test_definitions() {
+        // function greet(name) print(name) end
+        // function t.m() end
+        // print(function() end)
+        let greet = Proto {
+            numparams: 1,
+            framesize: 3,
+            instructions: vec![
+                ad("GGET", 1, 0),
+                ad("MOV", 2, 0),
+                abc("CALL", 1, 1, 2),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"print")],
+            ..Default::default()
+        };
+        let empty = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            ..Default::default()
+        };
+
+        let main = Proto {
+            flags: 3,
+            framesize: 2,
+            instructions: vec![
+                ad("FNEW", 0, 0),
+                ad("GSET", 0, 1),
+                ad("GGET", 0, 2),
+                ad("FNEW", 1, 3),
+                abc("TSETS", 1, 0, 4),
+                ad("GGET", 0, 5),
+                ad("FNEW", 1, 6),
+                abc("CALL", 0, 1, 2),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![
+                Kgc::Child,
+                Kgc::Str(b"print"),
+                Kgc::Str(b"m"),
+                Kgc::Child,
+                Kgc::Str(b"t"),
+                Kgc::Str(b"greet"),
+                Kgc::Child,
+            ],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![greet, empty.clone(), empty, main]);
+        let module = Module::from_dump(&dump);
+        assert_eq!(
+            module.to_lua(),
+            "function greet(arg0)\n    print(arg0)\nend\nfunction t.m()\nend\nprint(function_2)\n"
+        );
+
+        // Without the module, closures are only referred to by index.
+        assert_eq!(
+            module.main().to_lua(),
+            "greet = function_0\nt.m = function_1\nprint(function_2)\n"
+        );
+    }
+
+    #[test]
+    pub fn test_goto() {
+        // function(x) if x then goto test end repeat x = 1 ::test:: until x end
+        let main = Proto {
+            numparams: 1,
+            framesize: 1,
+            instructions: vec![
+                ad("IST", 0, 0),
+                jump("JMP", 1, 1, 3),
+                ad("KSHORT", 0, 1),
+                ad("ISF", 0, 0),
+                jump("JMP", 1, 4, 2),
+                ad("RET0", 0, 1),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            to_lua(main),
+            "if arg0 then goto label3 end\n::label2::\narg0 = 1\n::label3::\nif not arg0 then goto label2 end\n"
+        );
+    }
+}
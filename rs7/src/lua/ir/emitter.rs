@@ -1,21 +1,90 @@
-use crate::lua::ir::{Insn, Label};
+use crate::lua::ir::{Insn, Label, Operand};
 
+#[derive(Default)]
 pub struct Emitter {
     pub instructions: Vec<Insn>,
+    /// The index of the bytecode instruction being lifted.
+    pc: usize,
+    /// The bytecode position of each label, once it is marked.
+    labels: Vec<Option<usize>>,
+    /// The index of the bytecode instruction each IR instruction was lifted from.
+    positions: Vec<usize>,
+    /// The conditional branch awaiting its target, and the bytecode instruction it was lifted from.
+    pending: Option<(usize, usize)>,
 }
 
 impl Emitter {
     pub fn new() -> Self {
-        Self { instructions: vec![] }
+        Self {
+            instructions: vec![],
+            pc: 0,
+            labels: vec![],
+            positions: vec![],
+            pending: None,
+        }
+    }
+
+    /// Returns the instructions emitted so far.
+    pub fn instructions(&self) -> &[Insn] {
+        &self.instructions
+    }
+
+    /// Returns the index of the bytecode instruction being lifted.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Moves to the bytecode instruction at `pc`. Instructions are lifted in order, so `pc` never
+    /// decreases.
+    pub fn seek(&mut self, pc: usize) {
+        debug_assert!(pc >= self.pc, "Moving back from {} to {}", self.pc, pc);
+        self.pc = pc;
+    }
+
+    /// Allocates a label, which does not point anywhere until it is marked.
+    pub fn define_label(&mut self) -> u32 {
+        self.labels.push(None);
+        self.labels.len() as u32 - 1
+    }
+
+    /// Points a label at the current bytecode instruction.
+    pub fn mark_label(&mut self, id: u32) {
+        self.labels[id as usize] = Some(self.pc);
+    }
+
+    /// Returns the bytecode instruction a label points at, if it was marked.
+    pub fn resolve(&self, id: u32) -> Option<usize> {
+        self.labels.get(id as usize).copied().flatten()
+    }
+
+    /// Returns the index of the first IR instruction lifted from the bytecode instruction at `bc`
+    /// or after it.
+    pub fn ir_index(&self, bc: usize) -> usize {
+        self.positions.partition_point(|pc| *pc < bc)
+    }
+
+    /// Returns the index of the bytecode instruction the IR instruction at `ir` was lifted from.
+    pub fn position(&self, ir: usize) -> usize {
+        self.positions[ir]
     }
 
     pub fn emit(&mut self, insn: Insn) {
         self.instructions.push(insn);
+        self.positions.push(self.pc);
     }
 
-    pub fn fixup_branch(&mut self, tgt: Label) {
-        let idx = self.instructions.len() - 1;
+    /// Emits a conditional branch whose target is given by the `JMP` that follows it.
+    ///
+    /// See `fixup_branch`.
+    pub fn emit_pending_branch(&mut self, cond: Operand) {
+        self.pending = Some((self.instructions.len(), self.pc));
+        self.emit(Insn::ConditionalBranch {
+            cond,
+            target: Label::None,
+        });
+    }
 
+    pub fn fixup_branch(&mut self, tgt: Label) {
         //   ISLT lhs, rgs
         //   JMP label1
         //   JMP label2
@@ -28,14 +97,89 @@ impl Emitter {
         // 3, which jumps to `label2`.
         //
         // It is guaranteed that all compare-and-test instructions are immediately
-        // followed by a branching instruction.
-        if let Insn::ConditionalBranch { target, .. } = &mut self.instructions[idx] {
-            if let Label::None = target {
-                *target = tgt;
-                return;
-            }
+        // followed by a branching instruction. Lifting is a single forward pass: the
+        // comparison is remembered when it is emitted, and only pairs with a jump lifted
+        // from the very next bytecode instruction.
+        //
+        // A branch may also directly follow the function header, in which case there is nothing
+        // to fix.
+        if let Some((index, pc)) = self.pending.take()
+            && pc + 1 == self.pc
+            && let Insn::ConditionalBranch { target, .. } = &mut self.instructions[index]
+        {
+            *target = tgt;
+            return;
         }
 
         self.emit(Insn::Branch { target: tgt });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        bytecode::fixture::{self, Proto, jump},
+        ir::{BasicBlock, Emitter, Insn, Label, find_loops},
+    };
+
+    fn lift(instructions: Vec<u32>) -> Emitter {
+        let main = Proto {
+            instructions,
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        assert!(find_loops(&dump.main().instructions).is_empty());
+
+        let mut emitter = Emitter::new();
+        dump.main()
+            .instructions
+            .iter()
+            .enumerate()
+            .for_each(|(pc, insn)| Insn::parse(*insn, pc, false, &mut emitter));
+        emitter
+    }
+
+    #[test]
+    pub fn test_empty_function() {
+        // A prototype made of its header only.
+        let emitter = lift(vec![]);
+        assert!(matches!(emitter.instructions[..], [Insn::Nop]));
+
+        let (def, uses) = BasicBlock::new(0, &emitter.instructions).def_use();
+        assert!(def.is_empty() && uses.is_empty());
+    }
+
+    #[test]
+    pub fn test_leading_branch() {
+        let emitter = lift(vec![jump("JMP", 0, 0, 1)]);
+        assert!(matches!(
+            emitter.instructions[..],
+            [
+                Insn::Nop,
+                Insn::Branch {
+                    target: Label::Label { .. }
+                }
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_labels() {
+        let mut emitter = Emitter::new();
+        let forward = emitter.define_label();
+        let backward = emitter.define_label();
+        assert_ne!(forward, backward);
+
+        emitter.seek(2);
+        emitter.mark_label(backward);
+        assert_eq!(emitter.resolve(backward), Some(2));
+        assert_eq!(emitter.resolve(forward), None);
+
+        emitter.seek(5);
+        emitter.mark_label(forward);
+        assert_eq!(emitter.pc(), 5);
+        assert_eq!(emitter.resolve(forward), Some(5));
+        assert_eq!(emitter.resolve(42), None);
+    }
+}
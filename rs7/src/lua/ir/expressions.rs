@@ -0,0 +1,516 @@
+//! Expression reconstruction.
+//!
+//! LuaJIT evaluates nested expressions into temporary slots above the active locals: `b * 2`
+//! compiles to `MULVN tmp, b, 2`, and `tmp` is then consumed by whatever needed the value. This
+//! pass folds such temporaries back into the source expressions that produced them.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use crate::lua::{
+    ast::{BinaryOp, Expression, FormatOptions, Formatted, UnaryOp},
+    bytecode::{Complex, NumericValue, Prototype, table_item::TableItem},
+    ir::{BasicOperand, CmpOp, Constant, Expr, Insn, Operand, Place, Primitive, Visitor},
+};
+
+/// Tracks the expressions held by temporary slots of a prototype.
+pub struct Expressions<'a> {
+    proto: &'a Prototype,
+    /// The instruction at which local variables are named.
+    pc: usize,
+    /// Slots below this one hold local variables, which are never folded.
+    locals: u32,
+    temporaries: HashMap<u32, Expression>,
+    /// The positions at which locals that are not in scope yet are named.
+    pending: HashMap<u32, usize>,
+    /// The expression producing the multi-result tail, each of whose values is kept.
+    multires: Option<Expression>,
+    options: FormatOptions,
+}
+
+impl<'a> Expressions<'a> {
+    /// Creates an empty reconstruction context.
+    ///
+    /// # Arguments
+    ///
+    /// * `proto` - The prototype the instructions belong to.
+    /// * `pc` - The index of the instruction at which local variables are named.
+    /// * `locals` - The amount of active local variables; every slot above them is a temporary.
+    pub fn new(proto: &'a Prototype, pc: usize, locals: u32) -> Self {
+        Self {
+            proto,
+            pc,
+            locals,
+            temporaries: HashMap::new(),
+            pending: HashMap::new(),
+            multires: None,
+            options: FormatOptions::default(),
+        }
+    }
+
+    /// Sets the options expressions are rendered with; see `display`.
+    pub fn with_options(mut self, options: &FormatOptions) -> Self {
+        self.options = options.clone();
+        self
+    }
+
+    /// Renders an expression with the options of this context.
+    pub fn display<'e>(&'e self, expression: &'e Expression) -> Formatted<'e> {
+        expression.display(&self.options)
+    }
+
+    /// Moves the point at which local variables are named to the instruction at `pc`.
+    pub fn seek(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// Forgets the expression held by a temporary, once its slot is given a value that is not
+    /// folded.
+    pub fn discard(&mut self, slot: u32) {
+        self.temporaries.remove(&slot);
+        self.pending.remove(&slot);
+    }
+
+    /// Forgets the expressions held by every slot an instruction writes.
+    pub fn discard_written(&mut self, insn: &Insn) {
+        struct Written<'e>(&'e mut HashMap<u32, Expression>);
+
+        impl Visitor for Written<'_> {
+            fn visit_def(&mut self, operand: &BasicOperand) {
+                if let BasicOperand::Var(slot) = operand {
+                    self.0.remove(slot);
+                }
+            }
+        }
+
+        insn.accept(&mut Written(&mut self.temporaries));
+    }
+
+    /// Forgets the expressions held by every temporary, which are only folded within their basic
+    /// block.
+    pub fn clear(&mut self) {
+        self.temporaries.clear();
+    }
+
+    /// Names a slot as at the instruction at `pc` until it is discarded, for locals whose value is
+    /// stored before they come into scope, such as a table filled by its constructor.
+    pub fn name_at(&mut self, slot: u32, pc: usize) {
+        self.pending.insert(slot, pc);
+    }
+
+    /// Records the value an instruction stores in a temporary, if any.
+    pub fn assign(&mut self, insn: &Insn) {
+        // The values read by the instruction are computed before the slots it writes are forgotten.
+        let folded = match insn {
+            // The expanded values are only ever consumed as the tail of a list.
+            Insn::Assign {
+                rhs: Operand::Expr(Expr::Vararg { multi: true }),
+                ..
+            } => {
+                self.multires = Some(Expression::Vararg);
+                None
+            }
+            Insn::Assign {
+                lhs: Place::Basic(BasicOperand::Var(slot)),
+                rhs,
+            } if *slot >= self.locals => Some((*slot, self.operand(rhs))),
+            Insn::Call {
+                func,
+                args,
+                results,
+                multi,
+                ..
+            } => {
+                let args = self.values(args, *multi);
+                let call = Expression::call(self.basic(func), args);
+
+                match results {
+                    None => {
+                        self.multires = Some(call);
+                        None
+                    }
+                    // A call truncated to a single value can be folded into its consumer.
+                    Some(results) if results.len() == 1 && results.start >= self.locals => Some((results.start, call)),
+                    Some(_) => None,
+                }
+            }
+            // The values are stored in a table, which is not folded.
+            Insn::StoreMulti { .. } => {
+                self.multires = None;
+                None
+            }
+            _ => None,
+        };
+
+        // This includes the destination of a conditional assignment, whose value depends on the path
+        // taken.
+        self.discard_written(insn);
+        if let Some((slot, value)) = folded {
+            self.temporaries.insert(slot, value);
+        }
+    }
+
+    /// Returns the expressions of a list of values, such as the arguments of a call or the values
+    /// returned by a function.
+    ///
+    /// # Arguments
+    ///
+    /// * `values` - The fixed values.
+    /// * `multi` - Whether the list ends with the pending multi-result tail, which is consumed.
+    pub fn values(&mut self, values: &[BasicOperand], multi: bool) -> Vec<Expression> {
+        let mut expressions: Vec<_> = values.iter().map(|value| self.basic(value)).collect();
+        if multi {
+            expressions.extend(self.multires.take());
+        }
+        expressions
+    }
+
+    /// Returns the expression held by a slot.
+    ///
+    /// Without debug information, parameters are named like in the header of their function, see
+    /// `find_definitions`.
+    pub fn slot(&self, slot: u32) -> Expression {
+        if let Some(value) = self.temporaries.get(&slot) {
+            return value.clone();
+        }
+
+        let pc = self.pending.get(&slot).map_or(self.pc, |pc| self.pc.max(*pc));
+        let name = self
+            .proto
+            .debug()
+            .and_then(|debug| debug.variable_name(pc as u32, slot));
+        Expression::Local(name.map_or_else(
+            || {
+                let parameter =
+                    (0..self.proto.parameter_count() as usize).find(|i| self.proto.param_slot(*i) == Some(slot));
+                match parameter {
+                    Some(i) => format!("arg{}", i),
+                    None => format!("var{}", slot),
+                }
+            },
+            Cow::into_owned,
+        ))
+    }
+
+    pub fn basic(&self, operand: &BasicOperand) -> Expression {
+        match *operand {
+            BasicOperand::Var(slot) => self.slot(slot),
+            BasicOperand::Upvalue(index) => {
+                let name = self.proto.upvalue_name(index as usize);
+                Expression::Upvalue(name.map_or_else(|| format!("uv{}", index), Cow::into_owned))
+            }
+            BasicOperand::UnsignedLiteral(value) => Expression::Integer(value as i64),
+            BasicOperand::SignedLiteral(value) => Expression::Integer(value as i64),
+            BasicOperand::Pri(Primitive::Nil) => Expression::Nil,
+            BasicOperand::Pri(Primitive::True) => Expression::True,
+            BasicOperand::Pri(Primitive::False) => Expression::False,
+            BasicOperand::Pri(Primitive::Invalid(value)) => Expression::Opaque(format!("primitive {}", value)),
+            BasicOperand::Branch(_) => unreachable!("Branch targets are not values"),
+            BasicOperand::Global(_) => match operand.resolve(self.proto) {
+                Some(Constant::String(name)) => Expression::Global(String::from_utf8_lossy(name).into_owned()),
+                _ => panic!("Expected a string constant, found {:?}", operand),
+            },
+            _ => match operand.resolve(self.proto) {
+                Some(constant) => constant_expression(constant),
+                None => panic!("Invalid constant operand {:?}", operand),
+            },
+        }
+    }
+
+    pub fn operand(&self, operand: &Operand) -> Expression {
+        match operand {
+            Operand::Basic(operand) => self.basic(operand),
+            Operand::Expr(expr) => self.expression(expr),
+        }
+    }
+
+    fn expression(&self, expr: &Expr) -> Expression {
+        let binary = |op, lhs, rhs| Expression::binary(op, self.basic(lhs), self.basic(rhs));
+
+        match expr {
+            Expr::Binary(op, lhs, rhs) => {
+                let op = match op {
+                    CmpOp::Eq => BinaryOp::Eq,
+                    CmpOp::Ne => BinaryOp::Ne,
+                    CmpOp::Lt => BinaryOp::Lt,
+                    CmpOp::Le => BinaryOp::Le,
+                    CmpOp::Gt => BinaryOp::Gt,
+                    CmpOp::Ge => BinaryOp::Ge,
+                };
+                binary(op, lhs, rhs)
+            }
+            Expr::Add(lhs, rhs) => binary(BinaryOp::Add, lhs, rhs),
+            Expr::Sub(lhs, rhs) => binary(BinaryOp::Sub, lhs, rhs),
+            Expr::Mul(lhs, rhs) => binary(BinaryOp::Mul, lhs, rhs),
+            Expr::Div(lhs, rhs) => binary(BinaryOp::Div, lhs, rhs),
+            Expr::Rem(lhs, rhs) => binary(BinaryOp::Mod, lhs, rhs),
+            Expr::Pow(lhs, rhs) => binary(BinaryOp::Pow, lhs, rhs),
+            // Concatenation is right associative.
+            Expr::Cat(values) => values
+                .iter()
+                .rev()
+                .map(|value| self.basic(value))
+                .reduce(|rhs, lhs| Expression::binary(BinaryOp::Concat, lhs, rhs))
+                .unwrap_or_else(|| Expression::Opaque("empty concatenation".to_string())),
+            Expr::Index(table, key) => Expression::index(self.basic(table), self.basic(key)),
+            Expr::Not(value) => Expression::unary(UnaryOp::Not, self.basic(value)),
+            Expr::Negate(value) => Expression::unary(UnaryOp::Neg, self.basic(value)),
+            Expr::Len(value) => Expression::unary(UnaryOp::Len, self.basic(value)),
+            // Conditions are truthiness tests already.
+            Expr::Truthy(value) => self.basic(value),
+            Expr::NewTable { .. } => Expression::Table {
+                array: vec![],
+                hash: vec![],
+            },
+            Expr::DupTable(value) | Expr::Closure(value) => self.basic(value),
+            Expr::Vararg { .. } => Expression::Vararg,
+            Expr::Select(index) => Expression::call(
+                Expression::Global("select".to_string()),
+                vec![Expression::Integer(*index as i64 + 1), Expression::Vararg],
+            ),
+        }
+    }
+}
+
+fn constant_expression(constant: Constant) -> Expression {
+    match constant {
+        Constant::Number(NumericValue::Integer(value)) => Expression::Integer(value as i64),
+        Constant::Number(NumericValue::Number(value)) => Expression::Number(value),
+        Constant::String(value) => Expression::String(value.to_vec()),
+        Constant::Table(Complex::Table { array, hash }) => {
+            // The array part starts at index 0, which constructors only fill explicitly.
+            let mut entries = vec![];
+            if let Some(first) = array.first().filter(|item| !matches!(item, TableItem::Nil)) {
+                entries.push((Expression::Integer(0), table_item(first)));
+            }
+            entries.extend(hash.iter().map(|(key, value)| (table_item(key), table_item(value))));
+
+            Expression::Table {
+                array: array.iter().skip(1).map(table_item).collect(),
+                hash: entries,
+            }
+        }
+        Constant::Function(index) => Expression::Function(index),
+        Constant::CData(Complex::Signed(value)) => Expression::Int64(*value),
+        Constant::CData(Complex::Unsigned(value)) => Expression::UInt64(*value),
+        Constant::CData(Complex::Complex { real, imaginary }) => {
+            Expression::Complex(f64::from_bits(*real), f64::from_bits(*imaginary))
+        }
+        Constant::Table(other) | Constant::CData(other) => unreachable!("Unexpected constant {:?}", other),
+    }
+}
+
+fn table_item(item: &TableItem) -> Expression {
+    match item {
+        TableItem::Nil => Expression::Nil,
+        TableItem::False => Expression::False,
+        TableItem::True => Expression::True,
+        TableItem::Integer(value) => Expression::Integer(*value as i64),
+        TableItem::Numeric(value) => match value.value() {
+            NumericValue::Integer(value) => Expression::Integer(value as i64),
+            NumericValue::Number(value) => Expression::Number(value),
+        },
+        TableItem::String(value) => Expression::String(value.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        bytecode::{
+            Dump, LittleEndianBuffer,
+            fixture::{self, Kgc, Kn, Proto, abc, ad},
+        },
+        ir::{Emitter, Expressions, Insn},
+    };
+
+    #[test]
+    pub fn test_fold_temporaries() {
+        // local x = 1; return -(x + y.k) .. "!"
+        let main = Proto {
+            framesize: 4,
+            instructions: vec![
+                ad("KNUM", 0, 0),
+                ad("GGET", 1, 0),
+                abc("TGETS", 1, 1, 1),
+                abc("ADDVV", 1, 0, 1),
+                ad("UNM", 1, 1),
+                ad("KSTR", 2, 2),
+                abc("CAT", 1, 1, 2),
+                ad("RET1", 1, 2),
+            ],
+            kgc: vec![Kgc::Str(b"!"), Kgc::Str(b"k"), Kgc::Str(b"y")],
+            kn: vec![Kn::Num(1.5)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let proto = dump.main();
+
+        let mut emitter = Emitter::new();
+        let mut expressions = Expressions::new(proto, 8, 1);
+        for (pc, insn) in proto.instructions[..8].iter().enumerate() {
+            Insn::parse(*insn, pc, false, &mut emitter);
+            expressions.assign(emitter.instructions.last().unwrap());
+        }
+
+        assert_eq!(expressions.slot(0).to_string(), "var0");
+        assert_eq!(expressions.slot(1).to_string(), "-(var0 + y.k) .. \"!\"");
+    }
+
+    #[test]
+    pub fn test_fold_cdata() {
+        // local a, b, c = -5LL, 7ULL, 2i
+        let main = Proto {
+            framesize: 3,
+            instructions: vec![ad("KCDATA", 0, 0), ad("KCDATA", 1, 1), ad("KCDATA", 2, 2)],
+            kgc: vec![Kgc::Complex(0.0, 2.0), Kgc::U64(7), Kgc::I64(-5)],
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump {
+            flags: 2 | 4,
+            protos: vec![main],
+            ..Default::default()
+        }
+        .build();
+        let dump = Dump::new(LittleEndianBuffer(bytes));
+        let proto = dump.main();
+
+        let mut emitter = Emitter::new();
+        let mut expressions = Expressions::new(proto, 4, 0);
+        for (pc, insn) in proto.instructions.iter().enumerate() {
+            Insn::parse(*insn, pc, false, &mut emitter);
+            expressions.assign(emitter.instructions.last().unwrap());
+        }
+
+        assert_eq!(expressions.slot(0).to_string(), "-5LL");
+        assert_eq!(expressions.slot(1).to_string(), "7ULL");
+        assert_eq!(expressions.slot(2).to_string(), "2i");
+    }
+
+    #[test]
+    pub fn test_fold_field_chain() {
+        // return a.b.c, a["b c"]
+        let main = Proto {
+            framesize: 2,
+            instructions: vec![
+                ad("GGET", 0, 0),
+                abc("TGETS", 0, 0, 1),
+                abc("TGETS", 0, 0, 2),
+                ad("GGET", 1, 0),
+                abc("TGETS", 1, 1, 3),
+                ad("RET", 0, 3),
+            ],
+            kgc: vec![Kgc::Str(b"b c"), Kgc::Str(b"c"), Kgc::Str(b"b"), Kgc::Str(b"a")],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let proto = dump.main();
+
+        let mut emitter = Emitter::new();
+        let mut expressions = Expressions::new(proto, 6, 0);
+        for (pc, insn) in proto.instructions[..6].iter().enumerate() {
+            Insn::parse(*insn, pc, false, &mut emitter);
+            expressions.assign(emitter.instructions.last().unwrap());
+        }
+
+        // Each access replaces the temporary holding its table.
+        assert_eq!(expressions.slot(0).to_string(), "a.b.c");
+        assert_eq!(expressions.slot(1).to_string(), "a[\"b c\"]");
+    }
+
+    #[test]
+    pub fn test_fold_calls() {
+        // return f(g(), "x")[1]
+        let main = Proto {
+            framesize: 3,
+            instructions: vec![
+                ad("GGET", 0, 0),
+                ad("GGET", 1, 1),
+                abc("CALL", 1, 2, 1),
+                ad("KSTR", 2, 2),
+                abc("CALL", 0, 2, 3),
+                abc("TGETB", 0, 0, 1),
+                ad("RET1", 0, 2),
+            ],
+            kgc: vec![Kgc::Str(b"x"), Kgc::Str(b"g"), Kgc::Str(b"f")],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let proto = dump.main();
+
+        let mut emitter = Emitter::new();
+        let mut expressions = Expressions::new(proto, 7, 0);
+        for (pc, insn) in proto.instructions[..7].iter().enumerate() {
+            Insn::parse(*insn, pc, false, &mut emitter);
+            expressions.assign(emitter.instructions.last().unwrap());
+        }
+
+        assert_eq!(expressions.slot(0).to_string(), "f(g(), \"x\")[1]");
+    }
+
+    #[test]
+    pub fn test_parameter_names() {
+        // function(a, b, ...) return a + b, ... end
+        let main = Proto {
+            flags: 2,
+            numparams: 2,
+            framesize: 4,
+            instructions: vec![abc("ADDVV", 2, 0, 1), abc("VARG", 3, 0, 0), ad("RETM", 2, 1)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let proto = dump.main();
+
+        let mut emitter = Emitter::new();
+        let mut expressions = Expressions::new(proto, 2, 2);
+        Insn::parse(proto.instructions[1], 1, false, &mut emitter);
+        expressions.assign(&emitter.instructions[0]);
+
+        assert_eq!(expressions.slot(2).to_string(), "arg0 + arg1");
+        assert_eq!(expressions.slot(3).to_string(), "var3");
+    }
+
+    #[test]
+    pub fn test_fold_vararg() {
+        // return f(...)
+        //
+        // Prototypes with children never make tail calls, so the call is followed by `RETM`.
+        let main = Proto {
+            flags: 3,
+            framesize: 2,
+            instructions: vec![
+                ad("GGET", 0, 0),
+                abc("VARG", 1, 0, 0),
+                abc("CALLM", 0, 0, 0),
+                ad("RETM", 0, 0),
+            ],
+            kgc: vec![Kgc::Str(b"f")],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let proto = dump.main();
+
+        let mut emitter = Emitter::new();
+        let mut expressions = Expressions::new(proto, 4, 0);
+        proto
+            .instructions
+            .iter()
+            .enumerate()
+            .for_each(|(pc, insn)| Insn::parse(*insn, pc, false, &mut emitter));
+        emitter.instructions[..4]
+            .iter()
+            .for_each(|insn| expressions.assign(insn));
+
+        let Insn::Return { values, multi } = &emitter.instructions[4] else {
+            panic!("Expected a return");
+        };
+        let values = expressions.values(values, *multi);
+        assert_eq!(values.len(), 1);
+        assert_eq!(format!("return {}", values[0]), "return f(...)");
+    }
+}
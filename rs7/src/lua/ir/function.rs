@@ -1,9 +1,154 @@
-use crate::lua::{bytecode::Prototype, ir::Module};
+use crate::lua::{
+    bytecode::{NumericValue, Prototype, prototype::Upvalue},
+    ir::{BasicOperand, Constant, Emitter, Insn, Place},
+};
 
-pub struct Function {}
+/// The IR of a single prototype.
+pub struct Function<'a> {
+    proto: &'a Prototype,
+    emitter: Emitter,
+    gc64: bool,
+}
+
+impl<'a> Function<'a> {
+    /// Lifts every instruction of a prototype.
+    ///
+    /// # Arguments
+    ///
+    /// * `proto` - The prototype to lift.
+    /// * `gc64` - Whether the dump of the prototype was produced by a GC64 build; see
+    ///   `Dump::is_gc64`.
+    pub fn from_prototype(proto: &'a Prototype, gc64: bool) -> Self {
+        let mut emitter = Emitter::new();
+        proto
+            .instructions
+            .iter()
+            .enumerate()
+            .for_each(|(pc, insn)| Insn::parse(*insn, pc, gc64, &mut emitter));
+
+        // `TSETM` names the number constant holding its first key in the low half of its bits,
+        // biased by 2^52; see `lj_parse.c`. Lifting happens without the constants, so the key is
+        // decoded here.
+        for insn in &mut emitter.instructions {
+            if let Insn::StoreMulti {
+                lhs: Place::Index(_, key),
+            } = insn
+                && let Some(Constant::Number(value)) = key.resolve(proto)
+            {
+                *key = BasicOperand::UnsignedLiteral(match value {
+                    NumericValue::Integer(value) => value as u32,
+                    NumericValue::Number(value) => (value.to_bits() & 0xFFFF_FFFF) as u32,
+                });
+            }
+        }
+
+        Self { proto, emitter, gc64 }
+    }
+
+    /// Returns the prototype this function was lifted from, which holds its constants.
+    pub fn prototype(&self) -> &'a Prototype {
+        self.proto
+    }
+
+    /// Returns `true` if the dump of the prototype was produced by a GC64 build; see `Dump::is_gc64`.
+    pub fn is_gc64(&self) -> bool {
+        self.gc64
+    }
+
+    /// Returns the emitter holding the lifted instructions.
+    pub fn emitter(&self) -> &Emitter {
+        &self.emitter
+    }
+
+    pub fn instructions(&self) -> &[Insn] {
+        self.emitter.instructions()
+    }
+
+    /// Returns the amount of fixed parameters, which are held in the first slots.
+    pub fn parameter_count(&self) -> u8 {
+        self.proto.parameter_count()
+    }
+
+    pub fn upvalues(&self) -> &'a [Upvalue] {
+        &self.proto.uvs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::lua::{
+        bytecode::{
+            Dump, Instruction, LittleEndianBuffer,
+            fixture::{self, Proto, ad, jump},
+        },
+        ir::{BasicOperand, Emitter, Function, Insn, Label},
+    };
+
+    #[test]
+    pub fn test_from_prototype() {
+        // The output of `luajit -bs` for `print("hello")`.
+        let bytes = Bytes::from_static(&[
+            0x1B, 0x4C, 0x4A, 0x02, 0x02, 0x23, 0x02, 0x00, 0x02, 0x00, 0x02, 0x00, 0x04, 0x36, 0x00, 0x00, 0x00, 0x27,
+            0x01, 0x01, 0x00, 0x42, 0x00, 0x02, 0x01, 0x4B, 0x00, 0x01, 0x00, 0x0A, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x0A,
+            0x70, 0x72, 0x69, 0x6E, 0x74, 0x00,
+        ]);
+
+        let dump = Dump::new(LittleEndianBuffer(bytes));
+        let function = Function::from_prototype(dump.main(), false);
+
+        assert_eq!(function.parameter_count(), 0);
+        assert!(function.upvalues().is_empty());
+        assert_eq!(function.prototype().kgc.len(), 2);
+
+        let listing: Vec<_> = function.instructions().iter().map(|insn| insn.to_string()).collect();
+        assert_eq!(listing, ["nop", "v0 = _G[kgc[0]]", "v1 = kgc[1]", "v0(v1)", "return"]);
+        assert!(matches!(
+            function.instructions()[3],
+            Insn::Call {
+                func: BasicOperand::Var(0),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_branch_pairing() {
+        // if a < b then a = b end, repeated.
+        let mut instructions = vec![];
+        for i in 0..500 {
+            let pc = i * 3;
+            instructions.extend([ad("ISGE", 0, 1), jump("JMP", 2, pc + 1, pc + 3), ad("MOV", 0, 1)]);
+        }
+        instructions.push(ad("RET0", 0, 1));
+
+        let main = Proto {
+            numparams: 2,
+            framesize: 2,
+            instructions,
+            ..Default::default()
+        };
+        let dump = fixture::parse(vec![main]);
+        let proto = dump.main();
+
+        // Looks back at the bytecode for the comparison each jump belongs to.
+        let mut naive = Emitter::new();
+        for (pc, insn) in proto.instructions.iter().enumerate() {
+            let paired = pc > 0 && proto.instructions[pc - 1].is_condition();
+            match (insn, naive.instructions.last_mut()) {
+                (Instruction::JMP { .. }, Some(Insn::ConditionalBranch { target, .. })) if paired => {
+                    let bc = insn.branch_target(pc).unwrap();
+                    *target = Label::Label { bc };
+                }
+                _ => Insn::parse(*insn, pc, false, &mut naive),
+            }
+        }
+
+        let function = Function::from_prototype(proto, false);
+        assert_eq!(function.instructions().len(), 1002);
 
-impl Function {
-    pub fn new(module: &Module, proto: &Prototype) -> Self {
-        Self {}
+        let listing = |instructions: &[Insn]| instructions.iter().map(Insn::to_string).collect::<Vec<_>>();
+        assert_eq!(listing(function.instructions()), listing(naive.instructions()));
     }
 }
@@ -1,4 +1,9 @@
-use crate::lua::{bytecode, ir::Emitter};
+use std::ops::Range;
+
+use crate::lua::{
+    bytecode::{self, Complex, NumericValue, Prototype},
+    ir::Emitter,
+};
 
 /// A slot is a primitive bytecode `Instruction` operand.
 ///
@@ -7,6 +12,7 @@ use crate::lua::{bytecode, ir::Emitter};
 /// so the operands acquire metadata to retain this information instead. As a consequence,
 /// we chose to wrap them in a lightweight enumeration type, effectively encoding the
 /// information in the type system.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum BasicOperand {
     /// A variable slot number.
     Var(u32),
@@ -26,24 +32,75 @@ pub enum BasicOperand {
     Table(u32),
     /// A function prototype; negated index into constant table.
     Func(u32),
-    /// A data constant, negated index into constant table
-    Constant(u32),
+    /// An FFI cdata constant, such as `1LL`; negated index into constant table.
+    CData(u32),
+    /// A global variable, named by a string constant; negated index into constant table.
+    Global(u32),
     /// A branch target, relative to next instruction, biased with 0x8000
     Branch(u32),
 }
 
+/// The value of a constant operand, see `BasicOperand::resolve`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Constant<'a> {
+    Number(NumericValue),
+    String(&'a [u8]),
+    /// A template table, which is a `Complex::Table`.
+    Table(&'a Complex),
+    /// A function prototype, as its index in the dump.
+    Function(usize),
+    /// A cdata constant, which is a `Complex::Signed`, `Complex::Unsigned` or `Complex::Complex`.
+    CData(&'a Complex),
+}
+
 impl BasicOperand {
     pub fn len(self) -> Expr {
         Expr::Len(self)
     }
 
-    pub fn neg(self) -> Expr {
-        Expr::Negate(self)
+    /// Resolves an operand referring to the constant tables of a prototype.
+    ///
+    /// Number constants are indexed from the start of `kn`, but complex constants are indexed from
+    /// the end of `kgc`, as LuaJIT negates their operands: `Str(0)` is the last constant of `kgc`.
+    /// Globals resolve to their name.
+    ///
+    /// Returns `None` for operands which are not constants, or which do not refer to a constant of
+    /// the expected kind.
+    pub fn resolve<'a>(&self, proto: &'a Prototype) -> Option<Constant<'a>> {
+        match *self {
+            Self::Num(index) => proto
+                .numeric_constant(index)
+                .map(|value| Constant::Number(value.value())),
+            Self::Str(d) | Self::Global(d) => proto.string_constant(d).map(Constant::String),
+            Self::Table(d) => match proto.gc_constant(d)? {
+                table @ Complex::Table { .. } => Some(Constant::Table(table)),
+                _ => None,
+            },
+            Self::Func(d) => match proto.gc_constant(d)? {
+                Complex::Prototype(index) => Some(Constant::Function(*index)),
+                _ => None,
+            },
+            Self::CData(d) => match proto.gc_constant(d)? {
+                cdata @ (Complex::Signed(_) | Complex::Unsigned(_) | Complex::Complex { .. }) => {
+                    Some(Constant::CData(cdata))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
     }
+}
 
-    pub fn not(self) -> Expr {
-        Expr::Not(self)
-    }
+macro_rules! define_unop {
+    ($v:ident, $fn:ident, $e:ident) => {
+        impl ::core::ops::$v for BasicOperand {
+            type Output = Expr;
+
+            fn $fn(self) -> Self::Output {
+                Expr::$e(self)
+            }
+        }
+    };
 }
 
 macro_rules! define_binop {
@@ -59,6 +116,8 @@ macro_rules! define_binop {
 }
 
 // Define helpers to simplify combining slots in operands
+define_unop!(Neg, neg, Negate);
+define_unop!(Not, not, Not);
 define_binop!(Rem, rem);
 define_binop!(Mul, mul);
 define_binop!(Div, div);
@@ -70,16 +129,19 @@ impl BasicOperand {
     }
 }
 
-impl Into<Operand> for BasicOperand {
-    fn into(self) -> Operand {
-        Operand::Basic(self)
+impl From<BasicOperand> for Operand {
+    fn from(value: BasicOperand) -> Self {
+        Operand::Basic(value)
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Primitive {
     Nil,
     True,
     False,
+    /// A primitive operand other than the three LuaJIT defines, which a valid dump never holds.
+    Invalid(u16),
 }
 
 pub enum Operand {
@@ -91,7 +153,7 @@ pub enum Operand {
 ///
 /// # Examples:
 /// * `ADDVN a, b, c` would translate to:
-/// ```
+/// ```text
 /// Insn::Add {
 ///   lhs: Slot::Var(a),
 ///   rhs: Op::Expr(Expr::Add {
@@ -115,21 +177,51 @@ pub enum Expr {
     Rem(BasicOperand, BasicOperand),
     /// `lhs ^ rhs`.
     Pow(BasicOperand, BasicOperand),
-    /// `lhs .. ~ .. rhs`.
-    Cat(BasicOperand, BasicOperand),
+    /// `values[0] .. values[1] .. ~`: the concatenation of a range of consecutive slots.
+    Cat(Vec<BasicOperand>),
     /// `lhs[rhs]`.
     Index(BasicOperand, BasicOperand),
     /// `!value`.
     Not(BasicOperand),
     /// `-value`.
     Negate(BasicOperand),
+    /// `value` as a condition: `false` if it is `nil` or `false`, `true` otherwise.
+    Truthy(BasicOperand),
     /// `#value` (object length).
     Len(BasicOperand),
+    /// `{}`, with hints for the sizes of the array and hash parts.
+    NewTable { array_size: u32, hash_size: u32 },
+    /// A copy of a template table.
+    DupTable(BasicOperand),
+    /// A closure of a child prototype, capturing its upvalues.
+    Closure(BasicOperand),
+    /// `...`, either expanded to every extra argument as a multi-result tail, or truncated to the
+    /// first one.
+    Vararg { multi: bool },
+    /// The extra argument at the given position, starting at 0, or `nil` if there are fewer.
+    ///
+    /// The first one is `Vararg { multi: false }`.
+    Select(u32),
+}
+
+/// The destination of an assignment.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Place {
+    /// A variable slot, an upvalue, or a global.
+    Basic(BasicOperand),
+    /// `table[key]`.
+    Index(BasicOperand, BasicOperand),
 }
 
-impl Into<Operand> for Expr {
-    fn into(self) -> Operand {
-        Operand::Expr(self)
+impl From<BasicOperand> for Place {
+    fn from(value: BasicOperand) -> Self {
+        Place::Basic(value)
+    }
+}
+
+impl From<Expr> for Operand {
+    fn from(value: Expr) -> Self {
+        Operand::Expr(value)
     }
 }
 
@@ -141,18 +233,69 @@ impl Into<Operand> for Expr {
 /// depending on their operands). This first abstraction level unifies
 /// instructions so that each instruction is a logical unit of operation
 /// independant of its operands.
+///
+/// Globals are not table accesses in IR: `GGET` and `GSET` respectively read from and write to a
+/// `BasicOperand::Global` rather than indexing an implicit globals table with a string.
 #[rustfmt::skip]
 pub enum Insn {
-    Assign { lhs: Operand, rhs: Operand },
+    /// Has no effect on the program, such as the `LOOP` hint marking the head of a loop.
+    Nop,
+    Assign { lhs: Place, rhs: Operand },
+    /// Stores the multi-result tail of the previous call into `lhs`, a table entry with an integer
+    /// key, and the entries with the following keys.
+    StoreMulti { lhs: Place },
+    /// Performs the assignment only if `cond` evals to `true`.
+    ConditionalAssign { cond: Operand, lhs: Place, rhs: Operand },
+    /// Raises an error unless `value` has the type of the given tag, then falls through; `number`
+    /// accepts both number representations. `lj_parse.c` never emits these checks.
+    TypeCheck { value: BasicOperand, tag: u16, number: bool },
     /// Follows the given label if `cond` evals to `true`.
     ConditionalBranch { cond: Operand, target: Label },
     /// Unconditionally jumps to the target label.
     Branch { target: Label },
+    /// Enters a numeric `for` loop, skipping it by jumping to `exit` if it runs no iteration.
+    ///
+    /// `base` through `base + 2` hold the start, limit and step; the visible loop variable is
+    /// copied to `base + 3`.
+    NumericForInit { base: u32, exit: Label },
+    /// Steps a numeric `for` loop, jumping back to `body` while iterations remain.
+    NumericForLoop { base: u32, body: Label },
+    /// Steps a generic `for` loop: if the first value returned by the iterator, held in `base`, is
+    /// not `nil`, it is copied to the control variable in `base - 1` and control jumps to `body`.
+    GenericForLoop { base: u32, body: Label },
+    /// Closes the upvalues of every slot from `from` upwards, which closures capturing them keep
+    /// a copy of from then on.
+    CloseUpvalues { from: u32 },
+    /// Calls the function held in `func`.
+    Call {
+        /// The slot holding the function, which the results overwrite.
+        base: u32,
+        func: BasicOperand,
+        /// The fixed arguments.
+        args: Vec<BasicOperand>,
+        /// The slots receiving the results, or `None` if every result is kept as a multi-result tail.
+        results: Option<Range<u32>>,
+        /// Whether the multi-result tail of the previous call is appended to the arguments.
+        multi: bool,
+        /// Whether this is the implicit call of a generic `for` loop, whose function and arguments
+        /// are the three slots below `base` and whose results are the loop variables.
+        iterator: bool,
+    },
+    /// Calls the function held in `base` and returns its results; the callee replaces the frame
+    /// of the current function, so control never comes back.
+    TailCall {
+        base: u32,
+        /// The fixed arguments.
+        args: Vec<BasicOperand>,
+        /// Whether the multi-result tail of the previous call is appended to the arguments.
+        multi: bool,
+    },
     /// Returns control flow to the caller.
     Return {
-        base: BasicOperand,
-        /// The amount of return values, starting at the base `Slot`.
-        count: u16
+        /// The fixed return values.
+        values: Vec<BasicOperand>,
+        /// Whether the multi-result tail of the previous call is returned after the fixed values.
+        multi: bool,
     }
 }
 
@@ -170,7 +313,11 @@ pub enum CmpOp {
 /// The destination of a branch instruction.
 pub enum Label {
     None,
-    Label { ir: usize, bc: usize },
+    /// `bc` is the index of the target in `Prototype::instructions`; see `Emitter::ir_index` for
+    /// the IR instruction it resolves to.
+    Label {
+        bc: usize,
+    },
 }
 
 #[rustfmt::skip]
@@ -179,13 +326,18 @@ macro_rules! op {
     (Num $v:ident) => { BasicOperand::Num($v as u32) };
     (Str $v:ident) => { BasicOperand::Str($v as u32) };
     (Lit $v:ident) => { BasicOperand::UnsignedLiteral($v as u32) };
+    (SLit $v:ident) => { BasicOperand::SignedLiteral($v as i16 as i32) };
     (Uv $v:ident) => { BasicOperand::Upvalue($v as u32) };
+    (Tab $v:ident) => { BasicOperand::Table($v as u32) };
+    (Func $v:ident) => { BasicOperand::Func($v as u32) };
+    (CData $v:ident) => { BasicOperand::CData($v as u32) };
+    (Global $v:ident) => { BasicOperand::Global($v as u32) };
     (Pri $v:ident) => {
         BasicOperand::Pri(match $v {
             0 => Primitive::Nil,
-            1 => Primitive::True,
-            2 => Primitive::False,
-            _ => unimplemented!("Unknown primitive type")
+            1 => Primitive::False,
+            2 => Primitive::True,
+            value => Primitive::Invalid(value as u16),
         })
     }
 }
@@ -198,65 +350,160 @@ macro_rules! expr {
     (Mul $lhs:expr, $rhs:expr) => { $lhs * $rhs };
     (Mod $lhs:expr, $rhs:expr) => { $lhs % $rhs };
     (Pow $lhs:expr, $rhs:expr) => { Expr::Pow($lhs, $rhs) };
-    (Cat $lhs:expr, $rhs:expr) => { Expr::Cat($lhs, $rhs) };
     (Idx $lhs:expr, $rhs:expr) => { Expr::Index($lhs, $rhs) };
+    (Not $value:expr) => { !$value };
+    (Neg $value:expr) => { -$value };
 }
 
 impl Insn {
     #[inline]
-    fn emit_cond_branch(emitter: &mut Emitter, op: CmpOp, a: u8, d: u16) {
-        let op = Expr::Binary(op, op!(Var a), op!(Var d));
+    fn emit_cond_branch(emitter: &mut Emitter, op: CmpOp, a: u8, d: BasicOperand) {
+        let op = Expr::Binary(op, op!(Var a), d);
 
         // Some instructions are followed by explicit branches; others inline the branch label
         // in their operands. To account for this, we do not set the branch label here; explicit
-        // branching instructions will instead acquire the last emitted branch instruction and
-        // fixup the branch label. See `Emitter::fixup_branches`.
+        // branching instructions will instead acquire the pending branch instruction and
+        // fixup the branch label. See `Emitter::fixup_branch`.
 
-        emitter.emit(Self::ConditionalBranch {
-            cond: op.into(),
-            target: Label::None,
-        });
+        emitter.emit_pending_branch(op.into());
     }
 
     #[inline]
-    fn emit_assignment<L: Into<Operand>, R: Into<Operand>>(emitter: &mut Emitter, lhs: L, rhs: R) {
+    fn emit_assignment<L: Into<Place>, R: Into<Operand>>(emitter: &mut Emitter, lhs: L, rhs: R) {
         emitter.emit(Self::Assign {
             lhs: lhs.into(),
             rhs: rhs.into(),
         });
     }
 
-    pub fn parse(insn: bytecode::Instruction, emitter: &mut Emitter) {
+    /// Emits a truthiness test of `d`, branching to the target of the following jump if `d` is
+    /// truthy, or falsy if `truthy` is unset.
+    ///
+    /// The copy variants of the tests additionally store the tested value into `copy` when the
+    /// branch is taken; this is how `and` and `or` keep the value that decided their result.
+    #[inline]
+    fn emit_test(emitter: &mut Emitter, truthy: bool, d: u16, copy: Option<u8>) {
+        let cond = || -> Operand {
+            if truthy {
+                Expr::Truthy(op!(Var d)).into()
+            } else {
+                expr!(Not op!(Var d)).into()
+            }
+        };
+
+        if let Some(a) = copy {
+            emitter.emit(Self::ConditionalAssign {
+                cond: cond(),
+                lhs: op!(Var a).into(),
+                rhs: op!(Var d).into(),
+            });
+        }
+
+        // See `emit_cond_branch`.
+        emitter.emit_pending_branch(cond());
+    }
+
+    /// Emits a call.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - The slot holding the function; arguments follow it, after the frame link on GC64
+    ///   builds.
+    /// * `results` - The amount of results plus one, or zero to keep every result.
+    /// * `args` - The amount of fixed arguments.
+    /// * `multi` - Whether the arguments end with a multi-result tail.
+    /// * `gc64` - Whether the frame link takes the slot after the function; see `Dump::is_gc64`.
+    #[inline]
+    fn emit_call(emitter: &mut Emitter, base: u8, results: u8, args: u8, multi: bool, gc64: bool) {
+        let base = base as u32;
+        let first = base + 1 + gc64 as u32;
+        emitter.emit(Self::Call {
+            base,
+            func: op!(Var base),
+            args: (first..first + args as u32).map(BasicOperand::Var).collect(),
+            results: (results != 0).then(|| base..base + results as u32 - 1),
+            multi,
+            iterator: false,
+        });
+    }
+
+    /// Emits a tail call of the function in `base` with the `args` slots following it, or following
+    /// the frame link if `gc64` is set.
+    #[inline]
+    fn emit_tail_call(emitter: &mut Emitter, base: u8, args: u16, multi: bool, gc64: bool) {
+        let base = base as u32;
+        let first = base + 1 + gc64 as u32;
+        emitter.emit(Self::TailCall {
+            base,
+            args: (first..first + args as u32).map(BasicOperand::Var).collect(),
+            multi,
+        });
+    }
+
+    /// Emits a return of `count` consecutive slots starting at `base`.
+    #[inline]
+    fn emit_return(emitter: &mut Emitter, base: u8, count: u16, multi: bool) {
+        emitter.emit(Self::Return {
+            values: (0..count as u32).map(|i| BasicOperand::Var(base as u32 + i)).collect(),
+            multi,
+        });
+    }
+
+    /// Lifts a bytecode instruction.
+    ///
+    /// # Arguments
+    ///
+    /// * `insn` - The instruction to lift.
+    /// * `pc` - The index of the instruction in `Prototype::instructions`, which branch targets
+    ///   are relative to.
+    /// * `gc64` - Whether the dump was produced by a GC64 build, whose frame link takes the slot
+    ///   after the function of a call; see `Dump::is_gc64`.
+    /// * `emitter` - Receives the lifted instructions.
+    pub fn parse(insn: bytecode::Instruction, pc: usize, gc64: bool, emitter: &mut Emitter) {
         use bytecode::Instruction as I;
 
+        emitter.seek(pc);
+        let label = || match insn.branch_target(pc) {
+            Some(bc) => Label::Label { bc },
+            None => Label::None,
+        };
+
         match insn {
-            I::ISLT { a, d } => Self::emit_cond_branch(emitter, CmpOp::Lt, a, d),
-            I::ISGE { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ge, a, d),
-            I::ISLE { a, d } => Self::emit_cond_branch(emitter, CmpOp::Le, a, d),
-            I::ISGT { a, d } => Self::emit_cond_branch(emitter, CmpOp::Gt, a, d),
-            I::ISEQV { a, d } => Self::emit_cond_branch(emitter, CmpOp::Eq, a, d),
-            I::ISNEV { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ne, a, d),
-            I::ISEQS { a, d } => Self::emit_cond_branch(emitter, CmpOp::Eq, a, d),
-            I::ISNES { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ne, a, d),
-            I::ISEQN { a, d } => Self::emit_cond_branch(emitter, CmpOp::Eq, a, d),
-            I::ISNEN { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ne, a, d),
-            I::ISEQP { a, d } => Self::emit_cond_branch(emitter, CmpOp::Eq, a, d),
-            I::ISNEP { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ne, a, d),
-            I::ISTC { a, d } => todo!(),
-            I::ISFC { a, d } => todo!(),
-            I::IST { d } => todo!(),
-            I::ISF { d } => todo!(),
-            I::ISTYPE { a, d } => todo!(),
-            I::ISNUM { a, d } => todo!(),
+            I::ISLT { a, d } => Self::emit_cond_branch(emitter, CmpOp::Lt, a, op!(Var d)),
+            I::ISGE { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ge, a, op!(Var d)),
+            I::ISLE { a, d } => Self::emit_cond_branch(emitter, CmpOp::Le, a, op!(Var d)),
+            I::ISGT { a, d } => Self::emit_cond_branch(emitter, CmpOp::Gt, a, op!(Var d)),
+            I::ISEQV { a, d } => Self::emit_cond_branch(emitter, CmpOp::Eq, a, op!(Var d)),
+            I::ISNEV { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ne, a, op!(Var d)),
+            I::ISEQS { a, d } => Self::emit_cond_branch(emitter, CmpOp::Eq, a, op!(Str d)),
+            I::ISNES { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ne, a, op!(Str d)),
+            I::ISEQN { a, d } => Self::emit_cond_branch(emitter, CmpOp::Eq, a, op!(Num d)),
+            I::ISNEN { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ne, a, op!(Num d)),
+            I::ISEQP { a, d } => Self::emit_cond_branch(emitter, CmpOp::Eq, a, op!(Pri d)),
+            I::ISNEP { a, d } => Self::emit_cond_branch(emitter, CmpOp::Ne, a, op!(Pri d)),
+            I::ISTC { a, d } => Self::emit_test(emitter, true, d, Some(a)),
+            I::ISFC { a, d } => Self::emit_test(emitter, false, d, Some(a)),
+            I::IST { d } => Self::emit_test(emitter, true, d, None),
+            I::ISF { d } => Self::emit_test(emitter, false, d, None),
+            I::ISTYPE { a, d } => emitter.emit(Insn::TypeCheck {
+                value: op!(Var a),
+                tag: d,
+                number: false,
+            }),
+            I::ISNUM { a, d } => emitter.emit(Insn::TypeCheck {
+                value: op!(Var a),
+                tag: d,
+                number: true,
+            }),
             I::MOV { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(Var d)),
-            I::NOT { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(Var d).not()),
-            I::UNM { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(Var d).neg()),
+            I::NOT { a, d } => Self::emit_assignment(emitter, op!(Var a), expr!(Not op!(Var d))),
+            I::UNM { a, d } => Self::emit_assignment(emitter, op!(Var a), expr!(Neg op!(Var d))),
             I::LEN { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(Var d).len()),
             I::ADDVN { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Var b) + op!(Num c)),
             I::SUBVN { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Var b) - op!(Num c)),
             I::MULVN { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Var b) * op!(Num c)),
             I::DIVVN { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Var b) / op!(Num c)),
-            I::MODVN { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Var b) * op!(Num c)),
+            I::MODVN { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Var b) % op!(Num c)),
             I::ADDNV { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Num b) + op!(Var c)),
             I::SUBNV { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Num b) - op!(Var c)),
             I::MULNV { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Num b) * op!(Var c)),
@@ -268,75 +515,987 @@ impl Insn {
             I::DIVVV { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Var b) / op!(Var c)),
             I::MODVV { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Var b) % op!(Var c)),
             I::POW { a, b, c } => Self::emit_assignment(emitter, op!(Var a), op!(Var b).pow(op!(Var c))),
-            I::CAT { a, b, c } => Self::emit_assignment(emitter, op!(Var a), expr!(Cat op!(Var b), op!(Var c))),
+            I::CAT { a, b, c } => {
+                Self::emit_assignment(emitter, op!(Var a), Expr::Cat((b..=c).map(|slot| op!(Var slot)).collect()))
+            }
             I::KSTR { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(Str d)),
-            I::KCDATA { a, d } => todo!(),
-            I::KSHORT { a, d } => todo!(),
+            I::KCDATA { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(CData d)),
+            I::KSHORT { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(SLit d)),
             I::KNUM { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(Num d)),
             I::KPRI { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(Pri d)),
-            I::KNIL { a, d } => todo!(),
+            I::KNIL { a, d } => (a as u32..=d as u32)
+                .for_each(|slot| Self::emit_assignment(emitter, op!(Var slot), BasicOperand::Pri(Primitive::Nil))),
             I::UGET { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(Uv d)),
             I::USETV { a, d } => Self::emit_assignment(emitter, op!(Uv a), op!(Var d)),
             I::USETS { a, d } => Self::emit_assignment(emitter, op!(Uv a), op!(Str d)),
             I::USETN { a, d } => Self::emit_assignment(emitter, op!(Uv a), op!(Num d)),
             I::USETP { a, d } => Self::emit_assignment(emitter, op!(Uv a), op!(Pri d)),
-            I::UCLO { a, d } => todo!(),
-            I::FNEW { a, d } => todo!(),
-            I::TNEW { a, d } => todo!(),
-            I::TDUP { a, d } => todo!(),
-            I::GGET { a, d } => todo!(),
-            I::GSET { a, d } => todo!(),
+            // The jump usually targets the next instruction, and is never part of a condition.
+            I::UCLO { a, .. } => {
+                emitter.emit(Insn::CloseUpvalues { from: a as u32 });
+                emitter.emit(Insn::Branch { target: label() });
+            }
+            I::FNEW { a, d } => Self::emit_assignment(emitter, op!(Var a), Expr::Closure(op!(Func d))),
+            I::TNEW { a, d } => {
+                // See `lj_parse.c`: the low 11 bits are the array size, the rest is the log2 of the
+                // hash size.
+                let hash_bits = d as u32 >> 11;
+                let table = Expr::NewTable {
+                    array_size: d as u32 & 0x7FF,
+                    hash_size: if hash_bits == 0 { 0 } else { 1 << hash_bits },
+                };
+
+                Self::emit_assignment(emitter, op!(Var a), table)
+            }
+            I::TDUP { a, d } => Self::emit_assignment(emitter, op!(Var a), Expr::DupTable(op!(Tab d))),
+            I::GGET { a, d } => Self::emit_assignment(emitter, op!(Var a), op!(Global d)),
+            I::GSET { a, d } => Self::emit_assignment(emitter, op!(Global d), op!(Var a)),
             I::TGETV { a, b, c } => Self::emit_assignment(emitter, op!(Var a), expr!(Idx op!(Var b), op!(Var c))),
             I::TGETS { a, b, c } => Self::emit_assignment(emitter, op!(Var a), expr!(Idx op!(Var b), op!(Str c))),
             I::TGETB { a, b, c } => Self::emit_assignment(emitter, op!(Var a), expr!(Idx op!(Var b), op!(Lit c))),
-            I::TGETR { a, b, c } => todo!(),
-            I::TSETV { a, b, c } => Self::emit_assignment(emitter, expr!(Idx op!(Var b), op!(Var c)), op!(Var a)),
-            I::TSETS { a, b, c } => Self::emit_assignment(emitter, expr!(Idx op!(Var b), op!(Var c)), op!(Str a)),
-            I::TSETB { a, b, c } => Self::emit_assignment(emitter, expr!(Idx op!(Var b), op!(Var c)), op!(Lit a)),
-            I::TSETR { a, b, c } => todo!(),
-            I::TSETM { a, d } => todo!(),
-            I::CALLM { a, b, c } => todo!(),
-            I::CALL { a, b, c } => todo!(),
-            I::CALLMT { a, d } => todo!(),
-            I::CALLT { a, d } => todo!(),
-            I::ITERC { a, b, c } => todo!(),
-            I::ITERN { a, b, c } => todo!(),
-            I::VARG { a, b, c } => todo!(),
-            I::ISNEXT { a, d } => todo!(),
-            I::RETM { a, d } => todo!(),
-            I::RET { a, d } => emitter.emit(Insn::Return {
-                base: op!(Var a),
-                count: d - 1,
+            I::TGETR { a, b, c } => Self::emit_assignment(emitter, op!(Var a), expr!(Idx op!(Var b), op!(Var c))),
+            I::TSETV { a, b, c } => Self::emit_assignment(emitter, Place::Index(op!(Var b), op!(Var c)), op!(Var a)),
+            I::TSETS { a, b, c } => Self::emit_assignment(emitter, Place::Index(op!(Var b), op!(Str c)), op!(Var a)),
+            I::TSETB { a, b, c } => Self::emit_assignment(emitter, Place::Index(op!(Var b), op!(Lit c)), op!(Var a)),
+            I::TSETR { a, b, c } => Self::emit_assignment(emitter, Place::Index(op!(Var b), op!(Var c)), op!(Var a)),
+            // The key is the low half of the number constant, decoded by `Function::from_prototype`.
+            I::TSETM { a, d } => emitter.emit(Insn::StoreMulti {
+                lhs: Place::Index(BasicOperand::Var((a as u32).saturating_sub(1)), op!(Num d)),
             }),
-            I::RET0 { a, .. } => emitter.emit(Insn::Return {
-                base: op!(Var a),
-                count: 0,
+            I::CALLM { a, b, c } => Self::emit_call(emitter, a, b, c, true, gc64),
+            I::CALL { a, b, c } => Self::emit_call(emitter, a, b, c.saturating_sub(1), false, gc64),
+            I::CALLMT { a, d } => Self::emit_tail_call(emitter, a, d, true, gc64),
+            I::CALLT { a, d } => Self::emit_tail_call(emitter, a, d.saturating_sub(1), false, gc64),
+            // The iterator, its state and the control variable are copied above themselves before
+            // the call, to `base`, and to the two slots after the frame link. Those copies only
+            // exist for the call, which is made with the originals; they do not move on GC64 builds.
+            // Malformed operands are saturated rather than underflowing.
+            I::ITERC { a, b, .. } => {
+                let base = a as u32;
+                emitter.emit(Self::Call {
+                    base,
+                    func: BasicOperand::Var(base.saturating_sub(3)),
+                    args: vec![
+                        BasicOperand::Var(base.saturating_sub(2)),
+                        BasicOperand::Var(base.saturating_sub(1)),
+                    ],
+                    results: Some(base..(base + b as u32).saturating_sub(1)),
+                    multi: false,
+                    iterator: true,
+                })
+            }
+            I::ITERN { .. } => Self::parse(insn.despecialize(), pc, gc64, emitter),
+            // `b` is the amount of values plus one, or zero to keep every value.
+            I::VARG { a, b: 0, .. } => Self::emit_assignment(emitter, op!(Var a), Expr::Vararg { multi: true }),
+            I::VARG { a, b, .. } => (0..b as u32 - 1).for_each(|i| {
+                let value = if i == 0 {
+                    Expr::Vararg { multi: false }
+                } else {
+                    Expr::Select(i)
+                };
+                Self::emit_assignment(emitter, BasicOperand::Var(a as u32 + i), value)
             }),
-            I::RET1 { a, .. } => emitter.emit(Insn::Return {
-                base: op!(Var a),
-                count: 1,
+            I::ISNEXT { .. } => Self::parse(insn.despecialize(), pc, gc64, emitter),
+            I::RETM { a, d } => Self::emit_return(emitter, a, d, true),
+            I::RET { a, d } => Self::emit_return(emitter, a, d.saturating_sub(1), false),
+            I::RET0 { a, .. } => Self::emit_return(emitter, a, 0, false),
+            I::RET1 { a, .. } => Self::emit_return(emitter, a, 1, false),
+            I::FORI { a, .. } | I::JFORI { a, .. } => emitter.emit(Insn::NumericForInit {
+                base: a as u32,
+                exit: label(),
             }),
-            I::FORI { a, d } => todo!(),
-            I::JFORI { a, d } => todo!(),
-            I::FORL { a, d } => todo!(),
-            I::IFORL { a, d } => todo!(),
-            I::JFORL { a, d } => todo!(),
-            I::ITERL { a, d } => todo!(),
-            I::IITERL { a, d } => todo!(),
-            I::JITERL { a, d } => todo!(),
-            I::LOOP { a, d } => todo!(),
-            I::ILOOP { a, d } => todo!(),
-            I::JLOOP { a, d } => todo!(),
-            I::JMP { a, d } => emitter.fixup_branch(Label::Label { ir: 0, bc: d as usize }),
-            I::FUNCF { a } => todo!(),
-            I::IFUNCF { a } => todo!(),
-            I::JFUNCF { a, d } => todo!(),
-            I::FUNCV { a } => todo!(),
-            I::IFUNCV { a } => todo!(),
-            I::JFUNCV { a, d } => todo!(),
-            I::FUNCC { a } => todo!(),
-            I::FUNCCW { a } => todo!(),
-            I::FUNC { a } => todo!(),
+            I::FORL { a, .. } | I::IFORL { a, .. } => emitter.emit(Insn::NumericForLoop {
+                base: a as u32,
+                body: label(),
+            }),
+            // The operand of `JFORL` is the number of the trace that replaced the loop.
+            I::JFORL { a, .. } => emitter.emit(Insn::NumericForLoop {
+                base: a as u32,
+                body: Label::None,
+            }),
+            I::ITERL { a, .. } | I::IITERL { a, .. } => emitter.emit(Insn::GenericForLoop {
+                base: a as u32,
+                body: label(),
+            }),
+            // See `JFORL`.
+            I::JITERL { a, .. } => emitter.emit(Insn::GenericForLoop {
+                base: a as u32,
+                body: Label::None,
+            }),
+            // Loop headers only exist for the JIT compiler: control reaches them by falling through.
+            I::LOOP { .. } | I::ILOOP { .. } | I::JLOOP { .. } => emitter.emit(Insn::Nop),
+            I::JMP { .. } => emitter.fixup_branch(label()),
+            // The function header only holds the frame size, and sets up the frame on entry.
+            I::FUNCF { .. }
+            | I::IFUNCF { .. }
+            | I::JFUNCF { .. }
+            | I::FUNCV { .. }
+            | I::IFUNCV { .. }
+            | I::JFUNCV { .. }
+            | I::FUNCC { .. }
+            | I::FUNCCW { .. }
+            | I::FUNC { .. } => emitter.emit(Insn::Nop),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        bytecode::{
+            NumericValue,
+            fixture::{self, Kgc, Kn, Ktab, Proto, abc, ad, jump},
+        },
+        ir::{
+            BasicOperand, Cfg, Constant, Emitter, Expr, Function, Insn, Label, Module, Operand, Place, Primitive, cfg,
+        },
+    };
+
+    /// Lifts the instructions following the header of a stripped main prototype.
+    fn lift(instructions: Vec<u32>, kgc: Vec<Kgc>) -> Emitter {
+        let main = Proto {
+            framesize: 4,
+            instructions,
+            kgc,
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let mut emitter = Emitter::new();
+        dump.main()
+            .instructions
+            .iter()
+            .enumerate()
+            .skip(1)
+            .for_each(|(pc, insn)| Insn::parse(*insn, pc, false, &mut emitter));
+        emitter
+    }
+
+    #[test]
+    pub fn test_table_construction() {
+        // local t, u = { 1, 2, x = 3 }, { [k] = v, ... }
+        let emitter = lift(
+            vec![ad("TDUP", 0, 0), ad("TNEW", 1, 3 | (2 << 11)), ad("RET0", 0, 1)],
+            vec![Kgc::Table(
+                vec![Ktab::Nil, Ktab::Int(1), Ktab::Int(2)],
+                vec![(Ktab::Str("x"), Ktab::Int(3))],
+            )],
+        );
+
+        assert!(matches!(
+            emitter.instructions[0],
+            Insn::Assign {
+                lhs: Place::Basic(BasicOperand::Var(0)),
+                rhs: Operand::Expr(Expr::DupTable(BasicOperand::Table(0))),
+            }
+        ));
+        assert!(matches!(
+            emitter.instructions[1],
+            Insn::Assign {
+                lhs: Place::Basic(BasicOperand::Var(1)),
+                rhs: Operand::Expr(Expr::NewTable {
+                    array_size: 3,
+                    hash_size: 4,
+                }),
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_short_literals() {
+        // local x, y = -5, 32000
+        let emitter = lift(
+            vec![ad("KSHORT", 0, -5i16 as u16), ad("KSHORT", 1, 32000), ad("RET0", 0, 1)],
+            vec![],
+        );
+
+        let literals: Vec<_> = emitter.instructions[..2]
+            .iter()
+            .map(|insn| match insn {
+                Insn::Assign {
+                    lhs: Place::Basic(BasicOperand::Var(slot)),
+                    rhs: Operand::Basic(BasicOperand::SignedLiteral(value)),
+                } => (*slot, *value),
+                _ => panic!("Expected a literal assignment"),
+            })
+            .collect();
+        assert_eq!(literals, [(0, -5), (1, 32000)]);
+    }
+
+    #[test]
+    pub fn test_resolve_constants() {
+        // local a, b, c = "first", "second", 0.25
+        let main = Proto {
+            framesize: 3,
+            instructions: vec![ad("KSTR", 0, 0), ad("KSTR", 1, 1), ad("KNUM", 2, 1), ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Str(b"second"), Kgc::Str(b"first")],
+            kn: vec![Kn::Int(7), Kn::Num(0.25)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let function = Function::from_prototype(dump.main(), false);
+        let resolved: Vec<_> = function
+            .instructions()
+            .iter()
+            .filter_map(|insn| match insn {
+                Insn::Assign {
+                    rhs: Operand::Basic(operand),
+                    ..
+                } => operand.resolve(function.prototype()),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            resolved,
+            [
+                Constant::String(b"first"),
+                Constant::String(b"second"),
+                Constant::Number(NumericValue::Number(0.25)),
+            ]
+        );
+        assert_eq!(
+            BasicOperand::Num(0).resolve(function.prototype()),
+            Some(Constant::Number(NumericValue::Integer(7)))
+        );
+        assert_eq!(BasicOperand::Str(2).resolve(function.prototype()), None);
+        assert_eq!(BasicOperand::Table(0).resolve(function.prototype()), None);
+        assert_eq!(BasicOperand::Var(0).resolve(function.prototype()), None);
+    }
+
+    #[test]
+    pub fn test_cdata() {
+        // local x = 1LL
+        let emitter = lift(vec![ad("KCDATA", 0, 0), ad("RET1", 0, 2)], vec![Kgc::I64(1)]);
+        assert!(matches!(
+            emitter.instructions()[0],
+            Insn::Assign {
+                lhs: Place::Basic(BasicOperand::Var(0)),
+                rhs: Operand::Basic(BasicOperand::CData(0)),
+            }
+        ));
+        assert_eq!(emitter.instructions()[0].to_string(), "v0 = kgc[0]");
+    }
+
+    #[test]
+    pub fn test_nil_range() {
+        // local a, b, c
+        let emitter = lift(vec![ad("KNIL", 0, 2), ad("RET0", 0, 1)], vec![]);
+
+        let slots: Vec<_> = emitter.instructions[..3]
+            .iter()
+            .map(|insn| match insn {
+                Insn::Assign {
+                    lhs: Place::Basic(BasicOperand::Var(slot)),
+                    rhs: Operand::Basic(BasicOperand::Pri(Primitive::Nil)),
+                } => *slot,
+                _ => panic!("Expected a nil assignment"),
+            })
+            .collect();
+        assert_eq!(slots, [0, 1, 2]);
+        assert!(matches!(emitter.instructions[3], Insn::Return { .. }));
+    }
+
+    #[test]
+    pub fn test_unary_operators() {
+        // local x; local y, z = -x, not x
+        let emitter = lift(vec![ad("UNM", 1, 0), ad("NOT", 2, 0), ad("RET0", 0, 1)], vec![]);
+
+        assert!(matches!(
+            emitter.instructions[0],
+            Insn::Assign {
+                lhs: Place::Basic(BasicOperand::Var(1)),
+                rhs: Operand::Expr(Expr::Negate(BasicOperand::Var(0))),
+            }
+        ));
+        assert!(matches!(
+            emitter.instructions[1],
+            Insn::Assign {
+                lhs: Place::Basic(BasicOperand::Var(2)),
+                rhs: Operand::Expr(Expr::Not(BasicOperand::Var(0))),
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_globals() {
+        // x = y
+        let emitter = lift(
+            vec![ad("GGET", 0, 0), ad("GSET", 0, 1), ad("RET0", 0, 1)],
+            vec![Kgc::Str(b"x"), Kgc::Str(b"y")],
+        );
+
+        assert!(matches!(
+            emitter.instructions[0],
+            Insn::Assign {
+                lhs: Place::Basic(BasicOperand::Var(0)),
+                rhs: Operand::Basic(BasicOperand::Global(0)),
+            }
+        ));
+        assert!(matches!(
+            emitter.instructions[1],
+            Insn::Assign {
+                lhs: Place::Basic(BasicOperand::Global(1)),
+                rhs: Operand::Basic(BasicOperand::Var(0)),
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_table_get() {
+        // local v = t[k], t.x, t[3], rawget(t, k)
+        let emitter = lift(
+            vec![
+                abc("TGETV", 2, 0, 1),
+                abc("TGETS", 3, 0, 0),
+                abc("TGETB", 4, 0, 3),
+                abc("TGETR", 5, 0, 1),
+                ad("RET0", 0, 1),
+            ],
+            vec![Kgc::Str(b"x")],
+        );
+
+        let keys: Vec<_> = emitter.instructions[..4]
+            .iter()
+            .map(|insn| match insn {
+                Insn::Assign {
+                    lhs: Place::Basic(BasicOperand::Var(_)),
+                    rhs: Operand::Expr(Expr::Index(BasicOperand::Var(0), key)),
+                } => *key,
+                _ => panic!("Expected an indexed read"),
+            })
+            .collect();
+
+        assert_eq!(
+            keys,
+            [
+                BasicOperand::Var(1),
+                BasicOperand::Str(0),
+                BasicOperand::UnsignedLiteral(3),
+                BasicOperand::Var(1),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_table_set() {
+        // t[k], t.x, t[3] = v, v, v; rawset(t, k, v)
+        let emitter = lift(
+            vec![
+                abc("TSETV", 2, 0, 1),
+                abc("TSETS", 2, 0, 0),
+                abc("TSETB", 2, 0, 3),
+                abc("TSETR", 2, 0, 1),
+                ad("RET0", 0, 1),
+            ],
+            vec![Kgc::Str(b"x")],
+        );
+
+        let places: Vec<_> = emitter.instructions[..4]
+            .iter()
+            .map(|insn| match insn {
+                Insn::Assign {
+                    lhs,
+                    rhs: Operand::Basic(BasicOperand::Var(2)),
+                } => *lhs,
+                _ => panic!("Expected a store of slot 2"),
+            })
+            .collect();
+
+        assert_eq!(
+            places,
+            [
+                Place::Index(BasicOperand::Var(0), BasicOperand::Var(1)),
+                Place::Index(BasicOperand::Var(0), BasicOperand::Str(0)),
+                Place::Index(BasicOperand::Var(0), BasicOperand::UnsignedLiteral(3)),
+                Place::Index(BasicOperand::Var(0), BasicOperand::Var(1)),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_type_check() {
+        let emitter = lift(vec![ad("ISTYPE", 0, 0xFFF4), ad("ISNUM", 1, 0xFFF2), ad("RET0", 0, 1)], vec![]);
+
+        assert!(matches!(
+            emitter.instructions[0],
+            Insn::TypeCheck {
+                value: BasicOperand::Var(0),
+                tag: 0xFFF4,
+                number: false,
+            }
+        ));
+        assert!(matches!(
+            emitter.instructions[1],
+            Insn::TypeCheck {
+                value: BasicOperand::Var(1),
+                tag: 0xFFF2,
+                number: true,
+            }
+        ));
+        assert!(
+            emitter.instructions[..2]
+                .iter()
+                .all(|insn| matches!(cfg::branch(insn), (None, true)))
+        );
+    }
+
+    #[test]
+    pub fn test_call() {
+        // print("a", x); print(f())
+        let emitter = lift(
+            vec![
+                ad("GGET", 0, 0),
+                ad("KSTR", 1, 1),
+                ad("GGET", 2, 2),
+                abc("CALL", 0, 1, 3),
+                ad("GGET", 0, 0),
+                ad("GGET", 1, 3),
+                abc("CALL", 1, 0, 1),
+                abc("CALLM", 0, 1, 0),
+                ad("RET0", 0, 1),
+            ],
+            vec![Kgc::Str(b"f"), Kgc::Str(b"x"), Kgc::Str(b"a"), Kgc::Str(b"print")],
+        );
+
+        let Insn::Call {
+            base,
+            func,
+            args,
+            results,
+            multi,
+            iterator: false,
+        } = &emitter.instructions[3]
+        else {
+            panic!("Expected a call");
+        };
+        assert_eq!((*base, *func), (0, BasicOperand::Var(0)));
+        assert_eq!(args, &[BasicOperand::Var(1), BasicOperand::Var(2)]);
+        assert_eq!((results.clone(), *multi), (Some(0..0), false));
+
+        assert!(matches!(
+            &emitter.instructions[6],
+            Insn::Call {
+                base: 1,
+                results: None,
+                multi: false,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &emitter.instructions[7],
+            Insn::Call {
+                base: 0,
+                args,
+                results: Some(results),
+                multi: true,
+                ..
+            } if args.is_empty() && results.is_empty()
+        ));
+    }
+
+    #[test]
+    pub fn test_closure() {
+        // local function f() return function() end end
+        let inner = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            ..Default::default()
+        };
+        let outer = Proto {
+            flags: 1,
+            framesize: 1,
+            instructions: vec![ad("FNEW", 0, 0), ad("RET1", 0, 2)],
+            kgc: vec![Kgc::Child],
+            ..Default::default()
+        };
+        let main = Proto {
+            flags: 3,
+            framesize: 1,
+            instructions: vec![ad("FNEW", 0, 0), ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Child],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![inner, outer, main]);
+        let module = Module::from_dump(&dump);
+
+        let closure = |function: &Function| match function.instructions()[1] {
+            Insn::Assign {
+                lhs: Place::Basic(BasicOperand::Var(0)),
+                rhs: Operand::Expr(Expr::Closure(BasicOperand::Func(d))),
+            } => d,
+            _ => panic!("Expected a closure"),
+        };
+
+        let outer = module.child(module.main(), closure(module.main())).unwrap();
+        assert_eq!(outer.prototype().index, 1);
+        assert!(matches!(
+            outer.instructions()[2],
+            Insn::Return {
+                ref values,
+                multi: false
+            } if values[..] == [BasicOperand::Var(0)]
+        ));
+
+        let inner = module.child(outer, closure(outer)).unwrap();
+        assert_eq!(inner.prototype().index, 0);
+    }
+
+    #[test]
+    pub fn test_return() {
+        // return; return x; return x, y, z; return x, f()
+        let emitter = lift(
+            vec![ad("RET0", 0, 1), ad("RET1", 1, 2), ad("RET", 1, 4), ad("RETM", 0, 1)],
+            vec![],
+        );
+
+        let returns: Vec<_> = emitter
+            .instructions
+            .iter()
+            .map(|insn| match insn {
+                Insn::Return { values, multi } => (values.clone(), *multi),
+                _ => panic!("Expected a return"),
+            })
+            .collect();
+
+        assert_eq!(returns[0], (vec![], false));
+        assert_eq!(returns[1], (vec![BasicOperand::Var(1)], false));
+        assert_eq!(
+            returns[2],
+            (vec![BasicOperand::Var(1), BasicOperand::Var(2), BasicOperand::Var(3)], false)
+        );
+        assert_eq!(returns[3], (vec![BasicOperand::Var(0)], true));
+    }
+
+    #[test]
+    pub fn test_tail_call() {
+        // function(x) return f(x) end; function() return f(g()) end
+        let emitter = lift(
+            vec![
+                ad("GGET", 1, 0),
+                ad("MOV", 2, 0),
+                ad("CALLT", 1, 2),
+                ad("GGET", 0, 0),
+                ad("GGET", 1, 1),
+                abc("CALL", 1, 0, 1),
+                ad("CALLMT", 0, 0),
+            ],
+            vec![Kgc::Str(b"g"), Kgc::Str(b"f")],
+        );
+
+        assert!(matches!(
+            emitter.instructions[2],
+            Insn::TailCall {
+                base: 1,
+                ref args,
+                multi: false,
+            } if args[..] == [BasicOperand::Var(2)]
+        ));
+        assert!(matches!(
+            emitter.instructions[6],
+            Insn::TailCall {
+                base: 0,
+                ref args,
+                multi: true,
+            } if args.is_empty()
+        ));
+        assert_eq!(emitter.instructions[2].to_string(), "return v1(v2)");
+        assert_eq!(emitter.instructions[6].to_string(), "return v0(MULTRES)");
+
+        // Nothing follows a tail call.
+        let cfg = Cfg::new(&emitter);
+        assert_eq!(cfg.blocks().len(), 2);
+        assert!(cfg.successors(0).is_empty());
+    }
+
+    #[test]
+    pub fn test_numeric_for() {
+        // for i = 1, 10 do end
+        let emitter = lift(
+            vec![
+                ad("KSHORT", 0, 1),
+                ad("KSHORT", 1, 10),
+                ad("KSHORT", 2, 1),
+                jump("FORI", 0, 3, 5),
+                jump("FORL", 0, 4, 4),
+                ad("RET0", 0, 1),
+            ],
+            vec![],
+        );
+
+        assert!(matches!(
+            emitter.instructions[3],
+            Insn::NumericForInit {
+                base: 0,
+                exit: Label::Label { bc: 6 },
+            }
+        ));
+        assert!(matches!(
+            emitter.instructions[4],
+            Insn::NumericForLoop {
+                base: 0,
+                body: Label::Label { bc: 5 },
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_short_circuit() {
+        // local z = x and y; local w = x or y
+        let emitter = lift(
+            vec![
+                ad("ISFC", 2, 0),
+                jump("JMP", 3, 1, 3),
+                ad("MOV", 2, 1),
+                ad("ISTC", 3, 0),
+                jump("JMP", 4, 4, 6),
+                ad("MOV", 3, 1),
+                ad("RET0", 0, 1),
+            ],
+            vec![],
+        );
+
+        let listing: Vec<_> = emitter.instructions.iter().map(|insn| insn.to_string()).collect();
+        assert_eq!(
+            listing,
+            [
+                "if not v0 then v2 = v0",
+                "if not v0 goto 0004",
+                "v2 = v1",
+                "if truthy(v0) then v3 = v0",
+                "if truthy(v0) goto 0007",
+                "v3 = v1",
+                "return",
+            ]
+        );
+        assert!(matches!(
+            emitter.instructions[0],
+            Insn::ConditionalAssign {
+                cond: Operand::Expr(Expr::Not(BasicOperand::Var(0))),
+                lhs: Place::Basic(BasicOperand::Var(2)),
+                rhs: Operand::Basic(BasicOperand::Var(0)),
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_truthiness_branch() {
+        // if x then y = x end
+        let emitter = lift(
+            vec![ad("ISF", 0, 0), jump("JMP", 2, 1, 3), ad("MOV", 1, 0), ad("RET0", 0, 1)],
+            vec![],
+        );
+
+        assert!(matches!(
+            emitter.instructions[0],
+            Insn::ConditionalBranch {
+                cond: Operand::Expr(Expr::Not(BasicOperand::Var(0))),
+                target: Label::Label { bc: 4 },
+            }
+        ));
+        assert_eq!(emitter.instructions.len(), 3);
+    }
+
+    #[test]
+    pub fn test_generic_for() {
+        // for k, v in pairs(t) do end
+        for head in ["JMP", "ISNEXT"] {
+            let emitter = lift(
+                vec![
+                    ad("GGET", 0, 0),
+                    ad("GGET", 1, 1),
+                    abc("CALL", 0, 4, 2),
+                    jump(head, 3, 3, 5),
+                    ad("MOV", 5, 4),
+                    abc("ITERC", 3, 3, 3),
+                    jump("ITERL", 3, 6, 4),
+                    ad("RET0", 0, 1),
+                ],
+                vec![Kgc::Str(b"t"), Kgc::Str(b"pairs")],
+            );
+
+            let listing: Vec<_> = emitter.instructions.iter().map(|insn| insn.to_string()).collect();
+            assert_eq!(
+                listing,
+                [
+                    "v0 = _G[kgc[0]]",
+                    "v1 = _G[kgc[1]]",
+                    "v0..v2 = v0(v1)",
+                    "goto 0006",
+                    "v5 = v4",
+                    "v3..v4 = v0(v1, v2)",
+                    "iterloop v3 goto 0005",
+                    "return",
+                ]
+            );
+
+            let cfg = Cfg::new(&emitter);
+            let starts: Vec<_> = cfg.blocks().iter().map(|block| block.start).collect();
+            assert_eq!(starts, [0, 4, 5, 7]);
+            assert_eq!(cfg.successors(2), [3, 1]);
         }
     }
+
+    #[test]
+    pub fn test_specialized_generic_for() {
+        // for k, v in pairs(t) do x = k end, with and without the next() specialization
+        let generic_for = |head, iterator| Proto {
+            framesize: 7,
+            instructions: vec![
+                ad("GGET", 0, 0),
+                ad("GGET", 1, 1),
+                abc("CALL", 0, 4, 2),
+                jump(head, 3, 3, 5),
+                ad("MOV", 6, 3),
+                abc(iterator, 3, 3, 3),
+                jump("ITERL", 3, 6, 4),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"t"), Kgc::Str(b"pairs")],
+            ..Default::default()
+        };
+
+        let lift = |main| {
+            let dump = fixture::parse(vec![main]);
+            let function = Function::from_prototype(dump.main(), false);
+            let listing: Vec<_> = function.instructions().iter().map(|insn| insn.to_string()).collect();
+            (listing, function.to_lua())
+        };
+        assert_eq!(lift(generic_for("ISNEXT", "ITERN")), lift(generic_for("JMP", "ITERC")));
+    }
+
+    #[test]
+    pub fn test_iterator_call() {
+        // for i, v in ipairs(t) do end
+        let emitter = lift(
+            vec![
+                ad("GGET", 0, 0),
+                ad("GGET", 1, 1),
+                abc("CALL", 0, 4, 2),
+                jump("JMP", 3, 3, 4),
+                abc("ITERN", 3, 3, 3),
+                jump("ITERL", 3, 5, 4),
+                ad("RET0", 0, 1),
+            ],
+            vec![Kgc::Str(b"t"), Kgc::Str(b"ipairs")],
+        );
+
+        let Insn::Call {
+            base: 3,
+            func: BasicOperand::Var(0),
+            args,
+            results: Some(results),
+            multi: false,
+            iterator: true,
+        } = &emitter.instructions[4]
+        else {
+            panic!("Expected an iterator call");
+        };
+        assert_eq!(args[..], [BasicOperand::Var(1), BasicOperand::Var(2)]);
+        assert_eq!(*results, 3..5);
+
+        // The call producing the iterator is a regular one.
+        assert!(matches!(emitter.instructions[2], Insn::Call { iterator: false, .. }));
+    }
+
+    #[test]
+    pub fn test_vararg() {
+        // local a, b, c = ...; local d = ...; return ...
+        let emitter = lift(
+            vec![
+                abc("VARG", 0, 4, 0),
+                abc("VARG", 3, 2, 0),
+                abc("VARG", 4, 0, 0),
+                ad("RETM", 4, 0),
+            ],
+            vec![],
+        );
+
+        let listing: Vec<_> = emitter.instructions.iter().map(|insn| insn.to_string()).collect();
+        assert_eq!(
+            listing,
+            [
+                "v0 = (...)",
+                "v1 = select(2, ...)",
+                "v2 = select(3, ...)",
+                "v3 = (...)",
+                "v4 = ...",
+                "return MULTRES",
+            ]
+        );
+        assert!(matches!(
+            emitter.instructions[4],
+            Insn::Assign {
+                rhs: Operand::Expr(Expr::Vararg { multi: true }),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_close_upvalues() {
+        // for i = 1, 3 do local f = function() return i end end
+        let child = Proto {
+            framesize: 1,
+            instructions: vec![ad("UGET", 0, 0), ad("RET1", 0, 2)],
+            uvs: vec![0x8003],
+            ..Default::default()
+        };
+        let main = Proto {
+            flags: 1,
+            framesize: 5,
+            instructions: vec![
+                ad("KSHORT", 0, 1),
+                ad("KSHORT", 1, 3),
+                ad("KSHORT", 2, 1),
+                jump("FORI", 0, 3, 7),
+                ad("FNEW", 4, 0),
+                jump("UCLO", 3, 5, 6),
+                jump("FORL", 0, 6, 4),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Child],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![child, main]);
+        let function = Function::from_prototype(dump.main(), false);
+        let listing: Vec<_> = function.instructions().iter().map(|insn| insn.to_string()).collect();
+        assert_eq!(
+            listing[5..9],
+            ["v4 = closure(kgc[0])", "close v3", "goto 0007", "forloop v0 goto 0005"]
+        );
+
+        // The jump ends the block of the loop body.
+        let cfg = Cfg::new(function.emitter());
+        let starts: Vec<_> = cfg.blocks().iter().map(|block| block.start).collect();
+        assert_eq!(starts, [0, 5, 8, 9]);
+        assert_eq!(cfg.successors(1), [2]);
+    }
+
+    #[test]
+    pub fn test_store_multi() {
+        // return { f() }
+        let main = Proto {
+            framesize: 3,
+            instructions: vec![
+                ad("TNEW", 0, 1),
+                ad("GGET", 1, 0),
+                abc("CALL", 1, 0, 1),
+                ad("TSETM", 1, 0),
+                ad("RET1", 0, 2),
+            ],
+            kgc: vec![Kgc::Str(b"f")],
+            kn: vec![Kn::Num(f64::from_bits(0x4330_0000_0000_0001))],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let function = Function::from_prototype(dump.main(), false);
+        assert!(matches!(function.instructions()[3], Insn::Call { results: None, .. }));
+        assert!(matches!(
+            function.instructions()[4],
+            Insn::StoreMulti {
+                lhs: Place::Index(BasicOperand::Var(0), BasicOperand::UnsignedLiteral(1)),
+            }
+        ));
+        assert_eq!(function.instructions()[4].to_string(), "v0[1].. = MULTRES");
+    }
+
+    #[test]
+    pub fn test_malformed_operands() {
+        // Counts below their bias and slots below the frame parse, and lift without underflowing.
+        let lift = |instructions| {
+            let main = Proto {
+                framesize: 4,
+                instructions,
+                kn: vec![Kn::Int(1)],
+                ..Default::default()
+            };
+
+            let dump = fixture::parse(vec![main]);
+            let function = Function::from_prototype(dump.main(), false);
+            _ = function.to_lua();
+            function
+                .instructions()
+                .iter()
+                .map(|insn| insn.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(lift(vec![abc("CALL", 0, 1, 0), ad("RET", 0, 0)]), ["nop", "v0()", "return"]);
+        assert_eq!(lift(vec![ad("CALLT", 0, 0)]), ["nop", "return v0()"]);
+        assert_eq!(
+            lift(vec![ad("TSETM", 0, 0), ad("RET0", 0, 1)]),
+            ["nop", "v0[1].. = MULTRES", "return"]
+        );
+
+        // Both iteration forms, below the three slots holding the iterator, its state and control.
+        for (head, iterator, base) in [("JMP", "ITERC", 0), ("ISNEXT", "ITERN", 1)] {
+            let listing = lift(vec![
+                jump(head, base, 0, 2),
+                abc(iterator, base, 0, 0),
+                jump("ITERL", base, 2, 1),
+                ad("RET0", 0, 1),
+            ]);
+            assert_eq!(listing[2], "v0(v0, v0)");
+        }
+    }
+
+    #[test]
+    pub fn test_branch_targets() {
+        // if x < y then z = x end; goto done
+        let emitter = lift(
+            vec![
+                ad("ISGE", 0, 1),
+                jump("JMP", 3, 1, 3),
+                ad("MOV", 2, 0),
+                jump("JMP", 3, 3, 5),
+                ad("KPRI", 2, 0),
+                ad("RET0", 0, 1),
+            ],
+            vec![],
+        );
+
+        // The comparison acquires the target of the following jump; other jumps stand alone.
+        assert!(matches!(
+            emitter.instructions[0],
+            Insn::ConditionalBranch {
+                target: Label::Label { bc: 4 },
+                ..
+            }
+        ));
+        assert!(matches!(
+            emitter.instructions[2],
+            Insn::Branch {
+                target: Label::Label { bc: 6 },
+            }
+        ));
+    }
+
+    #[test]
+    pub fn test_loop_hint() {
+        // while true do x = y end
+        let emitter = lift(
+            vec![
+                jump("LOOP", 2, 0, 3),
+                ad("MOV", 0, 1),
+                jump("JMP", 2, 2, 0),
+                ad("RET0", 0, 1),
+            ],
+            vec![],
+        );
+
+        assert!(matches!(emitter.instructions[0], Insn::Nop));
+
+        // The hint neither starts nor ends a block, and does not count as an assignment.
+        let cfg = Cfg::new(&emitter);
+        let starts: Vec<_> = cfg.blocks().iter().map(|block| block.start).collect();
+        assert_eq!(starts, [0, 3]);
+        assert_eq!(cfg.successors(0), [0]);
+
+        let (def, uses) = cfg.blocks()[0].def_use();
+        assert_eq!((def.len(), uses.len()), (1, 1));
+    }
 }
@@ -0,0 +1,317 @@
+//! Local variable declarations.
+//!
+//! Unstripped dumps record the bytecode range each local variable is live in, in declaration
+//! order. A variable comes into scope right after the instructions computing its initial value,
+//! which is where `local name` belongs; this is more faithful than inferring declarations from
+//! first assignments, and keeps shadowing variables apart.
+
+use crate::lua::{
+    bytecode::{Prototype, debug::variable::Type},
+    ir::{BasicOperand, Cfg, Expr, Function, Insn, Operand, Place, Primitive, Visitor},
+};
+
+/// A `local` declaration.
+#[derive(Debug, PartialEq)]
+pub struct Declaration {
+    /// Index of the first instruction the variable is in scope for.
+    pub pc: usize,
+    /// The slot holding the variable.
+    pub slot: u32,
+    pub name: String,
+}
+
+/// Recovers the local declarations of a prototype from its debug information.
+///
+/// Parameters, the hidden control variables of `for` loops and the variables declared by a `for`
+/// header are not declared with `local`, and are not returned. Declarations are returned in scope
+/// order.
+pub fn declarations(proto: &Prototype) -> Vec<Declaration> {
+    let Some(debug) = proto.debug() else {
+        return vec![];
+    };
+
+    let variables = debug.variables();
+    let mut declarations = vec![];
+    for (index, variable) in variables.iter().enumerate() {
+        let start = variable.scope.start;
+        if index < proto.parameter_count() as usize || variable.tp != Type::String {
+            continue;
+        }
+
+        // Variables live at the same time are assigned consecutive slots in declaration order.
+        let active: Vec<_> = variables[..index]
+            .iter()
+            .filter(|other| other.scope.start <= start && start < other.scope.end)
+            .collect();
+
+        // The variables of a `for` header immediately follow its control variables.
+        let header = active
+            .iter()
+            .rev()
+            .find(|other| other.scope.start != start || other.tp != Type::String)
+            .is_some_and(|other| matches!(other.tp, Type::ForStep | Type::ForCtl));
+        if header {
+            continue;
+        }
+
+        declarations.push(Declaration {
+            pc: start as usize,
+            slot: active.len() as u32,
+            name: variable.name_lossy().into_owned(),
+        });
+    }
+
+    declarations
+}
+
+/// Finds the assignment giving a nil-initialized local its first value, so that `local x` followed
+/// by `x = value` can be written as `local x = value`.
+///
+/// Returns the index of the assignment in the IR, which must follow the `KPRI` or `KNIL` that
+/// initialized the variable in the same basic block, with the variable still in scope, neither read
+/// nor written in between, and no closure created in between that could capture it.
+///
+/// # Arguments
+///
+/// * `declaration` - The declaration, whose initial value is computed by the instruction before
+///   `pc`.
+/// * `function` - The function the variable belongs to.
+/// * `cfg` - The control-flow graph of `function`.
+pub fn deferred_initializer(declaration: &Declaration, function: &Function, cfg: &Cfg) -> Option<usize> {
+    /// Records whether an instruction reads or writes a slot.
+    struct Access {
+        slot: BasicOperand,
+        read: bool,
+        written: bool,
+    }
+
+    impl Visitor for Access {
+        fn visit_use(&mut self, operand: &BasicOperand) {
+            self.read |= *operand == self.slot;
+        }
+
+        fn visit_def(&mut self, operand: &BasicOperand) {
+            self.written |= *operand == self.slot;
+        }
+    }
+
+    let debug = function.prototype().debug()?;
+    let emitter = function.emitter();
+    let slot = BasicOperand::Var(declaration.slot);
+    let instructions = emitter.instructions();
+    let initializer =
+        (emitter.ir_index(declaration.pc.saturating_sub(1))..emitter.ir_index(declaration.pc)).find(|ir| {
+            matches!(
+                &instructions[*ir],
+                Insn::Assign {
+                    lhs: Place::Basic(lhs),
+                    rhs: Operand::Basic(BasicOperand::Pri(Primitive::Nil)),
+                } if *lhs == slot
+            )
+        })?;
+
+    let block = cfg
+        .blocks()
+        .iter()
+        .find(|block| (block.start..block.start + block.instructions.len()).contains(&initializer))?;
+
+    for (ir, insn) in (initializer + 1..).zip(&block.instructions[initializer + 1 - block.start..]) {
+        if matches!(
+            insn,
+            Insn::Assign {
+                rhs: Operand::Expr(Expr::Closure(_)),
+                ..
+            }
+        ) {
+            return None;
+        }
+
+        let mut access = Access {
+            slot,
+            read: false,
+            written: false,
+        };
+        insn.accept(&mut access);
+
+        if access.read {
+            return None;
+        } else if access.written {
+            // Conditional assignments and calls overwriting the slot are not initializers, and the
+            // slot may hold another variable once the scope of this one ended.
+            let pc = emitter.position(ir) as u32;
+            let in_scope = debug
+                .variable_name(pc, declaration.slot)
+                .is_some_and(|name| name == declaration.name);
+            return (in_scope && matches!(insn, Insn::Assign { .. })).then_some(ir);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        bytecode::{
+            Dump, LittleEndianBuffer,
+            fixture::{self, Proto, abc, ad, jump},
+        },
+        ir::{
+            Cfg, Expressions, Function, Insn,
+            locals::{Declaration, declarations, deferred_initializer},
+        },
+    };
+
+    fn build(numparams: u8, instructions: Vec<u32>, variables: Vec<(&'static str, u32, u32)>) -> Dump {
+        let main = Proto {
+            numparams,
+            framesize: 8,
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![1; instructions.len()],
+                variables,
+                ..Default::default()
+            }),
+            instructions,
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump {
+            protos: vec![main],
+            ..Default::default()
+        }
+        .build();
+        Dump::new(LittleEndianBuffer(bytes))
+    }
+
+    fn parse(numparams: u8, instructions: Vec<u32>, variables: Vec<(&'static str, u32, u32)>) -> Vec<Declaration> {
+        declarations(build(numparams, instructions, variables).main())
+    }
+
+    /// Renders the first declaration of a function as `local x = value` if it can absorb a later
+    /// assignment, or as `local x` otherwise.
+    fn render(numparams: u8, instructions: Vec<u32>, variables: Vec<(&'static str, u32, u32)>) -> String {
+        let dump = build(numparams, instructions, variables);
+        let function = Function::from_prototype(dump.main(), false);
+        let cfg = Cfg::new(function.emitter());
+        let declaration = &declarations(dump.main())[0];
+
+        match deferred_initializer(declaration, &function, &cfg) {
+            Some(ir) => {
+                let Insn::Assign { rhs, .. } = &function.instructions()[ir] else {
+                    panic!("Expected an assignment");
+                };
+                let expressions = Expressions::new(dump.main(), ir, declaration.slot + 1);
+                format!("local {} = {}", declaration.name, expressions.operand(rhs))
+            }
+            None => format!("local {}", declaration.name),
+        }
+    }
+
+    #[test]
+    pub fn test_shadowing() {
+        // function(a) do local x = a + 1; a = x end do local x = a * 2; a = x end end
+        let declarations = parse(
+            1,
+            vec![
+                abc("ADDVV", 1, 0, 0),
+                ad("MOV", 0, 1),
+                abc("MULVV", 1, 0, 0),
+                ad("MOV", 0, 1),
+                ad("RET0", 0, 1),
+            ],
+            vec![("a", 0, 6), ("x", 2, 3), ("x", 4, 5)],
+        );
+
+        assert_eq!(
+            declarations,
+            [
+                Declaration {
+                    pc: 2,
+                    slot: 1,
+                    name: "x".to_string(),
+                },
+                Declaration {
+                    pc: 4,
+                    slot: 1,
+                    name: "x".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_deferred_initializer() {
+        // local x; local y = 2; x = y
+        let deferred = render(
+            0,
+            vec![ad("KPRI", 0, 0), ad("KSHORT", 1, 2), ad("MOV", 0, 1), ad("RET0", 0, 1)],
+            vec![("x", 2, 5), ("y", 3, 5)],
+        );
+        assert_eq!(deferred, "local x = y");
+
+        // local x; local y = x; x = 1
+        let read = render(
+            0,
+            vec![ad("KPRI", 0, 0), ad("MOV", 1, 0), ad("KSHORT", 0, 1), ad("RET0", 0, 1)],
+            vec![("x", 2, 5), ("y", 3, 5)],
+        );
+        assert_eq!(read, "local x");
+
+        // function(a) local x; if a then x = 1 end end
+        let conditional = render(
+            1,
+            vec![
+                ad("KPRI", 1, 0),
+                ad("ISF", 0, 0),
+                jump("JMP", 2, 2, 4),
+                ad("KSHORT", 1, 1),
+                ad("RET0", 0, 1),
+            ],
+            vec![("a", 0, 6), ("x", 2, 6)],
+        );
+        assert_eq!(conditional, "local x");
+
+        // do local x end local y = 1
+        let scope = render(
+            0,
+            vec![ad("KPRI", 0, 0), ad("KSHORT", 0, 1), ad("RET0", 0, 1)],
+            vec![("x", 2, 2), ("y", 3, 4)],
+        );
+        assert_eq!(scope, "local x");
+    }
+
+    #[test]
+    pub fn test_for_variables() {
+        // for i = 1, 2 do local y = i end
+        let declarations = parse(
+            0,
+            vec![
+                ad("KSHORT", 0, 1),
+                ad("KSHORT", 1, 2),
+                ad("KSHORT", 2, 1),
+                jump("FORI", 0, 3, 6),
+                ad("MOV", 4, 3),
+                jump("FORL", 0, 5, 4),
+                ad("RET0", 0, 1),
+            ],
+            vec![
+                ("\u{1}", 4, 7),
+                ("\u{2}", 4, 7),
+                ("\u{3}", 4, 7),
+                ("i", 5, 7),
+                ("y", 6, 7),
+            ],
+        );
+
+        assert_eq!(
+            declarations,
+            [Declaration {
+                pc: 6,
+                slot: 4,
+                name: "y".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,373 @@
+//! Numeric `for` loop recognition.
+//!
+//! Numeric `for` loops are delimited by `FORI`, which jumps past the loop, and end with the `FORL`
+//! back-edge. Generic `for` loops start with a `JMP` (or `ISNEXT`) to their `ITERC` call, followed
+//! by the `ITERL` back-edge; they are only recognized so that their breaks are told apart from
+//! those of the loops around them. `while` and `repeat` loops are recovered by `structure`.
+//!
+//! Indices used in this module are positions in `Prototype::instructions`.
+
+use std::ops::Range;
+
+use crate::lua::{
+    ast::{Expression, FormatOptions},
+    bytecode::{Instruction, Prototype},
+    ir::{Emitter, Expressions, Insn},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LoopKind {
+    /// `for i = start, limit, step do ... end`: the header is `FORI` and the back-edge is `FORL`.
+    ///
+    /// The hidden control slots are `base` through `base + 2`, and the visible loop variable is
+    /// held in `base + 3`.
+    NumericFor { base: u8 },
+}
+
+/// A loop recovered from bytecode.
+#[derive(Debug, PartialEq)]
+pub struct Loop {
+    pub kind: LoopKind,
+    /// Index of the `FORI` instruction.
+    pub header: usize,
+    /// Index of the first instruction after the loop.
+    pub exit: usize,
+    /// Index of every `JMP` in the body that leaves the loop; each of them is a `break`.
+    pub breaks: Vec<usize>,
+}
+
+impl Loop {
+    /// Returns the range of instructions making up the body of the loop, excluding the header and
+    /// the back-edge.
+    pub fn body(&self) -> Range<usize> {
+        self.header + 1..self.exit - 1
+    }
+
+    /// Returns `true` if the branch at `pc` should be rendered as `break`.
+    pub fn is_break(&self, pc: usize) -> bool {
+        self.breaks.binary_search(&pc).is_ok()
+    }
+}
+
+/// Returns the range of instructions spanned by the loop starting at `pc`, if any.
+///
+/// Loops whose end lies past the last instruction are ignored.
+fn loop_region(instructions: &[Instruction], pc: usize) -> Option<Range<usize>> {
+    let insn = &instructions[pc];
+    let target = insn.branch_target(pc)?;
+
+    let region = match insn {
+        Instruction::LOOP { .. } | Instruction::ILOOP { .. } | Instruction::FORI { .. } | Instruction::JFORI { .. } => {
+            Some(pc..target)
+        }
+        Instruction::JMP { .. } | Instruction::ISNEXT { .. } => match instructions.get(target)?.despecialize() {
+            // The iterator call is followed by `ITERL`.
+            Instruction::ITERC { .. } => Some(pc..target + 2),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    region.filter(|region| region.end <= instructions.len())
+}
+
+/// Recognizes every numeric `for` loop in a prototype's instructions.
+///
+/// Loops are returned in header order, so outer loops precede the loops they contain.
+pub fn find_loops(instructions: &[Instruction]) -> Vec<Loop> {
+    let regions: Vec<_> = (0..instructions.len())
+        .filter_map(|pc| loop_region(instructions, pc))
+        .collect();
+
+    let mut loops = vec![];
+    for region in &regions {
+        let (header, exit) = (region.start, region.end);
+        let back_edge = exit - 1;
+        if back_edge <= header {
+            continue;
+        }
+
+        let kind = match instructions[header] {
+            Instruction::FORI { a, .. } | Instruction::JFORI { a, .. } => {
+                // `FORL` jumps back to the first instruction of the body.
+                let closed = matches!(
+                    instructions[back_edge],
+                    Instruction::FORL { .. } | Instruction::IFORL { .. } | Instruction::JFORL { .. }
+                );
+                if !closed {
+                    continue;
+                }
+
+                LoopKind::NumericFor { base: a }
+            }
+            _ => continue,
+        };
+
+        // Jumps out of a nested loop leave that loop, not this one.
+        let nested = |pc: usize| {
+            regions
+                .iter()
+                .any(|inner| inner != region && inner.start > header && inner.contains(&pc))
+        };
+
+        let breaks = (header + 1..back_edge)
+            .filter(|pc| matches!(instructions[*pc], Instruction::JMP { .. }))
+            .filter(|pc| instructions[*pc].branch_target(*pc) == Some(exit) && !nested(*pc))
+            .collect();
+
+        loops.push(Loop {
+            kind,
+            header,
+            exit,
+            breaks,
+        });
+    }
+
+    loops
+}
+
+/// The bounds of a numeric `for` loop, reconstructed as source expressions.
+#[derive(Debug, PartialEq)]
+pub struct NumericForBounds {
+    pub variable: String,
+    pub start: Expression,
+    pub limit: Expression,
+    pub step: Expression,
+}
+
+impl NumericForBounds {
+    /// Renders the first line of the loop with the given options, e.g. `for i = 1, n do`.
+    pub fn header(&self, options: &FormatOptions) -> String {
+        let (start, limit, step) = (
+            self.start.display(options),
+            self.limit.display(options),
+            self.step.display(options),
+        );
+        match self.step {
+            Expression::Integer(1) => format!("for {} = {}, {} do", self.variable, start, limit),
+            _ => format!("for {} = {}, {}, {} do", self.variable, start, limit, step),
+        }
+    }
+}
+
+/// Reconstructs the bounds of a numeric `for` loop.
+///
+/// The control slots are filled by the instructions preceding `FORI`, which may compute arbitrary
+/// expressions. These instructions are lifted from the start of the basic block containing the
+/// header, and the temporaries they define are folded into the expressions of each bound.
+///
+/// # Arguments
+///
+/// * `proto` - The prototype containing the loop.
+/// * `lp` - The loop, which must have been found in `proto`.
+/// * `gc64` - Whether the dump of the prototype was produced by a GC64 build; see `Dump::is_gc64`.
+pub fn numeric_for_bounds(proto: &Prototype, lp: &Loop, gc64: bool) -> NumericForBounds {
+    let LoopKind::NumericFor { base } = lp.kind;
+    let instructions = &proto.instructions;
+
+    // Branches and their targets delimit basic blocks.
+    let start = (0..lp.header)
+        .flat_map(|pc| {
+            let insn = &instructions[pc];
+            let leader = (insn.branch_target(pc).is_some() || insn.is_condition()).then_some(pc + 1);
+            let target = insn.branch_target(pc).filter(|target| *target <= lp.header);
+            leader.into_iter().chain(target)
+        })
+        .max()
+        .unwrap_or(0);
+
+    // `FORI` allocates its control slots at the first free slot, so every slot below is a local.
+    let mut emitter = Emitter::new();
+    let mut expressions = Expressions::new(proto, lp.header, base as u32);
+    for (pc, insn) in instructions.iter().enumerate().take(lp.header).skip(start) {
+        // Some instructions, such as `KNIL`, lift to several IR instructions, and others to none.
+        let lifted = emitter.instructions.len();
+        Insn::parse(*insn, pc, gc64, &mut emitter);
+        emitter.instructions[lifted..]
+            .iter()
+            .for_each(|insn| expressions.assign(insn));
+    }
+
+    let (start, limit, step) = (
+        expressions.slot(base as u32),
+        expressions.slot(base as u32 + 1),
+        expressions.slot(base as u32 + 2),
+    );
+
+    // The variable is named like in the body of the loop.
+    expressions.discard(base as u32 + 3);
+    expressions.seek(lp.header + 1);
+    let variable = expressions.slot(base as u32 + 3).to_string();
+
+    NumericForBounds {
+        variable,
+        start,
+        limit,
+        step,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        ast::FormatOptions,
+        bytecode::{
+            Dump, Instruction, LittleEndianBuffer,
+            fixture::{self, Kn, Proto, abc, ad, jump},
+        },
+        ir::loops::{Loop, LoopKind, find_loops, numeric_for_bounds},
+    };
+
+    fn parse(instructions: Vec<u32>) -> Vec<Instruction> {
+        let main = Proto {
+            numparams: 1,
+            framesize: 6,
+            instructions,
+            kn: vec![Kn::Int(1)],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        dump.main().instructions.clone()
+    }
+
+    #[test]
+    pub fn test_numeric_for_break() {
+        // function(x) for i = 1, 2 do if x then break end end end
+        let instructions = parse(vec![
+            ad("KSHORT", 1, 1),
+            ad("KSHORT", 2, 2),
+            ad("KSHORT", 3, 1),
+            jump("FORI", 1, 3, 7),
+            ad("IST", 0, 0),
+            jump("JMP", 5, 5, 7),
+            jump("FORL", 1, 6, 4),
+            ad("RET0", 0, 1),
+        ]);
+
+        let loops = find_loops(&instructions);
+        assert_eq!(
+            loops,
+            vec![Loop {
+                kind: LoopKind::NumericFor { base: 1 },
+                header: 4,
+                exit: 8,
+                breaks: vec![6],
+            }]
+        );
+        assert_eq!(loops[0].body(), 5..7);
+        assert!(loops[0].is_break(6));
+        assert!(!loops[0].is_break(5));
+    }
+
+    #[test]
+    pub fn test_nested_break() {
+        // for i = 1, 2 do while true do break end break end
+        let instructions = parse(vec![
+            ad("KSHORT", 1, 1),
+            ad("KSHORT", 2, 2),
+            ad("KSHORT", 3, 1),
+            jump("FORI", 1, 3, 9),
+            jump("LOOP", 5, 4, 7),
+            jump("JMP", 5, 5, 7),
+            jump("JMP", 5, 6, 4),
+            jump("JMP", 5, 7, 9),
+            jump("FORL", 1, 8, 4),
+            ad("RET0", 0, 1),
+        ]);
+
+        // The `while` loop is left to `structure`, and its break does not leave the `for` loop.
+        let loops = find_loops(&instructions);
+        assert_eq!(loops.len(), 1);
+        assert_eq!((loops[0].header, loops[0].breaks.as_slice()), (4, [8].as_slice()));
+    }
+
+    #[test]
+    pub fn test_out_of_range_exit() {
+        // A `LOOP` jumping ten instructions past the end, and a `JMP` to an `ITERC` with no `ITERL`.
+        let instructions = parse(vec![ad("LOOP", 0, 0x800A), jump("JMP", 0, 1, 2), abc("ITERC", 1, 2, 3)]);
+
+        assert!(find_loops(&instructions).is_empty());
+    }
+
+    #[test]
+    pub fn test_numeric_for_bounds() {
+        // function(a, b) for i = a, b*2 do end end
+        let main = Proto {
+            numparams: 2,
+            framesize: 6,
+            instructions: vec![
+                ad("MOV", 2, 0),
+                abc("MULVN", 3, 1, 0),
+                ad("KSHORT", 4, 1),
+                jump("FORI", 2, 3, 5),
+                jump("FORL", 2, 4, 4),
+                ad("RET0", 0, 1),
+            ],
+            kn: vec![Kn::Int(2)],
+            debug: Some(fixture::Debug {
+                firstline: 1,
+                numline: 1,
+                lines: vec![1, 1, 1, 1, 1, 1],
+                variables: vec![
+                    ("a", 0, 7),
+                    ("b", 0, 7),
+                    ("\u{1}", 4, 6),
+                    ("\u{2}", 4, 6),
+                    ("\u{3}", 4, 6),
+                    ("i", 5, 6),
+                ],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump {
+            protos: vec![main.clone()],
+            ..Default::default()
+        }
+        .build();
+        let dump = Dump::new(LittleEndianBuffer(bytes));
+        let proto = dump.main();
+
+        let loops = find_loops(&proto.instructions);
+        assert_eq!(
+            loops,
+            vec![Loop {
+                kind: LoopKind::NumericFor { base: 2 },
+                header: 4,
+                exit: 6,
+                breaks: vec![],
+            }]
+        );
+
+        let bounds = numeric_for_bounds(proto, &loops[0], false);
+        assert_eq!(bounds.header(&FormatOptions::default()), "for i = a, b * 2 do");
+
+        // Without debug information, parameters are named after their position.
+        let stripped = fixture::parse(vec![Proto { debug: None, ..main }]);
+        let bounds = numeric_for_bounds(stripped.main(), &loops[0], false);
+        assert_eq!(bounds.header(&FormatOptions::default()), "for var5 = arg0, arg1 * 2 do");
+    }
+
+    #[test]
+    pub fn test_numeric_for_nil_bounds() {
+        // for i = nil, nil, 1 do end, where both bounds come from a single KNIL
+        let main = Proto {
+            framesize: 4,
+            instructions: vec![
+                ad("KNIL", 0, 1),
+                ad("KSHORT", 2, 1),
+                jump("FORI", 0, 2, 4),
+                jump("FORL", 0, 3, 3),
+                ad("RET0", 0, 1),
+            ],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let loops = find_loops(&dump.main().instructions);
+        let bounds = numeric_for_bounds(dump.main(), &loops[0], false);
+        assert_eq!(bounds.header(&FormatOptions::default()), "for var3 = nil, nil do");
+    }
+}
@@ -1,9 +1,203 @@
-use crate::lua::bytecode::Dump;
+use std::collections::HashMap;
 
-pub struct Module {}
+use crate::lua::{
+    bytecode::{Complex, Dump},
+    ir::{Function, find_definitions},
+};
 
-impl Module {
-    pub fn new(dump: &Dump) -> Self {
-        todo!()
+/// The IR of every prototype in a dump.
+pub struct Module<'a> {
+    dump: &'a Dump,
+    /// The functions of the dump, in the order of their prototypes.
+    functions: Vec<Function<'a>>,
+    main: usize,
+}
+
+impl<'a> Module<'a> {
+    /// Lifts every prototype of a dump.
+    pub fn from_dump(dump: &'a Dump) -> Self {
+        let functions: Vec<_> = dump
+            .prototypes()
+            .map(|proto| Function::from_prototype(proto, dump.is_gc64()))
+            .collect();
+
+        Self {
+            dump,
+            main: dump.main().index,
+            functions,
+        }
+    }
+
+    /// Returns the dump the functions were lifted from.
+    pub fn dump(&self) -> &'a Dump {
+        self.dump
+    }
+
+    /// Returns the function of the top-level chunk.
+    pub fn main(&self) -> &Function<'a> {
+        &self.functions[self.main]
+    }
+
+    /// Returns every function, children first.
+    pub fn functions(&self) -> &[Function<'a>] {
+        &self.functions
+    }
+
+    /// Returns the function referred to by a constant of `function`, such as the operand of `FNEW`.
+    ///
+    /// # Arguments
+    ///
+    /// * `function` - The function the constant belongs to.
+    /// * `d` - The constant, addressed from the end of the table.
+    pub fn child(&self, function: &Function<'a>, d: u32) -> Option<&Function<'a>> {
+        match function.prototype().gc_constant(d)? {
+            Complex::Prototype(index) => self.functions.get(*index),
+            _ => None,
+        }
+    }
+
+    /// Recovers the names of the functions stored into a global or a table field right after
+    /// being created, keyed by prototype index.
+    ///
+    /// Anonymous functions, such as callbacks or local functions, have no entry.
+    pub fn function_names(&self) -> HashMap<usize, String> {
+        self.functions
+            .iter()
+            .flat_map(|function| find_definitions(self.dump, function.prototype()))
+            .map(|definition| (definition.proto, definition.name()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        bytecode::{
+            Dump, LittleEndianBuffer,
+            fixture::{self, Kgc, Proto, abc, ad},
+        },
+        ir::{Function, Module},
+    };
+
+    /// Returns the functions referred to by the constants of a function, in operand order.
+    fn children<'m, 'a>(module: &'m Module<'a>, function: &Function<'a>) -> Vec<&'m Function<'a>> {
+        (0..function.prototype().kgc.len() as u32)
+            .filter_map(|d| module.child(function, d))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_function_names() {
+        // function greet() end
+        // local t = {}
+        // function t.f() end
+        // print(function() end)
+        let child = Proto {
+            instructions: vec![ad("RET0", 0, 1)],
+            ..Default::default()
+        };
+
+        let main = Proto {
+            flags: 3,
+            framesize: 3,
+            instructions: vec![
+                ad("FNEW", 0, 0),
+                ad("GSET", 0, 1),
+                ad("TNEW", 0, 0),
+                ad("FNEW", 1, 2),
+                abc("TSETS", 1, 0, 3),
+                ad("GGET", 1, 4),
+                ad("FNEW", 2, 5),
+                abc("CALL", 1, 1, 2),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![
+                Kgc::Child,
+                Kgc::Str(b"print"),
+                Kgc::Str(b"f"),
+                Kgc::Child,
+                Kgc::Str(b"greet"),
+                Kgc::Child,
+            ],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![child.clone(), child.clone(), child, main]);
+        let names = Module::from_dump(&dump).function_names();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[&0], "greet");
+        assert_eq!(names[&1], "slot0.f");
+        assert!(!names.contains_key(&2));
+    }
+
+    #[test]
+    pub fn test_from_dump() {
+        // local function a() local function g() end return 1 end
+        // local function b() return 2 end
+        let proto = |framesize, instructions, kgc| Proto {
+            flags: 1,
+            framesize,
+            instructions,
+            kgc,
+            ..Default::default()
+        };
+
+        let g = proto(0, vec![ad("RET0", 0, 1)], vec![]);
+        let a = proto(
+            2,
+            vec![ad("FNEW", 0, 0), ad("KSHORT", 1, 1), ad("RET1", 1, 2)],
+            vec![Kgc::Child],
+        );
+        let b = proto(1, vec![ad("KSHORT", 0, 2), ad("RET1", 0, 2)], vec![]);
+
+        // Constants are written from the last one, so `b` is read first.
+        let main = Proto {
+            flags: 3,
+            framesize: 2,
+            instructions: vec![ad("FNEW", 0, 0), ad("FNEW", 1, 1), ad("RET0", 0, 1)],
+            kgc: vec![Kgc::Child, Kgc::Child],
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![g, a, b, main]);
+        let module = Module::from_dump(&dump);
+        assert_eq!(module.functions().len(), 4);
+        assert_eq!(module.main().prototype().index, 3);
+
+        let children = children(&module, module.main());
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[0].prototype().index, 1);
+        assert_eq!(children[1].prototype().index, 2);
+        assert_eq!(children[1].instructions()[2].to_string(), "return v0");
+
+        let nested = module.child(children[0], 0).unwrap();
+        assert_eq!(nested.prototype().index, 0);
+        assert!(module.child(nested, 0).is_none());
+        assert!(module.child(module.main(), 2).is_none());
+    }
+
+    #[test]
+    pub fn test_gc64_call() {
+        // print("hello"), where GC64 builds keep the frame link in the slot after the function.
+        let main = Proto {
+            framesize: 3,
+            instructions: vec![
+                ad("GGET", 0, 0),
+                ad("KSTR", 2, 1),
+                abc("CALL", 0, 1, 2),
+                ad("RET0", 0, 1),
+            ],
+            kgc: vec![Kgc::Str(b"hello"), Kgc::Str(b"print")],
+            ..Default::default()
+        };
+
+        let bytes = fixture::Dump {
+            flags: 2 | 8,
+            ..fixture::Dump::stripped(vec![main])
+        }
+        .build();
+        let dump = Dump::new(LittleEndianBuffer(bytes));
+        let module = Module::from_dump(&dump);
+        assert_eq!(module.main().to_lua(), "print(\"hello\")\n");
     }
 }
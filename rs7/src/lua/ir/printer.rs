@@ -1 +1,158 @@
+//! A textual form of IR instructions, for debugging.
+//!
+//! Constants are not resolved: operands are printed as the table they index into, e.g. `kn[2]`
+//! for a numeric constant, and `kgc[1]` for a complex constant addressed from the end of the
+//! table. Globals are printed as `_G[kgc[n]]`, and branches print the index of the bytecode
+//! instruction they target.
 
+use std::fmt;
+
+use crate::lua::ir::{BasicOperand, CmpOp, Expr, Insn, Label, Operand, Place, Primitive};
+
+impl fmt::Display for BasicOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Var(slot) => write!(f, "v{}", slot),
+            Self::Upvalue(index) => write!(f, "uv{}", index),
+            Self::UnsignedLiteral(value) => write!(f, "{}", value),
+            Self::SignedLiteral(value) => write!(f, "{}", value),
+            Self::Pri(Primitive::Nil) => f.write_str("nil"),
+            Self::Pri(Primitive::True) => f.write_str("true"),
+            Self::Pri(Primitive::False) => f.write_str("false"),
+            Self::Pri(Primitive::Invalid(value)) => write!(f, "pri[{}]", value),
+            Self::Num(index) => write!(f, "kn[{}]", index),
+            Self::Str(index) | Self::Table(index) | Self::Func(index) | Self::CData(index) => {
+                write!(f, "kgc[{}]", index)
+            }
+            Self::Global(index) => write!(f, "_G[kgc[{}]]", index),
+            Self::Branch(offset) => write!(f, "=> {}", *offset as i32 - 0x8000),
+        }
+    }
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Eq => "==",
+            Self::Ne => "~=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Binary(op, lhs, rhs) => write!(f, "{} {} {}", lhs, op, rhs),
+            Self::Add(lhs, rhs) => write!(f, "{} + {}", lhs, rhs),
+            Self::Sub(lhs, rhs) => write!(f, "{} - {}", lhs, rhs),
+            Self::Mul(lhs, rhs) => write!(f, "{} * {}", lhs, rhs),
+            Self::Div(lhs, rhs) => write!(f, "{} / {}", lhs, rhs),
+            Self::Rem(lhs, rhs) => write!(f, "{} % {}", lhs, rhs),
+            Self::Pow(lhs, rhs) => write!(f, "{} ^ {}", lhs, rhs),
+            Self::Cat(values) => {
+                let values: Vec<_> = values.iter().map(ToString::to_string).collect();
+                f.write_str(&values.join(" .. "))
+            }
+            Self::Index(table, key) => write!(f, "{}[{}]", table, key),
+            Self::Not(value) => write!(f, "not {}", value),
+            Self::Negate(value) => write!(f, "-{}", value),
+            Self::Truthy(value) => write!(f, "truthy({})", value),
+            Self::Len(value) => write!(f, "#{}", value),
+            Self::NewTable { array_size, hash_size } => write!(f, "new({}, {})", array_size, hash_size),
+            Self::DupTable(table) => write!(f, "dup({})", table),
+            Self::Closure(func) => write!(f, "closure({})", func),
+            Self::Vararg { multi: true } => f.write_str("..."),
+            Self::Vararg { multi: false } => f.write_str("(...)"),
+            Self::Select(index) => write!(f, "select({}, ...)", index + 1),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expr(expr) => expr.fmt(f),
+            Self::Basic(operand) => operand.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Place {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Basic(operand) => operand.fmt(f),
+            Self::Index(table, key) => write!(f, "{}[{}]", table, key),
+        }
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("?"),
+            Self::Label { bc } => write!(f, "{:04}", bc),
+        }
+    }
+}
+
+/// Writes a list of operands, followed by `MULTRES` if it ends with a multi-result tail.
+fn write_list(f: &mut fmt::Formatter<'_>, values: &[BasicOperand], multi: bool) -> fmt::Result {
+    let mut values: Vec<_> = values.iter().map(|value| value.to_string()).collect();
+    if multi {
+        values.push("MULTRES".to_string());
+    }
+    f.write_str(&values.join(", "))
+}
+
+impl fmt::Display for Insn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nop => f.write_str("nop"),
+            Self::Assign { lhs, rhs } => write!(f, "{} = {}", lhs, rhs),
+            Self::ConditionalAssign { cond, lhs, rhs } => write!(f, "if {} then {} = {}", cond, lhs, rhs),
+            Self::TypeCheck { value, tag, number } => {
+                write!(f, "{} {} {}", if *number { "isnum" } else { "istype" }, value, tag)
+            }
+            Self::ConditionalBranch { cond, target } => write!(f, "if {} goto {}", cond, target),
+            Self::Branch { target } => write!(f, "goto {}", target),
+            Self::NumericForInit { base, exit } => write!(f, "forprep v{} else goto {}", base, exit),
+            Self::NumericForLoop { base, body } => write!(f, "forloop v{} goto {}", base, body),
+            Self::GenericForLoop { base, body } => write!(f, "iterloop v{} goto {}", base, body),
+            Self::StoreMulti { lhs } => write!(f, "{}.. = MULTRES", lhs),
+            Self::CloseUpvalues { from } => write!(f, "close v{}", from),
+            Self::Call {
+                func,
+                args,
+                results,
+                multi,
+                ..
+            } => {
+                match results {
+                    Some(results) if results.is_empty() => {}
+                    Some(results) => write!(f, "v{}..v{} = ", results.start, results.end - 1)?,
+                    None => f.write_str("MULTRES = ")?,
+                }
+
+                write!(f, "{}(", func)?;
+                write_list(f, args, *multi)?;
+                f.write_str(")")
+            }
+            Self::TailCall { base, args, multi } => {
+                write!(f, "return v{}(", base)?;
+                write_list(f, args, *multi)?;
+                f.write_str(")")
+            }
+            Self::Return { values, multi } => {
+                f.write_str("return")?;
+                if !values.is_empty() || *multi {
+                    f.write_str(" ")?;
+                }
+                write_list(f, values, *multi)
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+
+/// A set of variable slots.
+///
+/// LuaJIT frames are at most 250 slots wide, so the set is a fixed-size bitmap. Malformed
+/// operands can still name slots beyond it: those are never part of the set.
+#[derive(Default, Copy, Clone, PartialEq, Eq)]
+pub struct SlotSet([u64; 4]);
+
+impl SlotSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a slot to the set, returning `true` if it was not present. Out of range slots are
+    /// ignored.
+    pub fn insert(&mut self, slot: u32) -> bool {
+        let Some((word, bit)) = Self::locate(slot) else {
+            return false;
+        };
+        let present = (self.0[word] & bit) != 0;
+        self.0[word] |= bit;
+        !present
+    }
+
+    /// Removes a slot from the set, returning `true` if it was present.
+    pub fn remove(&mut self, slot: u32) -> bool {
+        let Some((word, bit)) = Self::locate(slot) else {
+            return false;
+        };
+        let present = (self.0[word] & bit) != 0;
+        self.0[word] &= !bit;
+        present
+    }
+
+    pub fn contains(&self, slot: u32) -> bool {
+        Self::locate(slot).is_some_and(|(word, bit)| (self.0[word] & bit) != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| *word == 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Returns the slots present in either set.
+    pub fn union(&self, other: &Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] | other.0[i]))
+    }
+
+    /// Returns the slots present in this set but not in `other`.
+    pub fn difference(&self, other: &Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] & !other.0[i]))
+    }
+
+    /// Iterates over the slots of this set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..256).filter(|slot| self.contains(*slot))
+    }
+
+    fn locate(slot: u32) -> Option<(usize, u64)> {
+        (slot < 256).then(|| ((slot / 64) as usize, 1 << (slot % 64)))
+    }
+}
+
+impl FromIterator<u32> for SlotSet {
+    fn from_iter<T: IntoIterator<Item = u32>>(iter: T) -> Self {
+        let mut set = Self::new();
+        iter.into_iter().for_each(|slot| _ = set.insert(slot));
+        set
+    }
+}
+
+impl fmt::Debug for SlotSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
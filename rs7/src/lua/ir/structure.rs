@@ -0,0 +1,394 @@
+//! Control-flow structuring.
+//!
+//! LuaJIT compiles structured statements to a few fixed branch patterns, which this pass matches
+//! against the control-flow graph:
+//!
+//! * `if` skips its `then` block with a forward conditional branch, and a `then` block followed
+//!   by an `else` block ends with a jump over the latter.
+//! * `while` evaluates its condition at the head of the loop, leaves it with a forward conditional
+//!   branch, and ends its body with a jump back to the head. `break` jumps past that jump.
+//! * A numeric `for` loop starts with `FORI`, which skips the loop, and ends its body with `FORL`,
+//!   which jumps back to the start of the body. `break` jumps past `FORL`.
+//!
+//! A pattern is only matched if no other branch enters the instructions it spans; branches that do
+//! not fit any pattern are kept as `Goto`.
+
+use std::ops::Range;
+
+use crate::lua::ir::{Cfg, Insn, Label, cfg::branch};
+
+/// A structured statement, whose instructions are referred to by their index in the IR.
+#[derive(Debug, PartialEq)]
+pub enum StructuredStmt {
+    /// A sequence of statements.
+    Block(Vec<StructuredStmt>),
+    /// An instruction that does not branch, or only to the next instruction.
+    Insn(usize),
+    /// `if not cond then ... else ... end`, where `cond` is the condition of the branch at
+    /// `branch`, which skips the `then` block.
+    If {
+        branch: usize,
+        then: Box<StructuredStmt>,
+        otherwise: Option<Box<StructuredStmt>>,
+    },
+    /// `while not cond do ... end`.
+    While {
+        /// The instructions evaluating the condition at the head of the loop.
+        header: Range<usize>,
+        /// The branch leaving the loop if its condition holds, or `None` for `while true`.
+        exit: Option<usize>,
+        body: Box<StructuredStmt>,
+    },
+    /// `for i = start, limit, step do ... end`, whose control slots are initialized by the
+    /// `NumericForInit` at `init`.
+    NumericFor { init: usize, body: Box<StructuredStmt> },
+    /// Leaves the innermost loop; only if the condition of the branch at `branch` holds, if it is
+    /// a conditional branch.
+    Break { branch: usize },
+    /// A branch to the instruction at `target` that could not be structured.
+    Goto { branch: usize, target: usize },
+}
+
+/// The innermost loop around the instructions being structured.
+#[derive(Copy, Clone)]
+struct Enclosing {
+    /// The instruction following the loop.
+    exit: usize,
+}
+
+struct Structurer<'a> {
+    instructions: Vec<&'a Insn>,
+    /// The IR instruction each instruction may branch to.
+    targets: Vec<Option<usize>>,
+}
+
+impl Structurer<'_> {
+    /// Returns `true` if a branch outside of `range`, other than the one at `except`, targets an
+    /// instruction in it.
+    fn is_entered(&self, range: Range<usize>, except: Option<usize>) -> bool {
+        self.targets.iter().enumerate().any(|(ir, target)| {
+            Some(ir) != except && !range.contains(&ir) && target.is_some_and(|t| range.contains(&t))
+        })
+    }
+
+    fn block(&self, range: Range<usize>, enclosing: Option<Enclosing>) -> StructuredStmt {
+        let mut statements = vec![];
+        let mut ir = range.start;
+        while ir < range.end {
+            let (statement, next) = self.statement(ir, range.end, enclosing);
+            statements.push(statement);
+            ir = next;
+        }
+
+        StructuredStmt::Block(statements)
+    }
+
+    /// Structures the statement starting at `ir`, returning it along with the index of the
+    /// instruction following it.
+    fn statement(&self, ir: usize, end: usize, enclosing: Option<Enclosing>) -> (StructuredStmt, usize) {
+        if let Some(statement) = self.loop_at(ir, end).or_else(|| self.numeric_for(ir, end)) {
+            return statement;
+        }
+
+        let Some(target) = self.targets[ir] else {
+            return (StructuredStmt::Insn(ir), ir + 1);
+        };
+
+        let statement = match self.instructions[ir] {
+            Insn::Branch { .. } | Insn::ConditionalBranch { .. }
+                if enclosing.is_some_and(|enclosing| enclosing.exit == target) =>
+            {
+                StructuredStmt::Break { branch: ir }
+            }
+            Insn::ConditionalBranch { .. }
+                if ir < target && target <= end && !self.is_entered(ir + 1..target, None) =>
+            {
+                return self.conditional(ir, target, end, enclosing);
+            }
+            Insn::Branch { .. } if target == ir + 1 => StructuredStmt::Insn(ir),
+            Insn::Branch { .. } | Insn::ConditionalBranch { .. } => StructuredStmt::Goto { branch: ir, target },
+            _ => StructuredStmt::Insn(ir),
+        };
+
+        (statement, ir + 1)
+    }
+
+    /// Structures the `if` statement whose condition is evaluated by the branch at `ir`.
+    fn conditional(
+        &self,
+        ir: usize,
+        target: usize,
+        end: usize,
+        enclosing: Option<Enclosing>,
+    ) -> (StructuredStmt, usize) {
+        // The `then` block of an `if` with an `else` ends with a jump over the latter.
+        let skip = target - 1;
+        let join = match (self.instructions[skip], self.targets[skip]) {
+            (Insn::Branch { .. }, Some(join))
+                if skip > ir
+                    && target < join
+                    && join <= end
+                    && !self.is_entered(target..join, Some(ir))
+                    && !self.targets.contains(&Some(skip)) =>
+            {
+                Some(join)
+            }
+            _ => None,
+        };
+
+        match join {
+            Some(join) => {
+                let statement = StructuredStmt::If {
+                    branch: ir,
+                    then: Box::new(self.block(ir + 1..skip, enclosing)),
+                    otherwise: Some(Box::new(self.block(target..join, enclosing))),
+                };
+                (statement, join)
+            }
+            None => {
+                let statement = StructuredStmt::If {
+                    branch: ir,
+                    then: Box::new(self.block(ir + 1..target, enclosing)),
+                    otherwise: None,
+                };
+                (statement, target)
+            }
+        }
+    }
+
+    /// Structures the loop whose head is the instruction at `head`, if any.
+    fn loop_at(&self, head: usize, end: usize) -> Option<(StructuredStmt, usize)> {
+        // The body ends with the last jump back to the head.
+        let back = (head..end)
+            .rev()
+            .find(|ir| matches!(self.instructions[*ir], Insn::Branch { .. }) && self.targets[*ir] == Some(head))?;
+        let exit = back + 1;
+        if self.is_entered(head + 1..exit, None) {
+            return None;
+        }
+
+        // The condition is evaluated before the first branch of the loop.
+        let first = (head..back).find(|ir| self.targets[*ir].is_some());
+        let condition = first.filter(|ir| {
+            matches!(self.instructions[*ir], Insn::ConditionalBranch { .. }) && self.targets[*ir] == Some(exit)
+        });
+
+        let enclosing = Some(Enclosing { exit });
+        let statement = match condition {
+            Some(branch) => StructuredStmt::While {
+                header: head..branch,
+                exit: Some(branch),
+                body: Box::new(self.block(branch + 1..back, enclosing)),
+            },
+            None => StructuredStmt::While {
+                header: head..head,
+                exit: None,
+                body: Box::new(self.block(head..back, enclosing)),
+            },
+        };
+
+        Some((statement, exit))
+    }
+
+    /// Structures the numeric `for` loop initialized by the instruction at `init`, if any.
+    fn numeric_for(&self, init: usize, end: usize) -> Option<(StructuredStmt, usize)> {
+        if !matches!(self.instructions[init], Insn::NumericForInit { .. }) {
+            return None;
+        }
+
+        let exit = self.targets[init].filter(|exit| init + 1 < *exit && *exit <= end)?;
+        let back = exit - 1;
+        let closed =
+            matches!(self.instructions[back], Insn::NumericForLoop { .. }) && self.targets[back] == Some(init + 1);
+        if !closed || self.is_entered(init + 1..exit, None) {
+            return None;
+        }
+
+        let statement = StructuredStmt::NumericFor {
+            init,
+            body: Box::new(self.block(init + 1..back, Some(Enclosing { exit }))),
+        };
+        Some((statement, exit))
+    }
+}
+
+/// Recovers the structured statements of the instructions of a control-flow graph.
+///
+/// Returns a `StructuredStmt::Block` holding every instruction of the graph.
+pub fn structure(cfg: &Cfg) -> StructuredStmt {
+    let blocks = cfg.blocks();
+    let instructions: Vec<_> = blocks.iter().flat_map(|block| block.instructions).collect();
+
+    // Branches end their block: the target is the successor that is not the next block.
+    let mut targets = vec![None; instructions.len()];
+    for (id, block) in blocks.iter().enumerate() {
+        let Some(last) = block.instructions.last() else {
+            continue;
+        };
+
+        let (Some(Label::Label { .. }), fallthrough) = branch(last) else {
+            continue;
+        };
+
+        let successor = match (fallthrough, cfg.successors(id)) {
+            (true, [_, target]) | (true, [target]) | (false, [target]) => Some(*target),
+            _ => None,
+        };
+        targets[block.start + block.instructions.len() - 1] = successor.map(|successor| blocks[successor].start);
+    }
+
+    let structurer = Structurer { instructions, targets };
+    structurer.block(0..structurer.instructions.len(), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lua::{
+        bytecode::fixture::{self, Proto, abc, ad, jump},
+        ir::{Cfg, Function, StructuredStmt, structure},
+    };
+
+    fn structured(numparams: u8, instructions: Vec<u32>) -> StructuredStmt {
+        let main = Proto {
+            numparams,
+            framesize: 3,
+            instructions,
+            ..Default::default()
+        };
+
+        let dump = fixture::parse(vec![main]);
+        let function = Function::from_prototype(dump.main(), false);
+        structure(&Cfg::new(function.emitter()))
+    }
+
+    fn block(statements: Vec<StructuredStmt>) -> Box<StructuredStmt> {
+        Box::new(StructuredStmt::Block(statements))
+    }
+
+    #[test]
+    pub fn test_if_else() {
+        // function(x, y) local z; if x < y then z = 1 else z = 2 end return z end
+        let statement = structured(
+            2,
+            vec![
+                ad("ISGE", 0, 1),
+                jump("JMP", 3, 1, 4),
+                ad("KSHORT", 2, 1),
+                jump("JMP", 3, 3, 5),
+                ad("KSHORT", 2, 2),
+                ad("RET1", 2, 2),
+            ],
+        );
+
+        assert_eq!(
+            statement,
+            StructuredStmt::Block(vec![
+                StructuredStmt::Insn(0),
+                StructuredStmt::If {
+                    branch: 1,
+                    then: block(vec![StructuredStmt::Insn(2)]),
+                    otherwise: Some(block(vec![StructuredStmt::Insn(4)])),
+                },
+                StructuredStmt::Insn(5),
+            ])
+        );
+    }
+
+    #[test]
+    pub fn test_while() {
+        // function(x) while x do x = f(x) if x then break end end end
+        let statement = structured(
+            1,
+            vec![
+                ad("ISF", 0, 0),
+                jump("JMP", 1, 1, 10),
+                ad("LOOP", 1, 0),
+                ad("GGET", 1, 0),
+                ad("MOV", 2, 0),
+                abc("CALL", 1, 2, 2),
+                ad("MOV", 0, 1),
+                ad("IST", 0, 0),
+                jump("JMP", 1, 8, 10),
+                jump("JMP", 1, 9, 0),
+                ad("RET0", 0, 1),
+            ],
+        );
+
+        // The `JMP` following each test is merged into it.
+        let body = (2..7)
+            .map(StructuredStmt::Insn)
+            .chain([StructuredStmt::Break { branch: 7 }]);
+        assert_eq!(
+            statement,
+            StructuredStmt::Block(vec![
+                StructuredStmt::Insn(0),
+                StructuredStmt::While {
+                    header: 1..1,
+                    exit: Some(1),
+                    body: block(body.collect()),
+                },
+                StructuredStmt::Insn(9),
+            ])
+        );
+    }
+
+    #[test]
+    pub fn test_numeric_for() {
+        // function(x) for i = 1, 2 do if x then break end end end
+        let statement = structured(
+            1,
+            vec![
+                ad("KSHORT", 1, 1),
+                ad("KSHORT", 2, 2),
+                ad("KSHORT", 3, 1),
+                jump("FORI", 1, 3, 7),
+                ad("IST", 0, 0),
+                jump("JMP", 5, 5, 7),
+                jump("FORL", 1, 6, 4),
+                ad("RET0", 0, 1),
+            ],
+        );
+
+        assert_eq!(
+            statement,
+            StructuredStmt::Block(vec![
+                StructuredStmt::Insn(0),
+                StructuredStmt::Insn(1),
+                StructuredStmt::Insn(2),
+                StructuredStmt::Insn(3),
+                StructuredStmt::NumericFor {
+                    init: 4,
+                    body: block(vec![StructuredStmt::Break { branch: 5 }]),
+                },
+                StructuredStmt::Insn(7),
+            ])
+        );
+    }
+
+    #[test]
+    pub fn test_goto() {
+        // function(x) if x then goto test end repeat x = 1 ::test:: until x end
+        let statement = structured(
+            1,
+            vec![
+                ad("IST", 0, 0),
+                jump("JMP", 1, 1, 3),
+                ad("KSHORT", 0, 1),
+                ad("ISF", 0, 0),
+                jump("JMP", 1, 4, 2),
+                ad("RET0", 0, 1),
+            ],
+        );
+
+        assert_eq!(
+            statement,
+            StructuredStmt::Block(vec![
+                StructuredStmt::Insn(0),
+                StructuredStmt::Goto { branch: 1, target: 3 },
+                StructuredStmt::Insn(2),
+                StructuredStmt::Goto { branch: 3, target: 2 },
+                StructuredStmt::Insn(4),
+            ])
+        );
+    }
+}
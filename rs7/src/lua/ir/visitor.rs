@@ -0,0 +1,113 @@
+use crate::lua::ir::{BasicOperand, Expr, Insn, Operand, Place};
+
+/// Walks the operands of IR instructions.
+///
+/// Operands are visited in evaluation order: every operand an instruction reads is visited before
+/// the operand it writes.
+pub trait Visitor {
+    /// Called for every operand read by an instruction.
+    fn visit_use(&mut self, _operand: &BasicOperand) {}
+
+    /// Called for the operand written by an instruction.
+    fn visit_def(&mut self, _operand: &BasicOperand) {}
+}
+
+impl Expr {
+    fn accept(&self, visitor: &mut impl Visitor) {
+        match self {
+            Expr::Binary(_, lhs, rhs)
+            | Expr::Add(lhs, rhs)
+            | Expr::Sub(lhs, rhs)
+            | Expr::Mul(lhs, rhs)
+            | Expr::Div(lhs, rhs)
+            | Expr::Rem(lhs, rhs)
+            | Expr::Pow(lhs, rhs)
+            | Expr::Index(lhs, rhs) => {
+                visitor.visit_use(lhs);
+                visitor.visit_use(rhs);
+            }
+            Expr::Cat(values) => values.iter().for_each(|value| visitor.visit_use(value)),
+            Expr::Not(value)
+            | Expr::Negate(value)
+            | Expr::Truthy(value)
+            | Expr::Len(value)
+            | Expr::DupTable(value)
+            | Expr::Closure(value) => visitor.visit_use(value),
+            Expr::NewTable { .. } | Expr::Vararg { .. } | Expr::Select(_) => {}
+        }
+    }
+}
+
+impl Operand {
+    fn accept(&self, visitor: &mut impl Visitor) {
+        match self {
+            Operand::Expr(expr) => expr.accept(visitor),
+            Operand::Basic(operand) => visitor.visit_use(operand),
+        }
+    }
+}
+
+impl Place {
+    fn accept(&self, visitor: &mut impl Visitor) {
+        match self {
+            Place::Basic(operand) => visitor.visit_def(operand),
+            // Storing into a table reads both the table and the key.
+            Place::Index(table, key) => {
+                visitor.visit_use(table);
+                visitor.visit_use(key);
+            }
+        }
+    }
+}
+
+impl Insn {
+    /// Visits the operands of this instruction.
+    pub fn accept(&self, visitor: &mut impl Visitor) {
+        match self {
+            Insn::Nop => {}
+            Insn::Assign { lhs, rhs } => {
+                rhs.accept(visitor);
+                lhs.accept(visitor);
+            }
+            // The destination may not be written, but is treated as if it always was.
+            Insn::ConditionalAssign { cond, lhs, rhs } => {
+                cond.accept(visitor);
+                rhs.accept(visitor);
+                lhs.accept(visitor);
+            }
+            Insn::StoreMulti { lhs } => lhs.accept(visitor),
+            Insn::TypeCheck { value, .. } => visitor.visit_use(value),
+            Insn::ConditionalBranch { cond, .. } => cond.accept(visitor),
+            // Closing upvalues does not change the values of slots.
+            Insn::Branch { .. } | Insn::CloseUpvalues { .. } => {}
+            Insn::NumericForInit { base, .. } => {
+                (0..3).for_each(|i| visitor.visit_use(&BasicOperand::Var(base + i)));
+                visitor.visit_def(&BasicOperand::Var(base + 3));
+            }
+            Insn::NumericForLoop { base, .. } => {
+                (0..3).for_each(|i| visitor.visit_use(&BasicOperand::Var(base + i)));
+                visitor.visit_def(&BasicOperand::Var(*base));
+                visitor.visit_def(&BasicOperand::Var(base + 3));
+            }
+            Insn::GenericForLoop { base, .. } => {
+                visitor.visit_use(&BasicOperand::Var(*base));
+                visitor.visit_def(&BasicOperand::Var(base.saturating_sub(1)));
+            }
+            Insn::Call {
+                func, args, results, ..
+            } => {
+                visitor.visit_use(func);
+                args.iter().for_each(|arg| visitor.visit_use(arg));
+                results
+                    .iter()
+                    .flat_map(Clone::clone)
+                    .for_each(|slot| visitor.visit_def(&BasicOperand::Var(slot)));
+            }
+            Insn::TailCall { base, args, .. } => {
+                visitor.visit_use(&BasicOperand::Var(*base));
+                args.iter().for_each(|arg| visitor.visit_use(arg));
+            }
+            Insn::Return { values, .. } => values.iter().for_each(|value| visitor.visit_use(value)),
+        }
+    }
+}
@@ -1,6 +1,3 @@
-mod lua;
-mod utils;
-
 fn main() {
     println!("Hello, world!");
 }
@@ -1,14 +1,49 @@
-use std::ops::BitOrAssign;
+use std::{fmt, ops::BitOrAssign};
 
-use bytes::Buf;
+use bytes::{Buf, BufMut};
 use num::Zero;
 
+/// An error raised when a variable-length integer cannot be read.
+#[derive(Debug, PartialEq)]
+pub enum LebError {
+    /// The buffer ends before the last byte of the integer.
+    Truncated,
+    /// The integer has more continuation bytes than its type can hold.
+    Overlong,
+}
+
+impl fmt::Display for LebError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "buffer ends within a variable-length integer"),
+            Self::Overlong => write!(f, "variable-length integer overflows its type"),
+        }
+    }
+}
+
+impl std::error::Error for LebError {}
+
 pub trait ReadVar: Buf {
+    /// Reads a LEB128 integer, panicking if the buffer is truncated or the encoding overflows `T`.
     fn read_leb<T: ReadVarImpl<T>>(&mut self) -> T;
+
+    /// Reads a LEB128 integer, failing if the buffer is truncated or the encoding overflows `T`.
+    fn try_read_leb<T: ReadVarImpl<T>>(&mut self) -> Result<T, LebError>;
+}
+
+pub trait ReadVarImpl<T>: Zero + BitOrAssign<Self> {
+    fn read(data: &mut impl Buf) -> Result<T, LebError>;
 }
 
-pub(crate) trait ReadVarImpl<T>: Zero + BitOrAssign<Self> {
-    fn read(data: &mut impl Buf) -> T;
+/// Reads the next byte of an integer of `bits` bits, of which `shift` have already been read.
+fn next_byte(data: &mut impl Buf, shift: u32, bits: u32) -> Result<u8, LebError> {
+    if shift >= bits {
+        Err(LebError::Overlong)
+    } else if !data.has_remaining() {
+        Err(LebError::Truncated)
+    } else {
+        Ok(data.get_u8())
+    }
 }
 
 // https://github.com/rust-lang/rust/blob/30f74ff0dc4d66debc8b50724c446f817e5f75f4/compiler/rustc_serialize/src/leb128.rs
@@ -16,19 +51,19 @@ macro_rules! impl_unsigned {
     ($($t:ty),*) => {
         $(
             impl ReadVarImpl<$t> for $t {
-                fn read(data: &mut impl Buf) -> $t {
-                    let byte = data.get_u8();
+                fn read(data: &mut impl Buf) -> Result<$t, LebError> {
+                    let byte = next_byte(data, 0, <$t>::BITS)?;
                     if (byte & 0x80) == 0 {
-                        return byte as $t;
+                        return Ok(byte as $t);
                     }
 
                     let mut result = (byte & 0x7F) as $t;
                     let mut shift = 7;
                     loop {
-                        let byte = data.get_u8();
+                        let byte = next_byte(data, shift, <$t>::BITS)?;
 
                         if (byte & 0x80) == 0 {
-                            return result | (byte as $t) << shift;
+                            return Ok(result | (byte as $t) << shift);
                         } else {
                             result |= ((byte & 0x7F) as $t) << shift;
                         }
@@ -44,13 +79,13 @@ macro_rules! impl_signed {
     ($($t:ty),*) => {
         $(
             impl ReadVarImpl<$t> for $t {
-                fn read(data: &mut impl Buf) -> $t {
+                fn read(data: &mut impl Buf) -> Result<$t, LebError> {
                     let mut result = 0;
                     let mut shift = 0;
                     let mut byte;
 
                     loop {
-                        byte = data.get_u8();
+                        byte = next_byte(data, shift, <$t>::BITS)?;
                         result |= ((byte & 0x7F) as $t) << shift;
                         shift += 7;
 
@@ -64,7 +99,7 @@ macro_rules! impl_signed {
                         result |= (!0 << shift);
                     }
 
-                    result
+                    Ok(result)
                 }
             }
         )*
@@ -73,9 +108,149 @@ macro_rules! impl_signed {
 
 impl<S: Buf> ReadVar for S {
     fn read_leb<T: ReadVarImpl<T>>(&mut self) -> T {
+        match self.try_read_leb() {
+            Ok(value) => value,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    fn try_read_leb<T: ReadVarImpl<T>>(&mut self) -> Result<T, LebError> {
         T::read(self)
     }
 }
 
 impl_unsigned!(u8, u16, u32, u64, u128, usize);
 impl_signed!(i8, i16, i32, i64, i128, isize);
+
+pub trait WriteVar: BufMut {
+    /// Writes a LEB128 integer.
+    fn write_leb<T: WriteVarImpl>(&mut self, value: T);
+}
+
+pub trait WriteVarImpl {
+    fn write(self, out: &mut impl BufMut);
+}
+
+macro_rules! impl_write_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl WriteVarImpl for $t {
+                fn write(mut self, out: &mut impl BufMut) {
+                    loop {
+                        let byte = (self & 0x7F) as u8;
+                        self >>= 7;
+                        if self == 0 {
+                            out.put_u8(byte);
+                            return;
+                        }
+
+                        out.put_u8(byte | 0x80);
+                    }
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_write_signed {
+    ($($t:ty),*) => {
+        $(
+            impl WriteVarImpl for $t {
+                fn write(mut self, out: &mut impl BufMut) {
+                    loop {
+                        let byte = (self & 0x7F) as u8;
+                        // Arithmetic shift, so that negative values converge to -1.
+                        self >>= 7;
+
+                        // Stop once the remaining bits are all copies of the sign bit of this byte.
+                        if (self == 0 && (byte & 0x40) == 0) || (self == -1 && (byte & 0x40) != 0) {
+                            out.put_u8(byte);
+                            return;
+                        }
+
+                        out.put_u8(byte | 0x80);
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl<S: BufMut> WriteVar for S {
+    fn write_leb<T: WriteVarImpl>(&mut self, value: T) {
+        value.write(self)
+    }
+}
+
+impl_write_unsigned!(u8, u16, u32, u64, u128, usize);
+impl_write_signed!(i8, i16, i32, i64, i128, isize);
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::utils::{LebError, ReadVar, WriteVar};
+
+    macro_rules! assert_round_trip {
+        ($($t:ty: [$($value:expr),*]),*) => {
+            $($(
+                let mut out = BytesMut::new();
+                out.write_leb::<$t>($value);
+                assert_eq!(out.freeze().read_leb::<$t>(), $value, "{} {}", stringify!($t), $value);
+            )*)*
+        };
+    }
+
+    #[test]
+    pub fn test_read_leb() {
+        assert_eq!((&[0xE5, 0x8E, 0x26][..]).read_leb::<u32>(), 624485);
+        assert_eq!((&[0xC0, 0xBB, 0x78][..]).read_leb::<i32>(), -123456);
+        assert_eq!((&[0xFF, 0x01][..]).read_leb::<u8>(), 0xFF);
+    }
+
+    #[test]
+    pub fn test_truncated() {
+        assert_eq!((&[][..]).try_read_leb::<u32>(), Err(LebError::Truncated));
+        assert_eq!((&[0xE5, 0x8E][..]).try_read_leb::<u32>(), Err(LebError::Truncated));
+        assert_eq!((&[0xC0, 0xBB][..]).try_read_leb::<i32>(), Err(LebError::Truncated));
+    }
+
+    #[test]
+    pub fn test_overlong() {
+        assert_eq!((&[0x80, 0x80, 0x01][..]).try_read_leb::<u8>(), Err(LebError::Overlong));
+        assert_eq!((&[0x80; 6][..]).try_read_leb::<u32>(), Err(LebError::Overlong));
+        assert_eq!((&[0xFF; 10][..]).try_read_leb::<u32>(), Err(LebError::Overlong));
+        assert_eq!((&[0x80, 0x80, 0x00][..]).try_read_leb::<i8>(), Err(LebError::Overlong));
+
+        // The last byte the type can hold is accepted, even if it carries extra bits.
+        assert_eq!((&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F][..]).try_read_leb::<u32>(), Ok(u32::MAX));
+    }
+
+    #[test]
+    pub fn test_write_leb() {
+        let mut out = BytesMut::new();
+        out.write_leb(624485u32);
+        out.write_leb(-123456i32);
+        out.write_leb(0x40u8);
+        out.write_leb(0x40i8);
+        assert_eq!(&out[..], &[0xE5, 0x8E, 0x26, 0xC0, 0xBB, 0x78, 0x40, 0xC0, 0x00]);
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        assert_round_trip!(
+            u8: [0, 1, 0x7F, 0x80, u8::MAX],
+            u16: [0, 0x7F, 0x80, u16::MAX],
+            u32: [0, 0x7F, 0x80, u32::MAX],
+            u64: [0, 0x7F, 0x80, u64::MAX],
+            u128: [0, 0x7F, 0x80, u128::MAX],
+            usize: [0, 0x7F, 0x80, usize::MAX],
+            i8: [0, -1, 0x3F, 0x40, -0x40, -0x41, i8::MIN, i8::MAX],
+            i16: [0, -1, 0x3F, 0x40, -0x40, -0x41, i16::MIN, i16::MAX],
+            i32: [0, -1, 0x3F, 0x40, -0x40, -0x41, i32::MIN, i32::MAX],
+            i64: [0, -1, 0x3F, 0x40, -0x40, -0x41, i64::MIN, i64::MAX],
+            i128: [0, -1, 0x3F, 0x40, -0x40, -0x41, i128::MIN, i128::MAX],
+            isize: [0, -1, 0x3F, 0x40, -0x40, -0x41, isize::MIN, isize::MAX]
+        );
+    }
+}